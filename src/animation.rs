@@ -0,0 +1,100 @@
+use crate::tracer::*;
+
+pub enum SceneChange {
+    Add(Box<dyn Hit>),
+    Replace(ObjectId, Box<dyn Hit>),
+    Remove(ObjectId),
+}
+
+//Changes turning one animation frame into the next
+pub struct SceneDiff {
+    pub(crate) changes: Vec<SceneChange>,
+}
+
+impl Default for SceneDiff {
+    fn default() -> SceneDiff {
+        SceneDiff::new()
+    }
+}
+
+impl SceneDiff {
+    pub fn new() -> SceneDiff {
+        SceneDiff {
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, obj: Box<dyn Hit>) {
+        self.changes.push(SceneChange::Add(obj));
+    }
+
+    pub fn replace(&mut self, id: ObjectId, obj: Box<dyn Hit>) {
+        self.changes.push(SceneChange::Replace(id, obj));
+    }
+
+    pub fn remove(&mut self, id: ObjectId) {
+        self.changes.push(SceneChange::Remove(id));
+    }
+
+    //Update the scene in place, only touching the changed objects.
+    //Returns the handles of newly added objects in the order they were added.
+    pub fn apply(self, scene: &mut Scene) -> Vec<ObjectId> {
+        let mut added = Vec::new();
+        for change in self.changes {
+            match change {
+                SceneChange::Add(obj) => added.push(scene.add(obj)),
+                SceneChange::Replace(id, obj) => {
+                    scene.replace(id, obj);
+                }
+                SceneChange::Remove(id) => {
+                    scene.remove(id);
+                }
+            }
+        }
+        added
+    }
+}
+
+//Sampler seed of a frame, so noise changes from frame to frame instead of sticking to the image
+//while the sequence stays reproducible from the base seed
+pub fn frame_seed(seed: u64, frame: usize) -> u64 {
+    //splitmix64 finalizer
+    let mut h = seed ^ (frame as u64).wrapping_mul(0x9E3779B97F4A7C15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D049BB133111EB);
+    h ^ (h >> 31)
+}
+
+//Base scene plus one diff per frame, frames are produced by applying the diffs in sequence
+pub struct Animation {
+    pub scene: Scene,
+    frames: std::vec::IntoIter<SceneDiff>,
+    frame: usize,
+}
+
+impl Animation {
+    pub fn new(scene: Scene, diffs: Vec<SceneDiff>) -> Animation {
+        Animation {
+            scene,
+            frames: diffs.into_iter(),
+            frame: 0,
+        }
+    }
+
+    #[inline]
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    //Advance to the next frame, returns false once all diffs were applied
+    pub fn advance(&mut self) -> bool {
+        match self.frames.next() {
+            Some(diff) => {
+                diff.apply(&mut self.scene);
+                self.frame += 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,683 @@
+use crate::error::*;
+use crate::linalg::*;
+use crate::sampler::*;
+use crate::tracer::*;
+
+//Named, defaulted and validated alternatives to the camera constructors and Renderer::new(),
+//errors name the offending parameter instead of producing NaN rays later on
+
+pub struct CameraBuilder {
+    look_from: Vec3,
+    look_at: Vec3,
+    width: usize,
+    height: usize,
+    fov: fVec,
+    aperture: fVec,
+    bokeh: Bokeh,
+    shift: (fVec, fVec),
+    tilt: (fVec, fVec),
+    //Distance to look_at if not set
+    focus_distance: Option<fVec>,
+    up: Vec3,
+    roll: fVec,
+    //Film width and height, square pixels and 36 wide if not set
+    film_back: Option<(fVec, fVec)>,
+    //Replaces fov if set
+    focal_length: Option<fVec>,
+    //Replaces shift if set
+    film_offset: Option<(fVec, fVec)>,
+    overscan: (usize, usize),
+}
+
+impl Camera {
+    pub fn builder(look_from: Vec3, look_at: Vec3) -> CameraBuilder {
+        CameraBuilder {
+            look_from,
+            look_at,
+            width: 640,
+            height: 360,
+            fov: 45.0,
+            aperture: 0.0,
+            bokeh: Bokeh::default(),
+            shift: (0.0, 0.0),
+            tilt: (0.0, 0.0),
+            focus_distance: None,
+            up: Vec3::unit_y(),
+            roll: 0.0,
+            film_back: None,
+            focal_length: None,
+            film_offset: None,
+            overscan: (0, 0),
+        }
+    }
+
+    //Exactly the viewpoint of an imported camera to world matrix, see camera_matrix_pose(). In
+    //focus one unit in front of the lens unless the focus distance is set.
+    pub fn builder_from_matrix(matrix: [[fVec; 4]; 4]) -> CameraBuilder {
+        let (look_from, look_at, up) = camera_matrix_pose(matrix);
+        Camera::builder(look_from, look_at).up(up)
+    }
+}
+
+impl CameraBuilder {
+    pub fn resolution(mut self, width: usize, height: usize) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    //Horizontal field of view in degrees
+    pub fn fov(mut self, degrees: fVec) -> Self {
+        self.fov = degrees;
+        self
+    }
+
+    //Lens radius, 0 for a pinhole camera with everything in focus
+    pub fn aperture(mut self, radius: fVec) -> Self {
+        self.aperture = radius;
+        self
+    }
+
+    //Straight aperture blades shaping out of focus highlights into a polygon, turned by rotation
+    //degrees clockwise. 0 blades for a round opening.
+    pub fn blades(mut self, count: u32, rotation: fVec) -> Self {
+        self.bokeh.blades = count;
+        self.bokeh.rotation = rotation;
+        self
+    }
+
+    //Squash out of focus highlights towards the film center at the edges, 0 to 1, see Bokeh
+    pub fn cat_eye(mut self, strength: fVec) -> Self {
+        self.bokeh.cat_eye = strength;
+        self
+    }
+
+    pub fn focus_distance(mut self, distance: fVec) -> Self {
+        self.focus_distance = Some(distance);
+        self
+    }
+
+    //Move the image right and up by fractions of its width without turning the camera, for
+    //architectural shots that keep verticals parallel
+    pub fn shift(mut self, x: fVec, y: fVec) -> Self {
+        self.shift = (x, y);
+        self
+    }
+
+    //Turn the plane of focus by degrees around the right and up axes, for focus along a receding
+    //surface or a miniature look. Positive moves its top and right side further away.
+    pub fn tilt(mut self, x: fVec, y: fVec) -> Self {
+        self.tilt = (x, y);
+        self
+    }
+
+    //Focus on the surface seen through the center of the image, look_at stays in focus if
+    //nothing is there
+    pub fn autofocus(mut self, scene: &Scene) -> Self {
+        if let Some(distance) = scene.autofocus(self.look_from, self.look_at) {
+            self.focus_distance = Some(distance);
+        }
+        self
+    }
+
+    //Size of the film, usually in millimeters as in camera metadata. Its aspect ratio wins over
+    //the one of the resolution, pixels are stretched to fit.
+    pub fn film_back(mut self, width: fVec, height: fVec) -> Self {
+        self.film_back = Some((width, height));
+        self
+    }
+
+    //Field of view from the focal length, in the unit of the film back
+    pub fn focal_length(mut self, length: fVec) -> Self {
+        self.focal_length = Some(length);
+        self
+    }
+
+    //Shift in the unit of the film back instead of film widths, like the film offset or
+    //principal point of camera metadata
+    pub fn film_offset(mut self, x: fVec, y: fVec) -> Self {
+        self.film_offset = Some((x, y));
+        self
+    }
+
+    //Pixels of the resolution on each side that show more of the scene around the frame, which
+    //is what fov and the film back describe. For lens distortion and effects that need a margin.
+    pub fn overscan(mut self, x: usize, y: usize) -> Self {
+        self.overscan = (x, y);
+        self
+    }
+
+    //Closest direction to look_at from look_from that ends up at the top of the image, +y if
+    //not set. Up parallel to the view direction is replaced by a world axis.
+    pub fn up(mut self, up: Vec3) -> Self {
+        self.up = up;
+        self
+    }
+
+    //Degrees to turn the camera clockwise around the view direction
+    pub fn roll(mut self, degrees: fVec) -> Self {
+        self.roll = degrees;
+        self
+    }
+
+    pub fn build(self) -> Result<Camera> {
+        if self.width == 0 || self.height == 0 {
+            return Err(Error::invalid_parameter("camera resolution", format!("{}x{} is empty", self.width, self.height)));
+        }
+        let (overscan_x, overscan_y) = self.overscan;
+        if 2 * overscan_x >= self.width || 2 * overscan_y >= self.height {
+            return Err(Error::invalid_parameter(
+                "camera overscan",
+                format!("{:?} leaves nothing of the {}x{} image", self.overscan, self.width, self.height),
+            ));
+        }
+        let (frame_width, frame_height) = (self.width - 2 * overscan_x, self.height - 2 * overscan_y);
+        let (film_width, film_height) = self.film_back.unwrap_or((36.0, 36.0 * frame_height as fVec / frame_width as fVec));
+        if !(film_width > 0.0 && film_width.is_finite() && film_height > 0.0 && film_height.is_finite()) {
+            return Err(Error::invalid_parameter("camera film back", format!("{:?} must be positive", (film_width, film_height))));
+        }
+        let fov = match self.focal_length {
+            Some(length) if length > 0.0 && length.is_finite() => 2.0 * (film_width / (2.0 * length)).atan().to_degrees(),
+            Some(length) => return Err(Error::invalid_parameter("camera focal length", format!("{} must be positive", length))),
+            None => self.fov,
+        };
+        let shift = match self.film_offset {
+            Some((x, y)) => (x / film_width, y / film_width),
+            None => self.shift,
+        };
+        if !(fov > 0.0 && fov < 180.0) {
+            return Err(Error::invalid_parameter("camera fov", format!("{} must be between 0 and 180 degrees", fov)));
+        }
+        if !(self.aperture >= 0.0 && self.aperture.is_finite()) {
+            return Err(Error::invalid_parameter("camera aperture", format!("{} must be finite and not negative", self.aperture)));
+        }
+        if self.bokeh.blades > 0 && self.bokeh.blades < 3 {
+            return Err(Error::invalid_parameter("camera blades", format!("{} must be 0 or at least 3", self.bokeh.blades)));
+        }
+        if !self.bokeh.rotation.is_finite() {
+            return Err(Error::invalid_parameter("camera blade rotation", format!("{} must be finite", self.bokeh.rotation)));
+        }
+        if !(0.0..=1.0).contains(&self.bokeh.cat_eye) {
+            return Err(Error::invalid_parameter("camera cat eye", format!("{} must be between 0 and 1", self.bokeh.cat_eye)));
+        }
+        let view = self.look_at - self.look_from;
+        if view.length() == 0.0 || !view.length().is_finite() {
+            return Err(Error::invalid_parameter("camera look_at", "must differ from look_from"));
+        }
+        if !(self.up.length() > 0.0 && self.up.length().is_finite()) {
+            return Err(Error::invalid_parameter("camera up", "must be a finite, non-zero vector"));
+        }
+        if !self.roll.is_finite() {
+            return Err(Error::invalid_parameter("camera roll", format!("{} must be finite", self.roll)));
+        }
+        if !(shift.0.is_finite() && shift.1.is_finite()) {
+            return Err(Error::invalid_parameter("camera shift", format!("{:?} must be finite", shift)));
+        }
+        if !(self.tilt.0.abs() < 90.0 && self.tilt.1.abs() < 90.0) {
+            return Err(Error::invalid_parameter("camera tilt", format!("{:?} must be between -90 and 90 degrees", self.tilt)));
+        }
+        let focus_distance = self.focus_distance.unwrap_or(view.length());
+        if !(focus_distance > 0.0 && focus_distance.is_finite()) {
+            return Err(Error::invalid_parameter("camera focus distance", format!("{} must be positive", focus_distance)));
+        }
+        let mut camera = Camera::new(
+            self.look_from,
+            self.look_at,
+            self.width,
+            self.height,
+            fov,
+            self.aperture,
+            focus_distance,
+        );
+        camera.set_orientation(self.up, self.roll);
+        camera.bokeh = self.bokeh;
+        camera.tilt = self.tilt;
+        //The frame gets the film back, the overscan pixels the same film per pixel around it
+        let scale = (self.width as fVec / frame_width as fVec, self.height as fVec / frame_height as fVec);
+        camera.viewport_height = camera.viewport_width * film_height / film_width * scale.1;
+        camera.viewport_width *= scale.0;
+        camera.shift = (shift.0 / scale.0, shift.1 / scale.0);
+        Ok(camera)
+    }
+}
+
+pub struct OrthographicCameraBuilder {
+    look_from: Vec3,
+    look_at: Vec3,
+    width: usize,
+    height: usize,
+    //Framing look_at like the default fov of a perspective camera if not set
+    view_width: Option<fVec>,
+    up: Vec3,
+    roll: fVec,
+}
+
+impl OrthographicCamera {
+    pub fn builder(look_from: Vec3, look_at: Vec3) -> OrthographicCameraBuilder {
+        OrthographicCameraBuilder {
+            look_from,
+            look_at,
+            width: 640,
+            height: 360,
+            view_width: None,
+            up: Vec3::unit_y(),
+            roll: 0.0,
+        }
+    }
+}
+
+impl OrthographicCameraBuilder {
+    pub fn resolution(mut self, width: usize, height: usize) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    //Scene units covered by the film horizontally
+    pub fn view_width(mut self, width: fVec) -> Self {
+        self.view_width = Some(width);
+        self
+    }
+
+    //See CameraBuilder::up()
+    pub fn up(mut self, up: Vec3) -> Self {
+        self.up = up;
+        self
+    }
+
+    pub fn roll(mut self, degrees: fVec) -> Self {
+        self.roll = degrees;
+        self
+    }
+
+    pub fn build(self) -> Result<OrthographicCamera> {
+        if self.width == 0 || self.height == 0 {
+            return Err(Error::invalid_parameter("camera resolution", format!("{}x{} is empty", self.width, self.height)));
+        }
+        let view = self.look_at - self.look_from;
+        if view.length() == 0.0 || !view.length().is_finite() {
+            return Err(Error::invalid_parameter("camera look_at", "must differ from look_from"));
+        }
+        if !(self.up.length() > 0.0 && self.up.length().is_finite()) {
+            return Err(Error::invalid_parameter("camera up", "must be a finite, non-zero vector"));
+        }
+        if !self.roll.is_finite() {
+            return Err(Error::invalid_parameter("camera roll", format!("{} must be finite", self.roll)));
+        }
+        let view_width = self.view_width.unwrap_or(fVec::tan(22.5f32.to_radians()) * view.length() * 2.0);
+        if !(view_width > 0.0 && view_width.is_finite()) {
+            return Err(Error::invalid_parameter("camera view width", format!("{} must be positive", view_width)));
+        }
+        let mut camera = OrthographicCamera::new(self.look_from, self.look_at, self.width, self.height, view_width);
+        camera.set_orientation(self.up, self.roll);
+        Ok(camera)
+    }
+}
+
+pub struct FisheyeCameraBuilder {
+    look_from: Vec3,
+    look_at: Vec3,
+    width: usize,
+    height: usize,
+    fov: fVec,
+    mapping: FisheyeMapping,
+    aperture: fVec,
+    bokeh: Bokeh,
+    //Distance to look_at if not set
+    focus_distance: Option<fVec>,
+    up: Vec3,
+    roll: fVec,
+}
+
+impl FisheyeCamera {
+    pub fn builder(look_from: Vec3, look_at: Vec3) -> FisheyeCameraBuilder {
+        FisheyeCameraBuilder {
+            look_from,
+            look_at,
+            width: 640,
+            height: 360,
+            fov: 180.0,
+            mapping: FisheyeMapping::Equidistant,
+            aperture: 0.0,
+            bokeh: Bokeh::default(),
+            focus_distance: None,
+            up: Vec3::unit_y(),
+            roll: 0.0,
+        }
+    }
+}
+
+impl FisheyeCameraBuilder {
+    pub fn resolution(mut self, width: usize, height: usize) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    //Angle across the width of the image in degrees
+    pub fn fov(mut self, degrees: fVec) -> Self {
+        self.fov = degrees;
+        self
+    }
+
+    pub fn mapping(mut self, mapping: FisheyeMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+
+    //Lens radius, 0 for everything in focus
+    pub fn aperture(mut self, radius: fVec) -> Self {
+        self.aperture = radius;
+        self
+    }
+
+    //See CameraBuilder::blades()
+    pub fn blades(mut self, count: u32, rotation: fVec) -> Self {
+        self.bokeh.blades = count;
+        self.bokeh.rotation = rotation;
+        self
+    }
+
+    pub fn cat_eye(mut self, strength: fVec) -> Self {
+        self.bokeh.cat_eye = strength;
+        self
+    }
+
+    pub fn focus_distance(mut self, distance: fVec) -> Self {
+        self.focus_distance = Some(distance);
+        self
+    }
+
+    //See CameraBuilder::autofocus()
+    pub fn autofocus(mut self, scene: &Scene) -> Self {
+        if let Some(distance) = scene.autofocus(self.look_from, self.look_at) {
+            self.focus_distance = Some(distance);
+        }
+        self
+    }
+
+    //See CameraBuilder::up()
+    pub fn up(mut self, up: Vec3) -> Self {
+        self.up = up;
+        self
+    }
+
+    pub fn roll(mut self, degrees: fVec) -> Self {
+        self.roll = degrees;
+        self
+    }
+
+    pub fn build(self) -> Result<FisheyeCamera> {
+        if self.width == 0 || self.height == 0 {
+            return Err(Error::invalid_parameter("camera resolution", format!("{}x{} is empty", self.width, self.height)));
+        }
+        if !(self.fov > 0.0 && self.fov <= 360.0) {
+            return Err(Error::invalid_parameter("camera fov", format!("{} must be between 0 and 360 degrees", self.fov)));
+        }
+        if !(self.aperture >= 0.0 && self.aperture.is_finite()) {
+            return Err(Error::invalid_parameter("camera aperture", format!("{} must be finite and not negative", self.aperture)));
+        }
+        if self.bokeh.blades > 0 && self.bokeh.blades < 3 {
+            return Err(Error::invalid_parameter("camera blades", format!("{} must be 0 or at least 3", self.bokeh.blades)));
+        }
+        if !self.bokeh.rotation.is_finite() {
+            return Err(Error::invalid_parameter("camera blade rotation", format!("{} must be finite", self.bokeh.rotation)));
+        }
+        if !(0.0..=1.0).contains(&self.bokeh.cat_eye) {
+            return Err(Error::invalid_parameter("camera cat eye", format!("{} must be between 0 and 1", self.bokeh.cat_eye)));
+        }
+        let view = self.look_at - self.look_from;
+        if view.length() == 0.0 || !view.length().is_finite() {
+            return Err(Error::invalid_parameter("camera look_at", "must differ from look_from"));
+        }
+        if !(self.up.length() > 0.0 && self.up.length().is_finite()) {
+            return Err(Error::invalid_parameter("camera up", "must be a finite, non-zero vector"));
+        }
+        if !self.roll.is_finite() {
+            return Err(Error::invalid_parameter("camera roll", format!("{} must be finite", self.roll)));
+        }
+        let focus_distance = self.focus_distance.unwrap_or(view.length());
+        if !(focus_distance > 0.0 && focus_distance.is_finite()) {
+            return Err(Error::invalid_parameter("camera focus distance", format!("{} must be positive", focus_distance)));
+        }
+        let mut camera = FisheyeCamera::new(
+            self.look_from,
+            self.look_at,
+            self.width,
+            self.height,
+            self.fov,
+            self.mapping,
+            self.aperture,
+            focus_distance,
+        );
+        camera.set_orientation(self.up, self.roll);
+        camera.bokeh = self.bokeh;
+        Ok(camera)
+    }
+}
+
+pub struct MovingCameraBuilder {
+    camera: Box<dyn CameraModel>,
+    keys: Vec<CameraKey>,
+    //From the first to the last key if not set
+    shutter: Option<(fVec, fVec)>,
+}
+
+impl MovingCamera {
+    pub fn builder(camera: Box<dyn CameraModel>) -> MovingCameraBuilder {
+        MovingCameraBuilder {
+            camera,
+            keys: Vec::new(),
+            shutter: None,
+        }
+    }
+}
+
+impl MovingCameraBuilder {
+    //Pose at time, see CameraBuilder::up() and roll()
+    pub fn key(mut self, time: fVec, look_from: Vec3, look_at: Vec3, up: Vec3, roll: fVec) -> Result<Self> {
+        if !time.is_finite() {
+            return Err(Error::invalid_parameter("camera key time", format!("{} must be finite", time)));
+        }
+        let view = look_at - look_from;
+        if view.length() == 0.0 || !view.length().is_finite() {
+            return Err(Error::invalid_parameter("camera key look_at", "must differ from look_from"));
+        }
+        if !(up.length() > 0.0 && up.length().is_finite()) {
+            return Err(Error::invalid_parameter("camera key up", "must be a finite, non-zero vector"));
+        }
+        if !roll.is_finite() {
+            return Err(Error::invalid_parameter("camera key roll", format!("{} must be finite", roll)));
+        }
+        self.keys.push(CameraKey::look_at(time, look_from, look_at, up, roll));
+        Ok(self)
+    }
+
+    pub fn shutter(mut self, open: fVec, close: fVec) -> Self {
+        self.shutter = Some((open, close));
+        self
+    }
+
+    pub fn build(self) -> Result<MovingCamera> {
+        if self.keys.is_empty() {
+            return Err(Error::invalid_parameter("camera keys", "at least 1 key is needed"));
+        }
+        let times = self.keys.iter().map(|k| k.time);
+        let first = times.clone().fold(fVec::INFINITY, fVec::min);
+        let last = times.fold(fVec::NEG_INFINITY, fVec::max);
+        let (open, close) = self.shutter.unwrap_or((first, last));
+        if !(open.is_finite() && close.is_finite() && open <= close) {
+            return Err(Error::invalid_parameter("camera shutter", format!("{} to {} must be finite and in order", open, close)));
+        }
+        Ok(MovingCamera::new(self.camera, self.keys, (open, close)))
+    }
+}
+
+pub struct RendererBuilder {
+    samples: usize,
+    bounces: usize,
+    //Random if not set
+    seed: Option<u64>,
+    integrator: Integrator,
+    sampler: Sampler,
+    //Tuned per scene if not set
+    tile_size: Option<usize>,
+    tile_order: TileOrder,
+    backdrop: Backdrop,
+    exposure: Option<Exposure>,
+    //Whole image if not set
+    region: Option<Tile>,
+    depth_of_field: bool,
+    aovs: bool,
+    progress: bool,
+    cancel: Option<CancelToken>,
+}
+
+impl Renderer {
+    pub fn builder() -> RendererBuilder {
+        RendererBuilder {
+            samples: 64,
+            bounces: 8,
+            seed: None,
+            integrator: Integrator::PathTracer,
+            sampler: Sampler::Random,
+            tile_size: None,
+            tile_order: TileOrder::Scanline,
+            backdrop: Backdrop::Environment,
+            exposure: None,
+            region: None,
+            depth_of_field: true,
+            aovs: false,
+            progress: true,
+            cancel: None,
+        }
+    }
+}
+
+impl RendererBuilder {
+    //Samples per pixel
+    pub fn samples(mut self, samples: usize) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    //Maximum path length
+    pub fn bounces(mut self, bounces: usize) -> Self {
+        self.bounces = bounces;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn integrator(mut self, integrator: Integrator) -> Self {
+        self.integrator = integrator;
+        self
+    }
+
+    pub fn sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    pub fn tile_size(mut self, size: usize) -> Self {
+        self.tile_size = Some(size);
+        self
+    }
+
+    pub fn tile_order(mut self, order: TileOrder) -> Self {
+        self.tile_order = order;
+        self
+    }
+
+    //What camera rays show where they miss the scene
+    pub fn backdrop(mut self, backdrop: Backdrop) -> Self {
+        self.backdrop = backdrop;
+        self
+    }
+
+    //Radiance as rendered if not set
+    pub fn exposure(mut self, exposure: Exposure) -> Self {
+        self.exposure = Some(exposure);
+        self
+    }
+
+    //Pixels [x0, x1) x [y0, y1) to render, clipped to the image
+    pub fn region(mut self, region: Tile) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    //Off renders with pinhole cameras for previews, see Renderer::set_depth_of_field()
+    pub fn depth_of_field(mut self, enabled: bool) -> Self {
+        self.depth_of_field = enabled;
+        self
+    }
+
+    //AOVs in the frame of Renderer::render(), see Renderer::set_aovs()
+    pub fn aovs(mut self, enabled: bool) -> Self {
+        self.aovs = enabled;
+        self
+    }
+
+    pub fn progress(mut self, enabled: bool) -> Self {
+        self.progress = enabled;
+        self
+    }
+
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    pub fn build(self) -> Result<Renderer> {
+        if self.samples == 0 {
+            return Err(Error::invalid_parameter("samples", "at least 1 sample per pixel is needed"));
+        }
+        if self.bounces == 0 {
+            return Err(Error::invalid_parameter("bounces", "at least 1 bounce is needed"));
+        }
+        if self.tile_size == Some(0) {
+            return Err(Error::invalid_parameter("tile size", "must be positive"));
+        }
+        if let Integrator::BvhHeatmap { max_nodes: 0 } = self.integrator {
+            return Err(Error::invalid_parameter("heatmap max_nodes", "must be positive"));
+        }
+        if let Some(exposure) = self.exposure {
+            for (name, value) in [("ISO", exposure.iso), ("shutter speed", exposure.shutter_speed), ("f-number", exposure.f_number)] {
+                if !(value > 0.0 && value.is_finite()) {
+                    return Err(Error::invalid_parameter(name, format!("{} must be positive", value)));
+                }
+            }
+        }
+        if let Some(region) = self.region {
+            if region.x0 >= region.x1 || region.y0 >= region.y1 {
+                return Err(Error::invalid_parameter("region", "must contain at least one pixel"));
+            }
+        }
+
+        let mut renderer = Renderer::new(self.samples, self.bounces);
+        if let Some(seed) = self.seed {
+            renderer.set_seed(seed);
+        }
+        renderer.set_integrator(self.integrator);
+        renderer.set_sampler(self.sampler);
+        match self.tile_size {
+            Some(size) => renderer.set_tile_size(size),
+            None => renderer.set_tile_auto_tune(true),
+        }
+        renderer.set_tile_order(self.tile_order);
+        renderer.set_backdrop(self.backdrop);
+        renderer.set_exposure(self.exposure);
+        renderer.set_region(self.region);
+        renderer.set_depth_of_field(self.depth_of_field);
+        renderer.set_aovs(self.aovs);
+        renderer.set_progress(self.progress);
+        if let Some(token) = self.cancel {
+            renderer.set_cancel_token(token);
+        }
+        Ok(renderer)
+    }
+}
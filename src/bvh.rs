@@ -0,0 +1,120 @@
+use crate::geom::*;
+use crate::linalg::*;
+use crate::tracer::*;
+
+struct BvhNode {
+    bounds: Aabb,
+    //Leaves reference count primitives from start, inner nodes have their first child
+    //directly after them and the second at start
+    start: usize,
+    count: usize,
+}
+
+//Bounding volume hierarchy over primitive indices
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+}
+
+const LEAF_SIZE: usize = 4;
+
+impl Bvh {
+    //Split at the median centroid along the widest axis
+    pub fn build(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * bounds.len().div_ceil(LEAF_SIZE)),
+            indices: (0..bounds.len()).collect(),
+        };
+        if !bounds.is_empty() {
+            bvh.build_node(bounds, 0, bounds.len());
+        }
+        bvh
+    }
+
+    fn build_node(&mut self, bounds: &[Aabb], start: usize, end: usize) {
+        let node = self.nodes.len();
+        let node_bounds = self.indices[start..end].iter().fold(Aabb::empty(), |b, &i| b.union(bounds[i]));
+        self.nodes.push(BvhNode {
+            bounds: node_bounds,
+            start,
+            count: end - start,
+        });
+        if end - start <= LEAF_SIZE {
+            return;
+        }
+
+        let centers = Aabb::from_points(self.indices[start..end].iter().map(|&i| bounds[i].centroid()));
+        let axis = |v: Vec3| match centers.widest_axis() {
+            0 => v.x,
+            1 => v.y,
+            _ => v.z,
+        };
+        let mid = (start + end) / 2;
+        self.indices[start..end].select_nth_unstable_by(mid - start, |&a, &b| {
+            axis(bounds[a].centroid()).total_cmp(&axis(bounds[b].centroid()))
+        });
+
+        self.build_node(bounds, start, mid);
+        let second = self.nodes.len();
+        self.build_node(bounds, mid, end);
+        self.nodes[node].start = second;
+        self.nodes[node].count = 0;
+    }
+
+    //Calls visit for every primitive whose leaf the ray reaches, visit returns the distance of
+    //a closer hit to shorten the ray. Returns the number of nodes visited.
+    pub fn traverse(&self, ray: &Ray, mut visit: impl FnMut(usize, &Ray) -> Option<fVec>) -> usize {
+        let mut ray = *ray;
+        let mut visited = 0;
+        let mut stack = Vec::with_capacity(32);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            visited += 1;
+            if !node.bounds.hit(&ray) {
+                continue;
+            }
+            if node.count > 0 {
+                for &prim in self.indices[node.start..node.start + node.count].iter() {
+                    if let Some(t) = visit(prim, &ray) {
+                        ray.max = t;
+                    }
+                }
+            } else {
+                stack.push(node.start);
+                stack.push(i + 1);
+            }
+        }
+        visited
+    }
+
+    //Like traverse(), but stops at the first primitive visit returns true for, in no particular
+    //order. Returns whether there was one and the number of nodes visited.
+    pub fn any(&self, ray: &Ray, mut visit: impl FnMut(usize) -> bool) -> (bool, usize) {
+        let mut visited = 0;
+        let mut stack = Vec::with_capacity(32);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            visited += 1;
+            if !node.bounds.hit(ray) {
+                continue;
+            }
+            if node.count > 0 {
+                if self.indices[node.start..node.start + node.count].iter().any(|&prim| visit(prim)) {
+                    return (true, visited);
+                }
+            } else {
+                stack.push(node.start);
+                stack.push(i + 1);
+            }
+        }
+        (false, visited)
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    fs,
+    io::{self, Read, Write},
+};
+
+use crate::error::*;
+use crate::framebuffer::*;
+use crate::image::*;
+use crate::sampler::Sampler;
+use crate::tracer::*;
+
+const CHECKPOINT_MAGIC: &[u8; 4] = b"RTCK";
+const CHECKPOINT_VERSION: u32 = 8;
+
+//What a checkpoint renders besides the renderer settings, resuming with something else is refused
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CheckpointJob {
+    pub integrator: String,
+    //Command line preset the render was started with
+    pub preset: String,
+    //Scene file, empty for the built-in scene
+    pub scene: String,
+}
+
+//State of an interrupted render, enough to finish the remaining tiles later. The random state
+//kept in materials is not saved: resumed tiles draw fresh material random numbers, so they match
+//an uninterrupted render in distribution, not bit for bit. AOVs are not saved either, renders
+//recording them can't be resumed.
+pub struct Checkpoint {
+    pub seed: u64,
+    pub samples: usize,
+    pub bounces: usize,
+    pub sampler: Sampler,
+    //done is indexed by the tiles of this size, resume with the same size
+    pub tile_size: usize,
+    //Region of the render, tiles start at its corner
+    pub region: Option<Tile>,
+    pub done: Vec<bool>,
+    pub frame: FrameBuffer,
+    pub job: CheckpointJob,
+}
+
+fn read_u32(src: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    src.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(src: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    src.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+//Strings longer than what is left of the file are corrupt
+fn read_string(src: &mut impl Read, file_len: u64) -> io::Result<String> {
+    let len = read_u32(src)? as u64;
+    if len > file_len {
+        return Err(invalid("checkpoint is truncated"));
+    }
+    let mut buf = vec![0; len as usize];
+    src.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| invalid("checkpoint holds invalid text"))
+}
+
+impl Checkpoint {
+    pub fn save(&self, path: &str) -> Result<()> {
+        let mut out: Vec<u8> = Vec::new();
+
+        out.write_all(CHECKPOINT_MAGIC)?;
+        out.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+        out.write_all(&(self.frame.width() as u32).to_le_bytes())?;
+        out.write_all(&(self.frame.height() as u32).to_le_bytes())?;
+        out.write_all(&(self.samples as u32).to_le_bytes())?;
+        out.write_all(&(self.bounces as u32).to_le_bytes())?;
+        out.write_all(&self.seed.to_le_bytes())?;
+        let sampler: u32 = match self.sampler {
+            Sampler::Random => 0,
+            Sampler::Halton => 1,
+        };
+        out.write_all(&sampler.to_le_bytes())?;
+        out.write_all(&(self.tile_size as u32).to_le_bytes())?;
+        //Empty for the whole image
+        let region = self.region.unwrap_or(Tile { x0: 0, y0: 0, x1: 0, y1: 0 });
+        for v in [region.x0, region.y0, region.x1, region.y1] {
+            out.write_all(&(v as u32).to_le_bytes())?;
+        }
+        out.write_all(&(self.done.len() as u32).to_le_bytes())?;
+        for d in self.done.iter() {
+            out.push(*d as u8);
+        }
+        write_string(&mut out, &self.job.integrator);
+        write_string(&mut out, &self.job.preset);
+        write_string(&mut out, &self.job.scene);
+        //Average color, alpha and weight per pixel
+        for y in 0..self.frame.height() {
+            for x in 0..self.frame.width() {
+                let col = self.frame.color(x, y).unwrap();
+                let (alpha, weight) = (self.frame.alpha(x, y).unwrap(), self.frame.weight(x, y).unwrap());
+                for c in [col.r, col.g, col.b, alpha, weight] {
+                    out.write_all(&c.to_le_bytes())?;
+                }
+            }
+        }
+
+        //A crash while writing leaves the previous checkpoint intact
+        let temp = format!("{}.tmp", path);
+        fs::write(&temp, out)?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    //Refuse to resume a render of something else
+    pub fn check_job(&self, job: &CheckpointJob) -> Result<()> {
+        let fields = [
+            ("integrator", &self.job.integrator, &job.integrator),
+            ("preset", &self.job.preset, &job.preset),
+            ("scene", &self.job.scene, &job.scene),
+        ];
+        for (name, saved, current) in fields {
+            if saved != current {
+                let describe = |s: &str| if s.is_empty() { "none".to_string() } else { s.to_string() };
+                return Err(Error::invalid_parameter(
+                    "checkpoint",
+                    format!("was rendered with {} {}, not {}", name, describe(saved), describe(current)),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    //Set up renderer to render the tiles left with the settings the checkpoint was started with.
+    //The camera and scene must be the same, which is up to the caller.
+    pub fn restore(&self, renderer: &mut Renderer, cam: &dyn CameraModel) -> Result<()> {
+        let (width, height) = cam.resolution();
+        if (width, height) != (self.frame.width(), self.frame.height()) {
+            return Err(Error::invalid_parameter(
+                "checkpoint",
+                format!(
+                    "image is {}x{}, the camera renders {}x{}",
+                    self.frame.width(),
+                    self.frame.height(),
+                    width,
+                    height
+                ),
+            ));
+        }
+        if renderer.aovs() {
+            return Err(Error::invalid_parameter("checkpoint", "AOVs are not kept in checkpoints, can't resume with AOVs"));
+        }
+        renderer.set_seed(self.seed);
+        renderer.set_samples(self.samples);
+        renderer.set_bounces(self.bounces);
+        renderer.set_sampler(self.sampler);
+        renderer.set_tile_auto_tune(false);
+        renderer.set_tile_size(self.tile_size);
+        renderer.set_region(self.region);
+        if renderer.tiles(cam).len() != self.done.len() {
+            return Err(Error::invalid_parameter("checkpoint", "tile layout does not match the image"));
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let file = fs::File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut src = io::BufReader::new(file);
+
+        let mut magic = [0; 4];
+        src.read_exact(&mut magic)?;
+        if &magic != CHECKPOINT_MAGIC || read_u32(&mut src)? != CHECKPOINT_VERSION {
+            return Err(invalid("not a compatible checkpoint file").into());
+        }
+
+        let width = read_u32(&mut src)? as usize;
+        let height = read_u32(&mut src)? as usize;
+        let samples = read_u32(&mut src)? as usize;
+        let bounces = read_u32(&mut src)? as usize;
+        let seed = read_u64(&mut src)?;
+        let sampler = match read_u32(&mut src)? {
+            0 => Sampler::Random,
+            1 => Sampler::Halton,
+            _ => return Err(invalid("checkpoint has an unknown sampler").into()),
+        };
+        let tile_size = read_u32(&mut src)? as usize;
+        let region = Tile {
+            x0: read_u32(&mut src)? as usize,
+            y0: read_u32(&mut src)? as usize,
+            x1: read_u32(&mut src)? as usize,
+            y1: read_u32(&mut src)? as usize,
+        };
+        let tiles = read_u32(&mut src)? as usize;
+        //Checked before allocating, corrupt sizes could ask for any amount of memory
+        let pixel_bytes = (width as u64).checked_mul(height as u64).and_then(|n| n.checked_mul(20));
+        if pixel_bytes.is_none_or(|n| n.saturating_add(tiles as u64) > file_len) {
+            return Err(invalid("checkpoint is truncated or corrupt").into());
+        }
+
+        let mut done = vec![0; tiles];
+        src.read_exact(&mut done)?;
+        let job = CheckpointJob {
+            integrator: read_string(&mut src, file_len)?,
+            preset: read_string(&mut src, file_len)?,
+            scene: read_string(&mut src, file_len)?,
+        };
+
+        let mut frame = FrameBuffer::new(width, height);
+        let mut px = [0; 20];
+        for y in 0..height {
+            for x in 0..width {
+                src.read_exact(&mut px)?;
+                let channel = |i: usize| f32::from_le_bytes(px[4 * i..4 * i + 4].try_into().unwrap());
+                let (color, weight) = (Color::new(channel(0), channel(1), channel(2)), channel(4));
+                frame.accumulate(x, y, color * weight, channel(3) * weight, weight);
+            }
+        }
+
+        Ok(Self {
+            seed,
+            samples,
+            bounces,
+            sampler,
+            tile_size,
+            region: (region.x0 < region.x1).then_some(region),
+            done: done.into_iter().map(|d| d != 0).collect(),
+            frame,
+            job,
+        })
+    }
+}
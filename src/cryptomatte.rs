@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+
+use crate::error::*;
+use crate::framebuffer::*;
+use crate::image::*;
+use crate::json::*;
+
+//Cryptomatte (Psyop's open specification) id mattes, so compositors can pull a matte for any
+//object or material by name after rendering. Every pixel stores the ids of the names its samples
+//saw with their coverage, in rank order, as pairs in the RGBA channels of numbered layers.
+
+//Id and coverage pairs per pixel, 3 layers of 2
+const RANKS: usize = 6;
+
+//MurmurHash3 x86 32 bit, the hash the specification names
+pub fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    let mut h = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        h ^= mix(u32::from_le_bytes(block.try_into().unwrap()));
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        h ^= mix(tail.iter().rev().fold(0, |k, &b| (k << 8) | b as u32));
+    }
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+//Bits of the float id of a name, as listed in the manifest. Hashes that would be denormal, infinite
+//or NaN as a float get a flipped exponent bit.
+pub fn cryptomatte_id(name: &str) -> u32 {
+    let hash = murmur3_32(name.as_bytes(), 0);
+    let exponent = (hash >> 23) & 0xff;
+    if exponent == 0 || exponent == 0xff {
+        hash ^ (1 << 23)
+    } else {
+        hash
+    }
+}
+
+//Channels and header metadata of one cryptomatte layer, e.g. CryptoObject
+pub fn cryptomatte_layer(
+    layer: &str,
+    ids: &[Vec<(u32, fCol)>],
+    names: &BTreeMap<String, u32>,
+) -> (Vec<ExrChannel>, Vec<(String, String)>) {
+    let mut channels = Vec::new();
+    for part in 0..RANKS / 2 {
+        for (i, channel) in ["R", "G", "B", "A"].iter().enumerate() {
+            let (rank, coverage) = (2 * part + i / 2, i % 2 == 1);
+            channels.push(ExrChannel {
+                name: format!("{}{:02}.{}", layer, part, channel),
+                values: ids
+                    .iter()
+                    .map(|ids| match ids.get(rank) {
+                        Some(&(_, c)) if coverage => c,
+                        Some(&(id, _)) => f32::from_bits(id),
+                        None => 0.0,
+                    })
+                    .collect(),
+            });
+        }
+    }
+
+    //Layers are told apart by the first 7 hex digits of the hash of their name
+    let key = format!("cryptomatte/{}", &format!("{:08x}", murmur3_32(layer.as_bytes(), 0))[..7]);
+    let manifest = Json::object(
+        names
+            .iter()
+            .map(|(name, id)| (name.as_str(), Json::String(format!("{:08x}", id))))
+            .collect(),
+    );
+    let metadata = vec![
+        (format!("{}/name", key), layer.to_string()),
+        (format!("{}/hash", key), "MurmurHash3_32".to_string()),
+        (format!("{}/conversion", key), "uint32_to_float32".to_string()),
+        (format!("{}/manifest", key), manifest.to_string()),
+    ];
+    (channels, metadata)
+}
+
+//CryptoObject and CryptoMaterial layers of the AOVs
+pub fn cryptomatte_layers(aovs: &Aovs) -> (Vec<ExrChannel>, Vec<(String, String)>) {
+    let (mut channels, mut metadata) = cryptomatte_layer("CryptoObject", &aovs.object_ids, &aovs.object_names);
+    let (material_channels, material_metadata) =
+        cryptomatte_layer("CryptoMaterial", &aovs.material_ids, &aovs.material_names);
+    channels.extend(material_channels);
+    metadata.extend(material_metadata);
+    (channels, metadata)
+}
+
+impl FrameBuffer {
+    //EXR with only the cryptomatte layers, false without AOVs
+    pub fn save_cryptomatte(&self, path: &str) -> Result<bool> {
+        let Some(aovs) = self.aovs() else {
+            return Ok(false);
+        };
+        let (channels, metadata) = cryptomatte_layers(&aovs);
+        write_exr(path, self.width(), self.height(), &channels, &metadata)?;
+        Ok(true)
+    }
+
+    //Beauty in the unnamed layer with every AOV and the cryptomatte layers in one EXR, the way
+    //compositing packages expect render passes. Without AOVs only the beauty is written.
+    pub fn save_multilayer_exr(&self, beauty: &Image, path: &str) -> Result<()> {
+        let mut channels = beauty.exr_channels();
+        let mut metadata = beauty.metadata().to_vec();
+        if let Some(aovs) = self.aovs() {
+            let layer = |name: &str, values: Vec<fCol>| ExrChannel {
+                name: name.to_string(),
+                values,
+            };
+            channels.extend([
+                layer("albedo.R", aovs.albedo.iter().map(|c| c.r).collect()),
+                layer("albedo.G", aovs.albedo.iter().map(|c| c.g).collect()),
+                layer("albedo.B", aovs.albedo.iter().map(|c| c.b).collect()),
+                layer("normal.X", aovs.normal.iter().map(|n| n.x).collect()),
+                layer("normal.Y", aovs.normal.iter().map(|n| n.y).collect()),
+                layer("normal.Z", aovs.normal.iter().map(|n| n.z).collect()),
+                layer("depth.Z", aovs.depth.clone()),
+                //Object index plus one, 0 where no object is seen
+                layer("object.ID", aovs.object.iter().map(|o| o.map_or(0.0, |id| id.index() as fCol + 1.0)).collect()),
+            ]);
+            let (crypto_channels, crypto_metadata) = cryptomatte_layers(&aovs);
+            channels.extend(crypto_channels);
+            metadata.extend(crypto_metadata);
+        }
+        write_exr(path, self.width(), self.height(), &channels, &metadata)
+    }
+}
@@ -0,0 +1,276 @@
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+
+use crate::animation::*;
+use crate::framebuffer::*;
+use crate::image::*;
+use crate::job::*;
+use crate::json::*;
+use crate::linalg::*;
+use crate::tracer::*;
+
+//Resident renderer for editor integrations. The scene is loaded and prepared once, then driven
+//by one JSON command per input line, each answered by one JSON line:
+//  {"cmd": "render", "frame": 3, "output": "out/frame_{frame}.png", "shutter": [3, 3.5]}
+//  {"cmd": "camera", "from": [0, 3, -5], "at": [0, 0, 2], "fov": 45, "aperture": 0.1,
+//   "motion": [{"time": 0, "from": [x, y, z], "at": [x, y, z]}, ...], "shutter": [0, 1]}
+//  {"cmd": "settings", "samples": 64, "bounces": 8, "depth_of_field": false}
+//  {"cmd": "exposure", "iso": 100, "shutter_speed": 0.008, "f_number": 8, "output": "out/exposed.png"}
+//  {"cmd": "quit"}
+//Answers are {"ok": true, ...} or {"ok": false, "error": "..."}. Exposure without its values
+//leaves renders as rendered, with an output the last frame is saved again at the new exposure.
+//A camera with motion keys flies along them like the "motion" of a scene file camera, "from" and
+//"at" are then replaced by the keys. A render can move the shutter, e.g. to the time of its frame.
+pub struct Daemon {
+    pub animation: Animation,
+    pub renderer: Renderer,
+    pub scene_name: String,
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    pub bounces: usize,
+    //Base seed, each frame renders with frame_seed(seed, frame)
+    pub seed: u64,
+    pub look_from: Vec3,
+    pub look_at: Vec3,
+    pub fov: fVec,
+    pub aperture: fVec,
+    //Time, from and at of each camera key, a still camera if empty
+    pub motion: Vec<(fVec, Vec3, Vec3)>,
+    //From the first to the last key if not set
+    pub shutter: Option<(fVec, fVec)>,
+    //Job, frame and render time of the last render, for saving it again at another exposure
+    pub last_render: Option<(RenderJob, FrameBuffer, Duration)>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("daemon: {}", msg))
+}
+
+fn vec3(value: &Json) -> io::Result<Vec3> {
+    match value.as_array() {
+        Some([x, y, z]) => match (x.as_f64(), y.as_f64(), z.as_f64()) {
+            (Some(x), Some(y), Some(z)) => Ok(Vec3::new(x as fVec, y as fVec, z as fVec)),
+            _ => Err(invalid("vector components must be numbers")),
+        },
+        _ => Err(invalid("expected a vector of 3 numbers")),
+    }
+}
+
+fn number(cmd: &Json, key: &str) -> io::Result<Option<f64>> {
+    cmd.get(key)
+        .map(|v| v.as_f64().ok_or_else(|| invalid(&format!("{} must be a number", key))))
+        .transpose()
+}
+
+//Resolved with the renderer's exposure, returns the path written
+fn save_frame(renderer: &Renderer, job: &RenderJob, frame: &FrameBuffer, render_time: Duration) -> io::Result<String> {
+    let mut img = renderer.resolve(frame);
+    for (key, value) in job.metadata(render_time) {
+        img.set_metadata(&key, &value);
+    }
+    if let Some(exposure) = renderer.exposure() {
+        img.set_metadata("raytrace/exposure", &exposure.to_string());
+    }
+    Ok(job.save(&img)?)
+}
+
+fn shutter(value: &Json) -> io::Result<(fVec, fVec)> {
+    match value.as_array() {
+        Some([open, close]) => match (open.as_f64(), close.as_f64()) {
+            (Some(open), Some(close)) => Ok((open as fVec, close as fVec)),
+            _ => Err(invalid("shutter times must be numbers")),
+        },
+        _ => Err(invalid("shutter must be [open, close]")),
+    }
+}
+
+fn count(cmd: &Json, key: &str) -> io::Result<Option<usize>> {
+    cmd.get(key)
+        .map(|v| v.as_usize().ok_or_else(|| invalid(&format!("{} must be a non-negative integer", key))))
+        .transpose()
+}
+
+impl Daemon {
+    //Serve commands until quit or the end of input
+    pub fn run(&mut self, input: impl BufRead, output: &mut impl Write) -> io::Result<()> {
+        self.renderer.set_progress(false);
+        self.renderer.prepare(&mut self.animation.scene);
+
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (response, quit) = match Json::parse(&line).and_then(|cmd| self.handle(&cmd)) {
+                Ok(Some(mut fields)) => {
+                    fields.insert(0, ("ok", true.into()));
+                    (Json::object(fields), false)
+                }
+                Ok(None) => (Json::object(vec![("ok", true.into())]), true),
+                Err(e) => (
+                    Json::object(vec![("ok", false.into()), ("error", e.to_string().as_str().into())]),
+                    false,
+                ),
+            };
+            writeln!(output, "{}", response)?;
+            output.flush()?;
+            if quit {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    //Extra response fields, None to stop serving
+    fn handle(&mut self, cmd: &Json) -> io::Result<Option<Vec<(&'static str, Json)>>> {
+        match cmd.get("cmd").and_then(Json::as_str) {
+            Some("render") => self.render(cmd).map(Some),
+            Some("camera") => {
+                if let Some(from) = cmd.get("from") {
+                    self.look_from = vec3(from)?;
+                }
+                if let Some(at) = cmd.get("at") {
+                    self.look_at = vec3(at)?;
+                }
+                if let Some(fov) = number(cmd, "fov")? {
+                    self.fov = fov as fVec;
+                }
+                if let Some(aperture) = number(cmd, "aperture")? {
+                    self.aperture = aperture as fVec;
+                }
+                if let Some(motion) = cmd.get("motion") {
+                    let keys = motion.as_array().ok_or_else(|| invalid("motion must be an array of keys"))?;
+                    self.motion = keys
+                        .iter()
+                        .map(|key| {
+                            let time = number(key, "time")?.ok_or_else(|| invalid("motion keys need a time"))?;
+                            let from = vec3(key.get("from").ok_or_else(|| invalid("motion keys need from"))?)?;
+                            let at = vec3(key.get("at").ok_or_else(|| invalid("motion keys need at"))?)?;
+                            Ok((time as fVec, from, at))
+                        })
+                        .collect::<io::Result<_>>()?;
+                }
+                if let Some(value) = cmd.get("shutter") {
+                    self.shutter = Some(shutter(value)?);
+                }
+                Ok(Some(Vec::new()))
+            }
+            Some("settings") => {
+                if let Some(samples) = count(cmd, "samples")? {
+                    self.samples = samples;
+                    self.renderer.set_samples(samples);
+                }
+                if let Some(bounces) = count(cmd, "bounces")? {
+                    self.bounces = bounces;
+                    self.renderer.set_bounces(bounces);
+                }
+                if let Some(width) = count(cmd, "width")? {
+                    self.width = width.max(1);
+                }
+                if let Some(height) = count(cmd, "height")? {
+                    self.height = height.max(1);
+                }
+                if let Some(enabled) = cmd.get("depth_of_field") {
+                    let enabled = enabled.as_bool().ok_or_else(|| invalid("depth_of_field must be true or false"))?;
+                    self.renderer.set_depth_of_field(enabled);
+                }
+                Ok(Some(Vec::new()))
+            }
+            Some("exposure") => {
+                let exposure = match (number(cmd, "iso")?, number(cmd, "shutter_speed")?, number(cmd, "f_number")?) {
+                    (None, None, None) => None,
+                    (Some(iso), Some(shutter_speed), Some(f_number)) => {
+                        if ![iso, shutter_speed, f_number].iter().all(|v| *v > 0.0 && v.is_finite()) {
+                            return Err(invalid("iso, shutter_speed and f_number must be positive"));
+                        }
+                        Some(Exposure {
+                            iso: iso as fCol,
+                            shutter_speed: shutter_speed as fCol,
+                            f_number: f_number as fCol,
+                        })
+                    }
+                    _ => return Err(invalid("iso, shutter_speed and f_number must be given together")),
+                };
+                self.renderer.set_exposure(exposure);
+                let mut fields = Vec::new();
+                if let Some(exposure) = exposure {
+                    fields.push(("ev100", (exposure.ev100() as f64).into()));
+                }
+                if let Some(output) = cmd.get("output") {
+                    let output = output.as_str().ok_or_else(|| invalid("output must be a string"))?;
+                    let (job, frame, render_time) = self.last_render.as_mut().ok_or_else(|| invalid("nothing was rendered yet"))?;
+                    job.output = output.replace("{frame}", &self.animation.frame().to_string());
+                    let path = save_frame(&self.renderer, job, frame, *render_time)?;
+                    fields.push(("output", path.as_str().into()));
+                }
+                Ok(Some(fields))
+            }
+            Some("quit") => Ok(None),
+            Some(other) => Err(invalid(&format!("unknown command {}", other))),
+            None => Err(invalid("missing cmd")),
+        }
+    }
+
+    //Frames only move forward, the animation is advanced to the requested frame before rendering
+    fn render(&mut self, cmd: &Json) -> io::Result<Vec<(&'static str, Json)>> {
+        let frame = count(cmd, "frame")?.unwrap_or(self.animation.frame());
+        if frame < self.animation.frame() {
+            return Err(invalid(&format!("frame {} was already passed, at frame {}", frame, self.animation.frame())));
+        }
+        while self.animation.frame() < frame {
+            if !self.animation.advance() {
+                return Err(invalid(&format!("animation ends at frame {}", self.animation.frame())));
+            }
+        }
+
+        let output = cmd.get("output").and_then(Json::as_str).ok_or_else(|| invalid("missing output"))?;
+        let seed = frame_seed(self.seed, frame);
+        self.renderer.set_seed(seed);
+        let job = RenderJob {
+            scene: self.scene_name.clone(),
+            width: self.width,
+            height: self.height,
+            samples: self.samples,
+            bounces: self.bounces,
+            seed,
+            output: output.replace("{frame}", &frame.to_string()),
+            depth: BitDepth::Eight,
+            transfer: Transfer::Srgb,
+            scene_hash: None,
+        };
+        let cam = Camera::builder(self.look_from, self.look_at)
+            .resolution(self.width, self.height)
+            .fov(self.fov)
+            .aperture(self.aperture)
+            .build()?;
+        let mut buffer = FrameBuffer::new(cam.rasterize_width, cam.rasterize_height);
+
+        //Animated like the CLI animates a scene file camera
+        let cam: Box<dyn CameraModel> = if self.motion.is_empty() {
+            Box::new(cam)
+        } else {
+            let mut builder = MovingCamera::builder(Box::new(cam));
+            for &(time, from, at) in self.motion.iter() {
+                builder = builder.key(time, from, at, Vec3::unit_y(), 0.0)?;
+            }
+            let shutter = cmd.get("shutter").map(shutter).transpose()?.or(self.shutter);
+            if let Some((open, close)) = shutter {
+                builder = builder.shutter(open, close);
+            }
+            Box::new(builder.build()?)
+        };
+
+        let mut done = vec![false; self.renderer.tiles(cam.as_ref()).len()];
+        let stats = self.renderer.render_into(&self.animation.scene, cam.as_ref(), &mut buffer, &mut done);
+        let path = save_frame(&self.renderer, &job, &buffer, stats.time)?;
+        self.last_render = Some((job, buffer, stats.time));
+        Ok(vec![
+            ("frame", (frame as f64).into()),
+            ("output", path.as_str().into()),
+            //As a string, JSON numbers lose precision beyond 2^53
+            ("seed", seed.to_string().as_str().into()),
+            ("seconds", stats.time.as_secs_f64().into()),
+        ])
+    }
+}
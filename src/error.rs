@@ -0,0 +1,55 @@
+use std::io;
+
+use thiserror::Error;
+
+//Errors of the library entry points: saving and loading images, scenes and checkpoints, and
+//building cameras and renderers. Parsers inside modules still use io::Result and are converted
+//at the boundary.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    //The image can't be represented in the output format, e.g. too large for its header fields
+    #[error("cannot encode {format}: {message}")]
+    Encode { format: &'static str, message: String },
+    //The file is malformed or uses a feature the decoder doesn't support
+    #[error("cannot decode {format}: {message}")]
+    Decode { format: &'static str, message: String },
+    #[error("{path}: {message}")]
+    SceneParse { path: String, message: String },
+    #[error("invalid {name}: {message}")]
+    InvalidParameter { name: &'static str, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn invalid_parameter(name: &'static str, message: impl Into<String>) -> Error {
+        Error::InvalidParameter {
+            name,
+            message: message.into(),
+        }
+    }
+
+    //Malformed files become SceneParse, failures to read them stay Io
+    pub fn scene(path: &str, err: io::Error) -> Error {
+        match err.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => Error::SceneParse {
+                path: path.to_string(),
+                message: err.to_string(),
+            },
+            kind => Error::Io(io::Error::new(kind, format!("{}: {}", path, err))),
+        }
+    }
+}
+
+//For code that still reports io::Result, like the daemon protocol
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            Error::InvalidParameter { .. } => io::Error::new(io::ErrorKind::InvalidInput, err.to_string()),
+            _ => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use crate::image::*;
+use crate::linalg::*;
+
+//Per pixel geometry of the first hit, used to keep filters from blurring across edges
+pub struct GBuffer {
+    pub width: usize,
+    pub height: usize,
+    pub(crate) normal: Vec<Vec3>,
+    //Distance from the camera, infinite for pixels showing the environment
+    pub depth: Vec<fVec>,
+}
+
+impl GBuffer {
+    pub fn new(width: usize, height: usize) -> GBuffer {
+        GBuffer {
+            width,
+            height,
+            normal: vec![Vec3::origin(); width * height],
+            depth: vec![fVec::INFINITY; width * height],
+        }
+    }
+}
+
+fn edge_weight(normal: Vec3, depth: fVec, other_normal: Vec3, other_depth: fVec) -> fVec {
+    if !depth.is_finite() || !other_depth.is_finite() {
+        return if depth.is_finite() == other_depth.is_finite() {
+            1.0
+        } else {
+            0.0
+        };
+    }
+    let depth_weight = (-(depth - other_depth).abs() / (0.1 * depth.max(0.0001))).exp();
+    let normal_weight = (normal * other_normal).max(0.0).powi(8);
+    depth_weight * normal_weight
+}
+
+//Upsample low to the resolution of high_geometry, weighting the neighbouring low resolution
+//pixels by distance and by how similar their normal and depth are to the target pixel
+pub(crate) fn joint_bilateral_upsample(low: &[Color], low_geometry: &GBuffer, high_geometry: &GBuffer) -> Vec<Color> {
+    let (lw, lh) = (low_geometry.width, low_geometry.height);
+    let (hw, hh) = (high_geometry.width, high_geometry.height);
+    let scale_x = lw as fVec / hw as fVec;
+    let scale_y = lh as fVec / hh as fVec;
+    let mut out = Vec::with_capacity(hw * hh);
+
+    for y in 0..hh {
+        for x in 0..hw {
+            let i = y * hw + x;
+            let normal = high_geometry.normal[i];
+            let depth = high_geometry.depth[i];
+
+            let lx = (x as fVec + 0.5) * scale_x - 0.5;
+            let ly = (y as fVec + 0.5) * scale_y - 0.5;
+            let x0 = lx.floor();
+            let y0 = ly.floor();
+
+            let mut sum = Color::black();
+            let mut weight_sum = 0.0;
+            let mut nearest = (fVec::INFINITY, Color::black());
+            for dy in 0..2 {
+                for dx in 0..2 {
+                    let sx = (x0 + dx as fVec).clamp(0.0, (lw - 1) as fVec);
+                    let sy = (y0 + dy as fVec).clamp(0.0, (lh - 1) as fVec);
+                    let j = sy as usize * lw + sx as usize;
+
+                    let bilinear = (1.0 - (lx - sx).abs()).max(0.0) * (1.0 - (ly - sy).abs()).max(0.0);
+                    let w = bilinear * edge_weight(normal, depth, low_geometry.normal[j], low_geometry.depth[j]);
+                    sum = sum + low[j] * w;
+                    weight_sum += w;
+
+                    let dist = (lx - sx).abs() + (ly - sy).abs();
+                    if dist < nearest.0 {
+                        nearest = (dist, low[j]);
+                    }
+                }
+            }
+
+            out.push(if weight_sum > 1e-4 {
+                sum * (1.0 / weight_sum)
+            } else {
+                nearest.1
+            });
+        }
+    }
+
+    out
+}
+
+//Ink lines for non-photoreal output, drawn where the first hit geometry jumps between pixels
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Outline {
+    pub color: Color,
+    //Edge where neighbouring normals enclose more than this angle, in radians
+    pub crease_angle: fVec,
+    //Edge where neighbouring depths differ by more than this fraction of the nearer one
+    pub depth_ratio: fVec,
+}
+
+impl Default for Outline {
+    fn default() -> Self {
+        Outline {
+            color: Color::black(),
+            crease_angle: 0.8,
+            depth_ratio: 0.1,
+        }
+    }
+}
+
+//Pixels on an edge, each edge is one pixel wide and lies on the side nearer to the camera
+pub(crate) fn outline_mask(geometry: &GBuffer, outline: &Outline) -> Vec<bool> {
+    let (width, height) = (geometry.width, geometry.height);
+    let min_cos = outline.crease_angle.cos();
+    let mut mask = vec![false; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let depth = geometry.depth[i];
+            if !depth.is_finite() {
+                continue;
+            }
+            let neighbours = [(x.wrapping_sub(1), y), (x + 1, y), (x, y.wrapping_sub(1)), (x, y + 1)];
+            mask[i] = neighbours.iter().any(|&(nx, ny)| {
+                if nx >= width || ny >= height {
+                    return false;
+                }
+                let j = ny * width + nx;
+                let other = geometry.depth[j];
+                //Silhouette against something further away, including the environment
+                if other - depth > outline.depth_ratio * depth {
+                    return true;
+                }
+                //Creases are drawn on both sides, unless the other side is clearly nearer
+                (other - depth).abs() <= outline.depth_ratio * depth && geometry.normal[i] * geometry.normal[j] < min_cos
+            });
+        }
+    }
+    mask
+}
+
+//Edge-avoiding à-trous wavelet denoiser (Dammertz et al. 2010), for noisy renders without an
+//external denoiser. Every pass blurs with a 5x5 B-spline kernel whose taps are twice as far apart
+//as in the pass before, weighted down across geometry edges and color differences.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Denoiser {
+    //Passes, 5 reach 62 pixels in every direction
+    pub iterations: usize,
+    //Color difference at which neighbours stop counting in the first pass, halved in every pass.
+    //Measured on colors compressed to [0, 1) by c / (1 + c), so highlights don't dominate.
+    pub color_sigma: fCol,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Denoiser {
+            iterations: 5,
+            color_sigma: 0.5,
+        }
+    }
+}
+
+pub(crate) fn atrous_denoise(color: &[Color], geometry: &GBuffer, denoiser: &Denoiser) -> Vec<Color> {
+    const KERNEL: [fCol; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+    let (width, height) = (geometry.width, geometry.height);
+    let compress = |c: Color| Color::new(c.r / (1.0 + c.r), c.g / (1.0 + c.g), c.b / (1.0 + c.b));
+    let mut current = color.to_vec();
+    let mut sigma = denoiser.color_sigma;
+
+    for pass in 0..denoiser.iterations {
+        let step = 1isize << pass;
+        let mut next = Vec::with_capacity(current.len());
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let center = compress(current[i]);
+                let mut sum = Color::black();
+                let mut weight_sum = 0.0;
+                for (ky, kernel_y) in KERNEL.iter().enumerate() {
+                    for (kx, kernel_x) in KERNEL.iter().enumerate() {
+                        let nx = x as isize + (kx as isize - 2) * step;
+                        let ny = y as isize + (ky as isize - 2) * step;
+                        if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                            continue;
+                        }
+                        let j = ny as usize * width + nx as usize;
+                        let diff = compress(current[j]) - center;
+                        let distance = diff.r * diff.r + diff.g * diff.g + diff.b * diff.b;
+                        let color_weight = (-distance / (sigma * sigma)).exp();
+                        let geometry_weight =
+                            edge_weight(geometry.normal[i], geometry.depth[i], geometry.normal[j], geometry.depth[j]);
+                        let w = kernel_x * kernel_y * color_weight * geometry_weight;
+                        sum = sum + current[j] * w;
+                        weight_sum += w;
+                    }
+                }
+                //The center pixel always has weight, unless its color is not finite
+                next.push(if weight_sum > 0.0 {
+                    sum * (1.0 / weight_sum)
+                } else {
+                    current[i]
+                });
+            }
+        }
+        current = next;
+        sigma *= 0.5;
+    }
+
+    current
+}
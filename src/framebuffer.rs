@@ -0,0 +1,415 @@
+use std::collections::BTreeMap;
+
+use crate::cryptomatte::cryptomatte_id;
+use crate::filter::*;
+use crate::image::*;
+use crate::linalg::*;
+use crate::tracer::ObjectId;
+
+//Sum of linear radiance, coverage and sample weight per pixel, what the Renderer writes into. Renders add
+//to it, so passes over different sample ranges build up one image, and it is resolved into an
+//Image for output.
+#[derive(Clone, Debug)]
+pub struct FrameBuffer {
+    width: usize,
+    height: usize,
+    sum: Vec<Color>,
+    //Samples that didn't show a transparent backdrop, see Backdrop::Transparent
+    coverage: Vec<fCol>,
+    weight: Vec<fCol>,
+    aovs: Option<Aovs>,
+}
+
+//Arbitrary output variables: what camera rays see at their first hit, for external denoisers
+//and compositing
+#[derive(Clone, Debug)]
+pub struct Aovs {
+    //Averaged over all samples like the radiance, black and zero where the environment is seen
+    pub(crate) albedo: Vec<Color>,
+    pub(crate) normal: Vec<Vec3>,
+    //Nearest over all samples, infinite for the environment
+    pub depth: Vec<fVec>,
+    //Of the first sample that hit something
+    pub(crate) object: Vec<Option<ObjectId>>,
+    //Cryptomatte ids seen by the samples with their share of them, most covering first after
+    //aovs(), and the names behind the ids
+    pub(crate) object_ids: Vec<Vec<(u32, fCol)>>,
+    pub(crate) material_ids: Vec<Vec<(u32, fCol)>>,
+    pub(crate) object_names: BTreeMap<String, u32>,
+    pub(crate) material_names: BTreeMap<String, u32>,
+    samples: Vec<fCol>,
+    //Radiance per object seen, for deep output, None unless asked for
+    fragments: Option<Vec<Vec<Fragment>>>,
+}
+
+//Samples of a pixel whose first hit was the same object, or the environment for None
+#[derive(Clone, Debug)]
+struct Fragment {
+    object: Option<ObjectId>,
+    //Nearest hit
+    depth: fVec,
+    sum: Color,
+    coverage: fCol,
+}
+
+impl Aovs {
+    fn new(len: usize) -> Aovs {
+        Aovs {
+            albedo: vec![Color::black(); len],
+            normal: vec![Vec3::origin(); len],
+            depth: vec![fVec::INFINITY; len],
+            object: vec![None; len],
+            object_ids: vec![Vec::new(); len],
+            material_ids: vec![Vec::new(); len],
+            object_names: BTreeMap::new(),
+            material_names: BTreeMap::new(),
+            samples: vec![0.0; len],
+            fragments: None,
+        }
+    }
+
+    fn add_id(ids: &mut Vec<(u32, fCol)>, names: &mut BTreeMap<String, u32>, name: &str) {
+        let id = match names.get(name) {
+            Some(&id) => id,
+            None => *names.entry(name.to_string()).or_insert(cryptomatte_id(name)),
+        };
+        match ids.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, count)) => *count += 1.0,
+            None => ids.push((id, 1.0)),
+        }
+    }
+
+    fn coverage(&self, ids: &[Vec<(u32, fCol)>]) -> Vec<Vec<(u32, fCol)>> {
+        ids.iter()
+            .zip(self.samples.iter())
+            .map(|(ids, &n)| {
+                let mut ids: Vec<(u32, fCol)> = ids.iter().map(|&(id, count)| (id, count / n)).collect();
+                ids.sort_by(|a, b| b.1.total_cmp(&a.1));
+                ids
+            })
+            .collect()
+    }
+
+    fn average<T: Copy>(&self, values: &[T], scale: impl Fn(T, fCol) -> T) -> Vec<T> {
+        values
+            .iter()
+            .zip(self.samples.iter())
+            .map(|(&v, &n)| if n > 0.0 { scale(v, 1.0 / n) } else { v })
+            .collect()
+    }
+}
+
+//First hit of a camera ray sample
+pub struct FirstHitSample<'a> {
+    pub(crate) albedo: Color,
+    pub(crate) normal: Vec3,
+    pub depth: fVec,
+    pub(crate) object: Option<ObjectId>,
+    //Object and material name, unnamed materials are left out of the material mattes
+    pub(crate) names: Option<(&'a str, Option<&'a str>)>,
+    //What the sample adds to the pixel, for deep output
+    pub(crate) radiance: Color,
+    pub(crate) alpha: fCol,
+}
+
+impl FrameBuffer {
+    pub fn new(width: usize, height: usize) -> FrameBuffer {
+        FrameBuffer {
+            width,
+            height,
+            sum: vec![Color::black(); width * height],
+            coverage: vec![0.0; width * height],
+            weight: vec![0.0; width * height],
+            aovs: None,
+        }
+    }
+
+    //Also record albedo, normal, depth and object AOVs. They are written by tiled rendering, not
+    //by the half resolution indirect mode, and not kept in checkpoints.
+    pub fn with_aovs(width: usize, height: usize) -> FrameBuffer {
+        FrameBuffer {
+            aovs: Some(Aovs::new(width * height)),
+            ..FrameBuffer::new(width, height)
+        }
+    }
+
+    //AOVs plus the radiance of every object seen by a pixel, see deep_samples()
+    pub fn with_deep(width: usize, height: usize) -> FrameBuffer {
+        let mut frame = FrameBuffer::with_aovs(width, height);
+        if let Some(aovs) = &mut frame.aovs {
+            aovs.fragments = Some(vec![Vec::new(); width * height]);
+        }
+        frame
+    }
+
+    pub fn has_aovs(&self) -> bool {
+        self.aovs.is_some()
+    }
+
+    //Add a sample to the AOVs, if recorded
+    pub fn record_first_hit(&mut self, x: usize, y: usize, hit: &FirstHitSample) {
+        let i = self.index(x, y).expect("pixel outside of the frame buffer");
+        let Some(aovs) = &mut self.aovs else {
+            return;
+        };
+        aovs.albedo[i] = aovs.albedo[i] + hit.albedo;
+        aovs.normal[i] = aovs.normal[i] + hit.normal;
+        aovs.depth[i] = aovs.depth[i].min(hit.depth);
+        if aovs.object[i].is_none() {
+            aovs.object[i] = hit.object;
+        }
+        if let Some((object, material)) = hit.names {
+            Aovs::add_id(&mut aovs.object_ids[i], &mut aovs.object_names, object);
+            if let Some(material) = material {
+                Aovs::add_id(&mut aovs.material_ids[i], &mut aovs.material_names, material);
+            }
+        }
+        aovs.samples[i] += 1.0;
+        if let Some(fragments) = &mut aovs.fragments {
+            //Invalid samples count as opaque black like in the image
+            let valid = hit.radiance.r.is_finite() && hit.radiance.g.is_finite() && hit.radiance.b.is_finite();
+            let (radiance, alpha) = if valid { (hit.radiance, hit.alpha) } else { (Color::black(), 1.0) };
+            let fragments = &mut fragments[i];
+            let fragment = match fragments.iter().position(|f| f.object == hit.object) {
+                Some(k) => &mut fragments[k],
+                None => {
+                    fragments.push(Fragment {
+                        object: hit.object,
+                        depth: fVec::INFINITY,
+                        sum: Color::black(),
+                        coverage: 0.0,
+                    });
+                    fragments.last_mut().unwrap()
+                }
+            };
+            fragment.depth = fragment.depth.min(hit.depth);
+            fragment.sum = fragment.sum + radiance;
+            fragment.coverage += alpha;
+        }
+    }
+
+    //Deep samples of every pixel front to back, one per object seen and one for the environment.
+    //Each object covers its share of the samples, so alphas are relative to what the samples in
+    //front leave over: compositing them over each other gives the average of the image again.
+    pub fn deep_samples(&self) -> Option<Vec<Vec<DeepSample>>> {
+        let aovs = self.aovs.as_ref()?;
+        let fragments = aovs.fragments.as_ref()?;
+        let pixels = fragments
+            .iter()
+            .zip(aovs.samples.iter())
+            .map(|(fragments, &n)| {
+                let mut fragments: Vec<&Fragment> = fragments.iter().collect();
+                fragments.sort_by(|a, b| a.depth.total_cmp(&b.depth));
+                //Share of the pixel not covered by the samples so far
+                let mut left = 1.0;
+                let mut out = Vec::with_capacity(fragments.len());
+                for f in fragments {
+                    if left <= 1e-6 {
+                        break;
+                    }
+                    let (color, coverage) = (f.sum * (1.0 / n), f.coverage / n);
+                    out.push(DeepSample {
+                        color: color * (1.0 / left),
+                        alpha: (coverage / left).min(1.0),
+                        depth: f.depth,
+                    });
+                    left -= coverage;
+                }
+                out
+            })
+            .collect();
+        Some(pixels)
+    }
+
+    //Sums of albedo, normal and id counts are divided by the sample count
+    pub fn aovs(&self) -> Option<Aovs> {
+        let aovs = self.aovs.as_ref()?;
+        Some(Aovs {
+            albedo: aovs.average(&aovs.albedo, |c, s| c * s),
+            normal: aovs.average(&aovs.normal, |n, s| n * s),
+            depth: aovs.depth.clone(),
+            object: aovs.object.clone(),
+            object_ids: aovs.coverage(&aovs.object_ids),
+            material_ids: aovs.coverage(&aovs.material_ids),
+            object_names: aovs.object_names.clone(),
+            material_names: aovs.material_names.clone(),
+            samples: aovs.samples.clone(),
+            //Only needed for deep_samples()
+            fragments: None,
+        })
+    }
+
+    //First hit normal and depth from the AOVs, to guide filters
+    pub fn geometry(&self) -> Option<GBuffer> {
+        let aovs = self.aovs()?;
+        Some(GBuffer {
+            width: self.width,
+            height: self.height,
+            normal: aovs
+                .normal
+                .iter()
+                .map(|n| if n.is_tiny(0.0001) { *n } else { n.unit() })
+                .collect(),
+            depth: aovs.depth,
+        })
+    }
+
+    //Copy with the average radiance denoised, None without AOVs to guide the denoiser
+    pub fn denoised(&self, denoiser: &Denoiser) -> Option<FrameBuffer> {
+        let geometry = self.geometry()?;
+        let colors: Vec<Color> = (0..self.sum.len())
+            .map(|i| if self.weight[i] > 0.0 { self.sum[i] * (1.0 / self.weight[i]) } else { Color::black() })
+            .collect();
+        let mut out = self.clone();
+        for (i, col) in atrous_denoise(&colors, &geometry, denoiser).into_iter().enumerate() {
+            //Pixels without samples stay empty
+            out.sum[i] = col * self.weight[i];
+        }
+        Some(out)
+    }
+
+    //AOVs as named linear images: albedo, normal (xyz as rgb, not normalized), depth and object
+    //index plus one, 0 where no object is seen, in all channels. Meant for float formats like EXR.
+    pub fn aov_images(&self) -> Option<[(&'static str, Image); 4]> {
+        let aovs = self.aovs()?;
+        let image = |f: &dyn Fn(usize) -> Color| {
+            let mut img = Image::new(self.width, self.height);
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    *img.px_mut(x, y).unwrap() = f(y * self.width + x);
+                }
+            }
+            img
+        };
+        let gray = |v: fVec| Color::new(v, v, v);
+        Some([
+            ("albedo", image(&|i| aovs.albedo[i])),
+            ("normal", image(&|i| Color::new(aovs.normal[i].x, aovs.normal[i].y, aovs.normal[i].z))),
+            ("depth", image(&|i| gray(aovs.depth[i]))),
+            ("object", image(&|i| gray(aovs.object[i].map_or(0.0, |id| id.index() as fVec + 1.0)))),
+        ])
+    }
+
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    #[inline]
+    fn index(&self, x: usize, y: usize) -> Option<usize> {
+        (x < self.width && y < self.height).then(|| y * self.width + x)
+    }
+
+    //Add samples whose radiance and coverage sum up to sum and coverage, panics outside of the buffer
+    #[inline]
+    pub fn accumulate(&mut self, x: usize, y: usize, sum: Color, coverage: fCol, weight: fCol) {
+        let i = self.index(x, y).expect("pixel outside of the frame buffer");
+        self.sum[i] = self.sum[i] + sum;
+        self.coverage[i] += coverage;
+        self.weight[i] += weight;
+    }
+
+    //Overwrite the color of a pixel with an opaque one, keeping its weight
+    pub fn set(&mut self, x: usize, y: usize, color: Color) {
+        let i = self.index(x, y).expect("pixel outside of the frame buffer");
+        if self.weight[i] <= 0.0 {
+            self.weight[i] = 1.0;
+        }
+        self.sum[i] = color * self.weight[i];
+        self.coverage[i] = self.weight[i];
+    }
+
+    //Weighted average radiance, black for pixels without samples
+    #[inline]
+    pub fn color(&self, x: usize, y: usize) -> Option<Color> {
+        let i = self.index(x, y)?;
+        Some(if self.weight[i] > 0.0 {
+            self.sum[i] * (1.0 / self.weight[i])
+        } else {
+            Color::black()
+        })
+    }
+
+    //Fraction of the samples that hit the scene, 0 for pixels without samples
+    #[inline]
+    pub fn alpha(&self, x: usize, y: usize) -> Option<fCol> {
+        let i = self.index(x, y)?;
+        Some(if self.weight[i] > 0.0 {
+            self.coverage[i] / self.weight[i]
+        } else {
+            0.0
+        })
+    }
+
+    #[inline]
+    pub fn weight(&self, x: usize, y: usize) -> Option<fCol> {
+        self.index(x, y).map(|i| self.weight[i])
+    }
+
+    pub fn clear(&mut self) {
+        self.sum.fill(Color::black());
+        self.coverage.fill(0.0);
+        self.weight.fill(0.0);
+        if let Some(aovs) = &mut self.aovs {
+            let deep = aovs.fragments.is_some();
+            *aovs = Aovs::new(self.width * self.height);
+            if deep {
+                aovs.fragments = Some(vec![Vec::new(); self.width * self.height]);
+            }
+        }
+    }
+
+    //Take the pixels in [x0, x1) x [y0, y1) from a buffer of the same size, e.g. tiles rendered
+    //by another thread
+    pub fn copy_rect(&mut self, other: &FrameBuffer, (x0, y0): (usize, usize), (x1, y1): (usize, usize)) {
+        assert_eq!((self.width, self.height), (other.width, other.height), "frame buffer sizes differ");
+        for y in y0..y1.min(self.height) {
+            let row = y * self.width;
+            let (start, end) = (row + x0.min(self.width), row + x1.min(self.width));
+            self.sum[start..end].copy_from_slice(&other.sum[start..end]);
+            self.coverage[start..end].copy_from_slice(&other.coverage[start..end]);
+            self.weight[start..end].copy_from_slice(&other.weight[start..end]);
+            if let (Some(aovs), Some(other)) = (&mut self.aovs, &other.aovs) {
+                aovs.albedo[start..end].copy_from_slice(&other.albedo[start..end]);
+                aovs.normal[start..end].copy_from_slice(&other.normal[start..end]);
+                aovs.depth[start..end].copy_from_slice(&other.depth[start..end]);
+                aovs.object[start..end].copy_from_slice(&other.object[start..end]);
+                aovs.object_ids[start..end].clone_from_slice(&other.object_ids[start..end]);
+                aovs.material_ids[start..end].clone_from_slice(&other.material_ids[start..end]);
+                aovs.samples[start..end].copy_from_slice(&other.samples[start..end]);
+                if let (Some(fragments), Some(other)) = (&mut aovs.fragments, &other.fragments) {
+                    fragments[start..end].clone_from_slice(&other[start..end]);
+                }
+            }
+        }
+        if let (Some(aovs), Some(other)) = (&mut self.aovs, &other.aovs) {
+            aovs.object_names.extend(other.object_names.iter().map(|(n, id)| (n.clone(), *id)));
+            aovs.material_names.extend(other.material_names.iter().map(|(n, id)| (n.clone(), *id)));
+        }
+    }
+
+    //Alpha of every pixel, as Image::set_alpha() takes it
+    pub fn alpha_channel(&self) -> Vec<fCol> {
+        (0..self.width * self.height).map(|i| self.alpha(i % self.width, i / self.width).unwrap()).collect()
+    }
+
+    pub fn resolve(&self) -> Image {
+        self.resolve_with(|col| col)
+    }
+
+    //Image of the average radiance passed through map, e.g. to limit highlights
+    pub fn resolve_with(&self, map: impl Fn(Color) -> Color) -> Image {
+        let mut img = Image::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                *img.px_mut(x, y).unwrap() = map(self.color(x, y).unwrap());
+            }
+        }
+        img
+    }
+}
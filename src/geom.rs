@@ -0,0 +1,209 @@
+use crate::linalg::*;
+use crate::tracer::*;
+
+//Axis aligned bounding box, empty when min > max on any axis
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    //Contains nothing, the identity of union() and grow()
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3::new(fVec::INFINITY, fVec::INFINITY, fVec::INFINITY),
+            max: Vec3::new(-fVec::INFINITY, -fVec::INFINITY, -fVec::INFINITY),
+        }
+    }
+
+    #[inline]
+    pub fn point(p: Vec3) -> Aabb {
+        Aabb { min: p, max: p }
+    }
+
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Aabb {
+        points.into_iter().fold(Aabb::empty(), |b, p| b.grow(p))
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    //Smallest box containing self and p
+    #[inline]
+    pub fn grow(self, p: Vec3) -> Aabb {
+        self.union(Aabb::point(p))
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: Vec3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Vec3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z)),
+        }
+    }
+
+    //Overlap of both boxes, empty if they are disjoint
+    pub fn intersect(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: Vec3::new(self.min.x.max(other.min.x), self.min.y.max(other.min.y), self.min.z.max(other.min.z)),
+            max: Vec3::new(self.max.x.min(other.max.x), self.max.y.min(other.max.y), self.max.z.min(other.max.z)),
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, p: Vec3) -> bool {
+        (self.min.x..=self.max.x).contains(&p.x)
+            && (self.min.y..=self.max.y).contains(&p.y)
+            && (self.min.z..=self.max.z).contains(&p.z)
+    }
+
+    #[inline]
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    #[inline]
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) / 2.0
+    }
+
+    //0 for empty boxes
+    pub fn surface_area(&self) -> fVec {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let e = self.extent();
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+
+    //Index of the longest axis, 0 to 2 for x to z
+    pub fn widest_axis(&self) -> usize {
+        let e = self.extent();
+        if e.x >= e.y && e.x >= e.z {
+            0
+        } else if e.y >= e.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    //Slab test, the part of the ray's [min, max] interval inside the box
+    pub fn ray_interval(&self, ray: &Ray) -> Option<(fVec, fVec)> {
+        let mut t0 = ray.min;
+        let mut t1 = ray.max;
+        for (o, d, lo, hi) in [
+            (ray.origin.x, ray.direction.x, self.min.x, self.max.x),
+            (ray.origin.y, ray.direction.y, self.min.y, self.max.y),
+            (ray.origin.z, ray.direction.z, self.min.z, self.max.z),
+        ] {
+            let inv = 1.0 / d;
+            let (near, far) = {
+                let a = (lo - o) * inv;
+                let b = (hi - o) * inv;
+                if a < b {
+                    (a, b)
+                } else {
+                    (b, a)
+                }
+            };
+            //NaN from 0 * inf leaves the interval unchanged
+            if near > t0 {
+                t0 = near;
+            }
+            if far < t1 {
+                t1 = far;
+            }
+            if t0 > t1 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+
+    #[inline]
+    pub fn hit(&self, ray: &Ray) -> bool {
+        self.ray_interval(ray).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box() -> Aabb {
+        Aabb {
+            min: Vec3::origin(),
+            max: Vec3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn empty_is_identity() {
+        let b = unit_box();
+        assert!(Aabb::empty().is_empty());
+        assert_eq!(Aabb::empty().union(b), b);
+        assert_eq!(Aabb::empty().surface_area(), 0.0);
+    }
+
+    #[test]
+    fn grow_and_union() {
+        let b = Aabb::from_points([Vec3::new(1.0, -2.0, 0.5), Vec3::new(-1.0, 3.0, 0.0)]);
+        assert_eq!(b.min, Vec3::new(-1.0, -2.0, 0.0));
+        assert_eq!(b.max, Vec3::new(1.0, 3.0, 0.5));
+        assert!(b.contains(Vec3::new(0.0, 0.0, 0.25)));
+        assert!(!b.contains(Vec3::new(0.0, 0.0, 1.0)));
+
+        let u = unit_box().union(Aabb::point(Vec3::new(2.0, 0.5, 0.5)));
+        assert_eq!(u.max, Vec3::new(2.0, 1.0, 1.0));
+        assert_eq!(u.widest_axis(), 0);
+    }
+
+    #[test]
+    fn intersect() {
+        let shifted = Aabb {
+            min: Vec3::new(0.5, 0.5, 0.5),
+            max: Vec3::new(2.0, 2.0, 2.0),
+        };
+        let overlap = unit_box().intersect(shifted);
+        assert_eq!(overlap.min, Vec3::new(0.5, 0.5, 0.5));
+        assert_eq!(overlap.max, Vec3::new(1.0, 1.0, 1.0));
+
+        let far = Aabb::point(Vec3::new(5.0, 5.0, 5.0));
+        assert!(unit_box().intersect(far).is_empty());
+    }
+
+    #[test]
+    fn area_and_centroid() {
+        let b = Aabb {
+            min: Vec3::origin(),
+            max: Vec3::new(1.0, 2.0, 3.0),
+        };
+        assert_eq!(b.surface_area(), 22.0);
+        assert_eq!(b.centroid(), Vec3::new(0.5, 1.0, 1.5));
+        assert_eq!(b.widest_axis(), 2);
+    }
+
+    #[test]
+    fn ray_slab() {
+        let b = unit_box();
+        let ray = Ray::new(Vec3::new(0.5, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(b.ray_interval(&ray), Some((1.0, 2.0)));
+
+        //Axis parallel ray outside the slab
+        let miss = Ray::new(Vec3::new(2.0, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!(!b.hit(&miss));
+
+        //Box behind the ray
+        let behind = Ray::new(Vec3::new(0.5, 0.5, 3.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!(!b.hit(&behind));
+
+        //Starting inside clamps to the ray's own interval
+        let inside = Ray::new(Vec3::new(0.5, 0.5, 0.5), Vec3::new(1.0, 1.0, 0.0));
+        let (t0, t1) = b.ray_interval(&inside).unwrap();
+        assert_eq!(t0, inside.min);
+        assert_eq!(t1, 0.5);
+    }
+}
@@ -0,0 +1,394 @@
+use std::cell::RefCell;
+use std::f32::consts::PI;
+
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use crate::geom::*;
+use crate::image::*;
+use crate::linalg::*;
+use crate::tracer::*;
+
+//Spatial-directional tree (SD-tree) after Mueller et al., "Practical Path Guiding": a binary tree
+//over space whose leaves hold quadtrees over the sphere of directions. Instead of training in
+//separate passes, every spatial leaf learns on its own schedule: once it has recorded twice as
+//many paths as at its last rebuild, what it recorded becomes its sampling distribution, and leaves
+//that recorded many paths are split in half.
+
+//Probability of sampling from the material instead of the learned distribution
+const BSDF_FRACTION: fVec = 0.5;
+//Fraction of the learned distribution spread uniformly, keeps unexplored directions reachable
+const UNIFORM_FRACTION: fVec = 0.1;
+//Quadrants holding more than this fraction of the energy are subdivided at a rebuild
+const SUBDIVIDE_FRACTION: fCol = 0.01;
+const MAX_QUAD_DEPTH: usize = 16;
+//Paths recorded before a leaf first samples from what it learned, doubling from then on
+const FIRST_REBUILD: usize = 128;
+//Paths a spatial leaf records before it is split
+const SPLIT_SAMPLES: usize = 4096;
+
+//Equal-area cylindrical mapping between directions and the unit square, cos(theta) along u
+fn to_square(dir: Vec3) -> (fVec, fVec) {
+    let u = ((dir.y + 1.0) / 2.0).clamp(0.0, 1.0);
+    let v = dir.z.atan2(dir.x).rem_euclid(2.0 * PI) / (2.0 * PI);
+    (u, v.clamp(0.0, 1.0))
+}
+
+fn from_square(u: fVec, v: fVec) -> Vec3 {
+    let cos_theta = 2.0 * u - 1.0;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vec3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin())
+}
+
+//Quadrant of (u, v) and the position within it
+#[inline]
+fn quadrant(u: fVec, v: fVec) -> (usize, fVec, fVec) {
+    let (qu, qv) = ((u >= 0.5) as usize, (v >= 0.5) as usize);
+    (qu * 2 + qv, (2.0 * u - qu as fVec).min(1.0), (2.0 * v - qv as fVec).min(1.0))
+}
+
+//Energy per quadrant, children[q] is 0 for quadrants that aren't subdivided
+#[derive(Clone, Copy)]
+struct QuadNode {
+    sum: [fCol; 4],
+    children: [u32; 4],
+}
+
+//Adaptive histogram over the unit square, node 0 is the root
+#[derive(Clone)]
+struct QuadTree {
+    nodes: Vec<QuadNode>,
+}
+
+impl QuadTree {
+    fn new() -> QuadTree {
+        QuadTree {
+            nodes: vec![QuadNode {
+                sum: [0.0; 4],
+                children: [0; 4],
+            }],
+        }
+    }
+
+    fn total(&self) -> fCol {
+        self.nodes[0].sum.iter().sum()
+    }
+
+    fn record(&mut self, (mut u, mut v): (fVec, fVec), value: fCol) {
+        let mut i = 0;
+        loop {
+            let (q, qu, qv) = quadrant(u, v);
+            self.nodes[i].sum[q] += value;
+            match self.nodes[i].children[q] {
+                0 => break,
+                child => (i, u, v) = (child as usize, qu, qv),
+            }
+        }
+    }
+
+    //Density over the unit square, uniform while nothing is recorded
+    fn pdf(&self, (mut u, mut v): (fVec, fVec)) -> fVec {
+        if self.total() <= 0.0 {
+            return 1.0;
+        }
+        let mut density = 1.0;
+        let mut i = 0;
+        loop {
+            let node = &self.nodes[i];
+            let (q, qu, qv) = quadrant(u, v);
+            let sum: fCol = node.sum.iter().sum();
+            if node.sum[q] <= 0.0 {
+                return 0.0;
+            }
+            density *= 4.0 * node.sum[q] / sum;
+            match node.children[q] {
+                0 => return density,
+                child => (i, u, v) = (child as usize, qu, qv),
+            }
+        }
+    }
+
+    //Point on the unit square distributed like pdf(), for a tree with energy
+    fn sample(&self, rng: &mut impl Rng) -> (fVec, fVec) {
+        let (mut origin, mut size) = ((0.0, 0.0), 1.0);
+        let mut i = 0;
+        loop {
+            let node = &self.nodes[i];
+            let mut target = rng.gen_range(0.0..1.0) * node.sum.iter().sum::<fCol>();
+            let mut q = 3;
+            for (k, &s) in node.sum.iter().enumerate() {
+                if target < s {
+                    q = k;
+                    break;
+                }
+                target -= s;
+            }
+            size /= 2.0;
+            origin = (origin.0 + (q / 2) as fVec * size, origin.1 + (q % 2) as fVec * size);
+            match node.children[q] {
+                0 => return (origin.0 + rng.gen_range(0.0..size), origin.1 + rng.gen_range(0.0..size)),
+                child => i = child as usize,
+            }
+        }
+    }
+
+    //Tree keeping the energy, subdivided where it is concentrated and collapsed where it is not
+    fn refined(&self) -> QuadTree {
+        let total = self.total();
+        let mut out = QuadTree::new();
+        if total > 0.0 {
+            self.refine_node(Some(0), self.nodes[0].sum, total, 0, 0, &mut out);
+        }
+        out
+    }
+
+    //Fills node at of out from node src of self, or spreads energy evenly for new nodes
+    fn refine_node(&self, src: Option<usize>, sum: [fCol; 4], total: fCol, at: usize, depth: usize, out: &mut QuadTree) {
+        out.nodes[at].sum = sum;
+        for (q, &energy) in sum.iter().enumerate() {
+            if depth + 1 >= MAX_QUAD_DEPTH || energy <= SUBDIVIDE_FRACTION * total {
+                continue;
+            }
+            let child_src = src.map(|i| self.nodes[i].children[q] as usize).filter(|&c| c != 0);
+            let child_sum = match child_src {
+                Some(c) => self.nodes[c].sum,
+                None => [energy / 4.0; 4],
+            };
+            let child = out.nodes.len();
+            out.nodes.push(QuadNode {
+                sum: [0.0; 4],
+                children: [0; 4],
+            });
+            out.nodes[at].children[q] = child as u32;
+            self.refine_node(child_src, child_sum, total, child, depth + 1, out);
+        }
+    }
+
+    //Same subdivision without energy
+    fn cleared(&self) -> QuadTree {
+        let mut out = self.clone();
+        for node in out.nodes.iter_mut() {
+            node.sum = [0.0; 4];
+        }
+        out
+    }
+}
+
+//Directional distribution of a spatial leaf: sampled from the last rebuild while recording into
+//the next one
+#[derive(Clone)]
+struct DirectionalTree {
+    sampling: QuadTree,
+    recording: QuadTree,
+    samples: usize,
+    next_rebuild: usize,
+}
+
+impl DirectionalTree {
+    fn new() -> DirectionalTree {
+        DirectionalTree {
+            sampling: QuadTree::new(),
+            recording: QuadTree::new(),
+            samples: 0,
+            next_rebuild: FIRST_REBUILD,
+        }
+    }
+
+    fn record(&mut self, dir: Vec3, value: fCol) {
+        self.recording.record(to_square(dir), value);
+        self.samples += 1;
+        if self.samples >= self.next_rebuild {
+            self.sampling = self.recording.refined();
+            self.recording = self.sampling.cleared();
+            self.next_rebuild *= 2;
+        }
+    }
+
+    //Solid angle density of sample()
+    fn pdf(&self, dir: Vec3) -> fVec {
+        let uniform = 1.0 / (4.0 * PI);
+        UNIFORM_FRACTION * uniform + (1.0 - UNIFORM_FRACTION) * self.sampling.pdf(to_square(dir)) * uniform
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> Vec3 {
+        let (u, v) = if rng.gen_range(0.0..1.0) < UNIFORM_FRACTION {
+            (rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0))
+        } else {
+            self.sampling.sample(rng)
+        };
+        from_square(u, v)
+    }
+}
+
+enum SpatialNode {
+    //Points below split on the axis go to the first child
+    Inner { axis: usize, split: fVec, children: [usize; 2] },
+    Leaf { bounds: Aabb, tree: DirectionalTree },
+}
+
+#[inline]
+fn coordinate(p: Vec3, axis: usize) -> fVec {
+    match axis {
+        0 => p.x,
+        1 => p.y,
+        _ => p.z,
+    }
+}
+
+//Spatial-directional radiance cache learned while rendering, used to importance sample bounces
+pub struct PathGuide {
+    //Leaves are not split below this size
+    min_cell_size: fVec,
+    nodes: RefCell<Vec<SpatialNode>>,
+    rng: RefCell<SmallRng>,
+}
+
+impl PathGuide {
+    //bounds is where the scene is, points outside of it fall into the nearest leaves
+    pub fn new(bounds: Aabb, min_cell_size: fVec, seed: u64) -> Self {
+        let bounds = if bounds.is_empty() {
+            Aabb {
+                min: Vec3::new(-1.0, -1.0, -1.0),
+                max: Vec3::new(1.0, 1.0, 1.0),
+            }
+        } else {
+            bounds
+        };
+        Self {
+            min_cell_size,
+            nodes: RefCell::new(vec![SpatialNode::Leaf {
+                bounds,
+                tree: DirectionalTree::new(),
+            }]),
+            rng: RefCell::new(SmallRng::seed_from_u64(seed)),
+        }
+    }
+
+    fn leaf(nodes: &[SpatialNode], p: Vec3) -> usize {
+        let mut i = 0;
+        while let SpatialNode::Inner { axis, split, children } = &nodes[i] {
+            i = children[(coordinate(p, *axis) >= *split) as usize];
+        }
+        i
+    }
+
+    //Halve a leaf along its widest axis, both halves start from what it learned
+    fn split(&self, nodes: &mut Vec<SpatialNode>, i: usize) {
+        let SpatialNode::Leaf { bounds, tree } = &nodes[i] else {
+            return;
+        };
+        let axis = bounds.widest_axis();
+        let (lo, hi) = (coordinate(bounds.min, axis), coordinate(bounds.max, axis));
+        if (hi - lo) / 2.0 < self.min_cell_size {
+            return;
+        }
+        let split = (lo + hi) / 2.0;
+        let mut tree = tree.clone();
+        tree.samples /= 2;
+        let (mut first, mut second) = (*bounds, *bounds);
+        match axis {
+            0 => (first.max.x, second.min.x) = (split, split),
+            1 => (first.max.y, second.min.y) = (split, split),
+            _ => (first.max.z, second.min.z) = (split, split),
+        }
+        let children = [nodes.len(), nodes.len() + 1];
+        nodes.push(SpatialNode::Leaf {
+            bounds: first,
+            tree: tree.clone(),
+        });
+        nodes.push(SpatialNode::Leaf { bounds: second, tree });
+        nodes[i] = SpatialNode::Inner { axis, split, children };
+    }
+
+    //Learn that radiance arrived at point from dir
+    pub fn record(&self, point: Vec3, dir: Vec3, radiance: Color) {
+        let lum = radiance.luminance();
+        if !lum.is_finite() || lum <= 0.0 {
+            return;
+        }
+        let mut nodes = self.nodes.borrow_mut();
+        let i = Self::leaf(&nodes, point);
+        let SpatialNode::Leaf { tree, .. } = &mut nodes[i] else {
+            unreachable!()
+        };
+        tree.record(dir.unit(), lum);
+        if tree.samples >= SPLIT_SAMPLES {
+            self.split(&mut nodes, i);
+        }
+    }
+
+    //Like Material::bounce, but mixes material sampling with the learned distribution
+    pub fn bounce(&self, ray: &Ray, hit: &HitResult, material: &dyn Material) -> (Color, Option<Ray>) {
+        let (col, bounced) = material.bounce(ray, hit);
+        let bounced = match bounced {
+            Some(b) => b,
+            None => return (col, None),
+        };
+        if material.pdf(ray, hit, bounced.direction.unit()) <= 0.0 {
+            return (col, Some(bounced));
+        }
+
+        let nodes = self.nodes.borrow();
+        let SpatialNode::Leaf { tree, .. } = &nodes[Self::leaf(&nodes, hit.intersect)] else {
+            unreachable!()
+        };
+        //Nothing learned here yet
+        if tree.sampling.total() <= 0.0 {
+            return (col, Some(bounced));
+        }
+
+        let mut rng = self.rng.borrow_mut();
+        let dir = if rng.gen_range(0.0..1.0) < BSDF_FRACTION {
+            bounced.direction.unit()
+        } else {
+            tree.sample(&mut *rng)
+        };
+
+        let pdf = BSDF_FRACTION * material.pdf(ray, hit, dir) + (1.0 - BSDF_FRACTION) * tree.pdf(dir);
+        let f = material.eval(ray, hit, dir);
+        if pdf <= 0.0 || f == Color::black() {
+            return (Color::black(), None);
+        }
+        (f * (1.0 / pdf), Some(Ray::new(hit.intersect, dir)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //The learned distribution is a density over the sphere that follows the recorded radiance
+    #[test]
+    fn quadtree_learns_a_normalized_density() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let bright = Vec3::new(0.3, 0.9, 0.1).unit();
+        let mut tree = DirectionalTree::new();
+        for _ in 0..4 * FIRST_REBUILD {
+            //Most radiance from a cone around bright, coarse enough for the grid below to resolve
+            let in_cone = rng.gen_range(0.0..1.0) < 0.8;
+            let dir = loop {
+                let dir = from_square(rng.gen(), rng.gen());
+                if !in_cone || dir * bright > 0.9 {
+                    break dir;
+                }
+            };
+            tree.record(dir, 1.0);
+        }
+        assert!(tree.sampling.nodes.len() > 1);
+
+        const N: usize = 256;
+        let mut integral = 0.0;
+        for i in 0..N {
+            for j in 0..N {
+                let dir = from_square((i as fVec + 0.5) / N as fVec, (j as fVec + 0.5) / N as fVec);
+                integral += tree.pdf(dir) * 4.0 * PI / (N * N) as fVec;
+            }
+        }
+        assert!((integral - 1.0).abs() < 0.01, "{integral}");
+        assert!(tree.pdf(bright) > 10.0 * tree.pdf(-bright));
+
+        let near = (0..1000).filter(|_| tree.sample(&mut rng) * bright > 0.9).count();
+        assert!(near > 500, "{near}");
+    }
+}
@@ -1,6 +1,9 @@
+use std::f32::consts::PI;
 use std::rc::Rc;
 
+use crate::geom::*;
 use crate::linalg::*;
+use crate::mesh::*;
 use crate::tracer::*;
 
 pub struct Sphere {
@@ -12,38 +15,192 @@ pub struct Sphere {
 impl Hit for Sphere {
     fn hit(&self, ray: &Ray) -> Option<HitResult> {
         let z = ray.origin - self.origin;
-        let a = ray.direction * ray.direction;
-        let half_b = z * ray.direction;
-        let c = z * z - self.radius * self.radius;
+        let (t_near, t_far) = solve_quadratic(
+            ray.direction * ray.direction,
+            2.0 * (z * ray.direction),
+            z * z - self.radius * self.radius,
+        )?;
+        let t = if ray.min <= t_near && t_near <= ray.max {
+            t_near
+        } else if ray.min <= t_far && t_far <= ray.max {
+            t_far
+        } else {
+            return None;
+        };
 
-        let disc = half_b * half_b - a * c;
+        let intersect = ray.at(t);
+        let local = intersect - self.origin;
+        let normal = local / self.radius;
+        Some(HitResult {
+            normal,
+            //Along the latitude circle around the y axis
+            tangent: Vec3::new(-local.z, 0.0, local.x),
+            uv: Sphere::uv(normal),
+            //v runs along half a great circle
+            uv_width: ray.uv_width(t, normal, 1.0 / (PI * self.radius)),
+            intersect,
+            face: 0,
+            at: t,
+        })
+    }
 
-        if disc < 0.0 {
-            None
-        } else {
-            let disc_sqrt = disc.sqrt();
-            let t;
-            let t_near = (-half_b - disc_sqrt) / a;
-            if ray.min <= t_near && t_near <= ray.max {
-                t = t_near;
-            } else {
-                let t_far = (-half_b + disc_sqrt) / a;
-                if ray.min <= t_far && t_far <= ray.max {
-                    t = t_far
-                } else {
-                    return None;
-                }
-            }
-            let intersect = ray.at(t);
-            Some(HitResult {
-                normal: (intersect - self.origin) / self.radius,
-                intersect: intersect,
-                at: t,
-            })
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        Some(Aabb {
+            min: self.origin - r,
+            max: self.origin + r,
+        })
+    }
+
+    fn to_mesh(&self, subdivisions: usize) -> Option<Mesh> {
+        Some(Sphere::to_mesh(self, subdivisions))
+    }
+}
+
+impl Sphere {
+    //Longitude and latitude of a point on the unit sphere, u grows along the tangent
+    pub fn uv(normal: Vec3) -> (fVec, fVec) {
+        let u = 0.5 + normal.z.atan2(normal.x) / (2.0 * PI);
+        let v = 0.5 + normal.y.clamp(-1.0, 1.0).asin() / PI;
+        (u, v)
+    }
+
+    pub fn to_mesh(&self, subdivisions: usize) -> Mesh {
+        Mesh::icosphere(subdivisions).transformed(self.radius, self.origin)
+    }
+}
+
+//Ring around the y axis through origin, the tube of minor_radius follows a circle of major_radius
+pub struct Torus {
+    pub origin: Vec3,
+    pub major_radius: fVec,
+    pub minor_radius: fVec,
+    pub material: Rc<dyn Material>,
+}
+
+impl Hit for Torus {
+    fn hit(&self, ray: &Ray) -> Option<HitResult> {
+        let (big, small) = (self.major_radius, self.minor_radius);
+        //Start from the bounding sphere, the quartic loses precision for far away origins
+        let mut z = ray.origin - self.origin;
+        let (t_enter, t_exit) = solve_quadratic(
+            ray.direction * ray.direction,
+            2.0 * (z * ray.direction),
+            z * z - (big + small) * (big + small),
+        )?;
+        if t_exit < ray.min || t_enter > ray.max {
+            return None;
         }
+        let start = t_enter.max(0.0);
+        z = z + ray.direction * start;
+
+        //|p|^2 + R^2 - r^2 = 2 R |p_xz| squared, with p = z + t d
+        let d = ray.direction;
+        let dd = d * d;
+        let zd = z * d;
+        let k = z * z + big * big - small * small;
+        let four_r2 = 4.0 * big * big;
+        let (roots, count) = solve_quartic(
+            dd * dd,
+            4.0 * dd * zd,
+            4.0 * zd * zd + 2.0 * dd * k - four_r2 * (d.x * d.x + d.z * d.z),
+            4.0 * zd * k - 2.0 * four_r2 * (z.x * d.x + z.z * d.z),
+            k * k - four_r2 * (z.x * z.x + z.z * z.z),
+        );
+        let t = roots[..count].iter().map(|&t| t + start).find(|&t| ray.min <= t && t <= ray.max)?;
+
+        let intersect = ray.at(t);
+        let local = intersect - self.origin;
+        let ring = Vec3::new(local.x, 0.0, local.z);
+        let center = if ring.length() > 0.0 { ring.unit() * big } else { Vec3::origin() };
+        let normal = (local - center).unit();
+        Some(HitResult {
+            normal,
+            //Along the ring
+            tangent: Vec3::new(-local.z, 0.0, local.x),
+            uv: (
+                0.5 + local.z.atan2(local.x) / (2.0 * PI),
+                0.5 + local.y.atan2(ring.length() - big) / (2.0 * PI),
+            ),
+            //v runs once around the tube
+            uv_width: ray.uv_width(t, normal, 1.0 / (2.0 * PI * small)),
+            intersect,
+            face: 0,
+            at: t,
+        })
     }
 
     fn material(&self) -> &dyn Material {
         self.material.as_ref()
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let outer = self.major_radius + self.minor_radius;
+        let r = Vec3::new(outer, self.minor_radius, outer);
+        Some(Aabb {
+            min: self.origin - r,
+            max: self.origin + r,
+        })
+    }
+
+    fn to_mesh(&self, subdivisions: usize) -> Option<Mesh> {
+        //Grid of rings around the tube, doubling the resolution per subdivision
+        let (rings, sides) = (12 << subdivisions, 6 << subdivisions);
+        let mut mesh = Mesh {
+            vertices: Vec::with_capacity(rings * sides),
+            normals: Vec::with_capacity(rings * sides),
+            uvs: Vec::with_capacity(rings * sides),
+            triangles: Vec::with_capacity(2 * rings * sides),
+            materials: Vec::new(),
+        };
+        for i in 0..rings {
+            let u = i as fVec / rings as fVec;
+            let around = Vec3::new((2.0 * PI * u).cos(), 0.0, (2.0 * PI * u).sin());
+            for j in 0..sides {
+                let v = j as fVec / sides as fVec;
+                let (sin, cos) = (2.0 * PI * v).sin_cos();
+                let normal = around * cos + Vec3::unit_y() * sin;
+                mesh.vertices.push(self.origin + around * self.major_radius + normal * self.minor_radius);
+                mesh.normals.push(normal);
+                mesh.uvs.push((u, v));
+                let index = |i: usize, j: usize| (i % rings) * sides + j % sides;
+                let quad = [index(i, j), index(i + 1, j), index(i + 1, j + 1), index(i, j + 1)];
+                mesh.triangles.push([quad[0], quad[2], quad[1]]);
+                mesh.triangles.push([quad[0], quad[3], quad[2]]);
+            }
+        }
+        Some(mesh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torus_hits_the_tube_from_both_sides() {
+        let torus = Torus {
+            origin: Vec3::new(0.0, 1.0, 0.0),
+            major_radius: 1.0,
+            minor_radius: 0.25,
+            material: Rc::new(crate::material::DebugMaterial {}),
+        };
+        //Through the hole along the axis
+        assert!(torus.hit(&Ray::new(Vec3::new(0.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0))).is_none());
+        //Across the ring from far away, the tube is entered at x = -1.25 and x = 0.75
+        let mut ray = Ray::new(Vec3::new(-1000.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hit = torus.hit(&ray).unwrap();
+        assert!((hit.intersect.x + 1.25).abs() < 1e-3, "{:?}", hit.intersect);
+        assert!((hit.normal - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-3);
+        ray.min = 1000.0;
+        let hit = torus.hit(&ray).unwrap();
+        assert!((hit.intersect.x - 0.75).abs() < 1e-3, "{:?}", hit.intersect);
+        //Grazing the top of the tube
+        let ray = Ray::new(Vec3::new(-3.0, 1.2499, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(torus.hit(&ray).is_some());
+    }
 }
@@ -0,0 +1,193 @@
+use std::{fs, io, path::Path};
+
+use crate::linalg::*;
+
+//Photometric web as described by IESNA LM-63 (type C photometry)
+//Vertical angle 0 points straight down the light axis (nadir)
+#[derive(Debug)]
+pub struct IesProfile {
+    vertical_angles: Vec<fVec>,
+    horizontal_angles: Vec<fVec>,
+    //candela[h * vertical_angles.len() + v], normalized to a maximum of 1
+    candela: Vec<fVec>,
+    max_candela: fVec,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("IES: {}", msg))
+}
+
+impl IesProfile {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(src: &str) -> io::Result<Self> {
+        let mut lines = src.lines();
+        let tilt = loop {
+            let line = lines.next().ok_or_else(|| invalid("missing TILT line"))?;
+            if let Some(tilt) = line.trim().strip_prefix("TILT=") {
+                break tilt.trim().to_string();
+            }
+        };
+
+        let mut nums = lines
+            .flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|t| !t.is_empty())
+            .map(|t| t.parse::<fVec>().map_err(|_| invalid("malformed number")));
+        let mut next = || nums.next().unwrap_or_else(|| Err(invalid("unexpected end of file")));
+
+        if tilt == "INCLUDE" {
+            let _lamp_to_luminaire = next()?;
+            let pairs = next()? as usize;
+            for _ in 0..2 * pairs {
+                next()?;
+            }
+        }
+
+        let _num_lamps = next()?;
+        let _lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let num_vertical = next()? as usize;
+        let num_horizontal = next()? as usize;
+        let photometric_type = next()?;
+        //units, width, length, height, ballast factor, future use, input watts
+        for _ in 0..7 {
+            next()?;
+        }
+
+        if photometric_type != 1.0 {
+            return Err(invalid("only type C photometry is supported"));
+        }
+        if num_vertical == 0 || num_horizontal == 0 {
+            return Err(invalid("empty photometric web"));
+        }
+
+        let vertical_angles = (0..num_vertical).map(|_| next()).collect::<io::Result<Vec<_>>>()?;
+        let horizontal_angles = (0..num_horizontal)
+            .map(|_| next())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut candela = (0..num_vertical * num_horizontal)
+            .map(|_| next().map(|c| c * multiplier))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let max_candela = candela.iter().cloned().fold(0.0, fVec::max);
+        if max_candela > 0.0 {
+            for c in candela.iter_mut() {
+                *c /= max_candela;
+            }
+        }
+
+        Ok(Self {
+            vertical_angles,
+            horizontal_angles,
+            candela,
+            max_candela,
+        })
+    }
+
+    #[inline]
+    pub fn max_candela(&self) -> fVec {
+        self.max_candela
+    }
+
+    //Relative intensity in [0, 1] for the given angles in degrees
+    pub fn intensity(&self, vertical: fVec, horizontal: fVec) -> fVec {
+        let h = self.fold_horizontal(horizontal.rem_euclid(360.0));
+        let (h0, h1, ht) = lerp_index(&self.horizontal_angles, h);
+        let (v0, v1, vt) = match lerp_index_bounded(&self.vertical_angles, vertical) {
+            Some(v) => v,
+            None => return 0.0,
+        };
+
+        let n = self.vertical_angles.len();
+        let at = |h: usize, v: usize| self.candela[h * n + v];
+        let lower = at(h0, v0) * (1.0 - vt) + at(h0, v1) * vt;
+        let upper = at(h1, v0) * (1.0 - vt) + at(h1, v1) * vt;
+        lower * (1.0 - ht) + upper * ht
+    }
+
+    //Map the horizontal angle into the range covered by the file using its symmetry
+    fn fold_horizontal(&self, h: fVec) -> fVec {
+        let last = *self.horizontal_angles.last().unwrap();
+        if last <= 0.0 {
+            0.0
+        } else if last <= 90.0 {
+            let h = h % 180.0;
+            if h > 90.0 {
+                180.0 - h
+            } else {
+                h
+            }
+        } else if last <= 180.0 {
+            if h > 180.0 {
+                360.0 - h
+            } else {
+                h
+            }
+        } else {
+            h
+        }
+    }
+}
+
+fn lerp_index(angles: &[fVec], a: fVec) -> (usize, usize, fVec) {
+    lerp_index_bounded(angles, a).unwrap_or_else(|| {
+        if a < angles[0] {
+            (0, 0, 0.0)
+        } else {
+            (angles.len() - 1, angles.len() - 1, 0.0)
+        }
+    })
+}
+
+fn lerp_index_bounded(angles: &[fVec], a: fVec) -> Option<(usize, usize, fVec)> {
+    if a < angles[0] || a > angles[angles.len() - 1] {
+        return None;
+    }
+    if angles.len() == 1 {
+        return Some((0, 0, 0.0));
+    }
+    let i = angles.partition_point(|&x| x <= a).clamp(1, angles.len() - 1);
+    let span = angles[i] - angles[i - 1];
+    let t = if span > 0.0 {
+        (a - angles[i - 1]) / span
+    } else {
+        0.0
+    };
+    Some((i - 1, i, t))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: &str = "IESNA:LM-63-2002
+[TEST] small profile
+TILT=NONE
+1 1000 2 3 2 1 1 0.1 0.1 0
+1.0 1.0 50
+0 45 90
+0 90
+100 50 0
+200 100 0
+";
+
+    #[test]
+    fn interpolates_candela() {
+        let profile = IesProfile::parse(PROFILE).unwrap();
+        assert_eq!(profile.max_candela(), 400.0);
+        assert!((profile.intensity(0.0, 0.0) - 0.5).abs() < 1e-6);
+        assert!((profile.intensity(22.5, 0.0) - 0.375).abs() < 1e-6);
+        assert!((profile.intensity(0.0, 45.0) - 0.75).abs() < 1e-6);
+        //Quarter symmetry folds 135 degrees onto 45
+        assert!((profile.intensity(0.0, 135.0) - 0.75).abs() < 1e-6);
+        assert_eq!(profile.intensity(100.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let truncated = PROFILE.trim_end().rsplit_once('\n').unwrap().0;
+        assert!(IesProfile::parse(truncated).is_err());
+    }
+}
@@ -1,19 +1,72 @@
 use std::{
-    fs,
-    io::{self, Write},
-    ops::{Add, Mul},
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    ops::{Add, Mul, Sub},
+    path::Path,
 };
 
+use zune_jpeg::zune_core::{colorspace::ColorSpace, options::DecoderOptions};
+use zune_jpeg::JpegDecoder;
+
+use crate::error::*;
+
 #[allow(non_camel_case_types)]
 pub type fCol = f32;
 
-#[derive(Debug)]
+//Luminance in cd/m^2 (nits) of radiance 1, i.e. display white at the usual SDR reference level
+pub(crate) const NITS_PER_UNIT: fCol = 100.0;
+
+//Framebuffer of linear radiance, encoded with its transfer function only when saved in an
+//integer format
+#[derive(Clone, Debug)]
 pub struct Image {
     width: usize,
     height: usize,
-    pixels: Vec<Pixel>,
+    pixels: Vec<Color>,
+    transfer: Transfer,
+    //Coverage per pixel, None for opaque images. Colors are premultiplied by it.
+    alpha: Option<Vec<fCol>>,
+    //Key and value pairs written as PNG text chunks and EXR string attributes
+    metadata: Vec<(String, String)>,
 }
 
+//Encoding of linear color in integer image formats
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Transfer {
+    //Exact sRGB curve, what other software expects
+    #[default]
+    Srgb,
+    //Square root, a faster approximation of sRGB that is too bright in the shadows
+    Gamma2,
+}
+
+impl Transfer {
+    #[inline]
+    pub(crate) fn encode(self, col: Color) -> Color {
+        match self {
+            Transfer::Srgb => col.encode_srgb(),
+            Transfer::Gamma2 => Color::new(col.r.sqrt(), col.g.sqrt(), col.b.sqrt()),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn decode(self, col: Color) -> Color {
+        match self {
+            Transfer::Srgb => col.decode_srgb(),
+            Transfer::Gamma2 => col * col,
+        }
+    }
+}
+
+//Bits per channel of integer image formats
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BitDepth {
+    #[default]
+    Eight,
+    Sixteen,
+}
+
+//8-bit sRGB encoded color as stored in BMP and PNG files
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Pixel {
     pub r: u8,
@@ -21,7 +74,7 @@ pub struct Pixel {
     pub b: u8,
 }
 
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
 pub struct Color {
     pub r: fCol,
     pub g: fCol,
@@ -58,19 +111,74 @@ impl Color {
 
     #[inline]
     pub fn new(r: fCol, g: fCol, b: fCol) -> Self {
-        Self { r: r, g: g, b: b }
+        Self { r, g, b }
+    }
+
+    #[inline]
+    pub fn luminance(self) -> fCol {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    //Color with the given luminance in nits, in working radiance units
+    pub fn with_nits(self, nits: fCol) -> Self {
+        let lum = self.luminance();
+        if lum <= 0.0 {
+            return Color::black();
+        }
+        self * (nits / NITS_PER_UNIT / lum)
     }
 
+    //Scale down so no channel exceeds max, keeping the hue instead of clipping channels separately
     #[inline]
-    pub fn gamma2(self) -> Self {
+    pub fn limit(self, max: fCol) -> Self {
+        let m = self.r.max(self.g).max(self.b);
+        if m > max {
+            self * (max / m)
+        } else {
+            self
+        }
+    }
+
+    //Linear to sRGB encoded, as stored in 8 bit images
+    #[inline]
+    pub fn encode_srgb(self) -> Self {
+        Self {
+            r: srgb_encode(self.r),
+            g: srgb_encode(self.g),
+            b: srgb_encode(self.b),
+        }
+    }
+
+    //sRGB encoded to linear, the inverse of encode_srgb()
+    #[inline]
+    pub fn decode_srgb(self) -> Self {
         Self {
-            r: self.r.sqrt(),
-            g: self.g.sqrt(),
-            b: self.b.sqrt(),
+            r: srgb_decode(self.r),
+            g: srgb_decode(self.g),
+            b: srgb_decode(self.b),
         }
     }
 }
 
+//sRGB transfer function, linear near black and a 2.4 power curve above
+#[inline]
+fn srgb_encode(c: fCol) -> fCol {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[inline]
+fn srgb_decode(c: fCol) -> fCol {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
 impl Add for Color {
     type Output = Self;
 
@@ -84,6 +192,19 @@ impl Add for Color {
     }
 }
 
+impl Sub for Color {
+    type Output = Self;
+
+    #[inline]
+    fn sub(self, rhs: Self) -> Self::Output {
+        Color {
+            r: self.r - rhs.r,
+            g: self.g - rhs.g,
+            b: self.b - rhs.b,
+        }
+    }
+}
+
 impl Mul for Color {
     type Output = Self;
 
@@ -116,19 +237,41 @@ impl Pixel {
         buf.push(self.g);
         buf.push(self.r);
     }
+
+    fn write_to_buf_rgb(&self, buf: &mut Vec<u8>) {
+        buf.push(self.r);
+        buf.push(self.g);
+        buf.push(self.b);
+    }
 }
 
 impl From<Color> for Pixel {
+    //Quantizes encoded color, rounded, out of range values saturate
     #[inline]
     fn from(col: Color) -> Self {
         Pixel {
-            r: (col.r * 255.) as u8,
-            g: (col.g * 255.) as u8,
-            b: (col.b * 255.) as u8,
+            r: (col.r * 255. + 0.5) as u8,
+            g: (col.g * 255. + 0.5) as u8,
+            b: (col.b * 255. + 0.5) as u8,
         }
     }
 }
 
+impl From<Pixel> for Color {
+    #[inline]
+    fn from(px: Pixel) -> Self {
+        Color::from_rgb(px.r, px.g, px.b)
+    }
+}
+
+//File header and BITMAPINFOHEADER
+const BMP_HEADERS_SIZE: u32 = 14 + 40;
+
+//Rows of pixel data are padded to multiples of 4 bytes
+fn bmp_row_size(width: usize) -> usize {
+    (3 * width).div_ceil(4) * 4
+}
+
 struct BmpHeader {
     magic: u16,
     size: u32,
@@ -136,11 +279,11 @@ struct BmpHeader {
 }
 
 impl BmpHeader {
-    pub fn write_to_buf(&self, buf: &mut Vec<u8>) {
-        buf.write_all(&self.magic.to_be_bytes()).unwrap();
-        buf.write_all(&self.size.to_le_bytes()).unwrap();
-        buf.write(&[0; 4]).unwrap();
-        buf.write_all(&self.offset.to_le_bytes()).unwrap();
+    pub(crate) fn write_to_buf(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.magic.to_be_bytes());
+        buf.extend_from_slice(&self.size.to_le_bytes());
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&self.offset.to_le_bytes());
     }
 }
 
@@ -159,7 +302,7 @@ struct BmpInfo {
 }
 
 impl BmpInfo {
-    pub fn new_bgr(width: usize, height: usize, num_pixels: usize) -> BmpInfo {
+    pub(crate) fn new_bgr(width: usize, height: usize, size_image: u32) -> BmpInfo {
         BmpInfo {
             size: 40,
             width: width as i32,
@@ -167,7 +310,7 @@ impl BmpInfo {
             planes: 1,
             bit_count: 24,
             compression: 0,
-            size_image: (num_pixels as u32) * 3,
+            size_image,
             x_dpmeter: 0,
             y_dpmeter: 0,
             clr_used: 0,
@@ -175,32 +318,856 @@ impl BmpInfo {
         }
     }
 
-    pub fn write_to_buf(&self, buf: &mut Vec<u8>) {
-        buf.write_all(&self.size.to_le_bytes()).unwrap();
-        buf.write_all(&self.width.to_le_bytes()).unwrap();
-        buf.write_all(&self.height.to_le_bytes()).unwrap();
-        buf.write_all(&self.planes.to_le_bytes()).unwrap();
-        buf.write_all(&self.bit_count.to_le_bytes()).unwrap();
-        buf.write_all(&self.compression.to_le_bytes()).unwrap();
-        buf.write_all(&self.size_image.to_le_bytes()).unwrap();
-        buf.write_all(&self.x_dpmeter.to_le_bytes()).unwrap();
-        buf.write_all(&self.y_dpmeter.to_le_bytes()).unwrap();
-        buf.write_all(&self.clr_used.to_le_bytes()).unwrap();
-        buf.write_all(&self.clr_important.to_le_bytes()).unwrap();
+    pub(crate) fn write_to_buf(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.size.to_le_bytes());
+        buf.extend_from_slice(&self.width.to_le_bytes());
+        buf.extend_from_slice(&self.height.to_le_bytes());
+        buf.extend_from_slice(&self.planes.to_le_bytes());
+        buf.extend_from_slice(&self.bit_count.to_le_bytes());
+        buf.extend_from_slice(&self.compression.to_le_bytes());
+        buf.extend_from_slice(&self.size_image.to_le_bytes());
+        buf.extend_from_slice(&self.x_dpmeter.to_le_bytes());
+        buf.extend_from_slice(&self.y_dpmeter.to_le_bytes());
+        buf.extend_from_slice(&self.clr_used.to_le_bytes());
+        buf.extend_from_slice(&self.clr_important.to_le_bytes());
     }
 }
 
+//16-bit channels of encoded color, rounded and saturated like Pixel
+fn rgb16(col: Color) -> [u16; 3] {
+    [col.r, col.g, col.b].map(|c| (c * 65535. + 0.5) as u16)
+}
+
+//OpenEXR header attribute: name, type name, size and value
+fn write_exr_attribute(buf: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    buf.extend_from_slice(name.as_bytes());
+    buf.push(0);
+    buf.extend_from_slice(kind.as_bytes());
+    buf.push(0);
+    buf.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    buf.extend_from_slice(value);
+}
+
+fn decode_error(format: &'static str, message: impl Into<String>) -> Error {
+    Error::Decode {
+        format,
+        message: message.into(),
+    }
+}
+
+fn decode_png(data: &[u8], color: bool) -> Result<Image> {
+    let png_err = |e: png::DecodingError| match e {
+        png::DecodingError::IoError(e) => Error::Io(e),
+        e => decode_error("PNG", e.to_string()),
+    };
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().map_err(png_err)?;
+    //Samples are stored as linear^gamma, sRGB chunks override the gamma. Files written with a
+    //Transfer are decoded with it, so saved images load back as they were.
+    let (transfer, gamma) = match (reader.info().srgb, reader.info().source_gamma) {
+        (None, Some(gamma)) if gamma == png::ScaledFloat::new(0.5) => (Transfer::Gamma2, None),
+        (None, Some(gamma)) if gamma.into_value() > 0.0 => (Transfer::Srgb, Some(gamma.into_value())),
+        _ => (Transfer::Srgb, None),
+    };
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(png_err)?;
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Rgb => 3,
+        png::ColorType::Rgba => 4,
+        png::ColorType::Indexed => return Err(decode_error("PNG", "unexpanded palette")),
+    };
+    let samples: Vec<fCol> = match info.bit_depth {
+        png::BitDepth::Sixteen => buf[..info.buffer_size()]
+            .chunks_exact(2)
+            .map(|s| u16::from_be_bytes([s[0], s[1]]) as fCol / u16::MAX as fCol)
+            .collect(),
+        _ => buf[..info.buffer_size()].iter().map(|&s| s as fCol / u8::MAX as fCol).collect(),
+    };
+    let decode = |col: Color| match gamma {
+        _ if !color => col,
+        Some(gamma) => Color::new(col.r.powf(1.0 / gamma), col.g.powf(1.0 / gamma), col.b.powf(1.0 / gamma)),
+        None => transfer.decode(col),
+    };
+    let mut pixels = Vec::with_capacity(samples.len() / channels);
+    let mut alpha = (channels % 2 == 0).then(|| Vec::with_capacity(samples.len() / channels));
+    for px in samples.chunks_exact(channels) {
+        pixels.push(decode(if channels < 3 {
+            Color::new(px[0], px[0], px[0])
+        } else {
+            Color::new(px[0], px[1], px[2])
+        }));
+        if let Some(alpha) = &mut alpha {
+            alpha.push(px[channels - 1]);
+        }
+    }
+    let mut img = Image::from_decoded(info.width as usize, info.height as usize, pixels, alpha);
+    img.transfer = transfer;
+    let text = &reader.info().uncompressed_latin1_text;
+    img.metadata.extend(text.iter().map(|chunk| (chunk.keyword.clone(), chunk.text.clone())));
+    for chunk in reader.info().utf8_text.iter() {
+        img.metadata.push((chunk.keyword.clone(), chunk.get_text().map_err(png_err)?));
+    }
+    Ok(img)
+}
+
+fn decode_jpeg(data: &[u8], color: bool) -> Result<Image> {
+    let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::RGB);
+    let mut decoder = JpegDecoder::new_with_options(data, options);
+    let samples = decoder.decode().map_err(|e| decode_error("JPEG", e.to_string()))?;
+    let info = decoder.info().ok_or_else(|| decode_error("JPEG", "missing header"))?;
+    let pixels = samples
+        .chunks_exact(3)
+        .map(|px| {
+            let col = Color::from_rgb(px[0], px[1], px[2]);
+            if color {
+                Transfer::Srgb.decode(col)
+            } else {
+                col
+            }
+        })
+        .collect();
+    Ok(Image::from_decoded(info.width as usize, info.height as usize, pixels, None))
+}
+
+//Uncompressed 24-bit BMP, stored bottom-up if the height is positive and top-down otherwise
+fn decode_bmp(data: &[u8], color: bool) -> Result<Image> {
+    let err = |msg: &str| decode_error("BMP", msg);
+    let field = |pos: usize, len: usize| data.get(pos..pos + len).ok_or_else(|| err("truncated header"));
+    let u32_at = |pos: usize| -> Result<u32> { Ok(u32::from_le_bytes(field(pos, 4)?.try_into().unwrap())) };
+    let u16_at = |pos: usize| -> Result<u16> { Ok(u16::from_le_bytes(field(pos, 2)?.try_into().unwrap())) };
+    if field(0, 2)? != b"BM" {
+        return Err(err("missing BM signature"));
+    }
+    let offset = u32_at(10)? as usize;
+    //BITMAPINFOHEADER or one of its extensions, which only add fields at the end
+    if u32_at(14)? < 40 {
+        return Err(err("only BITMAPINFOHEADER and later headers are supported"));
+    }
+    let width = u32_at(18)? as i32;
+    let height = u32_at(22)? as i32;
+    if u16_at(28)? != 24 || u32_at(30)? != 0 {
+        return Err(err("only uncompressed 24-bit pixels are supported"));
+    }
+    if width <= 0 || height == 0 || height == i32::MIN {
+        return Err(err("invalid dimensions"));
+    }
+    let (width, top_down) = (width as usize, height < 0);
+    let height = height.unsigned_abs() as usize;
+
+    let row_size = bmp_row_size(width);
+    let rows = offset
+        .checked_add(row_size.checked_mul(height).ok_or_else(|| err("invalid dimensions"))?)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| err("truncated pixel data"))?;
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = if top_down { y } else { height - 1 - y };
+        let row = &rows[row * row_size..row * row_size + 3 * width];
+        pixels.extend(row.chunks_exact(3).map(|bgr| {
+            let col = Color::from_rgb(bgr[2], bgr[1], bgr[0]);
+            if color {
+                Transfer::Srgb.decode(col)
+            } else {
+                col
+            }
+        }));
+    }
+    Ok(Image::from_decoded(width, height, pixels, None))
+}
+
+//Radiance RGBE with the usual -Y h +X w orientation, flat or new-style run length encoded
+fn decode_hdr(data: &[u8]) -> Result<Image> {
+    let err = |msg: &str| decode_error("HDR", msg);
+    if !data.starts_with(b"#?") {
+        return Err(err("missing #? signature"));
+    }
+    let mut pos = 0;
+    let mut line = || -> Result<&[u8]> {
+        let end = pos + data[pos..].iter().position(|&b| b == b'\n').ok_or_else(|| err("truncated header"))?;
+        let line = &data[pos..end];
+        pos = end + 1;
+        Ok(line)
+    };
+    loop {
+        let header = line()?;
+        if header.is_empty() {
+            break;
+        }
+        if header.starts_with(b"FORMAT=") && header != b"FORMAT=32-bit_rle_rgbe" {
+            return Err(err("only RGBE pixels are supported"));
+        }
+    }
+    let resolution = String::from_utf8_lossy(line()?).into_owned();
+    let (width, height) = match resolution.split_whitespace().collect::<Vec<_>>()[..] {
+        ["-Y", h, "+X", w] => match (w.parse::<usize>(), h.parse::<usize>()) {
+            (Ok(w), Ok(h)) if w > 0 && h > 0 => (w, h),
+            _ => return Err(err("invalid resolution")),
+        },
+        _ => return Err(err("only the -Y h +X w orientation is supported")),
+    };
+
+    let byte = |pos: &mut usize| -> Result<u8> {
+        let b = *data.get(*pos).ok_or_else(|| err("truncated pixel data"))?;
+        *pos += 1;
+        Ok(b)
+    };
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut scanline = vec![[0u8; 4]; width];
+    for _ in 0..height {
+        let rle = (8..=0x7FFF).contains(&width) && data.get(pos..pos + 2) == Some(&[2, 2]);
+        if rle {
+            let len = (*data.get(pos + 2).unwrap_or(&0) as usize) << 8 | *data.get(pos + 3).unwrap_or(&0) as usize;
+            if len != width {
+                return Err(err("scanline length does not match the width"));
+            }
+            pos += 4;
+            for c in 0..4 {
+                let mut x = 0;
+                while x < width {
+                    let count = byte(&mut pos)? as usize;
+                    if count > 128 {
+                        let (count, value) = (count - 128, byte(&mut pos)?);
+                        if x + count > width {
+                            return Err(err("run exceeds the scanline"));
+                        }
+                        scanline[x..x + count].iter_mut().for_each(|px| px[c] = value);
+                        x += count;
+                    } else {
+                        if count == 0 || x + count > width {
+                            return Err(err("run exceeds the scanline"));
+                        }
+                        for px in scanline[x..x + count].iter_mut() {
+                            px[c] = byte(&mut pos)?;
+                        }
+                        x += count;
+                    }
+                }
+            }
+        } else {
+            for px in scanline.iter_mut() {
+                for c in px.iter_mut() {
+                    *c = byte(&mut pos)?;
+                }
+            }
+        }
+        pixels.extend(scanline.iter().map(|&[r, g, b, e]| {
+            if e == 0 {
+                Color::black()
+            } else {
+                //Midpoint of the quantization step
+                let scale = (e as i32 - 136) as fCol;
+                let scale = scale.exp2();
+                Color::new((r as fCol + 0.5) * scale, (g as fCol + 0.5) * scale, (b as fCol + 0.5) * scale)
+            }
+        }));
+    }
+    Ok(Image::from_decoded(width, height, pixels, None))
+}
+
+fn half_to_f32(h: u16) -> f32 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((h >> 10) & 0x1f) as i32;
+    let mantissa = (h & 0x3ff) as f32;
+    match exponent {
+        0 => sign * mantissa * (-24f32).exp2(),
+        31 if mantissa == 0.0 => sign * f32::INFINITY,
+        31 => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * ((exponent - 15) as f32).exp2(),
+    }
+}
+
+//Single part OpenEXR without compression, as written by write_exr() and TiledExrWriter, with
+//scanlines or tiles of a single level. Reads R, G, B and A, or Y for grayscale, from the
+//unnamed layer.
+fn decode_exr(data: &[u8]) -> Result<Image> {
+    let err = |msg: &str| decode_error("EXR", msg);
+    let truncated = || err("truncated file");
+    //Positions come from the file, so they are checked before adding to them
+    let bytes = |pos: usize, len: usize| data.get(pos..pos.checked_add(len).ok_or_else(truncated)?).ok_or_else(truncated);
+    let i32_at = |pos: usize| -> Result<i32> { Ok(i32::from_le_bytes(bytes(pos, 4)?.try_into().unwrap())) };
+    if !data.starts_with(&[0x76, 0x2F, 0x31, 0x01]) {
+        return Err(err("missing magic number"));
+    }
+    let version = i32_at(4)? as u32;
+    //Deep or multi-part files
+    if version & 0xff != 2 || version & (0x800 | 0x1000) != 0 {
+        return Err(err("only single part scanline or tiled files are supported"));
+    }
+
+    let string = |pos: &mut usize| -> Result<String> {
+        let end = *pos + data.get(*pos..).ok_or_else(truncated)?.iter().position(|&b| b == 0).ok_or_else(truncated)?;
+        let s = String::from_utf8_lossy(&data[*pos..end]).into_owned();
+        *pos = end + 1;
+        Ok(s)
+    };
+    let mut pos = 8;
+    //Name and pixel type, 0 for uint, 1 for half and 2 for float
+    let mut channels: Vec<(String, i32)> = Vec::new();
+    let mut window = None;
+    let mut compression = None;
+    let mut tiles = None;
+    let mut metadata = Vec::new();
+    while *data.get(pos).ok_or_else(truncated)? != 0 {
+        let name = string(&mut pos)?;
+        let kind = string(&mut pos)?;
+        let size = usize::try_from(i32_at(pos)?).map_err(|_| err("invalid attribute size"))?;
+        let value = bytes(pos + 4, size)?;
+        pos += 4 + size;
+        match name.as_str() {
+            "channels" => {
+                let mut p = 0;
+                while p < value.len() && value[p] != 0 {
+                    let end = p + value[p..].iter().position(|&b| b == 0).ok_or_else(truncated)?;
+                    let channel = String::from_utf8_lossy(&value[p..end]).into_owned();
+                    let field = |i: usize| -> Result<i32> {
+                        Ok(i32::from_le_bytes(value.get(i..i + 4).ok_or_else(truncated)?.try_into().unwrap()))
+                    };
+                    if field(end + 9)? != 1 || field(end + 13)? != 1 {
+                        return Err(err("subsampled channels are not supported"));
+                    }
+                    channels.push((channel, field(end + 1)?));
+                    p = end + 17;
+                }
+            }
+            "compression" => compression = value.first().copied(),
+            "dataWindow" if value.len() == 16 => {
+                let field = |i: usize| i32::from_le_bytes(value[4 * i..4 * i + 4].try_into().unwrap());
+                window = Some((field(0), field(1), field(2), field(3)));
+            }
+            "tiles" if value.len() == 9 => {
+                let field = |i: usize| u32::from_le_bytes(value[4 * i..4 * i + 4].try_into().unwrap()) as usize;
+                if value[8] & 0xf != 0 {
+                    return Err(err("only tiles of a single level are supported"));
+                }
+                tiles = Some((field(0), field(1)));
+            }
+            _ if kind == "string" => metadata.push((name, String::from_utf8_lossy(value).into_owned())),
+            _ => {}
+        }
+    }
+    pos += 1;
+    if compression != Some(0) {
+        return Err(err("only uncompressed files are supported"));
+    }
+    let (x0, y0, x1, y1) = window.ok_or_else(|| err("missing dataWindow"))?;
+    if x1 < x0 || y1 < y0 {
+        return Err(err("empty dataWindow"));
+    }
+    //The corners may lie anywhere in i32, their difference only fits in i64
+    let (width, height) = ((x1 as i64 - x0 as i64 + 1) as usize, (y1 as i64 - y0 as i64 + 1) as usize);
+    let sample_size = |kind: i32| if kind == 1 { 2 } else { 4 };
+    let pixel_size: usize = channels.iter().map(|(_, kind)| sample_size(*kind)).sum();
+    //Every pixel is stored in the file, anything larger than it is corrupt and must not be allocated
+    if pixel_size == 0 {
+        return Err(err("no channels"));
+    }
+    if width.checked_mul(height).and_then(|n| n.checked_mul(pixel_size)).is_none_or(|size| size > data.len()) {
+        return Err(err(&format!("{}x{} dataWindow is larger than the file", width, height)));
+    }
+    //Blocks are single scanlines without compression
+    let (block_width, block_height) = match tiles {
+        Some((0, _) | (_, 0)) => return Err(err("invalid tile size")),
+        Some(size) => size,
+        None if version & 0x200 != 0 => return Err(err("missing tiles attribute")),
+        None => (width, 1),
+    };
+    let blocks_x = width.div_ceil(block_width);
+    let blocks = blocks_x * height.div_ceil(block_height);
+
+    let mut values: Vec<Vec<fCol>> = vec![vec![0.0; width * height]; channels.len()];
+    for block in 0..blocks {
+        let offset = u64::from_le_bytes(bytes(pos + 8 * block, 8)?.try_into().unwrap());
+        let offset = usize::try_from(offset).ok().filter(|&o| o < data.len()).ok_or_else(truncated)?;
+        let outside = || err("block outside of the dataWindow");
+        //Tile coordinates and level, or the first scanline
+        let (bx, by, mut p) = if tiles.is_some() {
+            if i32_at(offset + 8)? != 0 || i32_at(offset + 12)? != 0 {
+                return Err(err("invalid tile block"));
+            }
+            let coordinate = |pos: usize| -> Result<usize> { usize::try_from(i32_at(pos)?).map_err(|_| outside()) };
+            (coordinate(offset)?, coordinate(offset + 4)?, offset + 20)
+        } else {
+            let line = usize::try_from(i32_at(offset)? as i64 - y0 as i64).map_err(|_| outside())?;
+            (0, line, offset + 8)
+        };
+        let (x, y) = (bx.saturating_mul(block_width), by.saturating_mul(block_height));
+        if x >= width || y >= height {
+            return Err(outside());
+        }
+        let (w, h) = (block_width.min(width - x), block_height.min(height - y));
+        if i32_at(p - 4)? as usize != w * h * pixel_size {
+            return Err(err("invalid block size"));
+        }
+        for row in y..y + h {
+            for ((_, kind), values) in channels.iter().zip(values.iter_mut()) {
+                let size = sample_size(*kind);
+                let samples = data.get(p..p + size * w).ok_or_else(truncated)?;
+                for (i, s) in samples.chunks_exact(size).enumerate() {
+                    values[row * width + x + i] = match kind {
+                        0 => u32::from_le_bytes(s.try_into().unwrap()) as fCol,
+                        1 => half_to_f32(u16::from_le_bytes(s.try_into().unwrap())),
+                        _ => f32::from_le_bytes(s.try_into().unwrap()),
+                    };
+                }
+                p += size * w;
+            }
+        }
+    }
+
+    let channel = |name: &str| channels.iter().position(|(n, _)| n == name).map(|i| &values[i]);
+    let (r, g, b) = match (channel("R"), channel("G"), channel("B"), channel("Y")) {
+        (Some(r), Some(g), Some(b), _) => (r, g, b),
+        (_, _, _, Some(y)) => (y, y, y),
+        _ => return Err(err("no R, G and B or Y channels")),
+    };
+    let pixels = (0..width * height).map(|i| Color::new(r[i], g[i], b[i])).collect();
+    //Already premultiplied
+    let mut img = Image::from_decoded(width, height, pixels, None);
+    img.alpha = channel("A").cloned();
+    img.metadata = metadata;
+    Ok(img)
+}
+
+//How the pixels of an EXR file are stored
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ExrLayout {
+    Scanline,
+    //Square tiles of the given size
+    Tiled(usize),
+    //Scanlines with a varying number of samples per pixel, at most the given number
+    Deep(usize),
+}
+
+//Header of a single precision, uncompressed file with the channels in the given order, which has
+//to be alphabetical
+fn exr_header(width: usize, height: usize, names: &[&str], layout: ExrLayout, metadata: &[(String, String)]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(512);
+    out.extend_from_slice(&[0x76, 0x2F, 0x31, 0x01]);
+    //Version 2, single part scanline, tiled or deep file, flagged when names exceed 31 bytes
+    let long_names = names.iter().map(|n| n.len()).chain(metadata.iter().map(|(n, _)| n.len())).any(|l| l > 31);
+    let tiled = matches!(layout, ExrLayout::Tiled(_));
+    let flags = match layout {
+        ExrLayout::Scanline => 0,
+        ExrLayout::Tiled(_) => 0x200,
+        ExrLayout::Deep(_) => 0x800,
+    };
+    out.extend_from_slice(&(2u32 | flags | if long_names { 0x400 } else { 0 }).to_le_bytes());
+
+    let mut list = Vec::new();
+    for name in names {
+        list.extend_from_slice(name.as_bytes());
+        list.push(0);
+        //Pixel type float, not perceptually linear, reserved, x and y sampling
+        list.extend_from_slice(&2i32.to_le_bytes());
+        list.extend_from_slice(&[0; 4]);
+        list.extend_from_slice(&1i32.to_le_bytes());
+        list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    list.push(0);
+    let mut window = Vec::with_capacity(16);
+    for v in [0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+    write_exr_attribute(&mut out, "channels", "chlist", &list);
+    write_exr_attribute(&mut out, "compression", "compression", &[0]);
+    write_exr_attribute(&mut out, "dataWindow", "box2i", &window);
+    write_exr_attribute(&mut out, "displayWindow", "box2i", &window);
+    //Tiles are stored in the order they are written
+    write_exr_attribute(&mut out, "lineOrder", "lineOrder", &[if tiled { 2 } else { 0 }]);
+    write_exr_attribute(&mut out, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    write_exr_attribute(&mut out, "screenWindowCenter", "v2f", &[0; 8]);
+    write_exr_attribute(&mut out, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    if let ExrLayout::Tiled(size) = layout {
+        //Square tiles of a single resolution level
+        let mut desc = Vec::with_capacity(9);
+        desc.extend_from_slice(&(size as u32).to_le_bytes());
+        desc.extend_from_slice(&(size as u32).to_le_bytes());
+        desc.push(0);
+        write_exr_attribute(&mut out, "tiles", "tiledesc", &desc);
+    }
+    if let ExrLayout::Deep(max_samples) = layout {
+        write_exr_attribute(&mut out, "type", "string", b"deepscanline");
+        write_exr_attribute(&mut out, "version", "int", &1i32.to_le_bytes());
+        write_exr_attribute(&mut out, "maxSamplesPerPixel", "int", &(max_samples as i32).to_le_bytes());
+    }
+    for (name, value) in metadata {
+        write_exr_attribute(&mut out, name, "string", value.as_bytes());
+    }
+    out.push(0);
+    out
+}
+
+//Tiled OpenEXR streamed to disk one tile at a time, so only the tile being written has to be
+//held in memory besides the source. Tiles can come in any order, finish() fills in the offset
+//table once all are written.
+pub(crate) struct TiledExrWriter {
+    out: BufWriter<File>,
+    width: usize,
+    height: usize,
+    tile_size: usize,
+    //Index of the caller's channels in the alphabetical order of the file
+    order: Vec<usize>,
+    //File position of every tile, row by row, 0 while it's missing
+    offsets: Vec<u64>,
+    table: u64,
+    pos: u64,
+}
+
+impl TiledExrWriter {
+    pub(crate) fn create(
+        path: &str,
+        width: usize,
+        height: usize,
+        channels: &[&str],
+        tile_size: usize,
+        metadata: &[(String, String)],
+    ) -> Result<TiledExrWriter> {
+        if width == 0 || height == 0 || width > i32::MAX as usize || height > i32::MAX as usize {
+            return Err(Error::Encode {
+                format: "EXR",
+                message: format!("{}x{} is not a valid size", width, height),
+            });
+        }
+        //Tile blocks store their size as i32
+        if tile_size == 0 || (tile_size as u64).pow(2) * 4 * channels.len() as u64 > i32::MAX as u64 {
+            return Err(Error::Encode {
+                format: "EXR",
+                message: format!("{}px tiles are not supported", tile_size),
+            });
+        }
+        let mut order: Vec<usize> = (0..channels.len()).collect();
+        order.sort_by_key(|&i| channels[i]);
+        let names: Vec<&str> = order.iter().map(|&i| channels[i]).collect();
+        let header = exr_header(width, height, &names, ExrLayout::Tiled(tile_size), metadata);
+
+        let tiles = width.div_ceil(tile_size) * height.div_ceil(tile_size);
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&header)?;
+        //Placeholder for the offset table
+        out.write_all(&vec![0; tiles * 8])?;
+        Ok(TiledExrWriter {
+            out,
+            width,
+            height,
+            tile_size,
+            order,
+            offsets: vec![0; tiles],
+            table: header.len() as u64,
+            pos: (header.len() + tiles * 8) as u64,
+        })
+    }
+
+    //Number of tiles across and down
+    pub(crate) fn tiles(&self) -> (usize, usize) {
+        (self.width.div_ceil(self.tile_size), self.height.div_ceil(self.tile_size))
+    }
+
+    //Pixels covered by a tile as (x0, y0) to (x1, y1) exclusive, smaller at the right and bottom
+    pub(crate) fn tile_rect(&self, tx: usize, ty: usize) -> (usize, usize, usize, usize) {
+        let (x0, y0) = (tx * self.tile_size, ty * self.tile_size);
+        (x0, y0, (x0 + self.tile_size).min(self.width), (y0 + self.tile_size).min(self.height))
+    }
+
+    //Values of every channel in the order given to create(), row by row within the tile
+    pub(crate) fn write_tile(&mut self, tx: usize, ty: usize, values: &[Vec<fCol>]) -> Result<()> {
+        let (tiles_x, tiles_y) = self.tiles();
+        let err = |message: String| Error::Encode { format: "EXR", message };
+        if tx >= tiles_x || ty >= tiles_y {
+            return Err(err(format!("tile ({}, {}) is outside of the image", tx, ty)));
+        }
+        let (x0, y0, x1, y1) = self.tile_rect(tx, ty);
+        let (w, h) = (x1 - x0, y1 - y0);
+        if values.len() != self.order.len() || values.iter().any(|v| v.len() != w * h) {
+            return Err(err(format!("tile ({}, {}) needs {} channels of {}x{} values", tx, ty, self.order.len(), w, h)));
+        }
+        let index = ty * tiles_x + tx;
+        if self.offsets[index] != 0 {
+            return Err(err(format!("tile ({}, {}) is written twice", tx, ty)));
+        }
+        self.offsets[index] = self.pos;
+
+        let size = w * h * 4 * self.order.len();
+        for v in [tx as i32, ty as i32, 0, 0, size as i32] {
+            self.out.write_all(&v.to_le_bytes())?;
+        }
+        for y in 0..h {
+            for &c in self.order.iter() {
+                for v in &values[c][y * w..(y + 1) * w] {
+                    self.out.write_all(&v.to_le_bytes())?;
+                }
+            }
+        }
+        self.pos += 20 + size as u64;
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<()> {
+        if let Some(missing) = self.offsets.iter().position(|&o| o == 0) {
+            let tiles_x = self.tiles().0;
+            return Err(Error::Encode {
+                format: "EXR",
+                message: format!("tile ({}, {}) was not written", missing % tiles_x, missing / tiles_x),
+            });
+        }
+        self.out.seek(SeekFrom::Start(self.table))?;
+        for offset in self.offsets.iter() {
+            self.out.write_all(&offset.to_le_bytes())?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+//Named float channel of an EXR file, row by row from the top
+pub struct ExrChannel {
+    pub(crate) name: String,
+    pub(crate) values: Vec<fCol>,
+}
+
+//Single precision OpenEXR without compression. Channels are written in alphabetical order, so
+//layers like "albedo.R" group together, metadata becomes string attributes of the header.
+pub(crate) fn write_exr(
+    path: &str,
+    width: usize,
+    height: usize,
+    channels: &[ExrChannel],
+    metadata: &[(String, String)],
+) -> Result<()> {
+    if width == 0 || height == 0 || width > i32::MAX as usize || height > i32::MAX as usize {
+        return Err(Error::Encode {
+            format: "EXR",
+            message: format!("{}x{} is not a valid size", width, height),
+        });
+    }
+    if let Some(channel) = channels.iter().find(|c| c.values.len() != width * height) {
+        return Err(Error::Encode {
+            format: "EXR",
+            message: format!("channel {} does not have {}x{} values", channel.name, width, height),
+        });
+    }
+    let mut channels: Vec<&ExrChannel> = channels.iter().collect();
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let names: Vec<&str> = channels.iter().map(|c| c.name.as_str()).collect();
+    let mut out = exr_header(width, height, &names, ExrLayout::Scanline, metadata);
+    out.reserve(width * height * 4 * channels.len() + height * 16);
+
+    //Offset table, one block per scanline
+    let line_size = width * 4 * channels.len();
+    let first_line = out.len() + height * 8;
+    for y in 0..height {
+        out.extend_from_slice(&((first_line + y * (line_size + 8)) as u64).to_le_bytes());
+    }
+    for y in 0..height {
+        out.extend_from_slice(&(y as i32).to_le_bytes());
+        out.extend_from_slice(&(line_size as i32).to_le_bytes());
+        for channel in channels.iter() {
+            for v in &channel.values[y * width..(y + 1) * width] {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+    }
+
+    fs::write(path, &out)?;
+    Ok(())
+}
+
+//Sample of a deep image, color premultiplied by alpha
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DeepSample {
+    pub color: Color,
+    pub alpha: fCol,
+    pub depth: fCol,
+}
+
+//Deep scanline OpenEXR without compression, with the samples of every pixel row by row from the
+//top. Channels are A, B, G, R and Z, metadata becomes string attributes like in write_exr().
+pub fn write_deep_exr(path: &str, width: usize, height: usize, pixels: &[Vec<DeepSample>], metadata: &[(String, String)]) -> Result<()> {
+    let err = |message: String| Error::Encode { format: "EXR", message };
+    if width == 0 || height == 0 || width > i32::MAX as usize || height > i32::MAX as usize {
+        return Err(err(format!("{}x{} is not a valid size", width, height)));
+    }
+    if pixels.len() != width * height {
+        return Err(err(format!("{} pixels given for a {}x{} image", pixels.len(), width, height)));
+    }
+    let max_samples = pixels.iter().map(Vec::len).max().unwrap_or(0);
+    //Sample counts of a line are stored as running i32 totals
+    if max_samples * width > i32::MAX as usize {
+        return Err(err(format!("{} samples per pixel are too many", max_samples)));
+    }
+    let header = exr_header(width, height, &["A", "B", "G", "R", "Z"], ExrLayout::Deep(max_samples), metadata);
+    let channels: [fn(&DeepSample) -> fCol; 5] = [|s| s.alpha, |s| s.color.b, |s| s.color.g, |s| s.color.r, |s| s.depth];
+
+    let mut lines = Vec::new();
+    let mut offsets = Vec::with_capacity(height);
+    let first_line = header.len() + height * 8;
+    for (y, row) in pixels.chunks(width).enumerate() {
+        offsets.push((first_line + lines.len()) as u64);
+        let samples: usize = row.iter().map(Vec::len).sum();
+        let data_size = (samples * 4 * channels.len()) as u64;
+        lines.extend_from_slice(&(y as i32).to_le_bytes());
+        //Offset table, sample data packed and unpacked, all the same without compression
+        lines.extend_from_slice(&((width * 4) as u64).to_le_bytes());
+        lines.extend_from_slice(&data_size.to_le_bytes());
+        lines.extend_from_slice(&data_size.to_le_bytes());
+        let mut total = 0;
+        for px in row {
+            total += px.len() as i32;
+            lines.extend_from_slice(&total.to_le_bytes());
+        }
+        for channel in channels.iter() {
+            for sample in row.iter().flatten() {
+                lines.extend_from_slice(&channel(sample).to_le_bytes());
+            }
+        }
+    }
+
+    let mut out = header;
+    out.reserve(height * 8 + lines.len());
+    for offset in offsets {
+        out.extend_from_slice(&offset.to_le_bytes());
+    }
+    out.extend_from_slice(&lines);
+    fs::write(path, &out)?;
+    Ok(())
+}
+
+//Shared exponent encoding of Radiance HDR files
+fn rgbe(col: Color) -> [u8; 4] {
+    let max = col.r.max(col.g).max(col.b);
+    if max.is_nan() || max <= 1e-32 {
+        return [0; 4];
+    }
+    let exponent = max.log2().floor() as i32 + 1;
+    let scale = 256.0 / (exponent as fCol).exp2();
+    //Rounding down keeps the largest channel below 256
+    let channel = |c: fCol| (c.max(0.0) * scale).min(255.0) as u8;
+    [channel(col.r), channel(col.g), channel(col.b), (exponent + 128).clamp(0, 255) as u8]
+}
+
 impl Image {
     pub fn new(width: usize, height: usize) -> Self {
         Image {
-            width: width,
-            height: height,
-            pixels: vec![Color::black().into(); width * height],
+            width,
+            height,
+            pixels: vec![Color::black(); width * height],
+            transfer: Transfer::Srgb,
+            alpha: None,
+            metadata: Vec::new(),
+        }
+    }
+
+    //Linear color from a PNG, JPEG, BMP, Radiance HDR or uncompressed OpenEXR file, chosen by the
+    //extension. Integer formats are decoded with the sRGB curve, or the gamma of PNG files that
+    //declare one instead. Alpha is kept, with the colors premultiplied by it, and so are PNG text
+    //chunks and EXR string attributes as metadata.
+    pub fn load(path: impl AsRef<Path>) -> Result<Image> {
+        Self::load_as(path.as_ref(), true)
+    }
+
+    //Non-color data like roughness maps, integer formats are scaled to [0, 1] without decoding
+    pub fn load_data(path: impl AsRef<Path>) -> Result<Image> {
+        Self::load_as(path.as_ref(), false)
+    }
+
+    fn load_as(path: &Path, color: bool) -> Result<Image> {
+        let data = fs::read(path)?;
+        let ext = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+        match ext.as_deref() {
+            Some("png") => decode_png(&data, color),
+            Some("jpg" | "jpeg") => decode_jpeg(&data, color),
+            Some("bmp") => decode_bmp(&data, color),
+            Some("hdr") => decode_hdr(&data),
+            Some("exr") => decode_exr(&data),
+            _ => Err(Error::Decode {
+                format: "image",
+                message: format!("unsupported file extension of {}", path.display()),
+            }),
+        }
+    }
+
+    //Colors and alpha of decoded files, alpha is premultiplied here
+    fn from_decoded(width: usize, height: usize, mut pixels: Vec<Color>, alpha: Option<Vec<fCol>>) -> Image {
+        if let Some(alpha) = &alpha {
+            pixels.iter_mut().zip(alpha.iter()).for_each(|(c, &a)| *c = *c * a);
+        }
+        Image {
+            pixels,
+            alpha,
+            ..Image::new(width, height)
+        }
+    }
+
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    //Colors not premultiplied by alpha, like textures expect
+    pub fn straight_pixels(&self) -> Vec<Color> {
+        (0..self.pixels.len()).map(|i| self.straight(i)).collect()
+    }
+
+    pub fn alpha(&self) -> Option<&[fCol]> {
+        self.alpha.as_deref()
+    }
+
+    //Written by PNG and EXR output, the other formats stay opaque. Panics if the size differs.
+    pub fn set_alpha(&mut self, alpha: Option<Vec<fCol>>) {
+        if let Some(alpha) = &alpha {
+            assert_eq!(alpha.len(), self.pixels.len(), "alpha size differs from the image");
+        }
+        self.alpha = alpha;
+    }
+
+    pub fn metadata(&self) -> &[(String, String)] {
+        &self.metadata
+    }
+
+    //Replaces an earlier value of the key
+    pub fn set_metadata(&mut self, key: &str, value: &str) {
+        match self.metadata.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.metadata.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    //Color not premultiplied by alpha, as PNG stores it
+    fn straight(&self, i: usize) -> Color {
+        match &self.alpha {
+            Some(alpha) if alpha[i] > 0.0 => self.pixels[i] * (1.0 / alpha[i]),
+            _ => self.pixels[i],
         }
     }
 
+    pub fn transfer(&self) -> Transfer {
+        self.transfer
+    }
+
+    //Transfer function of BMP, PNG, TIFF and PPM output, float formats stay linear
+    pub fn set_transfer(&mut self, transfer: Transfer) {
+        self.transfer = transfer;
+    }
+
+    #[inline]
+    fn encoded(&self, col: Color) -> Pixel {
+        self.transfer.encode(col).into()
+    }
+
     #[inline]
-    pub fn px_mut(&mut self, x: usize, y: usize) -> Option<&mut Pixel> {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    #[inline]
+    pub fn px_mut(&mut self, x: usize, y: usize) -> Option<&mut Color> {
         self.enforce(x, y)?;
         self.pixels.get_mut(y * self.width + x)
     }
@@ -215,39 +1182,483 @@ impl Image {
     }
 
     #[inline]
-    pub fn px(&self, x: usize, y: usize) -> Option<&Pixel> {
+    pub fn px(&self, x: usize, y: usize) -> Option<&Color> {
         self.enforce(x, y)?;
         self.pixels.get(y * self.width + x)
     }
 
-    pub fn save_bmp(&self, path: &str) -> io::Result<()> {
-        let mut file = fs::File::create(path)?;
-        let mut out: Vec<u8> = Vec::with_capacity(self.pixels.len() * 3);
+    //8-bit sRGB value of a pixel, as saved to BMP and PNG
+    #[inline]
+    pub fn pixel(&self, x: usize, y: usize) -> Option<Pixel> {
+        self.px(x, y).map(|&col| self.encoded(col))
+    }
+
+    //Linear color at film coordinates in [0, 1]
+    pub fn color_at(&self, u: fCol, v: fCol) -> Color {
+        let x = ((u * self.width as fCol) as usize).min(self.width - 1);
+        let y = ((v * self.height as fCol) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x]
+    }
+
+    //Copy other in with its top left corner at x, y, clipped to this image. Pixels outside of
+    //other stay as they are, alpha is added as opaque if only other has it.
+    pub fn paste(&mut self, other: &Image, x: usize, y: usize) {
+        if other.alpha.is_some() && self.alpha.is_none() {
+            self.alpha = Some(vec![1.0; self.pixels.len()]);
+        }
+        for oy in 0..other.height.min(self.height.saturating_sub(y)) {
+            for ox in 0..other.width.min(self.width.saturating_sub(x)) {
+                let (from, to) = (oy * other.width + ox, (y + oy) * self.width + x + ox);
+                self.pixels[to] = other.pixels[from];
+                if let Some(alpha) = &mut self.alpha {
+                    alpha[to] = other.alpha.as_ref().map_or(1.0, |a| a[from]);
+                }
+            }
+        }
+    }
+
+    //Pixels [x0, x1) x [y0, y1) clipped to this image, with its transfer, alpha and metadata
+    pub fn crop(&self, x0: usize, y0: usize, x1: usize, y1: usize) -> Image {
+        let (x1, y1) = (x1.min(self.width), y1.min(self.height));
+        let (x0, y0) = (x0.min(x1), y0.min(y1));
+        let mut out = Image::new(x1 - x0, y1 - y0);
+        out.transfer = self.transfer;
+        out.metadata = self.metadata.clone();
+        let mut alpha = self.alpha.as_ref().map(|_| Vec::with_capacity(out.pixels.len()));
+        for y in y0..y1 {
+            let row = y * self.width;
+            out.pixels[(y - y0) * out.width..(y - y0 + 1) * out.width].copy_from_slice(&self.pixels[row + x0..row + x1]);
+            if let (Some(alpha), Some(src)) = (&mut alpha, &self.alpha) {
+                alpha.extend_from_slice(&src[row + x0..row + x1]);
+            }
+        }
+        out.alpha = alpha;
+        out
+    }
+
+    //Box filtered copy at 1/factor of the size, averaged in linear space
+    pub fn downscale(&self, factor: usize) -> Image {
+        let factor = factor.max(1);
+        let mut out = Image::new(self.width.div_ceil(factor), self.height.div_ceil(factor));
+        out.transfer = self.transfer;
+        let mut alpha = self.alpha.as_ref().map(|_| vec![0.0; out.pixels.len()]);
+        for y in 0..out.height {
+            for x in 0..out.width {
+                let mut sum = Color::black();
+                let mut alpha_sum = 0.0;
+                let mut count = 0;
+                for sy in y * factor..((y + 1) * factor).min(self.height) {
+                    for sx in x * factor..((x + 1) * factor).min(self.width) {
+                        sum = sum + self.pixels[sy * self.width + sx];
+                        alpha_sum += self.alpha.as_ref().map_or(1.0, |a| a[sy * self.width + sx]);
+                        count += 1;
+                    }
+                }
+                out.pixels[y * out.width + x] = sum * (1.0 / count as fCol);
+                if let Some(alpha) = &mut alpha {
+                    alpha[y * out.width + x] = alpha_sum / count as fCol;
+                }
+            }
+        }
+        out.alpha = alpha;
+        out
+    }
+
+    pub fn save_bmp(&self, path: &str) -> Result<()> {
+        fs::write(path, self.encode_bmp()?)?;
+        Ok(())
+    }
+
+    //24-bit top-down BMP with the pixel data right after the headers
+    fn encode_bmp(&self) -> Result<Vec<u8>> {
+        let row_size = bmp_row_size(self.width);
+        let data_size = row_size as u64 * self.height as u64;
+        //Dimensions are stored as i32 and the file size as u32
+        if self.width > i32::MAX as usize || self.height > i32::MAX as usize || data_size > (u32::MAX - BMP_HEADERS_SIZE) as u64 {
+            return Err(Error::Encode {
+                format: "BMP",
+                message: format!("{}x{} is too large", self.width, self.height),
+            });
+        }
+        let mut out: Vec<u8> = Vec::with_capacity(BMP_HEADERS_SIZE as usize + data_size as usize);
 
         BmpHeader {
             magic: 0x424D,
-            size: 0,
-            offset: 64,
+            size: BMP_HEADERS_SIZE + data_size as u32,
+            offset: BMP_HEADERS_SIZE,
         }
         .write_to_buf(&mut out);
-        BmpInfo::new_bgr(self.width, self.height, self.pixels.len()).write_to_buf(&mut out);
+        BmpInfo::new_bgr(self.width, self.height, data_size as u32).write_to_buf(&mut out);
 
-        let offset = out.len();
-        let padding = (4 - (3 * self.width) % 4) % 4;
-        //println!("Offset: {}", offset);
-        for _ in offset..64 {
-            out.push(0xCC)
-        }
-        for (i, px) in self.pixels.iter().enumerate() {
-            px.write_to_buf_bgr(&mut out);
+        let padding = row_size - 3 * self.width;
+        for (i, &col) in self.pixels.iter().enumerate() {
+            self.encoded(col).write_to_buf_bgr(&mut out);
             if i % self.width == self.width - 1 {
-                for _ in 0..padding {
-                    out.push(0);
+                out.resize(out.len() + padding, 0);
+            }
+        }
+        Ok(out)
+    }
+
+    //Compressed and tagged with the transfer function, so viewers don't have to guess
+    pub fn save_png(&self, path: &str) -> Result<()> {
+        self.encode_png(path, BitDepth::Eight)
+    }
+
+    //16 bits per channel, for grading without banding
+    pub fn save_png16(&self, path: &str) -> Result<()> {
+        self.encode_png(path, BitDepth::Sixteen)
+    }
+
+    fn encode_png(&self, path: &str, depth: BitDepth) -> Result<()> {
+        let encode_err = |e: png::EncodingError| match e {
+            png::EncodingError::IoError(e) => Error::Io(e),
+            e => Error::Encode {
+                format: "PNG",
+                message: e.to_string(),
+            },
+        };
+        //PNG dimensions must be positive 31 bit integers
+        if self.width == 0 || self.height == 0 || self.width > i32::MAX as usize || self.height > i32::MAX as usize {
+            return Err(Error::Encode {
+                format: "PNG",
+                message: format!("{}x{} is not a valid size", self.width, self.height),
+            });
+        }
+        //Samples are big endian, alpha is straight and linear
+        let mut data = Vec::new();
+        for i in 0..self.pixels.len() {
+            let col = self.straight(i);
+            let alpha = self.alpha.as_ref().map(|a| a[i].clamp(0.0, 1.0));
+            match depth {
+                BitDepth::Eight => {
+                    self.encoded(col).write_to_buf_rgb(&mut data);
+                    data.extend(alpha.map(|a| (a * 255.0 + 0.5) as u8));
+                }
+                BitDepth::Sixteen => {
+                    rgb16(self.transfer.encode(col)).iter().for_each(|c| data.extend_from_slice(&c.to_be_bytes()));
+                    if let Some(a) = alpha {
+                        data.extend_from_slice(&((a * 65535.0 + 0.5) as u16).to_be_bytes());
+                    }
+                }
+            }
+        }
+
+        let out = io::BufWriter::new(fs::File::create(path)?);
+        let mut encoder = png::Encoder::new(out, self.width as u32, self.height as u32);
+        encoder.set_color(if self.alpha.is_some() {
+            png::ColorType::Rgba
+        } else {
+            png::ColorType::Rgb
+        });
+        encoder.set_depth(match depth {
+            BitDepth::Eight => png::BitDepth::Eight,
+            BitDepth::Sixteen => png::BitDepth::Sixteen,
+        });
+        match self.transfer {
+            Transfer::Srgb => encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual),
+            //File gamma is the exponent of the encoding
+            Transfer::Gamma2 => encoder.set_source_gamma(png::ScaledFloat::new(0.5)),
+        }
+        for (key, value) in self.metadata.iter() {
+            //tEXt is Latin-1, iTXt UTF-8
+            if value.is_ascii() {
+                encoder.add_text_chunk(key.clone(), value.clone()).map_err(encode_err)?;
+            } else {
+                encoder.add_itxt_chunk(key.clone(), value.clone()).map_err(encode_err)?;
+            }
+        }
+        let mut writer = encoder.write_header().map_err(encode_err)?;
+        writer.write_image_data(&data).map_err(encode_err)?;
+        writer.finish().map_err(encode_err)?;
+        Ok(())
+    }
+
+    //Baseline RGB TIFF without compression
+    pub fn save_tiff(&self, path: &str) -> Result<()> {
+        self.encode_tiff(path, BitDepth::Eight)
+    }
+
+    pub fn save_tiff16(&self, path: &str) -> Result<()> {
+        self.encode_tiff(path, BitDepth::Sixteen)
+    }
+
+    fn encode_tiff(&self, path: &str, depth: BitDepth) -> Result<()> {
+        let bits: u16 = match depth {
+            BitDepth::Eight => 8,
+            BitDepth::Sixteen => 16,
+        };
+        //Offsets are u32, so the whole file has to stay below 4 GiB
+        let data_size = self.pixels.len() as u64 * 3 * bits as u64 / 8;
+        if self.width == 0 || self.height == 0 || data_size > (u32::MAX - 1024) as u64 {
+            return Err(Error::Encode {
+                format: "TIFF",
+                message: format!("{}x{} is not a valid size", self.width, self.height),
+            });
+        }
+        const ENTRIES: u32 = 13;
+        //Header, directory, then bits per sample and the resolutions, then the pixels
+        let extra = 8 + 2 + ENTRIES * 12 + 4;
+        let data_offset = extra + 6 + 16;
+
+        let mut out: Vec<u8> = Vec::with_capacity(data_offset as usize + data_size as usize);
+        out.extend_from_slice(b"II*\0");
+        out.extend_from_slice(&8u32.to_le_bytes());
+        out.extend_from_slice(&(ENTRIES as u16).to_le_bytes());
+        //Tag, field type (3 short, 4 long, 5 rational), count and value or offset, sorted by tag
+        let mut entry = |tag: u16, kind: u16, count: u32, value: u32| {
+            out.extend_from_slice(&tag.to_le_bytes());
+            out.extend_from_slice(&kind.to_le_bytes());
+            out.extend_from_slice(&count.to_le_bytes());
+            if kind == 3 && count == 1 {
+                out.extend_from_slice(&(value as u16).to_le_bytes());
+                out.extend_from_slice(&[0; 2]);
+            } else {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+        };
+        entry(256, 4, 1, self.width as u32);
+        entry(257, 4, 1, self.height as u32);
+        entry(258, 3, 3, extra);
+        entry(259, 3, 1, 1);
+        //RGB
+        entry(262, 3, 1, 2);
+        entry(273, 4, 1, data_offset);
+        entry(277, 3, 1, 3);
+        entry(278, 4, 1, self.height as u32);
+        entry(279, 4, 1, data_size as u32);
+        entry(282, 5, 1, extra + 6);
+        entry(283, 5, 1, extra + 14);
+        entry(284, 3, 1, 1);
+        //Inch
+        entry(296, 3, 1, 2);
+        out.extend_from_slice(&0u32.to_le_bytes());
+        for _ in 0..3 {
+            out.extend_from_slice(&bits.to_le_bytes());
+        }
+        //72 dpi
+        for _ in 0..2 {
+            out.extend_from_slice(&72u32.to_le_bytes());
+            out.extend_from_slice(&1u32.to_le_bytes());
+        }
+
+        match depth {
+            BitDepth::Eight => self.pixels.iter().for_each(|&col| self.encoded(col).write_to_buf_rgb(&mut out)),
+            BitDepth::Sixteen => {
+                for &col in self.pixels.iter() {
+                    rgb16(self.transfer.encode(col)).iter().for_each(|c| out.extend_from_slice(&c.to_le_bytes()));
+                }
+            }
+        }
+
+        fs::write(path, &out)?;
+        Ok(())
+    }
+
+    //Single precision RGB OpenEXR without compression, keeping the full linear radiance
+    pub fn save_exr(&self, path: &str) -> Result<()> {
+        write_exr(path, self.width, self.height, &self.exr_channels(), &self.metadata)
+    }
+
+    //R, G, B and A if present, the unnamed layer of an EXR file. Alpha stays premultiplied, as
+    //EXR expects.
+    pub fn exr_channels(&self) -> Vec<ExrChannel> {
+        let channel = |name: &str, f: fn(&Color) -> fCol| ExrChannel {
+            name: name.to_string(),
+            values: self.pixels.iter().map(f).collect(),
+        };
+        let mut channels = vec![channel("R", |c| c.r), channel("G", |c| c.g), channel("B", |c| c.b)];
+        if let Some(alpha) = &self.alpha {
+            channels.push(ExrChannel {
+                name: "A".to_string(),
+                values: alpha.clone(),
+            });
+        }
+        channels
+    }
+
+    //Radiance RGBE file, smaller than EXR at about 1% precision
+    pub fn save_hdr(&self, path: &str) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(Error::Encode {
+                format: "HDR",
+                message: format!("{}x{} is not a valid size", self.width, self.height),
+            });
+        }
+        let mut out: Vec<u8> = Vec::with_capacity(self.pixels.len() * 4 + 128);
+        out.extend_from_slice(b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n");
+        out.extend_from_slice(format!("-Y {} +X {}\n", self.height, self.width).as_bytes());
+
+        for row in self.pixels.chunks(self.width) {
+            let row: Vec<[u8; 4]> = row.iter().map(|&col| rgbe(col)).collect();
+            //Flat scanlines can be mistaken for the old run length encoding, so use the new one
+            //where the format allows it, with literal runs only
+            if !(8..=0x7FFF).contains(&self.width) {
+                row.iter().for_each(|px| out.extend_from_slice(px));
+                continue;
+            }
+            out.extend_from_slice(&[2, 2, (self.width >> 8) as u8, self.width as u8]);
+            for c in 0..4 {
+                let channel: Vec<u8> = row.iter().map(|px| px[c]).collect();
+                for chunk in channel.chunks(128) {
+                    out.push(chunk.len() as u8);
+                    out.extend_from_slice(chunk);
                 }
             }
         }
 
-        file.write_all(&out)?;
+        fs::write(path, &out)?;
         Ok(())
     }
+
+    //Binary 8-bit PPM, readable by most image tools
+    pub fn save_ppm(&self, path: &str) -> Result<()> {
+        let mut out: Vec<u8> = Vec::with_capacity(self.pixels.len() * 3 + 32);
+        out.extend_from_slice(format!("P6\n{} {}\n255\n", self.width, self.height).as_bytes());
+        for &col in self.pixels.iter() {
+            self.encoded(col).write_to_buf_rgb(&mut out);
+        }
+
+        fs::write(path, &out)?;
+        Ok(())
+    }
+
+    //Portable float map with linear RGB, the float counterpart of PPM
+    pub fn save_pfm(&self, path: &str) -> Result<()> {
+        let mut out: Vec<u8> = Vec::with_capacity(self.pixels.len() * 12 + 32);
+        //A negative scale marks little endian data
+        out.extend_from_slice(format!("PF\n{} {}\n-1.0\n", self.width, self.height).as_bytes());
+        //Rows are stored bottom to top
+        for row in self.pixels.chunks(self.width.max(1)).rev() {
+            for col in row {
+                for c in [col.r, col.g, col.b] {
+                    out.extend_from_slice(&c.to_le_bytes());
+                }
+            }
+        }
+
+        fs::write(path, &out)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Distinct 8-bit values per pixel and channel
+    fn gradient(width: usize, height: usize) -> Image {
+        let mut img = Image::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let col = Color::from_rgb((x * 40) as u8, (y * 60) as u8, (x * 7 + y * 13) as u8);
+                img.pixels[y * width + x] = col.decode_srgb();
+            }
+        }
+        img
+    }
+
+    fn assert_same_pixels(a: &Image, b: &Image) {
+        assert_eq!((a.width(), a.height()), (b.width(), b.height()));
+        for y in 0..a.height() {
+            for x in 0..a.width() {
+                assert_eq!(a.pixel(x, y), b.pixel(x, y), "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn bmp_header() {
+        //3 pixels need a byte of row padding
+        let data = gradient(3, 2).encode_bmp().unwrap();
+        let u32_at = |pos: usize| u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        assert_eq!(&data[..2], b"BM");
+        assert_eq!(u32_at(2) as usize, data.len());
+        assert_eq!(u32_at(10), BMP_HEADERS_SIZE);
+        assert_eq!(u32_at(34), 2 * 12);
+        assert_eq!(data.len(), 54 + 2 * 12);
+    }
+
+    #[test]
+    fn bmp_round_trip() {
+        for (width, height) in [(1, 1), (3, 2), (4, 3), (5, 5)] {
+            let img = gradient(width, height);
+            let decoded = decode_bmp(&img.encode_bmp().unwrap(), true).unwrap();
+            assert_same_pixels(&img, &decoded);
+        }
+    }
+
+    #[test]
+    fn bmp_bottom_up() {
+        let img = gradient(3, 4);
+        let top_down = img.encode_bmp().unwrap();
+        //Same file with a positive height and the rows reversed
+        let mut bottom_up = top_down[..54].to_vec();
+        bottom_up[22..26].copy_from_slice(&4i32.to_le_bytes());
+        for row in top_down[54..].chunks_exact(bmp_row_size(3)).rev() {
+            bottom_up.extend_from_slice(row);
+        }
+        assert_same_pixels(&img, &decode_bmp(&bottom_up, true).unwrap());
+    }
+
+    #[test]
+    fn bmp_data_is_not_decoded() {
+        let mut img = Image::new(1, 1);
+        img.pixels[0] = Color::from_rgb(51, 102, 204).decode_srgb();
+        let decoded = decode_bmp(&img.encode_bmp().unwrap(), false).unwrap();
+        assert_eq!(decoded.pixels()[0], Color::from_rgb(51, 102, 204));
+    }
+
+    #[test]
+    fn bmp_rejects_unsupported() {
+        let data = gradient(2, 2).encode_bmp().unwrap();
+        assert!(decode_bmp(&data[..data.len() - 1], true).is_err());
+        assert!(decode_bmp(&data[..20], true).is_err());
+
+        let mut bgra = data.clone();
+        bgra[28..30].copy_from_slice(&32u16.to_le_bytes());
+        assert!(decode_bmp(&bgra, true).is_err());
+
+        let mut compressed = data;
+        compressed[30..34].copy_from_slice(&1u32.to_le_bytes());
+        assert!(decode_bmp(&compressed, true).is_err());
+    }
+
+    #[test]
+    fn png_round_trip_keeps_transfer() {
+        for transfer in [Transfer::Srgb, Transfer::Gamma2] {
+            let mut img = gradient(4, 3);
+            img.set_transfer(transfer);
+            let path = std::env::temp_dir().join(format!("raytrace-png-{}.png", std::process::id()));
+            img.save_png(path.to_str().unwrap()).unwrap();
+            let data = fs::read(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            let decoded = decode_png(&data, true).unwrap();
+            assert_eq!(decoded.transfer(), transfer);
+            assert_same_pixels(&img, &decoded);
+        }
+    }
+
+    #[test]
+    fn exr_rejects_corrupt_data_window() {
+        let img = gradient(4, 3);
+        let path = std::env::temp_dir().join(format!("raytrace-exr-{}.exr", std::process::id()));
+        write_exr(path.to_str().unwrap(), 4, 3, &img.exr_channels(), &[]).unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let decoded = decode_exr(&data).unwrap();
+        assert_eq!(decoded.pixels(), img.pixels());
+
+        let window = data.windows(17).position(|w| w == b"dataWindow\0box2i\0").unwrap() + 21;
+        for corners in [[i32::MIN, i32::MIN, i32::MAX, i32::MAX], [0, 0, 99999, 99999], [0, 5, 3, 7]] {
+            let mut corrupt = data.clone();
+            for (i, v) in corners.iter().enumerate() {
+                corrupt[window + 4 * i..window + 4 * i + 4].copy_from_slice(&v.to_le_bytes());
+            }
+            assert!(decode_exr(&corrupt).is_err(), "{:?}", corners);
+        }
+        for len in [10, data.len() / 2, data.len() - 1] {
+            assert!(decode_exr(&data[..len]).is_err());
+        }
+    }
 }
@@ -0,0 +1,219 @@
+use std::{fs, io, path::Path, time::Duration};
+
+use crate::error::*;
+use crate::framebuffer::*;
+use crate::image::*;
+use crate::tracer::Renderer;
+
+//Settings of a single render invocation, used to name its output
+pub struct RenderJob {
+    pub scene: String,
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    pub bounces: usize,
+    pub seed: u64,
+    //Output path, may contain {scene}, {width}, {height}, {samples}, {bounces} and {seed}
+    pub output: String,
+    //Of PNG and TIFF output, the float formats ignore it
+    pub depth: BitDepth,
+    //Of the integer formats, to set on the resolved image before saving
+    pub transfer: Transfer,
+    //Of the scene file, None for built-in scenes, which the name and seed identify
+    pub scene_hash: Option<u64>,
+}
+
+//How the two eyes of a stereo render are written
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StereoLayout {
+    //Two files, with .left and .right before the extension
+    Separate,
+    //One file twice as wide, left eye on the left
+    SideBySide,
+    //One file twice as high, left eye on top
+    OverUnder,
+}
+
+impl StereoLayout {
+    pub fn from_name(name: &str) -> Option<StereoLayout> {
+        Some(match name {
+            "separate" => StereoLayout::Separate,
+            "side-by-side" => StereoLayout::SideBySide,
+            "over-under" => StereoLayout::OverUnder,
+            _ => return None,
+        })
+    }
+}
+
+//FNV-1a of a file, to tell apart versions of a scene file in output metadata
+pub fn file_hash(path: &str) -> io::Result<u64> {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in fs::read(path)? {
+        h = (h ^ b as u64).wrapping_mul(0x100000001b3);
+    }
+    Ok(h)
+}
+
+impl RenderJob {
+    pub fn output_path(&self) -> Result<String> {
+        let mut path = String::with_capacity(self.output.len());
+        let mut rest = self.output.as_str();
+
+        while let Some(start) = rest.find('{') {
+            path.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| Error::invalid_parameter("output path", "unterminated '{'"))?
+                + start;
+            match &rest[start + 1..end] {
+                "scene" => path.push_str(&self.scene),
+                "width" => path.push_str(&self.width.to_string()),
+                "height" => path.push_str(&self.height.to_string()),
+                "samples" => path.push_str(&self.samples.to_string()),
+                "bounces" => path.push_str(&self.bounces.to_string()),
+                "seed" => path.push_str(&self.seed.to_string()),
+                key => {
+                    return Err(Error::invalid_parameter(
+                        "output path",
+                        format!("unknown placeholder {{{}}}", key),
+                    ))
+                }
+            }
+            rest = &rest[end + 1..];
+        }
+        path.push_str(rest);
+
+        Ok(path)
+    }
+
+    //Preview written next to the output while rendering, output directories are created
+    pub fn proxy_path(&self) -> Result<String> {
+        let path = self.output_path()?;
+        create_parent_dir(&path)?;
+        Ok(Path::new(&path).with_extension("proxy.png").to_string_lossy().into_owned())
+    }
+
+    //Arbitrary output variable next to the output, always EXR to keep values outside of [0, 1]
+    pub fn aov_path(&self, name: &str) -> Result<String> {
+        let path = self.output_path()?;
+        create_parent_dir(&path)?;
+        Ok(Path::new(&path)
+            .with_extension(format!("{}.exr", name))
+            .to_string_lossy()
+            .into_owned())
+    }
+
+    //Output path of outputs that only come as EXR, what names them in the error otherwise
+    pub fn exr_output_path(&self, what: &str) -> Result<String> {
+        let path = self.output_path()?;
+        if Path::new(&path).extension().and_then(|ext| ext.to_str()) != Some("exr") {
+            return Err(Error::invalid_parameter("output path", format!("{} output must be an .exr file", what)));
+        }
+        Ok(path)
+    }
+
+    //Beauty and AOVs in one EXR file, see FrameBuffer::save_multilayer_exr()
+    pub fn save_multilayer(&self, img: &Image, frame: &FrameBuffer) -> Result<String> {
+        let path = self.exr_output_path("multi-layer")?;
+        create_parent_dir(&path)?;
+        frame.save_multilayer_exr(img, &path)?;
+        Ok(path)
+    }
+
+    //Settings the output was rendered with, so an image can be traced back to them
+    pub fn metadata(&self, render_time: Duration) -> Vec<(String, String)> {
+        let mut metadata = vec![
+            ("Software".to_string(), format!("raytrace {}", env!("CARGO_PKG_VERSION"))),
+            ("raytrace/scene".to_string(), self.scene.clone()),
+        ];
+        if let Some(hash) = self.scene_hash {
+            metadata.push(("raytrace/scene_hash".to_string(), format!("{:016x}", hash)));
+        }
+        metadata.extend([
+            ("raytrace/resolution".to_string(), format!("{}x{}", self.width, self.height)),
+            ("raytrace/samples".to_string(), self.samples.to_string()),
+            ("raytrace/bounces".to_string(), self.bounces.to_string()),
+            ("raytrace/seed".to_string(), self.seed.to_string()),
+            ("raytrace/render_time".to_string(), format!("{:.3}s", render_time.as_secs_f64())),
+        ]);
+        metadata
+    }
+
+    //EXR streamed tile by tile from the frame, see Renderer::save_exr_tiled()
+    pub fn save_tiled(&self, renderer: &Renderer, frame: &FrameBuffer, tile_size: usize, metadata: &[(String, String)]) -> Result<String> {
+        let path = self.exr_output_path("tiled")?;
+        create_parent_dir(&path)?;
+        renderer.save_exr_tiled(frame, &path, tile_size, metadata)?;
+        Ok(path)
+    }
+
+    //Deep EXR next to the output with a sample per object seen by each pixel, None when the
+    //frame didn't record them, see FrameBuffer::with_deep()
+    pub fn save_deep(&self, frame: &FrameBuffer, metadata: &[(String, String)]) -> Result<Option<String>> {
+        let Some(pixels) = frame.deep_samples() else {
+            return Ok(None);
+        };
+        let path = self.aov_path("deep")?;
+        write_deep_exr(&path, frame.width(), frame.height(), &pixels, metadata)?;
+        Ok(Some(path))
+    }
+
+    //Stereo pair in the given layout, combined images take the metadata of the left eye.
+    //Returns the paths written.
+    pub fn save_stereo(&self, left: &Image, right: &Image, layout: StereoLayout) -> Result<Vec<String>> {
+        let (width, height) = (left.width(), left.height());
+        let (right_x, right_y) = match layout {
+            StereoLayout::Separate => {
+                let path = self.output_path()?;
+                let ext = Path::new(&path).extension().map_or("bmp".to_string(), |ext| ext.to_string_lossy().into_owned());
+                let mut paths = Vec::new();
+                for (img, eye) in [(left, "left"), (right, "right")] {
+                    let path = Path::new(&path).with_extension(format!("{}.{}", eye, ext)).to_string_lossy().into_owned();
+                    self.save_to(img, &path)?;
+                    paths.push(path);
+                }
+                return Ok(paths);
+            }
+            StereoLayout::SideBySide => (width, 0),
+            StereoLayout::OverUnder => (0, height),
+        };
+        let mut pair = Image::new(width + right_x, height + right_y);
+        pair.set_transfer(left.transfer());
+        for (key, value) in left.metadata() {
+            pair.set_metadata(key, value);
+        }
+        pair.paste(left, 0, 0);
+        pair.paste(right, right_x, right_y);
+        self.save(&pair).map(|path| vec![path])
+    }
+
+    //Create missing directories and save, picking the format from the extension
+    pub fn save(&self, img: &Image) -> Result<String> {
+        let path = self.output_path()?;
+        self.save_to(img, &path)?;
+        Ok(path)
+    }
+
+    fn save_to(&self, img: &Image, path: &str) -> Result<()> {
+        create_parent_dir(path)?;
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("png") if self.depth == BitDepth::Sixteen => img.save_png16(path)?,
+            Some("png") => img.save_png(path)?,
+            Some("tif" | "tiff") if self.depth == BitDepth::Sixteen => img.save_tiff16(path)?,
+            Some("tif" | "tiff") => img.save_tiff(path)?,
+            Some("exr") => img.save_exr(path)?,
+            Some("hdr") => img.save_hdr(path)?,
+            Some("ppm") => img.save_ppm(path)?,
+            Some("pfm") => img.save_pfm(path)?,
+            _ => img.save_bmp(path)?,
+        }
+        Ok(())
+    }
+}
+
+fn create_parent_dir(path: &str) -> io::Result<()> {
+    match Path::new(path).parent() {
+        Some(dir) => fs::create_dir_all(dir),
+        None => Ok(()),
+    }
+}
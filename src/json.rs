@@ -0,0 +1,312 @@
+use std::fmt;
+use std::io;
+
+//Minimal JSON document model for the control protocol and scene files
+#[derive(Clone, PartialEq, Debug)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    //Keys in document order
+    Object(Vec<(String, Json)>),
+}
+
+//Arrays and objects nested deeper are rejected, the parser recurses once per level and input
+//may come from daemon clients
+const MAX_DEPTH: usize = 128;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("JSON: {}", msg))
+}
+
+impl Json {
+    pub fn parse(src: &str) -> io::Result<Json> {
+        let mut parser = Parser {
+            chars: src.chars().collect(),
+            pos: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.chars.len() {
+            return Err(invalid("trailing characters after value"));
+        }
+        Ok(value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    //Non-negative integers only
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<f64> for Json {
+    fn from(n: f64) -> Json {
+        Json::Number(n)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+//Compact, single line serialization
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    //Arrays and objects currently open
+    depth: usize,
+}
+
+impl Parser {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn next(&mut self) -> io::Result<char> {
+        let c = *self.chars.get(self.pos).ok_or_else(|| invalid("unexpected end of input"))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, c: char) -> io::Result<()> {
+        self.skip_whitespace();
+        if self.next()? != c {
+            return Err(invalid(&format!("expected '{}'", c)));
+        }
+        Ok(())
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> io::Result<Json> {
+        for expected in word.chars() {
+            if self.next()? != expected {
+                return Err(invalid(&format!("expected '{}'", word)));
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> io::Result<Json> {
+        self.skip_whitespace();
+        match self.chars.get(self.pos) {
+            Some(&c @ ('{' | '[')) => {
+                if self.depth == MAX_DEPTH {
+                    return Err(invalid(&format!("nested deeper than {} levels", MAX_DEPTH)));
+                }
+                self.depth += 1;
+                let value = if c == '{' { self.object() } else { self.array() };
+                self.depth -= 1;
+                value
+            }
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('n') => self.keyword("null", Json::Null),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) => Err(invalid(&format!("unexpected character '{}'", c))),
+            None => Err(invalid("unexpected end of input")),
+        }
+    }
+
+    fn object(&mut self) -> io::Result<Json> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&'}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => continue,
+                '}' => return Ok(Json::Object(fields)),
+                _ => return Err(invalid("expected ',' or '}' in object")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> io::Result<Json> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => continue,
+                ']' => return Ok(Json::Array(items)),
+                _ => return Err(invalid("expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        if self.next()? != '"' {
+            return Err(invalid("expected string"));
+        }
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(s),
+                '\\' => match self.next()? {
+                    '"' => s.push('"'),
+                    '\\' => s.push('\\'),
+                    '/' => s.push('/'),
+                    'b' => s.push('\u{8}'),
+                    'f' => s.push('\u{c}'),
+                    'n' => s.push('\n'),
+                    'r' => s.push('\r'),
+                    't' => s.push('\t'),
+                    'u' => {
+                        let hex: String = (0..4).map(|_| self.next()).collect::<io::Result<_>>()?;
+                        let code = u32::from_str_radix(&hex, 16).map_err(|_| invalid("bad \\u escape"))?;
+                        //Surrogate pairs are not combined, lone halves become U+FFFD
+                        s.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    _ => return Err(invalid("bad escape sequence")),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> io::Result<Json> {
+        let start = self.pos;
+        while self
+            .chars
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+        {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        text.parse()
+            .map(Json::Number)
+            .map_err(|_| invalid(&format!("bad number '{}'", text)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nesting_is_capped() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(Json::parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Json::parse(&"{\"a\":".repeat(1_000_000)).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+//Path tracer library: build a Scene from objects and materials, or load one with scene_file,
+//then render it through a Camera with a Renderer. The raytracing binary is a thin CLI on top.
+
+//Private modules are internal to the renderer: acceleration structures, path guiding, photon
+//maps, microfacet math, shader nodes, the JSON and OBJ parsers and cryptomatte.
+//Items of public modules that only the renderer needs are pub(crate).
+pub mod animation;
+pub mod builder;
+mod bvh;
+pub mod checkpoint;
+mod cryptomatte;
+pub mod daemon;
+pub mod error;
+pub mod filter;
+pub mod framebuffer;
+pub mod geom;
+mod guiding;
+pub mod hit;
+pub mod ies;
+pub mod image;
+pub mod job;
+mod json;
+pub mod light;
+pub mod linalg;
+pub mod material;
+pub mod mesh;
+mod microfacet;
+pub mod noise;
+mod nodes;
+mod obj;
+mod photon;
+pub mod progress;
+pub mod sampler;
+pub mod scene_file;
+pub mod texture;
+pub mod tracer;
+pub mod usd;
+pub mod vdb;
+pub mod volume;
+
+pub use builder::{CameraBuilder, FisheyeCameraBuilder, MovingCameraBuilder, OrthographicCameraBuilder, RendererBuilder};
+pub use error::{Error, Result};
+pub use framebuffer::FrameBuffer;
+pub use image::{Color, Image};
+pub use linalg::Vec3;
+pub use scene_file::{load_scene_file, SceneDescription, SceneFile};
+pub use tracer::{Camera, CameraKey, CameraModel, Eye, FisheyeCamera, Hit, Material, MovingCamera, OrthographicCamera, Renderer, Scene, StereoCamera};
@@ -0,0 +1,227 @@
+use std::rc::Rc;
+
+use rand::{Rng, RngCore};
+
+use crate::ies::*;
+use crate::image::*;
+use crate::linalg::*;
+use crate::material::rand_on_unit_sphere;
+use crate::texture::*;
+use crate::tracer::*;
+
+pub struct LightSample {
+    //Unit vector pointing from the shaded point towards the light
+    pub direction: Vec3,
+    pub distance: fVec,
+    pub radiance: Color,
+}
+
+//Sky fading from black straight down to color straight up
+pub struct Background {
+    pub color: Color,
+}
+
+impl Environment for Background {
+    fn radiance(&self, direction: Vec3) -> Color {
+        self.color * ((direction.y + 1.0) / 2.0)
+    }
+}
+
+//Same radiance from every direction
+pub struct UniformEnvironment {
+    pub color: Color,
+}
+
+impl Environment for UniformEnvironment {
+    fn radiance(&self, _direction: Vec3) -> Color {
+        self.color
+    }
+}
+
+pub trait Light {
+    fn illuminate(&self, point: Vec3) -> Option<LightSample>;
+
+    //Random ray leaving the light together with the power it carries
+    fn emit(&self, _rng: &mut dyn RngCore) -> Option<(Ray, Color)> {
+        None
+    }
+}
+
+pub struct PointLight {
+    pub origin: Vec3,
+    pub color: Color,
+    pub intensity: fCol,
+    //Emission profile, oriented with its nadir pointing down the Y axis
+    pub profile: Option<Rc<IesProfile>>,
+}
+
+impl Light for PointLight {
+    fn illuminate(&self, point: Vec3) -> Option<LightSample> {
+        let to_light = self.origin - point;
+        let distance = to_light.length();
+        let direction = to_light / distance;
+
+        let mut intensity = self.intensity;
+        if let Some(profile) = &self.profile {
+            intensity *= profile_intensity(profile, -direction, -Vec3::unit_y());
+        }
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: self.color * (intensity / (distance * distance)),
+        })
+    }
+
+    fn emit(&self, rng: &mut dyn RngCore) -> Option<(Ray, Color)> {
+        let dir = rand_on_unit_sphere(rng);
+        let mut intensity = self.intensity * 4.0 * std::f32::consts::PI;
+        if let Some(profile) = &self.profile {
+            intensity *= profile_intensity(profile, dir, -Vec3::unit_y());
+        }
+        Some((Ray::new(self.origin, dir), self.color * intensity))
+    }
+}
+
+pub struct SpotLight {
+    pub origin: Vec3,
+    pub direction: Vec3,
+    pub color: Color,
+    pub intensity: fCol,
+    //Half angle of the cone in degrees
+    pub angle: fVec,
+    //Fraction of the cone over which the intensity fades out
+    pub blend: fVec,
+    //Emission profile, oriented with its nadir along the spot direction
+    pub profile: Option<Rc<IesProfile>>,
+}
+
+impl Light for SpotLight {
+    fn illuminate(&self, point: Vec3) -> Option<LightSample> {
+        let to_light = self.origin - point;
+        let distance = to_light.length();
+        let direction = to_light / distance;
+        let axis = self.direction.unit();
+
+        let cos_outer = self.angle.to_radians().cos();
+        let cos_inner = (self.angle * (1.0 - self.blend)).to_radians().cos();
+        let cos_theta = -direction * axis;
+        if cos_theta <= cos_outer {
+            return None;
+        }
+
+        let mut intensity = self.intensity * smoothstep(cos_outer, cos_inner, cos_theta);
+        if let Some(profile) = &self.profile {
+            intensity *= profile_intensity(profile, -direction, axis);
+        }
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: self.color * (intensity / (distance * distance)),
+        })
+    }
+
+    fn emit(&self, rng: &mut dyn RngCore) -> Option<(Ray, Color)> {
+        let axis = self.direction.unit();
+        let (tangent, bitangent) = axis.basis();
+        let cos_outer = self.angle.to_radians().cos();
+        let cos_inner = (self.angle * (1.0 - self.blend)).to_radians().cos();
+
+        //Uniform over the solid angle of the cone
+        let cos_theta = 1.0 - rng.gen_range(0.0..1.0) * (1.0 - cos_outer);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = rng.gen_range(0.0..(2.0 * std::f32::consts::PI));
+        let dir = axis * cos_theta + (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta;
+
+        let solid_angle = 2.0 * std::f32::consts::PI * (1.0 - cos_outer);
+        let mut intensity = self.intensity * solid_angle * smoothstep(cos_outer, cos_inner, cos_theta);
+        if let Some(profile) = &self.profile {
+            intensity *= profile_intensity(profile, dir, axis);
+        }
+        Some((Ray::new(self.origin, dir), self.color * intensity))
+    }
+}
+
+//Slide projector or gobo: throws an image onto the scene through a camera-like frustum
+pub struct ProjectorLight {
+    pub origin: Vec3,
+    pub look_at: Vec3,
+    pub image: Rc<dyn Texture>,
+    //Horizontal field of view in degrees
+    pub fov: fVec,
+    //Width over height of the projected image
+    pub aspect: fVec,
+    pub intensity: fCol,
+}
+
+impl ProjectorLight {
+    //Unit right, up and forward vectors, oriented like a camera at the projector
+    fn frame(&self) -> (Vec3, Vec3, Vec3) {
+        let forward = (self.look_at - self.origin).unit();
+        let right = Vec3::unit_y().cross(forward).unit();
+        (right, forward.cross(right).unit(), forward)
+    }
+
+    //Half extents of the image plane at distance 1
+    fn half_size(&self) -> (fVec, fVec) {
+        let half_width = (self.fov.to_radians() / 2.0).tan();
+        (half_width, half_width / self.aspect)
+    }
+}
+
+impl Light for ProjectorLight {
+    fn illuminate(&self, point: Vec3) -> Option<LightSample> {
+        let (right, up, forward) = self.frame();
+        let (half_width, half_height) = self.half_size();
+        let to_point = point - self.origin;
+        let depth = to_point * forward;
+        if depth <= 0.0 {
+            return None;
+        }
+        let x = to_point * right / (depth * half_width);
+        let y = to_point * up / (depth * half_height);
+        if x.abs() > 1.0 || y.abs() > 1.0 {
+            return None;
+        }
+
+        let distance = to_point.length();
+        let color = self.image.value((x + 1.0) / 2.0, (y + 1.0) / 2.0, point);
+        Some(LightSample {
+            direction: -to_point / distance,
+            distance,
+            radiance: color * (self.intensity / (distance * distance)),
+        })
+    }
+
+    fn emit(&self, rng: &mut dyn RngCore) -> Option<(Ray, Color)> {
+        let (right, up, forward) = self.frame();
+        let (half_width, half_height) = self.half_size();
+        let x: fVec = rng.gen_range(-1.0..1.0);
+        let y: fVec = rng.gen_range(-1.0..1.0);
+        let dir = forward + right * (x * half_width) + up * (y * half_height);
+
+        //Uniform on the image plane at distance 1, converted to solid angle
+        let length = dir.length();
+        let solid_angle = 4.0 * half_width * half_height / (length * length * length);
+        let color = self.image.value((x + 1.0) / 2.0, (y + 1.0) / 2.0, self.origin + dir);
+        Some((Ray::new(self.origin, dir / length), color * (self.intensity * solid_angle)))
+    }
+}
+
+fn smoothstep(edge0: fVec, edge1: fVec, x: fVec) -> fVec {
+    if edge1 <= edge0 {
+        return 1.0;
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+//Evaluate the profile for light leaving in `dir`, with the profile nadir along `axis`
+fn profile_intensity(profile: &IesProfile, dir: Vec3, axis: Vec3) -> fVec {
+    let (tangent, bitangent) = axis.basis();
+
+    let vertical = (dir * axis).clamp(-1.0, 1.0).acos().to_degrees();
+    let horizontal = (dir * bitangent).atan2(dir * tangent).to_degrees();
+    profile.intensity(vertical, horizontal)
+}
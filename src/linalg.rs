@@ -15,7 +15,7 @@ pub struct Vec3 {
 impl Vec3 {
     #[inline]
     pub fn new(x: fVec, y: fVec, z: fVec) -> Self {
-        Self { x: x, y: y, z: z }
+        Self { x, y, z }
     }
 
     #[inline]
@@ -77,6 +77,18 @@ impl Vec3 {
         }
     }
 
+    //Two unit vectors perpendicular to self (which must be unit length) and to each other
+    #[inline]
+    pub fn basis(self) -> (Self, Self) {
+        let helper = if self.x.abs() > 0.9 {
+            Self::unit_z()
+        } else {
+            Self::unit_x()
+        };
+        let tangent = self.cross(helper).unit();
+        (tangent, tangent.cross(self))
+    }
+
     #[inline]
     pub fn reflect(self, normal: Self) -> Self {
         self + normal * ((self * normal) * -2.0)
@@ -236,3 +248,260 @@ impl Sub<fVec> for Vec3 {
         }
     }
 }
+
+//Polynomial root finding for ray intersections. Coefficients are given from the highest power
+//down, real roots are returned in ascending order. Internally everything runs in f64 and avoids
+//subtracting nearly equal values, which at grazing angles would otherwise turn into holes and
+//speckles along silhouettes.
+
+//Roots of a t^2 + b t + c, equal for a double root or a linear equation
+pub(crate) fn solve_quadratic(a: fVec, b: fVec, c: fVec) -> Option<(fVec, fVec)> {
+    let (t0, t1) = quadratic(a as f64, b as f64, c as f64)?;
+    Some((t0 as fVec, t1 as fVec))
+}
+
+//Roots of a t^3 + b t^2 + c t + d, the first count entries are valid
+pub(crate) fn solve_cubic(a: fVec, b: fVec, c: fVec, d: fVec) -> ([fVec; 3], usize) {
+    let mut out = [0.0; 3];
+    let (a, b, c, d) = (a as f64, b as f64, c as f64, d as f64);
+    if a == 0.0 {
+        return match quadratic(b, c, d) {
+            Some((t0, t1)) => ([t0 as fVec, t1 as fVec, 0.0], 2),
+            None => (out, 0),
+        };
+    }
+    let (roots, count) = normalized_cubic(b / a, c / a, d / a);
+    for (o, r) in out.iter_mut().zip(roots.iter()) {
+        *o = *r as fVec;
+    }
+    (out, count)
+}
+
+//Roots of a t^4 + b t^3 + c t^2 + d t + e, the first count entries are valid
+pub(crate) fn solve_quartic(a: fVec, b: fVec, c: fVec, d: fVec, e: fVec) -> ([fVec; 4], usize) {
+    let mut out = [0.0; 4];
+    if a == 0.0 {
+        let (roots, count) = solve_cubic(b, c, d, e);
+        out[..3].copy_from_slice(&roots);
+        return (out, count);
+    }
+    let (a, b, c, d, e) = (a as f64, b as f64, c as f64, d as f64, e as f64);
+    let (roots, count) = normalized_quartic(b / a, c / a, d / a, e / a);
+    for (o, r) in out.iter_mut().zip(roots.iter()) {
+        *o = *r as fVec;
+    }
+    (out, count)
+}
+
+//Citardauq form: the root that would cancel is computed from the product c / a instead
+fn quadratic(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
+    if a == 0.0 {
+        if b == 0.0 {
+            return None;
+        }
+        return Some((-c / b, -c / b));
+    }
+    let disc = b * b - 4.0 * a * c;
+    if disc < 0.0 {
+        return None;
+    }
+    let q = -0.5 * (b + b.signum() * disc.sqrt());
+    if q == 0.0 {
+        return Some((0.0, 0.0));
+    }
+    let (t0, t1) = (q / a, c / q);
+    Some(if t0 <= t1 { (t0, t1) } else { (t1, t0) })
+}
+
+//Value and derivative of the polynomial at x, Horner's scheme
+fn evaluate(coeffs: &[f64], x: f64) -> (f64, f64) {
+    let (mut f, mut df) = (0.0, 0.0);
+    for &c in coeffs.iter() {
+        df = df * x + f;
+        f = f * x + c;
+    }
+    (f, df)
+}
+
+//Newton steps to recover the digits lost in the closed form solutions. Near a repeated root the
+//derivative vanishes too and a step can shoot off, those are not taken.
+fn polish(coeffs: &[f64], mut x: f64) -> f64 {
+    for _ in 0..2 {
+        let (f, df) = evaluate(coeffs, x);
+        if df == 0.0 {
+            break;
+        }
+        let next = x - f / df;
+        if evaluate(coeffs, next).0.abs() >= f.abs() {
+            break;
+        }
+        x = next;
+    }
+    x
+}
+
+//t^3 + a t^2 + b t + c
+fn normalized_cubic(a: f64, b: f64, c: f64) -> ([f64; 3], usize) {
+    let mut roots = [0.0; 3];
+    //Depressed cubic y^3 + p y + q with t = y - a / 3
+    let shift = -a / 3.0;
+    let p = b - a * a / 3.0;
+    let q = 2.0 * a * a * a / 27.0 - a * b / 3.0 + c;
+    let disc = q * q / 4.0 + p * p * p / 27.0;
+    //Rounding in the coefficients moves a zero discriminant either way, which would lose a double
+    //root or split it in two
+    let scale = (q * q / 4.0).max((p * p * p / 27.0).abs());
+
+    let count = if p != 0.0 && disc.abs() <= 1e-6 * scale {
+        //Single root and a double root
+        roots[0] = 3.0 * q / p + shift;
+        roots[1] = -1.5 * q / p + shift;
+        roots[2] = roots[1];
+        3
+    } else if disc > 0.0 {
+        //One real root, picking the sign that adds magnitudes
+        let u = (-q / 2.0 - q.signum() * disc.sqrt()).cbrt();
+        let v = if u == 0.0 { 0.0 } else { -p / (3.0 * u) };
+        roots[0] = u + v + shift;
+        1
+    } else if p == 0.0 {
+        roots[0] = shift;
+        1
+    } else {
+        //Three real roots, trigonometric form
+        let m = 2.0 * (-p / 3.0).sqrt();
+        let theta = (3.0 * q / (p * m)).clamp(-1.0, 1.0).acos() / 3.0;
+        for (k, root) in roots.iter_mut().enumerate() {
+            *root = m * (theta - 2.0 * std::f64::consts::PI * k as f64 / 3.0).cos() + shift;
+        }
+        3
+    };
+
+    for root in roots[..count].iter_mut() {
+        *root = polish(&[1.0, a, b, c], *root);
+    }
+    roots[..count].sort_by(f64::total_cmp);
+    (roots, count)
+}
+
+//t^4 + a t^3 + b t^2 + c t + d, Ferrari's method
+fn normalized_quartic(a: f64, b: f64, c: f64, d: f64) -> ([f64; 4], usize) {
+    let mut roots = [0.0; 4];
+    let mut count = 0;
+    //Depressed quartic y^4 + p y^2 + q y + r with t = y - a / 4
+    let shift = -a / 4.0;
+    let a2 = a * a;
+    let p = b - 3.0 * a2 / 8.0;
+    let q = c - a * b / 2.0 + a2 * a / 8.0;
+    let r = d - a * c / 4.0 + a2 * b / 16.0 - 3.0 * a2 * a2 / 256.0;
+
+    let mut push_square_roots = |z: f64, roots: &mut [f64; 4]| {
+        if z >= 0.0 {
+            let y = z.sqrt();
+            roots[count] = y + shift;
+            roots[count + 1] = -y + shift;
+            count += 2;
+        }
+    };
+
+    if q.abs() < 1e-12 {
+        //Biquadratic, quadratic in y^2
+        if let Some((z0, z1)) = quadratic(1.0, p, r) {
+            push_square_roots(z0, &mut roots);
+            push_square_roots(z1, &mut roots);
+        }
+    } else {
+        //Any positive root m of the resolvent cubic splits the quartic into two quadratics
+        let (resolvent, n) = normalized_cubic(p, p * p / 4.0 - r, -q * q / 8.0);
+        let m = resolvent[n - 1];
+        if m <= 0.0 {
+            return (roots, 0);
+        }
+        let s = (2.0 * m).sqrt();
+        for (sign, offset) in [(-1.0, q / (2.0 * s)), (1.0, -q / (2.0 * s))] {
+            if let Some((y0, y1)) = quadratic(1.0, sign * s, p / 2.0 + m + offset) {
+                roots[count] = y0 + shift;
+                roots[count + 1] = y1 + shift;
+                count += 2;
+            }
+        }
+    }
+
+    for root in roots[..count].iter_mut() {
+        *root = polish(&[1.0, a, b, c, d], *root);
+    }
+    roots[..count].sort_by(f64::total_cmp);
+    (roots, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Coefficients of the monic polynomial with the given roots, highest power first
+    fn expand(roots: &[f64]) -> Vec<fVec> {
+        let mut coeffs = vec![1.0f64];
+        for &r in roots {
+            let mut next = vec![0.0; coeffs.len() + 1];
+            for (i, &c) in coeffs.iter().enumerate() {
+                next[i] += c;
+                next[i + 1] -= c * r;
+            }
+            coeffs = next;
+        }
+        coeffs.into_iter().map(|c| c as fVec).collect()
+    }
+
+    //Every expected root is found, and every root found is one of them
+    fn check(found: &[fVec], expected: &[f64], tolerance: f64) {
+        for &e in expected {
+            assert!(found.iter().any(|&f| (f as f64 - e).abs() < tolerance), "{e} missing in {found:?}");
+        }
+        for &f in found {
+            assert!(expected.iter().any(|&e| (f as f64 - e).abs() < tolerance), "{f} is no root of {expected:?}");
+        }
+        assert!(found.windows(2).all(|w| w[0] <= w[1]), "{found:?} not ascending");
+    }
+
+    #[test]
+    fn cubic_roots() {
+        for (roots, tolerance) in [
+            (&[-2.0, 0.5, 3.0][..], 1e-5),
+            (&[1.0, 1.0, -2.0][..], 1e-3),
+            (&[1.0, 1.0, 1.0][..], 1e-2),
+            (&[1.0, 1.0001, -2.0][..], 1e-3),
+            (&[-1e-3, 0.0, 1e-3][..], 1e-4),
+            (&[100.0, 0.01, -0.5][..], 1e-3),
+        ] {
+            let c = expand(roots);
+            let (found, count) = solve_cubic(c[0], c[1], c[2], c[3]);
+            check(&found[..count], roots, tolerance);
+        }
+        //One real root, the other two are complex
+        let (found, count) = solve_cubic(1.0, 0.0, 1.0, -2.0);
+        check(&found[..count], &[1.0], 1e-5);
+    }
+
+    #[test]
+    fn quartic_roots() {
+        for (roots, tolerance) in [
+            (&[1.0, 2.0, 3.0, 4.0][..], 1e-4),
+            (&[-3.0, -0.5, 0.25, 6.0][..], 1e-4),
+            (&[1.0, 1.0, 3.0, 3.0][..], 1e-2),
+            (&[-1.0, 2.0, 2.0, 5.0][..], 1e-2),
+            (&[1.0, 1.001, 2.0, 4.0][..], 1e-2),
+            (&[-2.0, -2.0, -2.0, -2.0][..], 5e-2),
+        ] {
+            let c = expand(roots);
+            let (found, count) = solve_quartic(c[0], c[1], c[2], c[3], c[4]);
+            check(&found[..count], roots, tolerance);
+        }
+        //(t^2 + 1)(t^2 + 4) has no real roots, (t^2 - 1)(t^2 + 4) two
+        assert_eq!(solve_quartic(1.0, 0.0, 5.0, 0.0, 4.0).1, 0);
+        let (found, count) = solve_quartic(1.0, 0.0, 3.0, 0.0, -4.0);
+        check(&found[..count], &[-1.0, 1.0], 1e-5);
+        //Leading zero falls back to the cubic
+        let (found, count) = solve_quartic(0.0, 1.0, -6.0, 11.0, -6.0);
+        check(&found[..count], &[1.0, 2.0, 3.0], 1e-5);
+    }
+}
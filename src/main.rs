@@ -1,58 +1,768 @@
-#![allow(dead_code)]
-mod hit;
-mod image;
-mod linalg;
-mod material;
-mod tracer;
-
-use std::{cell::RefCell, io, rc::Rc};
-
-use hit::*;
-use image::*;
-use linalg::*;
-use material::*;
-use rand::SeedableRng;
-use tracer::*;
-
-fn main() -> io::Result<()> {
-    let renderer = Renderer::new(300, 20);
-    let cam = Camera::new(
-        Vec3::new(0.0, 3.0, -5.0), Vec3::new(0.0, 0.0, 2.0),
-        640,
-        360,
-        45.0,
-        0.1
+use std::{
+    cell::RefCell,
+    io,
+    path::Path,
+    rc::Rc,
+    time::Duration,
+};
+
+use clap::{builder::TypedValueParser, Parser, Subcommand};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use raytrace::animation::*;
+use raytrace::checkpoint::*;
+use raytrace::daemon::*;
+use raytrace::error::*;
+use raytrace::filter::Denoiser;
+use raytrace::framebuffer::*;
+use raytrace::hit::*;
+use raytrace::image::*;
+use raytrace::job::*;
+use raytrace::light::*;
+use raytrace::linalg::*;
+use raytrace::material::*;
+use raytrace::scene_file::*;
+use raytrace::tracer::*;
+
+//Edge length of the tiles of --tiled-exr output, what other renderers commonly use
+const EXR_TILE_SIZE: usize = 64;
+
+//Render settings from the command line, each preset fills in what is left out
+#[derive(Parser)]
+#[command(about = "Path tracer rendering the built-in demo scene or a JSON scene file")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(help = "Output image, .png, .tif, .exr, .hdr, .ppm, .pfm or .bmp, may contain {scene}, {width}, {height}, {samples}, {bounces} and {seed}")]
+    output: Option<String>,
+    #[arg(short = 'o', long = "output", global = true, help = "Output image, overrides OUTPUT")]
+    output_flag: Option<String>,
+    #[arg(long, global = true, help = "JSON scene file to render instead of the demo scene")]
+    scene: Option<String>,
+    #[arg(long, global = true, help = "Image width in pixels")]
+    width: Option<usize>,
+    #[arg(long, global = true, help = "Image height in pixels")]
+    height: Option<usize>,
+    #[arg(short, long, global = true, help = "Samples per pixel")]
+    samples: Option<usize>,
+    #[arg(short, long, global = true, help = "Maximum path length")]
+    bounces: Option<usize>,
+    #[arg(long, global = true, help = "Random seed, random if left out")]
+    seed: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 8,
+        value_parser = clap::builder::PossibleValuesParser::new(["8", "16"]).map(|s| s.parse::<u8>().unwrap()),
+        help = "Bits per channel of PNG and TIFF output"
+    )]
+    depth: u8,
+    #[arg(long, global = true, help = "Encode with gamma 2 instead of the exact sRGB curve, faster but shadows come out too bright")]
+    gamma2: bool,
+    #[arg(long, global = true, help = "Also write albedo, normal, depth, object ID and cryptomatte EXR images next to the output")]
+    aovs: bool,
+    #[arg(long, global = true, help = "Denoise the output with the built-in filter guided by normal and depth")]
+    denoise: bool,
+    #[arg(long, global = true, help = "Write the image with all AOVs and cryptomattes as layers of one EXR output")]
+    multilayer: bool,
+    #[arg(long, global = true, help = "Also write a deep EXR next to the output, with a sample per object seen by each pixel")]
+    deep: bool,
+    #[arg(long, global = true, help = "Stream EXR output to disk in tiles instead of resolving the whole image first, for very large renders")]
+    tiled_exr: bool,
+    #[arg(long, global = true, help = "Render without depth of field, everything in focus, for quick previews")]
+    no_dof: bool,
+    #[arg(long, global = true, help = "Transparent background, written as alpha to PNG and EXR output")]
+    transparent: bool,
+    #[arg(
+        long,
+        global = true,
+        requires_all = ["shutter_speed", "f_number"],
+        help = "Expose the image like a camera at this ISO, with --shutter-speed and --f-number, for light in cd/m²"
+    )]
+    iso: Option<fCol>,
+    #[arg(long, global = true, requires = "iso", value_parser = parse_shutter_speed, help = "Exposure time in seconds, e.g. 1/125")]
+    shutter_speed: Option<fCol>,
+    #[arg(long, global = true, requires = "iso", help = "Aperture of the exposure, e.g. 8 for f/8")]
+    f_number: Option<fCol>,
+    #[arg(
+        long,
+        global = true,
+        value_parser = clap::builder::PossibleValuesParser::new(["separate", "side-by-side", "over-under"]).map(|s| StereoLayout::from_name(&s).unwrap()),
+        help = "Render a stereo pair, as .left and .right images or both eyes in one image"
+    )]
+    stereo: Option<StereoLayout>,
+    #[arg(long, global = true, default_value_t = 0.065, requires = "stereo", help = "Distance between the eyes of --stereo in scene units")]
+    interocular: fVec,
+    #[arg(long, global = true, requires = "stereo", help = "Distance both eyes look at, what is in focus if left out")]
+    convergence: Option<fVec>,
+    #[arg(short = 'j', long, global = true, default_value_t = 1, help = "Render threads")]
+    threads: usize,
+    #[arg(
+        long,
+        global = true,
+        default_value = "scanline",
+        value_parser = clap::builder::PossibleValuesParser::new(["scanline", "spiral", "hilbert"]).map(|s| TileOrder::from_name(&s).unwrap()),
+        help = "Order tiles are rendered in, spiral starts at the center of the image"
+    )]
+    tile_order: TileOrder,
+    #[arg(long, global = true, value_parser = parse_region, help = "Only render the pixels from X0,Y0 up to X1,Y1, the rest of the image stays black")]
+    region: Option<Tile>,
+    #[arg(long, global = true, requires = "region", help = "Write only the --region instead of the whole image")]
+    crop: bool,
+    #[arg(long, global = true, help = "Finish the render saved in a checkpoint, its seed, samples, bounces and size are used")]
+    resume: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 60,
+        help = "Seconds between checkpoints written next to the output, 0 to only write one when interrupted"
+    )]
+    checkpoint_interval: u64,
+    #[arg(long, global = true, help = "Print ray counts, BVH traversal and time per stage after rendering")]
+    stats: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Low resolution, low sample direct lighting preview for asset browsers")]
+    Thumbnail { output: Option<String> },
+    #[command(about = "BVH traversal cost of the camera rays")]
+    Heatmap { output: Option<String> },
+    #[command(about = "Render a JSON scene file, the same as --scene")]
+    Scene {
+        #[arg(help = "JSON scene file")]
+        file: String,
+        output: Option<String>,
+    },
+    #[command(about = "Keep the scene loaded and render on request, commands are read from stdin")]
+    Daemon,
+}
+
+//Defaults of a kind of render
+struct Preset {
+    //Kept in checkpoints
+    name: &'static str,
+    output: &'static str,
+    width: usize,
+    height: usize,
+    samples: usize,
+    bounces: usize,
+    aperture: fVec,
+    integrator: Integrator,
+}
+
+const FINAL: Preset = Preset {
+    name: "final",
+    output: "outimage.bmp",
+    width: 640,
+    height: 360,
+    samples: 300,
+    bounces: 20,
+    aperture: 0.1,
+    integrator: Integrator::PathTracer,
+};
+
+//Seconds, also as a fraction like 1/125
+fn parse_shutter_speed(s: &str) -> std::result::Result<fCol, String> {
+    let seconds = match s.split_once('/') {
+        Some((num, den)) => num.trim().parse::<fCol>().ok().zip(den.trim().parse::<fCol>().ok()).map(|(n, d)| n / d),
+        None => s.trim().parse().ok(),
+    };
+    seconds.ok_or_else(|| format!("{} is neither seconds nor a fraction like 1/125", s))
+}
+
+//X0,Y0,X1,Y1 with the end exclusive, as Tile
+fn parse_region(s: &str) -> std::result::Result<Tile, String> {
+    let corners: Vec<usize> = s.split(',').map(|v| v.trim().parse()).collect::<std::result::Result<_, _>>().map_err(|e| format!("{}: {}", s, e))?;
+    match corners[..] {
+        [x0, y0, x1, y1] if x0 < x1 && y0 < y1 => Ok(Tile { x0, y0, x1, y1 }),
+        [_, _, _, _] => Err(format!("{} is empty, X1 and Y1 must be past X0 and Y0", s)),
+        _ => Err(format!("{} is not X0,Y0,X1,Y1", s)),
+    }
+}
+
+//--iso, clap makes sure the other exposure settings come with it
+fn exposure(cli: &Cli) -> Option<Exposure> {
+    cli.iso.map(|iso| Exposure {
+        iso,
+        shutter_speed: cli.shutter_speed.unwrap(),
+        f_number: cli.f_number.unwrap(),
+    })
+}
+
+fn main() {
+    if let Err(e) = run(Cli::parse()) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let (preset, output, scene) = match &cli.command {
+        None => (FINAL, cli.output.clone(), cli.scene.clone()),
+        Some(Command::Thumbnail { output }) => (
+            Preset {
+                name: "thumbnail",
+                output: "thumbnail.png",
+                width: 160,
+                height: 90,
+                samples: 16,
+                bounces: 4,
+                aperture: 0.0,
+                integrator: Integrator::DirectLighting,
+            },
+            output.clone(),
+            cli.scene.clone(),
+        ),
+        Some(Command::Heatmap { output }) => (
+            Preset {
+                name: "heatmap",
+                output: "heatmap.png",
+                samples: 1,
+                bounces: 1,
+                aperture: 0.0,
+                integrator: Integrator::BvhHeatmap { max_nodes: 64 },
+                ..FINAL
+            },
+            output.clone(),
+            cli.scene.clone(),
+        ),
+        Some(Command::Scene { file, output }) => (
+            Preset {
+                output: "outimage.png",
+                ..FINAL
+            },
+            output.clone(),
+            Some(file.clone()),
+        ),
+        Some(Command::Daemon) => return daemon(&cli),
+    };
+
+    let resume = cli.resume.as_deref().map(Checkpoint::load).transpose()?;
+    let mut job = RenderJob {
+        scene: scene.as_deref().map_or("spheres".to_string(), |path| {
+            Path::new(path)
+                .file_stem()
+                .map_or("scene".to_string(), |s| s.to_string_lossy().into_owned())
+        }),
+        width: cli.width.unwrap_or(preset.width).max(1),
+        height: cli.height.unwrap_or(preset.height).max(1),
+        samples: cli.samples.unwrap_or(preset.samples),
+        bounces: cli.bounces.unwrap_or(preset.bounces),
+        seed: cli.seed.unwrap_or_else(rand::random),
+        output: cli.output_flag.clone().or(output).unwrap_or_else(|| preset.output.to_string()),
+        depth: if cli.depth == 16 { BitDepth::Sixteen } else { BitDepth::Eight },
+        transfer: if cli.gamma2 { Transfer::Gamma2 } else { Transfer::Srgb },
+        scene_hash: scene.as_deref().map(file_hash).transpose()?,
+    };
+    let checkpoint_job = CheckpointJob {
+        integrator: format!("{:?}", preset.integrator),
+        preset: preset.name.to_string(),
+        scene: scene.clone().unwrap_or_default(),
+    };
+    //The scene is built from the seed as well, so the checkpoint settings apply before anything else
+    if let Some(checkpoint) = &resume {
+        checkpoint.check_job(&checkpoint_job)?;
+        if cli.aovs || cli.denoise || cli.multilayer || cli.deep {
+            return Err(Error::invalid_parameter(
+                "resume",
+                "AOVs are not kept in checkpoints, resume without --aovs, --denoise, --multilayer and --deep",
+            ));
+        }
+        job.width = checkpoint.frame.width();
+        job.height = checkpoint.frame.height();
+        job.samples = checkpoint.samples;
+        job.bounces = checkpoint.bounces;
+        job.seed = checkpoint.seed;
+        println!(
+            "Resuming {} of {} tiles with seed {}",
+            checkpoint.done.iter().filter(|d| !**d).count(),
+            checkpoint.done.len(),
+            checkpoint.seed
+        );
+    }
+    let options = RunOptions {
+        integrator: preset.integrator,
+        threads: cli.threads.max(1),
+        tile_order: cli.tile_order,
+        checkpoint_interval: Duration::from_secs(cli.checkpoint_interval),
+        resume,
+        checkpoint_job,
+        print_stats: cli.stats,
+        aovs: cli.aovs,
+        denoise: cli.denoise,
+        multilayer: cli.multilayer,
+        deep: cli.deep,
+        tiled_exr: cli.tiled_exr,
+        transparent: cli.transparent,
+        depth_of_field: !cli.no_dof,
+        exposure: exposure(&cli),
+        region: cli.region,
+        crop: cli.crop,
+        stereo: cli.stereo.map(|layout| Stereo {
+            layout,
+            interocular: cli.interocular,
+            convergence: cli.convergence,
+        }),
+    };
+
+    match scene {
+        //Scene exported to the JSON scene format, see scene_file for the schema
+        Some(path) => {
+            let desc = SceneDescription::read(&path)?;
+            let cam: Box<dyn CameraModel> = match desc.build(job.width, job.height, job.seed)?.camera {
+                Some(cam) => cam,
+                None => Box::new(create_camera(job.width, job.height, 0.0)?),
+            };
+            let create = |thread| Ok(desc.build(job.width, job.height, job.seed ^ thread_salt(thread))?.scene);
+            run_job(&job, create, cam.as_ref(), options)
+        }
+        None => {
+            let cam = create_camera(job.width, job.height, preset.aperture)?;
+            run_job(&job, |thread| Ok(create_scene(job.seed, thread_salt(thread))), &cam, options)
+        }
+    }
+}
+
+//Mixed into the seeds of the random state kept in materials, so the scenes of the render threads
+//don't repeat each other's random numbers. Zero for the first thread, which matches a single
+//threaded render.
+fn thread_salt(thread: usize) -> u64 {
+    (thread as u64).wrapping_mul(0x9E3779B97F4A7C15)
+}
+
+fn create_camera(width: usize, height: usize, aperture: fVec) -> Result<Camera> {
+    Camera::builder(Vec3::new(0.0, 3.0, -5.0), Vec3::new(0.0, 0.0, 2.0))
+        .resolution(width, height)
+        .fov(45.0)
+        .aperture(aperture)
+        .build()
+}
+
+fn create_renderer(job: &RenderJob, options: &RunOptions, tile_size: Option<usize>, cancel: &CancelToken) -> Result<Renderer> {
+    let mut builder = Renderer::builder()
+        .samples(job.samples)
+        .bounces(job.bounces)
+        .seed(job.seed)
+        .integrator(options.integrator)
+        .tile_order(options.tile_order)
+        .depth_of_field(options.depth_of_field)
+        .cancel_token(cancel.clone());
+    if let Some(size) = tile_size {
+        builder = builder.tile_size(size);
+    }
+    if options.transparent {
+        builder = builder.backdrop(Backdrop::Transparent);
+    }
+    if let Some(exposure) = options.exposure {
+        builder = builder.exposure(exposure);
+    }
+    if let Some(region) = options.region {
+        builder = builder.region(region);
+    }
+    builder.build()
+}
+
+struct RunOptions {
+    integrator: Integrator,
+    threads: usize,
+    tile_order: TileOrder,
+    //Zero to only write a checkpoint when interrupted
+    checkpoint_interval: Duration,
+    resume: Option<Checkpoint>,
+    //What checkpoints of the render record
+    checkpoint_job: CheckpointJob,
+    print_stats: bool,
+    aovs: bool,
+    denoise: bool,
+    //Beauty and AOVs in a single EXR
+    multilayer: bool,
+    //Deep EXR next to the output
+    deep: bool,
+    //EXR output streamed in tiles of EXR_TILE_SIZE
+    tiled_exr: bool,
+    transparent: bool,
+    depth_of_field: bool,
+    exposure: Option<Exposure>,
+    //Whole image if not set, see Renderer::set_region()
+    region: Option<Tile>,
+    //Save only the region
+    crop: bool,
+    stereo: Option<Stereo>,
+}
+
+//Both eyes of a stereo render, see StereoCamera
+#[derive(Clone, Copy)]
+struct Stereo {
+    layout: StereoLayout,
+    interocular: fVec,
+    //Distance to what the center of the image shows if not set
+    convergence: Option<fVec>,
+}
+
+//Frame of render_frame() with the renderer that resolves it
+struct Rendered {
+    renderer: Renderer,
+    frame: FrameBuffer,
+    done: Vec<bool>,
+    stats: RenderStats,
+    tile_size: usize,
+}
+
+fn run_job(job: &RenderJob, create: impl Fn(usize) -> Result<Scene> + Sync, cam: &dyn CameraModel, mut options: RunOptions) -> Result<()> {
+    //Fail before rendering instead of after
+    if options.multilayer {
+        job.exr_output_path("multi-layer")?;
+    }
+    if options.tiled_exr {
+        job.exr_output_path("tiled")?;
+        if options.multilayer || options.denoise {
+            return Err(Error::invalid_parameter("tiled EXR", "cannot be combined with --multilayer or --denoise"));
+        }
+    }
+    if let Some(region) = options.region {
+        let (width, height) = cam.resolution();
+        if region.x1 > width || region.y1 > height {
+            return Err(Error::invalid_parameter("region", format!("ends outside of the {}x{} image", width, height)));
+        }
+    }
+    if options.crop && (options.tiled_exr || options.multilayer || options.aovs || options.deep) {
+        return Err(Error::invalid_parameter("crop", "cannot be combined with --tiled-exr, --multilayer, --aovs or --deep"));
+    }
+    if options.stereo.is_some() && (options.resume.is_some() || options.tiled_exr || options.multilayer || options.aovs || options.deep) {
+        return Err(Error::invalid_parameter(
+            "stereo",
+            "cannot be combined with --resume, --tiled-exr, --multilayer, --aovs or --deep",
+        ));
+    }
+    let cancel = CancelToken::new();
+    let token = cancel.clone();
+    ctrlc::set_handler(move || {
+        eprintln!("\nInterrupted, finishing current tile");
+        token.cancel();
+    })
+    .map_err(io::Error::other)?;
+
+    match options.stereo {
+        Some(stereo) => run_stereo(job, &create, cam, &options, stereo, &cancel),
+        None => {
+            let resume = options.resume.take();
+            run_mono(job, &create, cam, &options, resume, &cancel)
+        }
+    }
+}
+
+//create(thread) builds the scene for a render thread. With several threads every thread builds its
+//own copy of the scene, as scenes are not shared across threads, and renders every threads-th
+//tile. This thread renders the first share with the scene it prepared and tuned the tile size on.
+//Pixels are seeded by position, so the image matches a single threaded render up to the random
+//state kept in materials. Periodic checkpoints need the whole image and are only written by
+//single threaded renders, periodic proxies only show the first share until the end.
+fn render_frame(
+    job: &RenderJob,
+    create: &(impl Fn(usize) -> Result<Scene> + Sync),
+    cam: &dyn CameraModel,
+    options: &RunOptions,
+    resume: Option<Checkpoint>,
+    checkpoint_path: Option<&str>,
+    cancel: &CancelToken,
+) -> Result<Rendered> {
+    let RunOptions {
+        threads,
+        checkpoint_interval,
+        aovs,
+        denoise,
+        multilayer,
+        deep,
+        ..
+    } = *options;
+    let mut scene = create(0)?;
+    let mut renderer = create_renderer(job, options, None, cancel)?;
+    renderer.set_proxy(&job.proxy_path()?, 4, Duration::from_secs(10), job.transfer);
+    if let Some(path) = checkpoint_path.filter(|_| threads <= 1 && !checkpoint_interval.is_zero()) {
+        renderer.set_checkpoint(path, checkpoint_interval, options.checkpoint_job.clone());
+    }
+    let prepare_time = renderer.prepare(&mut scene);
+    let (tile_size, mut frame, mut done) = match resume {
+        Some(checkpoint) => {
+            checkpoint.restore(&mut renderer, cam)?;
+            (checkpoint.tile_size, checkpoint.frame, checkpoint.done)
+        }
+        None => {
+            let (width, height) = cam.resolution();
+            //The denoiser and multi-layer output need the AOVs
+            let mut frame = if deep {
+                FrameBuffer::with_deep(width, height)
+            } else if aovs || denoise || multilayer {
+                FrameBuffer::with_aovs(width, height)
+            } else {
+                FrameBuffer::new(width, height)
+            };
+            let (tile_size, done) = renderer.tune_tile_size(&scene, cam, &mut frame);
+            (tile_size, frame, done)
+        }
+    };
+
+    let stats = if threads <= 1 {
+        renderer.render_into(&scene, cam, &mut frame, &mut done)
+    } else {
+        let tiles = renderer.tiles(cam);
+        //Every threads-th tile of the schedule, so the threads progress through it together
+        let schedule = renderer.tile_schedule(cam);
+        let share = |k: usize| schedule.iter().skip(k).step_by(threads).copied();
+        let render_share = |renderer: &Renderer, scene: &Scene, k: usize| {
+            let mut part = frame.clone();
+            let mut part_done = vec![true; done.len()];
+            for i in share(k) {
+                part_done[i] = done[i];
+            }
+            let stats = renderer.render_into(scene, cam, &mut part, &mut part_done);
+            Ok((part, part_done, stats))
+        };
+        let results = std::thread::scope(|s| {
+            let workers: Vec<_> = (1..threads)
+                .map(|k| {
+                    let render_share = &render_share;
+                    s.spawn(move || -> Result<_> {
+                        let mut scene = create(k)?;
+                        //Same settings as this thread's renderer, only this thread reports progress
+                        let mut renderer = create_renderer(job, options, Some(tile_size), cancel)?;
+                        renderer.set_progress(false);
+                        renderer.prepare(&mut scene);
+                        render_share(&renderer, &scene, k)
+                    })
+                })
+                .collect();
+            let mut results = vec![render_share(&renderer, &scene, 0)];
+            results.extend(workers.into_iter().map(|w| w.join().expect("render thread panicked")));
+            results
+        });
+
+        let mut stats = RenderStats::default();
+        for (k, result) in results.into_iter().enumerate() {
+            let (part, part_done, part_stats) = result?;
+            for i in share(k) {
+                let tile = &tiles[i];
+                done[i] = part_done[i];
+                frame.copy_rect(&part, (tile.x0, tile.y0), (tile.x1, tile.y1));
+            }
+            stats.merge(&part_stats);
+        }
+        renderer.write_proxy(&frame);
+        stats
+    };
+    println!(
+        "Prepared scene in {:.2?}, rendered in {:.2?} with {}px tiles",
+        prepare_time, stats.time, tile_size
     );
+    if renderer.frustum_culling() {
+        println!(
+            "Frustum culling skipped {} of {} objects for camera rays",
+            stats.culled_objects,
+            stats.culled_objects + stats.visible_objects
+        );
+    }
+    if options.print_stats {
+        println!("{}", stats);
+    }
+    for warning in renderer.warnings(&stats) {
+        eprintln!("Warning: {}", warning);
+    }
+    Ok(Rendered {
+        renderer,
+        frame,
+        done,
+        stats,
+        tile_size,
+    })
+}
+
+//Job metadata with the settings of the command line that change the image
+fn render_metadata(job: &RenderJob, renderer: &Renderer, options: &RunOptions, stats: &RenderStats) -> Vec<(String, String)> {
+    let mut metadata = job.metadata(stats.time);
+    if let Some(exposure) = options.exposure {
+        metadata.push(("raytrace/exposure".to_string(), exposure.to_string()));
+    }
+    //Where a cropped image goes in the whole one
+    if let Some(region) = renderer.region() {
+        metadata.push(("raytrace/region".to_string(), format!("{},{},{},{}", region.x0, region.y0, region.x1, region.y1)));
+    }
+    metadata
+}
 
+//The rendered region of img, which is all of it without a region
+fn cropped(img: Image, renderer: &Renderer) -> Image {
+    match renderer.region() {
+        Some(region) => img.crop(region.x0, region.y0, region.x1, region.y1),
+        None => img,
+    }
+}
 
-    let mut scene = create_scene();
+fn run_mono(
+    job: &RenderJob,
+    create: &(impl Fn(usize) -> Result<Scene> + Sync),
+    cam: &dyn CameraModel,
+    options: &RunOptions,
+    resume: Option<Checkpoint>,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let checkpoint_path = format!("{}.ckpt", job.output_path()?);
+    let Rendered {
+        renderer,
+        frame,
+        done,
+        stats,
+        tile_size,
+    } = render_frame(job, create, cam, options, resume, Some(&checkpoint_path), cancel)?;
 
-    scene.add(Box::new(Background {
-        color: Color::from_rgb(156, 233, 255),
-    }));
+    let metadata = render_metadata(job, &renderer, options, &stats);
+    let path = if options.tiled_exr {
+        job.save_tiled(&renderer, &frame, EXR_TILE_SIZE, &metadata)?
+    } else {
+        let denoised = if options.denoise { frame.denoised(&Denoiser::default()) } else { None };
+        let mut img = renderer.resolve(denoised.as_ref().unwrap_or(&frame));
+        img.set_transfer(job.transfer);
+        for (key, value) in metadata.iter() {
+            img.set_metadata(key, value);
+        }
+        if options.crop {
+            img = cropped(img, &renderer);
+        }
+        if options.multilayer {
+            job.save_multilayer(&img, &frame)?
+        } else {
+            job.save(&img)?
+        }
+    };
+    for (name, aov) in frame.aov_images().into_iter().flatten().filter(|_| options.aovs) {
+        aov.save_exr(&job.aov_path(name)?)?;
+    }
+    if options.aovs {
+        frame.save_cryptomatte(&job.aov_path("cryptomatte")?)?;
+    }
+    if options.deep {
+        job.save_deep(&frame, &metadata)?;
+    }
 
-    let img = renderer.render(&scene, &cam);
-    img.save_bmp("outimage.bmp")?;
+    if stats.interrupted {
+        Checkpoint {
+            seed: job.seed,
+            samples: job.samples,
+            bounces: job.bounces,
+            sampler: renderer.sampler(),
+            tile_size,
+            region: renderer.region(),
+            done,
+            frame,
+            job: options.checkpoint_job.clone(),
+        }
+        .save(&checkpoint_path)?;
+        println!("Saved partial image to {} and checkpoint to {}", path, checkpoint_path);
+        println!("Continue with --resume {}", checkpoint_path);
+    } else if Path::new(&checkpoint_path).exists() {
+        //Finished, periodic checkpoints of this render are stale now
+        std::fs::remove_file(&checkpoint_path)?;
+    }
     Ok(())
 }
 
+//Renders the eyes one after the other, without checkpoints as there is no resuming them
+fn run_stereo(
+    job: &RenderJob,
+    create: &(impl Fn(usize) -> Result<Scene> + Sync),
+    cam: &dyn CameraModel,
+    options: &RunOptions,
+    stereo: Stereo,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let convergence = match stereo.convergence {
+        Some(distance) => distance,
+        None => {
+            let (origin, (_, _, forward)) = cam.pose();
+            //Nothing at the center, the usual rule of thumb of 30 times the interocular distance
+            create(0)?.autofocus(origin, origin + forward).unwrap_or(30.0 * stereo.interocular)
+        }
+    };
+    println!("Stereo with interocular distance {} converging at {}", stereo.interocular, convergence);
 
-fn create_scene() ->  Scene {
+    let mut images = Vec::new();
+    let mut interrupted = false;
+    for eye in [Eye::Left, Eye::Right] {
+        let eye_cam = StereoCamera::new(cam, eye, stereo.interocular, convergence);
+        let rendered = render_frame(job, create, &eye_cam, options, None, None, cancel)?;
+        let denoised = if options.denoise { rendered.frame.denoised(&Denoiser::default()) } else { None };
+        let mut img = rendered.renderer.resolve(denoised.as_ref().unwrap_or(&rendered.frame));
+        img.set_transfer(job.transfer);
+        let mut metadata = render_metadata(job, &rendered.renderer, options, &rendered.stats);
+        metadata.push(("raytrace/stereo".to_string(), format!("interocular {}, convergence {}", stereo.interocular, convergence)));
+        for (key, value) in metadata.iter() {
+            img.set_metadata(key, value);
+        }
+        if options.crop {
+            img = cropped(img, &rendered.renderer);
+        }
+        images.push(img);
+        interrupted |= rendered.stats.interrupted;
+    }
+
+    let paths = job.save_stereo(&images[0], &images[1], stereo.layout)?;
+    if interrupted {
+        println!("Saved partial stereo images to {}, stereo renders can't be resumed", paths.join(" and "));
+    } else {
+        println!("Saved stereo images to {}", paths.join(" and "));
+    }
+    Ok(())
+}
+
+//Keep the scene loaded and render on request, see Daemon for the protocol
+fn daemon(cli: &Cli) -> Result<()> {
+    let seed = cli.seed.unwrap_or_else(rand::random);
+    let (samples, bounces) = (cli.samples.unwrap_or(64), cli.bounces.unwrap_or(8));
+    let mut renderer = Renderer::new(samples, bounces);
+    renderer.set_seed(seed);
+    renderer.set_exposure(exposure(cli));
+    renderer.set_depth_of_field(!cli.no_dof);
+    Daemon {
+        animation: Animation::new(create_scene(seed, 0), Vec::new()),
+        renderer,
+        scene_name: "spheres".to_string(),
+        width: cli.width.unwrap_or(640).max(1),
+        height: cli.height.unwrap_or(360).max(1),
+        samples,
+        bounces,
+        seed,
+        look_from: Vec3::new(0.0, 3.0, -5.0),
+        look_at: Vec3::new(0.0, 0.0, 2.0),
+        fov: 45.0,
+        aperture: 0.1,
+        motion: Vec::new(),
+        shutter: None,
+        last_render: None,
+    }
+    .run(io::stdin().lock(), &mut io::stdout().lock())?;
+    Ok(())
+}
+
+//The layout only depends on seed, material_salt changes the random state of the materials
+fn create_scene(seed: u64, material_salt: u64) -> Scene {
     let mut scene = Scene::new();
+    let mut rng = SmallRng::seed_from_u64(seed);
 
     let mat = Rc::new(DiffuseMaterial {
-        rng: Box::new(RefCell::new(rand::rngs::SmallRng::from_entropy())),
-        color: Color::new(0.3, 0.3, 0.3),
+        rng: Box::new(RefCell::new(SmallRng::seed_from_u64(rng.gen::<u64>() ^ material_salt))),
+        color: Rc::new(Color::new(0.3, 0.3, 0.3)),
     });
 
     let mat2 = Rc::new(ReflectiveMaterial {
-        color: Color::new(1.0, 1.0, 0.9),
+        color: Rc::new(Color::new(1.0, 1.0, 0.9)),
         fuzziness: 0.0,
-        rng: Box::new(RefCell::new(rand::rngs::SmallRng::from_entropy())),
+        fuzziness_map: None,
+        rng: Box::new(RefCell::new(SmallRng::seed_from_u64(rng.gen::<u64>() ^ material_salt))),
     });
     let mat3 = Rc::new(DielectricMaterial {
         ior: 1.5,
-        rng: Box::new(RefCell::new(rand::rngs::SmallRng::from_entropy())),
+        absorption: Color::black(),
+        priority: 0,
+        rng: Box::new(RefCell::new(SmallRng::seed_from_u64(rng.gen::<u64>() ^ material_salt))),
     });
 
     scene.add(Box::new(Sphere {
@@ -92,12 +802,15 @@ fn create_scene() ->  Scene {
         material: mat.clone(),
     }));
 
-    let mut rng = rand::rngs::SmallRng::from_entropy();
+    scene.set_environment(Box::new(Background {
+        color: Color::from_rgb(156, 233, 255),
+    }));
+
     for _ in 0..20 {
         let r = Vec3::random(&mut rng, 0.0, 1.0);
         let m = Rc::new(DiffuseMaterial {
-            rng: Box::new(RefCell::new(rand::rngs::SmallRng::from_entropy())),
-            color: Color::new(r.x, r.y, r.z) 
+            rng: Box::new(RefCell::new(SmallRng::seed_from_u64(rng.gen::<u64>() ^ material_salt))),
+            color: Rc::new(Color::new(r.x, r.y, r.z)) 
         });
         let mut pos = Vec3::random(&mut rng, -5.0, 5.0);
         
@@ -106,7 +819,7 @@ fn create_scene() ->  Scene {
             origin: pos,
             radius: 0.1,
             material: m
-        }))
+        }));
 
     }
 
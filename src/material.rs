@@ -1,50 +1,15 @@
 use std::cell::RefCell;
 use std::ops::DerefMut;
+use std::rc::Rc;
 
 use crate::image::*;
 use crate::linalg::*;
+use crate::microfacet::*;
+use crate::texture::*;
 use crate::tracer::*;
+use crate::usd::PreviewSurface;
 use rand::prelude::*;
 
-pub struct Background {
-    pub color: Color,
-}
-
-impl Hit for Background {
-    fn hit(&self, ray: &Ray) -> Option<HitResult> {
-        if ray.max.is_infinite() {
-            Some(HitResult {
-                intersect: Vec3 {
-                    x: 0.,
-                    y: 0.,
-                    z: 0.,
-                },
-                normal: Vec3 {
-                    x: 0.,
-                    y: 0.,
-                    z: 0.,
-                },
-                at: ray.max,
-            })
-        } else {
-            None
-        }
-    }
-
-    fn material(&self) -> &dyn Material {
-        self
-    }
-}
-
-impl Material for Background {
-    fn bounce(&self, ray: &Ray, _hit: &HitResult) -> (Color, Option<Ray>) {
-        (
-            self.color * ((ray.direction.unit().y + 1.0) / 2.0) as f32,
-            None,
-        )
-    }
-}
-
 pub struct DebugMaterial {}
 
 impl Material for DebugMaterial {
@@ -59,11 +24,54 @@ impl Material for DebugMaterial {
             None,
         )
     }
+
+    //bounce() is deterministic here
+    fn albedo(&self, ray: &Ray, hit: &HitResult) -> Color {
+        self.bounce(ray, hit).0
+    }
+}
+
+//Light emitting surface like a screen or LED panel, only visible to rays hitting it
+pub struct EmissiveMaterial {
+    //Emitted pattern, e.g. an image for a screen or stripes for a neon sign
+    pub radiance: Rc<dyn Texture>,
+    pub strength: fCol,
+}
+
+impl EmissiveMaterial {
+    //Emitter with the given luminance in nits, e.g. 200-500 for screens
+    pub fn from_nits(color: Color, nits: fCol) -> Self {
+        Self {
+            radiance: Rc::new(color.with_nits(nits)),
+            strength: 1.0,
+        }
+    }
+
+    //Patterned emitter where texture white has the given luminance in nits
+    pub fn from_texture_nits(texture: Rc<dyn Texture>, nits: fCol) -> Self {
+        Self {
+            radiance: texture,
+            strength: nits / NITS_PER_UNIT,
+        }
+    }
+}
+
+impl Material for EmissiveMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        if !hit.is_outside(ray) {
+            return (Color::black(), None);
+        }
+        (self.radiance.at_hit(hit) * self.strength, None)
+    }
+
+    fn albedo(&self, _ray: &Ray, _hit: &HitResult) -> Color {
+        Color::black()
+    }
 }
 
 pub struct DiffuseMaterial {
     pub rng: Box<RefCell<dyn RngCore>>,
-    pub color: Color,
+    pub color: Rc<dyn Texture>,
 }
 
 impl Material for DiffuseMaterial {
@@ -73,7 +81,7 @@ impl Material for DiffuseMaterial {
         }
         let scatter_dir = hit.normal + rand_on_unit_sphere(self.rng.borrow_mut().deref_mut());
         (
-            self.color,
+            self.color.at_hit(hit),
             Some(Ray::new(
                 hit.intersect,
                 if scatter_dir.is_tiny(0.0001) {
@@ -84,9 +92,289 @@ impl Material for DiffuseMaterial {
             )),
         )
     }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        if !hit.is_outside(ray) {
+            return Color::black();
+        }
+        let cos = hit.normal * light_dir;
+        if cos <= 0.0 {
+            return Color::black();
+        }
+        self.color.at_hit(hit) * (cos / std::f32::consts::PI)
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        if !hit.is_outside(ray) {
+            return 0.0;
+        }
+        (hit.normal * dir).max(0.0) / std::f32::consts::PI
+    }
+
+    fn albedo(&self, _ray: &Ray, hit: &HitResult) -> Color {
+        self.color.at_hit(hit)
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        Some(PreviewSurface {
+            diffuse_color: self.color.value(0.5, 0.5, Vec3::origin()),
+            ..PreviewSurface::default()
+        })
+    }
+}
+
+//Rough diffuse surface (clay, concrete), roughness is the standard deviation of the
+//facet slope angle in radians, 0 is Lambertian
+pub struct OrenNayarMaterial {
+    pub rng: Box<RefCell<dyn RngCore>>,
+    pub color: Color,
+    pub roughness: fVec,
+}
+
+impl OrenNayarMaterial {
+    fn factor(&self, normal: Vec3, view: Vec3, light_dir: Vec3) -> fVec {
+        let sigma2 = self.roughness * self.roughness;
+        let a = 1.0 - 0.5 * sigma2 / (sigma2 + 0.33);
+        let b = 0.45 * sigma2 / (sigma2 + 0.09);
+
+        let cos_i = (normal * light_dir).clamp(0.0, 1.0);
+        let cos_o = (normal * view).clamp(0.0, 1.0);
+        let sin_i = (1.0 - cos_i * cos_i).sqrt();
+        let sin_o = (1.0 - cos_o * cos_o).sqrt();
+
+        //Cosine of the azimuth between both directions, projected onto the tangent plane
+        let proj_i = light_dir - normal * cos_i;
+        let proj_o = view - normal * cos_o;
+        let cos_phi = if proj_i.is_tiny(0.0001) || proj_o.is_tiny(0.0001) {
+            0.0
+        } else {
+            (proj_i.unit() * proj_o.unit()).max(0.0)
+        };
+
+        let (sin_alpha, tan_beta) = if cos_i > cos_o {
+            (sin_o, sin_i / cos_i.max(0.0001))
+        } else {
+            (sin_i, sin_o / cos_o.max(0.0001))
+        };
+        a + b * cos_phi * sin_alpha * tan_beta
+    }
+}
+
+impl Material for OrenNayarMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        if !hit.is_outside(ray) {
+            return (Color::black(), None);
+        }
+        let scatter_dir = hit.normal + rand_on_unit_sphere(self.rng.borrow_mut().deref_mut());
+        let dir = if scatter_dir.is_tiny(0.0001) {
+            hit.normal
+        } else {
+            scatter_dir.unit()
+        };
+        //Cosine sampling cancels with the cosine term and 1/pi of the BRDF
+        let weight = self.factor(hit.normal, -ray.direction.unit(), dir);
+        (self.color * weight, Some(Ray::new(hit.intersect, dir)))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        if !hit.is_outside(ray) {
+            return Color::black();
+        }
+        let cos = hit.normal * light_dir;
+        if cos <= 0.0 {
+            return Color::black();
+        }
+        let factor = self.factor(hit.normal, -ray.direction.unit(), light_dir);
+        self.color * (factor * cos / std::f32::consts::PI)
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        if !hit.is_outside(ray) {
+            return 0.0;
+        }
+        (hit.normal * dir).max(0.0) / std::f32::consts::PI
+    }
+
+    fn albedo(&self, _ray: &Ray, _hit: &HitResult) -> Color {
+        self.color
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        Some(PreviewSurface {
+            diffuse_color: self.color,
+            ..PreviewSurface::default()
+        })
+    }
+}
+
+//Cloth like velvet or satin: a Lambertian base with a sheen lobe that lights up the rim where
+//fibers are seen edge on, see charlie_d
+pub struct VelvetMaterial {
+    pub rng: Box<RefCell<dyn RngCore>>,
+    pub color: Color,
+    pub sheen_color: Color,
+    pub sheen_roughness: fVec,
+}
+
+impl VelvetMaterial {
+    //Sheen without the cosine term
+    fn sheen(&self, normal: Vec3, view: Vec3, light_dir: Vec3) -> Color {
+        let n_dot_l = normal * light_dir;
+        let n_dot_v = normal * view;
+        if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+            return Color::black();
+        }
+        let h = (view + light_dir).unit();
+        self.sheen_color * (charlie_d(normal * h, self.sheen_roughness) * sheen_visibility(n_dot_l, n_dot_v))
+    }
+}
+
+impl Material for VelvetMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        if !hit.is_outside(ray) {
+            return (Color::black(), None);
+        }
+        let scatter_dir = hit.normal + rand_on_unit_sphere(self.rng.borrow_mut().deref_mut());
+        let dir = if scatter_dir.is_tiny(0.0001) {
+            hit.normal
+        } else {
+            scatter_dir.unit()
+        };
+        //Cosine sampling cancels with the cosine term, leaving pi times the BRDF
+        let sheen = self.sheen(hit.normal, -ray.direction.unit(), dir) * std::f32::consts::PI;
+        (self.color + sheen, Some(Ray::new(hit.intersect, dir)))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        if !hit.is_outside(ray) {
+            return Color::black();
+        }
+        let cos = hit.normal * light_dir;
+        if cos <= 0.0 {
+            return Color::black();
+        }
+        let sheen = self.sheen(hit.normal, -ray.direction.unit(), light_dir);
+        (self.color * (1.0 / std::f32::consts::PI) + sheen) * cos
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        if !hit.is_outside(ray) {
+            return 0.0;
+        }
+        (hit.normal * dir).max(0.0) / std::f32::consts::PI
+    }
+
+    fn albedo(&self, _ray: &Ray, _hit: &HitResult) -> Color {
+        self.color
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        Some(PreviewSurface {
+            diffuse_color: self.color,
+            roughness: 1.0,
+            ..PreviewSurface::default()
+        })
+    }
+}
+
+//Cel shading: diffuse light falls into a few flat bands instead of a smooth gradient. The band
+//level of a direction is its cosine rounded to a multiple of 1/bands, so on average it reflects
+//as much as a Lambertian surface. Pair with Renderer::set_outline() for ink lines.
+pub struct ToonMaterial {
+    pub rng: Box<RefCell<dyn RngCore>>,
+    pub color: Color,
+    pub bands: usize,
+}
+
+impl ToonMaterial {
+    fn band(&self, cos: fVec) -> fVec {
+        let bands = self.bands.max(1) as fVec;
+        (cos * bands).round() / bands
+    }
+}
+
+impl Material for ToonMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        if !hit.is_outside(ray) {
+            return (Color::black(), None);
+        }
+        let scatter_dir = hit.normal + rand_on_unit_sphere(self.rng.borrow_mut().deref_mut());
+        let dir = if scatter_dir.is_tiny(0.0001) {
+            hit.normal
+        } else {
+            scatter_dir.unit()
+        };
+        //Cosine sampling, the band replaces the cosine term
+        let cos = hit.normal * dir;
+        if cos <= 0.0 {
+            return (Color::black(), None);
+        }
+        (self.color * (self.band(cos) / cos), Some(Ray::new(hit.intersect, dir)))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        if !hit.is_outside(ray) {
+            return Color::black();
+        }
+        let cos = hit.normal * light_dir;
+        if cos <= 0.0 {
+            return Color::black();
+        }
+        self.color * (self.band(cos) / std::f32::consts::PI)
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        if !hit.is_outside(ray) {
+            return 0.0;
+        }
+        (hit.normal * dir).max(0.0) / std::f32::consts::PI
+    }
+
+    fn albedo(&self, _ray: &Ray, _hit: &HitResult) -> Color {
+        self.color
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        Some(PreviewSurface {
+            diffuse_color: self.color,
+            ..PreviewSurface::default()
+        })
+    }
+}
+
+//Invisible to camera rays when a backdrop is set, only darkening it where the scene casts shadows
+pub struct ShadowCatcher {
+    //Appearance in reflections and for indirect light
+    pub surface: DiffuseMaterial,
+}
+
+impl Material for ShadowCatcher {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        self.surface.bounce(ray, hit)
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        self.surface.eval(ray, hit, light_dir)
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        self.surface.pdf(ray, hit, dir)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        true
+    }
+
+    fn albedo(&self, ray: &Ray, hit: &HitResult) -> Color {
+        self.surface.albedo(ray, hit)
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        self.surface.preview()
+    }
 }
 
-fn rand_on_unit_sphere(rng: &mut (impl RngCore + ?Sized)) -> Vec3 {
+pub(crate) fn rand_on_unit_sphere(rng: &mut (impl RngCore + ?Sized)) -> Vec3 {
     loop {
         let x = Vec3::random(rng, -1.0, 1.0);
         if x*x <= 1.0 {
@@ -95,9 +383,14 @@ fn rand_on_unit_sphere(rng: &mut (impl RngCore + ?Sized)) -> Vec3 {
     }
 }
 
+//Mirror, optionally blurred by fuzziness. Reflectance follows Schlick's Fresnel approximation,
+//brightening towards white at grazing angles.
 pub struct ReflectiveMaterial {
-    pub color: Color,
+    //Base reflectivity at normal incidence
+    pub color: Rc<dyn Texture>,
     pub fuzziness: fVec,
+    //Scales fuzziness across the surface
+    pub fuzziness_map: Option<Rc<dyn Texture>>,
     pub rng: Box<RefCell<dyn RngCore>>,
 }
 
@@ -110,9 +403,10 @@ impl Material for ReflectiveMaterial {
         let unit_dir = ray.direction.unit();
         let reflected_dir = unit_dir.reflect(hit.normal);
 
-        let bounced_dir = if self.fuzziness < 0.01 {
+        let fuzziness = scalar_at_hit(self.fuzziness, &self.fuzziness_map, hit);
+        let bounced_dir = if fuzziness >= 0.01 {
             let rand_dir = rand_on_unit_sphere(self.rng.borrow_mut().deref_mut());
-            let mut fuzzy_dir = reflected_dir + rand_dir * self.fuzziness;
+            let mut fuzzy_dir = reflected_dir + rand_dir * fuzziness;
             if fuzzy_dir * hit.normal <= 0.0 {
                 let scatter_dir = hit.normal + rand_dir;
                 if fuzzy_dir.is_tiny(0.001) {
@@ -126,21 +420,1316 @@ impl Material for ReflectiveMaterial {
             reflected_dir
         };
 
-        (self.color, Some(Ray::new(hit.intersect, bounced_dir)))
+        (
+            schlick_fresnel(-unit_dir * hit.normal, self.color.at_hit(hit)),
+            Some(Ray::new(hit.intersect, bounced_dir)),
+        )
+    }
+
+    //A map can raise fuzziness anywhere unless there is none to scale
+    fn is_specular(&self) -> bool {
+        self.fuzziness == 0.0 || self.fuzziness < 0.01 && self.fuzziness_map.is_none()
+    }
+
+    fn albedo(&self, _ray: &Ray, hit: &HitResult) -> Color {
+        self.color.at_hit(hit)
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        Some(PreviewSurface {
+            diffuse_color: self.color.value(0.5, 0.5, Vec3::origin()),
+            metallic: 1.0,
+            roughness: self.fuzziness.min(1.0),
+            ..PreviewSurface::default()
+        })
     }
 }
 
 pub struct DielectricMaterial {
     pub ior: fVec,
+    //Beer-Lambert absorption per unit of distance traveled inside, black for clear glass
+    pub absorption: Color,
+    //Nested dielectric priority, see Medium
+    pub priority: i32,
     pub rng: Box<RefCell<dyn RngCore>>,
 }
 
+impl DielectricMaterial {
+    //Absorption that leaves color after light traveled distance inside, e.g. the tint seen
+    //through a pane of the given thickness
+    pub fn absorption_for(color: Color, distance: fVec) -> Color {
+        let coefficient = |c: fCol| -c.clamp(1e-6, 1.0).ln() / distance;
+        Color::new(coefficient(color.r), coefficient(color.g), coefficient(color.b))
+    }
+}
+
 impl Material for DielectricMaterial {
     fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        self.bounce_in(ray, hit, 1.0)
+    }
+
+    fn bounce_in(&self, ray: &Ray, hit: &HitResult, outside_ior: fVec) -> (Color, Option<Ray>) {
         let refracted =
             ray.direction
-                .refract(hit.normal, self.ior, self.rng.borrow_mut().deref_mut());
+                .refract(hit.normal, self.ior / outside_ior, self.rng.borrow_mut().deref_mut());
 
         (Color::white(), Some(Ray::new(hit.intersect, refracted)))
     }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+
+    fn medium(&self) -> Option<Medium> {
+        Some(Medium {
+            ior: self.ior,
+            priority: self.priority,
+            absorption: self.absorption,
+        })
+    }
+
+    fn albedo(&self, _ray: &Ray, _hit: &HitResult) -> Color {
+        Color::white()
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        Some(PreviewSurface {
+            diffuse_color: Color::white(),
+            roughness: 0.0,
+            opacity: 0.0,
+            ior: self.ior,
+            ..PreviewSurface::default()
+        })
+    }
+}
+
+//Frosted or ground glass: GGX microfacet reflection and transmission (Walter et al. 2007),
+//sampling microfacet normals and choosing between reflection and refraction by their Fresnel term
+pub struct RoughDielectricMaterial {
+    pub ior: fVec,
+    pub roughness: fVec,
+    //Beer-Lambert absorption per unit of distance traveled inside, black for clear glass
+    pub absorption: Color,
+    //Nested dielectric priority, see Medium
+    pub priority: i32,
+    pub rng: Box<RefCell<dyn RngCore>>,
+}
+
+impl RoughDielectricMaterial {
+    //Normal on the side of view and the IOR ratio transmitted over incident
+    fn orient(view: Vec3, normal: Vec3, ior: fVec) -> (Vec3, fVec) {
+        if view * normal >= 0.0 {
+            (normal, ior)
+        } else {
+            (-normal, 1.0 / ior)
+        }
+    }
+
+    //Half vector of view and light_dir on the side of normal, with the Jacobian of the mapping
+    //from half vectors to light directions
+    fn half_vector(view: Vec3, light_dir: Vec3, normal: Vec3, eta: fVec) -> Option<(Vec3, fVec)> {
+        let reflected = (normal * light_dir) > 0.0;
+        let h = if reflected { view + light_dir } else { view + light_dir * eta };
+        if h.is_tiny(1e-12) {
+            return None;
+        }
+        let h = if h * normal < 0.0 { -h.unit() } else { h.unit() };
+        let (v_dot_h, l_dot_h) = (view * h, light_dir * h);
+        //Backfacing microfacets
+        if v_dot_h <= 0.0 || (l_dot_h > 0.0) != reflected {
+            return None;
+        }
+        let jacobian = if reflected {
+            1.0 / (4.0 * v_dot_h)
+        } else {
+            let denom = v_dot_h + eta * l_dot_h;
+            eta * eta * l_dot_h.abs() / (denom * denom)
+        };
+        Some((h, jacobian))
+    }
+}
+
+impl Material for RoughDielectricMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        self.bounce_in(ray, hit, 1.0)
+    }
+
+    fn bounce_in(&self, ray: &Ray, hit: &HitResult, outside_ior: fVec) -> (Color, Option<Ray>) {
+        let view = -ray.direction.unit();
+        let (normal, eta) = Self::orient(view, hit.normal, self.ior / outside_ior);
+        let alpha = roughness_to_alpha(self.roughness);
+
+        let mut rng = self.rng.borrow_mut();
+        let h = sample_ggx_normal(normal, alpha, rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0));
+        let v_dot_h = view * h;
+        if v_dot_h <= 0.0 {
+            return (Color::black(), None);
+        }
+
+        let dir = if rng.gen_range(0.0..1.0) < dielectric_fresnel(v_dot_h, eta) {
+            (-view).reflect(h)
+        } else {
+            //Snell's law at the microfacet, total internal reflection has Fresnel 1 and never gets here
+            let k = 1.0 - (1.0 - v_dot_h * v_dot_h) / (eta * eta);
+            (h * (v_dot_h / eta - k.max(0.0).sqrt()) - view / eta).unit()
+        };
+        let (n_dot_v, n_dot_l, n_dot_h) = (normal * view, normal * dir, normal * h);
+        if n_dot_v <= 0.0 || (n_dot_l > 0.0) != (dir * h > 0.0) {
+            return (Color::black(), None);
+        }
+
+        //f * |n.l| / pdf with D and F cancelling out
+        let weight = v_dot_h * smith_g(n_dot_l.abs(), n_dot_v, alpha) / (n_dot_v * n_dot_h);
+        (Color::white() * weight, Some(Ray::new(hit.intersect, dir)))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        self.eval_in(ray, hit, light_dir, 1.0)
+    }
+
+    fn eval_in(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3, outside_ior: fVec) -> Color {
+        let view = -ray.direction.unit();
+        let (normal, eta) = Self::orient(view, hit.normal, self.ior / outside_ior);
+        let Some((h, jacobian)) = Self::half_vector(view, light_dir, normal, eta) else {
+            return Color::black();
+        };
+        let alpha = roughness_to_alpha(self.roughness);
+        let (n_dot_v, n_dot_l) = (normal * view, (normal * light_dir).abs());
+        if n_dot_v <= 0.0 {
+            return Color::black();
+        }
+
+        let fresnel = dielectric_fresnel(view * h, eta);
+        let lobe = if normal * light_dir > 0.0 { fresnel } else { 1.0 - fresnel };
+        //D G F / (4 |n.v| |n.l|) for reflection, the Jacobian generalizes it to transmission
+        let f = lobe * ggx_d(normal * h, alpha) * smith_g(n_dot_l, n_dot_v, alpha) * jacobian * (view * h)
+            / (n_dot_v * n_dot_l);
+        Color::white() * (f * n_dot_l)
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        self.pdf_in(ray, hit, dir, 1.0)
+    }
+
+    fn pdf_in(&self, ray: &Ray, hit: &HitResult, dir: Vec3, outside_ior: fVec) -> fVec {
+        let view = -ray.direction.unit();
+        let (normal, eta) = Self::orient(view, hit.normal, self.ior / outside_ior);
+        let Some((h, jacobian)) = Self::half_vector(view, dir, normal, eta) else {
+            return 0.0;
+        };
+        let fresnel = dielectric_fresnel(view * h, eta);
+        let lobe = if normal * dir > 0.0 { fresnel } else { 1.0 - fresnel };
+        let n_dot_h = normal * h;
+        lobe * ggx_d(n_dot_h, roughness_to_alpha(self.roughness)) * n_dot_h * jacobian
+    }
+
+    fn is_specular(&self) -> bool {
+        self.roughness == 0.0
+    }
+
+    fn medium(&self) -> Option<Medium> {
+        Some(Medium {
+            ior: self.ior,
+            priority: self.priority,
+            absorption: self.absorption,
+        })
+    }
+
+    fn albedo(&self, _ray: &Ray, _hit: &HitResult) -> Color {
+        Color::white()
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        Some(PreviewSurface {
+            diffuse_color: Color::white(),
+            roughness: self.roughness,
+            opacity: 0.0,
+            ior: self.ior,
+            ..PreviewSurface::default()
+        })
+    }
+}
+
+//Metallic-roughness model as used by glTF: GGX specular over a Lambertian base,
+//metals have no diffuse part and tint their reflection with the base color
+pub struct PbrMaterial {
+    pub base_color: Color,
+    pub metallic: fVec,
+    pub roughness: fVec,
+    //Grayscale maps scaling metallic and roughness across the surface
+    pub metallic_map: Option<Rc<dyn Texture>>,
+    pub roughness_map: Option<Rc<dyn Texture>>,
+    pub rng: Box<RefCell<dyn RngCore>>,
+}
+
+impl PbrMaterial {
+    //Metallic and roughness at the hit
+    fn params(&self, hit: &HitResult) -> (fVec, fVec) {
+        (
+            scalar_at_hit(self.metallic, &self.metallic_map, hit),
+            scalar_at_hit(self.roughness, &self.roughness_map, hit),
+        )
+    }
+
+    fn f0(&self, metallic: fVec) -> Color {
+        Color::new(0.04, 0.04, 0.04) * (1.0 - metallic) + self.base_color * metallic
+    }
+
+    //Probability of sampling the specular lobe instead of the diffuse one
+    fn specular_probability(metallic: fVec) -> fVec {
+        0.5 + 0.5 * metallic
+    }
+}
+
+impl Material for PbrMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        if !hit.is_outside(ray) {
+            return (Color::black(), None);
+        }
+
+        let view = -ray.direction.unit();
+        let (metallic, roughness) = self.params(hit);
+        let dir = {
+            let mut rng = self.rng.borrow_mut();
+            if rng.gen_range(0.0..1.0) < Self::specular_probability(metallic) {
+                let alpha = roughness_to_alpha(roughness);
+                let h = sample_ggx_normal(hit.normal, alpha, rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0));
+                (-view).reflect(h)
+            } else {
+                let scatter_dir = hit.normal + rand_on_unit_sphere(rng.deref_mut());
+                if scatter_dir.is_tiny(0.0001) {
+                    hit.normal
+                } else {
+                    scatter_dir.unit()
+                }
+            }
+        };
+
+        let pdf = self.pdf(ray, hit, dir);
+        if pdf <= 0.0 {
+            return (Color::black(), None);
+        }
+        (self.eval(ray, hit, dir) * (1.0 / pdf), Some(Ray::new(hit.intersect, dir)))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        let view = -ray.direction.unit();
+        let n_dot_l = hit.normal * light_dir;
+        let n_dot_v = hit.normal * view;
+        if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+            return Color::black();
+        }
+
+        let (metallic, roughness) = self.params(hit);
+        let alpha = roughness_to_alpha(roughness);
+        let h = (view + light_dir).unit();
+        let fresnel = schlick_fresnel(view * h, self.f0(metallic));
+        let specular = fresnel
+            * (ggx_d(hit.normal * h, alpha) * smith_g(n_dot_l, n_dot_v, alpha) / (4.0 * n_dot_l * n_dot_v));
+        let diffuse = (Color::white() - fresnel) * self.base_color * ((1.0 - metallic) / std::f32::consts::PI);
+
+        (specular + diffuse) * n_dot_l
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        let view = -ray.direction.unit();
+        let n_dot_l = hit.normal * dir;
+        if n_dot_l <= 0.0 || !hit.is_outside(ray) {
+            return 0.0;
+        }
+
+        let (metallic, roughness) = self.params(hit);
+        let alpha = roughness_to_alpha(roughness);
+        let h = (view + dir).unit();
+        let p_spec = Self::specular_probability(metallic);
+        p_spec * ggx_reflection_pdf(hit.normal * h, view * h, alpha)
+            + (1.0 - p_spec) * n_dot_l / std::f32::consts::PI
+    }
+
+    fn albedo(&self, _ray: &Ray, _hit: &HitResult) -> Color {
+        self.base_color
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        Some(PreviewSurface {
+            diffuse_color: self.base_color,
+            metallic: self.metallic,
+            roughness: self.roughness,
+            ..PreviewSurface::default()
+        })
+    }
+}
+
+//Brushed metal, roughness_u stretches highlights along the surface tangent and
+//roughness_v along the bitangent
+pub struct AnisotropicMaterial {
+    pub color: Color,
+    pub roughness_u: fVec,
+    pub roughness_v: fVec,
+    pub rng: Box<RefCell<dyn RngCore>>,
+}
+
+impl AnisotropicMaterial {
+    fn alphas(&self) -> (fVec, fVec) {
+        (roughness_to_alpha(self.roughness_u), roughness_to_alpha(self.roughness_v))
+    }
+
+    //World space direction in the local (tangent, bitangent, normal) frame
+    fn to_local(hit: &HitResult, dir: Vec3) -> Vec3 {
+        let (t, b) = hit.tangent_frame();
+        Vec3::new(dir * t, dir * b, dir * hit.normal)
+    }
+}
+
+impl Material for AnisotropicMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        if !hit.is_outside(ray) {
+            return (Color::black(), None);
+        }
+
+        let (alpha_x, alpha_y) = self.alphas();
+        let h = {
+            let mut rng = self.rng.borrow_mut();
+            let local = sample_ggx_aniso_normal(alpha_x, alpha_y, rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0));
+            let (t, b) = hit.tangent_frame();
+            t * local.x + b * local.y + hit.normal * local.z
+        };
+        let dir = ray.direction.unit().reflect(h);
+
+        let pdf = self.pdf(ray, hit, dir);
+        if pdf <= 0.0 {
+            return (Color::black(), None);
+        }
+        (self.eval(ray, hit, dir) * (1.0 / pdf), Some(Ray::new(hit.intersect, dir)))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        let view = Self::to_local(hit, -ray.direction.unit());
+        let light = Self::to_local(hit, light_dir);
+        if light.z <= 0.0 || view.z <= 0.0 {
+            return Color::black();
+        }
+
+        let (alpha_x, alpha_y) = self.alphas();
+        let h = (view + light).unit();
+        let fresnel = schlick_fresnel(view * h, self.color);
+        let g = smith_g1_aniso(view, alpha_x, alpha_y) * smith_g1_aniso(light, alpha_x, alpha_y);
+        fresnel * (ggx_d_aniso(h, alpha_x, alpha_y) * g / (4.0 * view.z))
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        let view = Self::to_local(hit, -ray.direction.unit());
+        let light = Self::to_local(hit, dir);
+        if light.z <= 0.0 || !hit.is_outside(ray) {
+            return 0.0;
+        }
+
+        let (alpha_x, alpha_y) = self.alphas();
+        let h = (view + light).unit();
+        let v_dot_h = view * h;
+        if v_dot_h <= 0.0 {
+            return 0.0;
+        }
+        ggx_d_aniso(h, alpha_x, alpha_y) * h.z / (4.0 * v_dot_h)
+    }
+
+    fn albedo(&self, _ray: &Ray, _hit: &HitResult) -> Color {
+        self.color
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        Some(PreviewSurface {
+            diffuse_color: self.color,
+            metallic: 1.0,
+            roughness: (self.roughness_u + self.roughness_v) / 2.0,
+            ..PreviewSurface::default()
+        })
+    }
+}
+
+//Metal with measured complex index of refraction, roughness 0 is a perfect mirror
+pub struct ConductorMaterial {
+    pub eta: Color,
+    pub k: Color,
+    pub roughness: fVec,
+    pub rng: Box<RefCell<dyn RngCore>>,
+}
+
+impl ConductorMaterial {
+    //RGB fits of measured spectral data
+    pub fn gold(roughness: fVec, rng: Box<RefCell<dyn RngCore>>) -> Self {
+        Self {
+            eta: Color::new(0.143119, 0.374957, 1.44248),
+            k: Color::new(3.98316, 2.38572, 1.60322),
+            roughness,
+            rng,
+        }
+    }
+
+    pub fn copper(roughness: fVec, rng: Box<RefCell<dyn RngCore>>) -> Self {
+        Self {
+            eta: Color::new(0.200438, 0.924033, 1.10221),
+            k: Color::new(3.91295, 2.45285, 2.14219),
+            roughness,
+            rng,
+        }
+    }
+
+    pub fn aluminum(roughness: fVec, rng: Box<RefCell<dyn RngCore>>) -> Self {
+        Self {
+            eta: Color::new(1.65746, 0.880369, 0.521229),
+            k: Color::new(9.22387, 6.26952, 4.837),
+            roughness,
+            rng,
+        }
+    }
+
+    pub fn silver(roughness: fVec, rng: Box<RefCell<dyn RngCore>>) -> Self {
+        Self {
+            eta: Color::new(0.155265, 0.116723, 0.138342),
+            k: Color::new(4.82835, 3.12225, 2.14696),
+            roughness,
+            rng,
+        }
+    }
+}
+
+impl Material for ConductorMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        if !hit.is_outside(ray) {
+            return (Color::black(), None);
+        }
+
+        let unit_dir = ray.direction.unit();
+        if self.is_specular() {
+            let fresnel = conductor_fresnel(-(unit_dir * hit.normal), self.eta, self.k);
+            return (fresnel, Some(Ray::new(hit.intersect, unit_dir.reflect(hit.normal))));
+        }
+
+        let h = {
+            let mut rng = self.rng.borrow_mut();
+            let alpha = roughness_to_alpha(self.roughness);
+            sample_ggx_normal(hit.normal, alpha, rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0))
+        };
+        let dir = unit_dir.reflect(h);
+
+        let pdf = self.pdf(ray, hit, dir);
+        if pdf <= 0.0 {
+            return (Color::black(), None);
+        }
+        (self.eval(ray, hit, dir) * (1.0 / pdf), Some(Ray::new(hit.intersect, dir)))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        let view = -ray.direction.unit();
+        let n_dot_l = hit.normal * light_dir;
+        let n_dot_v = hit.normal * view;
+        if self.is_specular() || n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+            return Color::black();
+        }
+
+        let alpha = roughness_to_alpha(self.roughness);
+        let h = (view + light_dir).unit();
+        let fresnel = conductor_fresnel(view * h, self.eta, self.k);
+        let single = fresnel * (ggx_d(hit.normal * h, alpha) * smith_g(n_dot_l, n_dot_v, alpha) / (4.0 * n_dot_v));
+        let f_avg = average_fresnel(conductor_fresnel(1.0, self.eta, self.k));
+        single + ggx_multiscatter(n_dot_l, n_dot_v, self.roughness, f_avg) * n_dot_l
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        if self.is_specular() || hit.normal * dir <= 0.0 || !hit.is_outside(ray) {
+            return 0.0;
+        }
+        let view = -ray.direction.unit();
+        let alpha = roughness_to_alpha(self.roughness);
+        let h = (view + dir).unit();
+        ggx_reflection_pdf(hit.normal * h, view * h, alpha)
+    }
+
+    fn is_specular(&self) -> bool {
+        self.roughness == 0.0
+    }
+
+    fn albedo(&self, _ray: &Ray, _hit: &HitResult) -> Color {
+        conductor_fresnel(1.0, self.eta, self.k)
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        Some(PreviewSurface {
+            diffuse_color: conductor_fresnel(1.0, self.eta, self.k),
+            metallic: 1.0,
+            roughness: self.roughness,
+            ..PreviewSurface::default()
+        })
+    }
+}
+
+//Dielectric coat over a base material (car paint, lacquered wood). Light reflected by the coat
+//never reaches the base, which only receives what the coat transmits on the way in and out.
+pub struct ClearCoatMaterial {
+    pub base: Box<dyn Material>,
+    pub ior: fVec,
+    //Roughness of the coat, 0 is a perfect mirror
+    pub roughness: fVec,
+    pub rng: Box<RefCell<dyn RngCore>>,
+}
+
+impl ClearCoatMaterial {
+    fn fresnel(&self, cos: fVec) -> fVec {
+        let f0 = ((self.ior - 1.0) / (self.ior + 1.0)).powi(2);
+        f0 + (1.0 - f0) * (1.0 - cos.clamp(0.0, 1.0)).powi(5)
+    }
+
+    //Coat reflection without the base, including the cosine term
+    fn coat_eval(&self, view: Vec3, normal: Vec3, light_dir: Vec3) -> fVec {
+        let n_dot_l = normal * light_dir;
+        let n_dot_v = normal * view;
+        if self.roughness == 0.0 || n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+            return 0.0;
+        }
+        let alpha = roughness_to_alpha(self.roughness);
+        let h = (view + light_dir).unit();
+        self.fresnel(view * h) * ggx_d(normal * h, alpha) * smith_g(n_dot_l, n_dot_v, alpha) / (4.0 * n_dot_v)
+    }
+
+    fn coat_pdf(&self, view: Vec3, normal: Vec3, dir: Vec3) -> fVec {
+        if self.roughness == 0.0 || normal * dir <= 0.0 {
+            return 0.0;
+        }
+        let h = (view + dir).unit();
+        ggx_reflection_pdf(normal * h, view * h, roughness_to_alpha(self.roughness))
+    }
+
+    //Fraction of light passing the coat towards the base and back out
+    fn transmission(&self, view: Vec3, normal: Vec3, dir: Vec3) -> fVec {
+        (1.0 - self.fresnel(normal * view)) * (1.0 - self.fresnel((normal * dir).abs()))
+    }
+}
+
+impl Material for ClearCoatMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        if !hit.is_outside(ray) {
+            return self.base.bounce(ray, hit);
+        }
+
+        let view = -ray.direction.unit();
+        //Choosing the coat with its reflectance makes the smooth coat weight exactly 1
+        let coat_probability = self.fresnel(hit.normal * view);
+        let (choose_coat, u1, u2) = {
+            let mut rng = self.rng.borrow_mut();
+            (
+                rng.gen_range(0.0..1.0) < coat_probability,
+                rng.gen_range(0.0..1.0),
+                rng.gen_range(0.0..1.0),
+            )
+        };
+
+        if choose_coat {
+            if self.roughness == 0.0 {
+                return (Color::white(), Some(Ray::new(hit.intersect, (-view).reflect(hit.normal))));
+            }
+            let h = sample_ggx_normal(hit.normal, roughness_to_alpha(self.roughness), u1, u2);
+            let dir = (-view).reflect(h);
+            let pdf = self.coat_pdf(view, hit.normal, dir);
+            if pdf <= 0.0 {
+                return (Color::black(), None);
+            }
+            let weight = self.coat_eval(view, hit.normal, dir) / (pdf * coat_probability);
+            return (Color::white() * weight, Some(Ray::new(hit.intersect, dir)));
+        }
+
+        match self.base.bounce(ray, hit) {
+            (col, Some(b)) => {
+                let weight = self.transmission(view, hit.normal, b.direction.unit()) / (1.0 - coat_probability);
+                (col * weight, Some(b))
+            }
+            (col, None) => (col, None),
+        }
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        if !hit.is_outside(ray) {
+            return self.base.eval(ray, hit, light_dir);
+        }
+        let view = -ray.direction.unit();
+        let coat = self.coat_eval(view, hit.normal, light_dir);
+        Color::white() * coat + self.base.eval(ray, hit, light_dir) * self.transmission(view, hit.normal, light_dir)
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        if !hit.is_outside(ray) {
+            return self.base.pdf(ray, hit, dir);
+        }
+        let view = -ray.direction.unit();
+        let coat_probability = self.fresnel(hit.normal * view);
+        coat_probability * self.coat_pdf(view, hit.normal, dir) + (1.0 - coat_probability) * self.base.pdf(ray, hit, dir)
+    }
+
+    fn is_specular(&self) -> bool {
+        self.roughness == 0.0 && self.base.is_specular()
+    }
+
+    fn albedo(&self, ray: &Ray, hit: &HitResult) -> Color {
+        self.base.albedo(ray, hit)
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        self.base.preview()
+    }
+}
+
+//Metallic paint: a diffuse pigment with small mirror-like flakes embedded in it. Space is split
+//into cubic cells of flake_size, a cell holds a flake with probability flake_density and the
+//flake normals scatter around the surface normal with a GGX distribution of flake_spread. The
+//glitter is deterministic per position, so it stays put across samples and frames.
+pub struct MetallicFlakeMaterial {
+    pub base_color: Color,
+    pub flake_color: Color,
+    //Edge length of a flake cell in world units
+    pub flake_size: fVec,
+    //Fraction of cells holding a flake, in [0, 1]
+    pub flake_density: fVec,
+    //Roughness controlling how far flakes tilt away from the surface normal
+    pub flake_spread: fVec,
+    //Roughness of a single flake, small for sparkles
+    pub flake_roughness: fVec,
+    pub rng: Box<RefCell<dyn RngCore>>,
+}
+
+//Uniform numbers in [0, 1) for a flake cell
+fn flake_random(cell: [i64; 3]) -> [fVec; 3] {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for c in cell {
+        h = (h ^ c as u64).wrapping_mul(0x100000001b3);
+    }
+    let mut next = || {
+        h = h.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = h;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        (z >> 40) as fVec / (1u64 << 24) as fVec
+    };
+    [next(), next(), next()]
+}
+
+impl MetallicFlakeMaterial {
+    //Car paint: the flakes under a smooth lacquer of the given roughness
+    pub fn car_paint(self, coat_roughness: fVec, rng: Box<RefCell<dyn RngCore>>) -> ClearCoatMaterial {
+        ClearCoatMaterial {
+            base: Box::new(self),
+            ior: 1.5,
+            roughness: coat_roughness,
+            rng,
+        }
+    }
+
+    //Normal of the flake at the hit, None where the pigment is visible or the flake faces away
+    fn flake_normal(&self, hit: &HitResult, view: Vec3) -> Option<Vec3> {
+        let p = hit.intersect / self.flake_size.max(1e-6);
+        let [presence, u1, u2] = flake_random([p.x.floor() as i64, p.y.floor() as i64, p.z.floor() as i64]);
+        if presence >= self.flake_density {
+            return None;
+        }
+        let normal = sample_ggx_normal(hit.normal, roughness_to_alpha(self.flake_spread), u1, u2);
+        if normal * view <= 0.0 {
+            return None;
+        }
+        Some(normal)
+    }
+
+    //Flake reflection, including the cosine term
+    fn flake_eval(&self, view: Vec3, flake: Vec3, light_dir: Vec3) -> Color {
+        let n_dot_l = flake * light_dir;
+        let n_dot_v = flake * view;
+        if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+            return Color::black();
+        }
+        let alpha = roughness_to_alpha(self.flake_roughness);
+        let h = (view + light_dir).unit();
+        schlick_fresnel(view * h, self.flake_color)
+            * (ggx_d(flake * h, alpha) * smith_g(n_dot_l, n_dot_v, alpha) / (4.0 * n_dot_v))
+    }
+}
+
+impl Material for MetallicFlakeMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        if !hit.is_outside(ray) {
+            return (Color::black(), None);
+        }
+
+        let view = -ray.direction.unit();
+        let dir = {
+            let mut rng = self.rng.borrow_mut();
+            match self.flake_normal(hit, view) {
+                Some(flake) => {
+                    let alpha = roughness_to_alpha(self.flake_roughness);
+                    let h = sample_ggx_normal(flake, alpha, rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0));
+                    (-view).reflect(h)
+                }
+                None => {
+                    let scatter_dir = hit.normal + rand_on_unit_sphere(rng.deref_mut());
+                    if scatter_dir.is_tiny(0.0001) {
+                        hit.normal
+                    } else {
+                        scatter_dir.unit()
+                    }
+                }
+            }
+        };
+
+        let pdf = self.pdf(ray, hit, dir);
+        if pdf <= 0.0 {
+            return (Color::black(), None);
+        }
+        (self.eval(ray, hit, dir) * (1.0 / pdf), Some(Ray::new(hit.intersect, dir)))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        let view = -ray.direction.unit();
+        let n_dot_l = hit.normal * light_dir;
+        if n_dot_l <= 0.0 || !hit.is_outside(ray) {
+            return Color::black();
+        }
+        match self.flake_normal(hit, view) {
+            Some(flake) => self.flake_eval(view, flake, light_dir),
+            None => self.base_color * (n_dot_l / std::f32::consts::PI),
+        }
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        let view = -ray.direction.unit();
+        let n_dot_l = hit.normal * dir;
+        if n_dot_l <= 0.0 || !hit.is_outside(ray) {
+            return 0.0;
+        }
+        match self.flake_normal(hit, view) {
+            Some(flake) => {
+                if flake * dir <= 0.0 {
+                    return 0.0;
+                }
+                let h = (view + dir).unit();
+                ggx_reflection_pdf(flake * h, view * h, roughness_to_alpha(self.flake_roughness))
+            }
+            None => n_dot_l / std::f32::consts::PI,
+        }
+    }
+
+    fn albedo(&self, ray: &Ray, hit: &HitResult) -> Color {
+        match self.flake_normal(hit, -ray.direction.unit()) {
+            Some(_) => self.flake_color,
+            None => self.base_color,
+        }
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        let coverage = self.flake_density.clamp(0.0, 1.0);
+        Some(PreviewSurface {
+            diffuse_color: self.base_color * (1.0 - coverage) + self.flake_color * coverage,
+            metallic: coverage,
+            roughness: self.flake_spread,
+            ..PreviewSurface::default()
+        })
+    }
+}
+
+//Uber material with the parameter set of Blender's Principled BSDF: Lambertian diffuse with a
+//velvet sheen, GGX specular, a GGX clear coat on top and smooth glass transmission
+pub struct PrincipledMaterial {
+    pub base_color: Color,
+    pub metallic: fVec,
+    pub roughness: fVec,
+    //Dielectric reflectance, 0.5 is 4% at normal incidence
+    pub specular: fVec,
+    //Weight, tint and roughness of the sheen lobe, see charlie_d
+    pub sheen: fVec,
+    pub sheen_tint: Color,
+    pub sheen_roughness: fVec,
+    pub clearcoat: fVec,
+    pub clearcoat_roughness: fVec,
+    pub transmission: fVec,
+    pub ior: fVec,
+    //Medium behind transmissive surfaces, as for DielectricMaterial
+    pub absorption: Color,
+    pub priority: i32,
+    //Grayscale maps scaling metallic and roughness across the surface
+    pub metallic_map: Option<Rc<dyn Texture>>,
+    pub roughness_map: Option<Rc<dyn Texture>>,
+    pub rng: Box<RefCell<dyn RngCore>>,
+}
+
+impl PrincipledMaterial {
+    //Blender's defaults
+    pub fn new(base_color: Color, rng: Box<RefCell<dyn RngCore>>) -> Self {
+        Self {
+            base_color,
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            sheen: 0.0,
+            sheen_tint: Color::white(),
+            sheen_roughness: 0.5,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.03,
+            transmission: 0.0,
+            ior: 1.45,
+            absorption: Color::black(),
+            priority: 0,
+            metallic_map: None,
+            roughness_map: None,
+            rng,
+        }
+    }
+
+    //Metallic and roughness at the hit
+    fn params(&self, hit: &HitResult) -> (fVec, fVec) {
+        (
+            scalar_at_hit(self.metallic, &self.metallic_map, hit),
+            scalar_at_hit(self.roughness, &self.roughness_map, hit),
+        )
+    }
+
+    fn f0(&self, metallic: fVec) -> Color {
+        let dielectric = 0.08 * self.specular;
+        Color::new(dielectric, dielectric, dielectric) * (1.0 - metallic) + self.base_color * metallic
+    }
+
+    //Selection probabilities of the diffuse, specular, clear coat and transmission lobes
+    fn lobe_probabilities(&self, metallic: fVec) -> [fVec; 4] {
+        let weights = [
+            (1.0 - metallic) * (1.0 - self.transmission),
+            1.0,
+            0.25 * self.clearcoat,
+            (1.0 - metallic) * self.transmission,
+        ];
+        let sum: fVec = weights.iter().sum();
+        weights.map(|w| w / sum)
+    }
+
+    //Everything except the transmission, including the cosine term
+    fn eval_reflection(&self, view: Vec3, normal: Vec3, light_dir: Vec3, (metallic, roughness): (fVec, fVec)) -> Color {
+        let n_dot_l = normal * light_dir;
+        let n_dot_v = normal * view;
+        if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+            return Color::black();
+        }
+        let h = (view + light_dir).unit();
+
+        let alpha = roughness_to_alpha(roughness);
+        let fresnel = schlick_fresnel(view * h, self.f0(metallic));
+        let specular = fresnel * (ggx_d(normal * h, alpha) * smith_g(n_dot_l, n_dot_v, alpha) / (4.0 * n_dot_l * n_dot_v));
+
+        let dielectric = (1.0 - metallic) * (1.0 - self.transmission);
+        let diffuse = self.base_color * (dielectric / std::f32::consts::PI);
+        let sheen = self.sheen_tint
+            * (dielectric * self.sheen * charlie_d(normal * h, self.sheen_roughness) * sheen_visibility(n_dot_l, n_dot_v));
+
+        let cc_alpha = roughness_to_alpha(self.clearcoat_roughness);
+        let cc_fresnel = 0.04 + 0.96 * (1.0 - (view * h).clamp(0.0, 1.0)).powi(5);
+        let clearcoat = 0.25 * self.clearcoat * cc_fresnel * ggx_d(normal * h, cc_alpha) * smith_g(n_dot_l, n_dot_v, cc_alpha)
+            / (4.0 * n_dot_l * n_dot_v);
+
+        (specular + diffuse + sheen + Color::white() * clearcoat) * n_dot_l
+    }
+
+    //Density of the non-delta lobes, already weighted by their selection probability
+    fn pdf_reflection(&self, view: Vec3, normal: Vec3, dir: Vec3, (metallic, roughness): (fVec, fVec)) -> fVec {
+        let n_dot_l = normal * dir;
+        if n_dot_l <= 0.0 {
+            return 0.0;
+        }
+        let [p_diffuse, p_specular, p_clearcoat, _] = self.lobe_probabilities(metallic);
+        let h = (view + dir).unit();
+        p_diffuse * n_dot_l / std::f32::consts::PI
+            + p_specular * ggx_reflection_pdf(normal * h, view * h, roughness_to_alpha(roughness))
+            + p_clearcoat * ggx_reflection_pdf(normal * h, view * h, roughness_to_alpha(self.clearcoat_roughness))
+    }
+}
+
+impl Material for PrincipledMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        self.bounce_in(ray, hit, 1.0)
+    }
+
+    fn bounce_in(&self, ray: &Ray, hit: &HitResult, outside_ior: fVec) -> (Color, Option<Ray>) {
+        let params = self.params(hit);
+        let (metallic, roughness) = params;
+        let [p_diffuse, p_specular, _, p_transmission] = self.lobe_probabilities(metallic);
+        let mut rng = self.rng.borrow_mut();
+
+        //Only transmitted light travels inside
+        if !hit.is_outside(ray) {
+            if p_transmission <= 0.0 {
+                return (Color::black(), None);
+            }
+            let dir = ray.direction.refract(hit.normal, self.ior / outside_ior, rng.deref_mut());
+            return (self.base_color, Some(Ray::new(hit.intersect, dir)));
+        }
+
+        let u: fVec = rng.gen_range(0.0..1.0);
+        if u >= 1.0 - p_transmission {
+            let dir = ray.direction.refract(hit.normal, self.ior / outside_ior, rng.deref_mut());
+            let weight = (1.0 - metallic) * self.transmission / p_transmission;
+            return (self.base_color * weight, Some(Ray::new(hit.intersect, dir)));
+        }
+
+        let view = -ray.direction.unit();
+        let dir = if u < p_diffuse {
+            let scatter_dir = hit.normal + rand_on_unit_sphere(rng.deref_mut());
+            if scatter_dir.is_tiny(0.0001) {
+                hit.normal
+            } else {
+                scatter_dir.unit()
+            }
+        } else {
+            let lobe_roughness = if u < p_diffuse + p_specular {
+                roughness
+            } else {
+                self.clearcoat_roughness
+            };
+            let h = sample_ggx_normal(hit.normal, roughness_to_alpha(lobe_roughness), rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0));
+            (-view).reflect(h)
+        };
+        drop(rng);
+
+        let pdf = self.pdf_reflection(view, hit.normal, dir, params);
+        if pdf <= 0.0 {
+            return (Color::black(), None);
+        }
+        (self.eval_reflection(view, hit.normal, dir, params) * (1.0 / pdf), Some(Ray::new(hit.intersect, dir)))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        if !hit.is_outside(ray) {
+            return Color::black();
+        }
+        self.eval_reflection(-ray.direction.unit(), hit.normal, light_dir, self.params(hit))
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        if !hit.is_outside(ray) {
+            return 0.0;
+        }
+        self.pdf_reflection(-ray.direction.unit(), hit.normal, dir, self.params(hit))
+    }
+
+    //Opaque surfaces have no inside to enter
+    fn medium(&self) -> Option<Medium> {
+        (self.transmission > 0.0).then_some(Medium {
+            ior: self.ior,
+            priority: self.priority,
+            absorption: self.absorption,
+        })
+    }
+
+    fn albedo(&self, _ray: &Ray, _hit: &HitResult) -> Color {
+        self.base_color
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        Some(PreviewSurface {
+            diffuse_color: self.base_color,
+            metallic: self.metallic,
+            roughness: self.roughness,
+            opacity: 1.0 - self.transmission,
+            ior: self.ior,
+        })
+    }
+}
+
+//Blend of two materials, each bounce picks second with probability weight(hit) and first otherwise.
+//A weight depending on the hit point acts as a mask, e.g. rust patches on metal.
+pub struct MixMaterial {
+    pub first: Box<dyn Material>,
+    pub second: Box<dyn Material>,
+    pub weight: Box<dyn Fn(&HitResult) -> fVec>,
+    pub rng: Box<RefCell<dyn RngCore>>,
+}
+
+impl MixMaterial {
+    pub fn constant(first: Box<dyn Material>, second: Box<dyn Material>, weight: fVec, rng: Box<RefCell<dyn RngCore>>) -> Self {
+        Self {
+            first,
+            second,
+            weight: Box::new(move |_| weight),
+            rng,
+        }
+    }
+
+    fn weight(&self, hit: &HitResult) -> fVec {
+        (self.weight)(hit).clamp(0.0, 1.0)
+    }
+}
+
+impl Material for MixMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        let u: fVec = self.rng.borrow_mut().gen_range(0.0..1.0);
+        if u < self.weight(hit) {
+            self.second.bounce(ray, hit)
+        } else {
+            self.first.bounce(ray, hit)
+        }
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        let w = self.weight(hit);
+        self.first.eval(ray, hit, light_dir) * (1.0 - w) + self.second.eval(ray, hit, light_dir) * w
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        let w = self.weight(hit);
+        self.first.pdf(ray, hit, dir) * (1.0 - w) + self.second.pdf(ray, hit, dir) * w
+    }
+
+    fn albedo(&self, ray: &Ray, hit: &HitResult) -> Color {
+        let w = self.weight(hit);
+        self.first.albedo(ray, hit) * (1.0 - w) + self.second.albedo(ray, hit) * w
+    }
+
+    fn is_specular(&self) -> bool {
+        self.first.is_specular() && self.second.is_specular()
+    }
+
+    fn opacity(&self, hit: &HitResult) -> fVec {
+        let w = self.weight(hit);
+        self.first.opacity(hit) * (1.0 - w) + self.second.opacity(hit) * w
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        let (a, b) = (self.first.preview()?, self.second.preview()?);
+        //Average weight is unknown for masks, blend evenly
+        let mix = |x: fVec, y: fVec| (x + y) / 2.0;
+        Some(PreviewSurface {
+            diffuse_color: (a.diffuse_color + b.diffuse_color) * 0.5,
+            metallic: mix(a.metallic, b.metallic),
+            roughness: mix(a.roughness, b.roughness),
+            opacity: mix(a.opacity, b.opacity),
+            ior: mix(a.ior, b.ior),
+        })
+    }
+}
+
+//Fakes small surface relief by tilting the shading normal along the slope of a height texture
+//(its luminance), without moving any geometry. Cheaper than a normal map to author.
+pub struct BumpMaterial {
+    pub base: Box<dyn Material>,
+    pub height: Rc<dyn Texture>,
+    //Height difference per unit of u and v that tilts the normal by 45 degrees
+    pub strength: fVec,
+}
+
+impl BumpMaterial {
+    //Finite difference step in uv space
+    const DELTA: fVec = 1.0 / 1024.0;
+
+    fn bumped(&self, hit: &HitResult) -> HitResult {
+        let (u, v) = hit.uv;
+        let height = |u: fVec, v: fVec| self.height.value(u, v, hit.intersect).luminance();
+        let h = height(u, v);
+        let dh_du = (height(u + Self::DELTA, v) - h) / Self::DELTA;
+        let dh_dv = (height(u, v + Self::DELTA) - h) / Self::DELTA;
+
+        //tangent x normal points towards increasing v
+        let (tangent, _) = hit.tangent_frame();
+        let bitangent = tangent.cross(hit.normal);
+        let normal = hit.normal - (tangent * dh_du + bitangent * dh_dv) * self.strength;
+        if normal.is_tiny(0.0001) {
+            return *hit;
+        }
+        HitResult {
+            normal: normal.unit(),
+            ..*hit
+        }
+    }
+}
+
+impl Material for BumpMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        self.base.bounce(ray, &self.bumped(hit))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        self.base.eval(ray, &self.bumped(hit), light_dir)
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        self.base.pdf(ray, &self.bumped(hit), dir)
+    }
+
+    fn albedo(&self, ray: &Ray, hit: &HitResult) -> Color {
+        self.base.albedo(ray, &self.bumped(hit))
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        self.base.is_shadow_catcher()
+    }
+
+    fn is_specular(&self) -> bool {
+        self.base.is_specular()
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        self.base.preview()
+    }
+
+    fn opacity(&self, hit: &HitResult) -> fVec {
+        self.base.opacity(hit)
+    }
+}
+
+//Cuts holes into the base material where the luminance of the opacity texture is below 1, e.g.
+//leaves or chain link fences from a single quad. Rays pass through with probability 1 - opacity.
+pub struct CutoutMaterial {
+    pub base: Box<dyn Material>,
+    pub opacity: Rc<dyn Texture>,
+}
+
+impl Material for CutoutMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        self.base.bounce(ray, hit)
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        self.base.eval(ray, hit, light_dir)
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        self.base.pdf(ray, hit, dir)
+    }
+
+    fn albedo(&self, ray: &Ray, hit: &HitResult) -> Color {
+        self.base.albedo(ray, hit)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        self.base.is_shadow_catcher()
+    }
+
+    fn is_specular(&self) -> bool {
+        self.base.is_specular()
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        Some(PreviewSurface {
+            opacity: self.opacity.value(0.5, 0.5, Vec3::origin()).luminance().clamp(0.0, 1.0),
+            ..self.base.preview()?
+        })
+    }
+
+    fn opacity(&self, hit: &HitResult) -> fVec {
+        self.opacity.value(hit.uv.0, hit.uv.1, hit.intersect).luminance().clamp(0.0, 1.0) * self.base.opacity(hit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reflector(fuzziness: fVec, fuzziness_map: Option<Rc<dyn Texture>>) -> ReflectiveMaterial {
+        ReflectiveMaterial {
+            color: Rc::new(Color::white()),
+            fuzziness,
+            fuzziness_map,
+            rng: Box::new(RefCell::new(SmallRng::seed_from_u64(3))),
+        }
+    }
+
+    //Only a mirror keeps every bounce on the reflected direction, fuzziness or its map spread them
+    #[test]
+    fn fuzzy_reflector_scatters() {
+        let ray = Ray::new(Vec3::new(-1.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0));
+        let hit = HitResult {
+            intersect: Vec3::origin(),
+            normal: Vec3::unit_y(),
+            tangent: Vec3::new(1.0, 0.0, 0.0),
+            uv: (0.5, 0.5),
+            uv_width: 0.0,
+            face: 0,
+            at: 1.0,
+        };
+        let reflected = Vec3::new(1.0, 1.0, 0.0).unit();
+        let spread = |material: &ReflectiveMaterial| {
+            (0..64)
+                .map(|_| {
+                    let bounced = material.bounce(&ray, &hit).1.unwrap();
+                    1.0 - bounced.direction.unit() * reflected
+                })
+                .fold(0.0, fVec::max)
+        };
+
+        let mirror = reflector(0.0, None);
+        assert!(mirror.is_specular());
+        assert!(spread(&mirror) < 1e-6);
+        let fuzzy = reflector(0.3, None);
+        assert!(!fuzzy.is_specular());
+        assert!(spread(&fuzzy) > 1e-3);
+        let mapped = reflector(0.005, Some(Rc::new(60.0)));
+        assert!(!mapped.is_specular());
+        assert!(spread(&mapped) > 1e-3);
+    }
+
+    //Inside water, light sampling must see the lobes bounce_in() samples: its weight is eval / pdf
+    #[test]
+    fn rough_dielectric_agrees_in_media() {
+        let glass = RoughDielectricMaterial {
+            ior: 1.5,
+            roughness: 0.3,
+            absorption: Color::black(),
+            priority: 0,
+            rng: Box::new(RefCell::new(SmallRng::seed_from_u64(5))),
+        };
+        let ray = Ray::new(Vec3::new(-1.0, 1.0, 0.3), Vec3::new(1.0, -1.0, -0.3));
+        let hit = HitResult {
+            intersect: Vec3::origin(),
+            normal: Vec3::unit_y(),
+            tangent: Vec3::new(1.0, 0.0, 0.0),
+            uv: (0.5, 0.5),
+            uv_width: 0.0,
+            face: 0,
+            at: 1.0,
+        };
+        let mut checked = 0;
+        for _ in 0..64 {
+            let (weight, Some(bounced)) = glass.bounce_in(&ray, &hit, 1.33) else {
+                continue;
+            };
+            let dir = bounced.direction.unit();
+            let pdf = glass.pdf_in(&ray, &hit, dir, 1.33);
+            if pdf < 1e-3 {
+                continue;
+            }
+            let expected = glass.eval_in(&ray, &hit, dir, 1.33).r / pdf;
+            assert!((weight.r - expected).abs() < 1e-3 * expected.max(1.0), "{} != {}", weight.r, expected);
+            checked += 1;
+        }
+        assert!(checked > 32);
+    }
+
+    //Transmissive principled surfaces are media, a matching IOR on both sides doesn't bend rays
+    #[test]
+    fn transmissive_principled_is_medium() {
+        let mut material = PrincipledMaterial::new(Color::white(), Box::new(RefCell::new(SmallRng::seed_from_u64(7))));
+        assert!(material.medium().is_none());
+        material.transmission = 1.0;
+        material.ior = 1.33;
+        assert_eq!(material.medium().map(|m| m.ior), Some(1.33));
+
+        let ray = Ray::new(Vec3::new(-1.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0));
+        let hit = HitResult {
+            intersect: Vec3::origin(),
+            normal: Vec3::unit_y(),
+            tangent: Vec3::new(1.0, 0.0, 0.0),
+            uv: (0.5, 0.5),
+            uv_width: 0.0,
+            face: 0,
+            at: 1.0,
+        };
+        let transmitted: Vec<Vec3> = (0..64)
+            .filter_map(|_| material.bounce_in(&ray, &hit, 1.33).1)
+            .map(|bounced| bounced.direction.unit())
+            .filter(|dir| *dir * hit.normal < 0.0)
+            .collect();
+        assert!(!transmitted.is_empty());
+        assert!(transmitted.iter().all(|dir| *dir * ray.direction.unit() > 1.0 - 1e-5));
+    }
 }
@@ -0,0 +1,591 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::bvh::*;
+use crate::geom::*;
+use crate::linalg::*;
+use crate::texture::*;
+use crate::tracer::*;
+
+//Indexed triangle mesh with per-vertex normals
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    pub vertices: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    //Per-vertex texture coordinates, empty to use the barycentric coordinates of each triangle
+    pub uvs: Vec<(fVec, fVec)>,
+    pub triangles: Vec<[usize; 3]>,
+    //Material slot per triangle as OBJ usemtl groups produce, empty to use slot 0 throughout
+    pub materials: Vec<usize>,
+}
+
+impl Mesh {
+    //Unit sphere around the origin, made by splitting each triangle of an icosahedron
+    //into four subdivisions times
+    pub fn icosphere(subdivisions: usize) -> Mesh {
+        let t = (1.0 + (5.0 as fVec).sqrt()) / 2.0;
+        let mut vertices: Vec<Vec3> = [
+            (-1.0, t, 0.0),
+            (1.0, t, 0.0),
+            (-1.0, -t, 0.0),
+            (1.0, -t, 0.0),
+            (0.0, -1.0, t),
+            (0.0, 1.0, t),
+            (0.0, -1.0, -t),
+            (0.0, 1.0, -t),
+            (t, 0.0, -1.0),
+            (t, 0.0, 1.0),
+            (-t, 0.0, -1.0),
+            (-t, 0.0, 1.0),
+        ]
+        .iter()
+        .map(|&(x, y, z)| Vec3::new(x, y, z).unit())
+        .collect();
+
+        let mut triangles = vec![
+            [0, 11, 5],
+            [0, 5, 1],
+            [0, 1, 7],
+            [0, 7, 10],
+            [0, 10, 11],
+            [1, 5, 9],
+            [5, 11, 4],
+            [11, 10, 2],
+            [10, 7, 6],
+            [7, 1, 8],
+            [3, 9, 4],
+            [3, 4, 2],
+            [3, 2, 6],
+            [3, 6, 8],
+            [3, 8, 9],
+            [4, 9, 5],
+            [2, 4, 11],
+            [6, 2, 10],
+            [8, 6, 7],
+            [9, 8, 1],
+        ];
+
+        for _ in 0..subdivisions {
+            let mut midpoints: HashMap<(usize, usize), usize> = HashMap::new();
+            let mut midpoint = |a: usize, b: usize, vertices: &mut Vec<Vec3>| {
+                *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                    vertices.push(((vertices[a] + vertices[b]) / 2.0).unit());
+                    vertices.len() - 1
+                })
+            };
+
+            let mut next = Vec::with_capacity(triangles.len() * 4);
+            for [a, b, c] in triangles {
+                let ab = midpoint(a, b, &mut vertices);
+                let bc = midpoint(b, c, &mut vertices);
+                let ca = midpoint(c, a, &mut vertices);
+                next.push([a, ab, ca]);
+                next.push([b, bc, ab]);
+                next.push([c, ca, bc]);
+                next.push([ab, bc, ca]);
+            }
+            triangles = next;
+        }
+
+        Mesh {
+            normals: vertices.clone(),
+            vertices,
+            uvs: Vec::new(),
+            triangles,
+            materials: Vec::new(),
+        }
+    }
+
+    //Smooth normals from the area weighted normals of the adjacent faces
+    pub fn compute_normals(&mut self) {
+        let mut normals = vec![Vec3::origin(); self.vertices.len()];
+        for &[a, b, c] in self.triangles.iter() {
+            let n = (self.vertices[b] - self.vertices[a]).cross(self.vertices[c] - self.vertices[a]);
+            for i in [a, b, c] {
+                normals[i] = normals[i] + n;
+            }
+        }
+        self.normals = normals
+            .into_iter()
+            .map(|n| if n.is_tiny(1e-12) { Vec3::unit_y() } else { n.unit() })
+            .collect();
+    }
+
+    //Split every triangle into four at its edge midpoints, interpolating normals and uvs.
+    //Midpoints are shared between neighbouring triangles so the mesh stays watertight.
+    pub fn subdivided(&self) -> Mesh {
+        let mut out = self.clone();
+        let mut midpoints = HashMap::new();
+        out.triangles = Vec::with_capacity(self.triangles.len() * 4);
+        out.materials = self.materials.iter().flat_map(|&m| [m; 4]).collect();
+        for &[a, b, c] in self.triangles.iter() {
+            let ab = out.midpoint(&mut midpoints, a, b);
+            let bc = out.midpoint(&mut midpoints, b, c);
+            let ca = out.midpoint(&mut midpoints, c, a);
+            out.triangles.push([a, ab, ca]);
+            out.triangles.push([b, bc, ab]);
+            out.triangles.push([c, ca, bc]);
+            out.triangles.push([ab, bc, ca]);
+        }
+        out
+    }
+
+    //Split only the edges longer than max_edge_length at their midpoints. A triangle becomes two,
+    //three or four depending on how many of its edges are split. The decision is made per edge,
+    //so neighbouring triangles agree and the mesh stays watertight.
+    pub fn split_long_edges(&self, max_edge_length: fVec) -> Mesh {
+        let mut out = self.clone();
+        let mut midpoints = HashMap::new();
+        out.triangles = Vec::with_capacity(self.triangles.len() * 2);
+        out.materials = Vec::with_capacity(self.materials.len() * 2);
+        for (face, &tri) in self.triangles.iter().enumerate() {
+            let long = |i: usize| (self.vertices[tri[i]] - self.vertices[tri[(i + 1) % 3]]).length() > max_edge_length;
+            let split = [long(0), long(1), long(2)];
+            let count = split.iter().filter(|&&s| s).count();
+            //Rotate so that the split edges start at the first edge
+            let first = match count {
+                0 | 3 => 0,
+                1 => split.iter().position(|&s| s).unwrap(),
+                _ => (split.iter().position(|&s| !s).unwrap() + 1) % 3,
+            };
+            let [a, b, c] = [tri[first], tri[(first + 1) % 3], tri[(first + 2) % 3]];
+            match count {
+                0 => out.triangles.push([a, b, c]),
+                1 => {
+                    let ab = out.midpoint(&mut midpoints, a, b);
+                    out.triangles.push([a, ab, c]);
+                    out.triangles.push([ab, b, c]);
+                }
+                2 => {
+                    let ab = out.midpoint(&mut midpoints, a, b);
+                    let bc = out.midpoint(&mut midpoints, b, c);
+                    out.triangles.push([ab, b, bc]);
+                    out.triangles.push([a, ab, bc]);
+                    out.triangles.push([a, bc, c]);
+                }
+                _ => {
+                    let ab = out.midpoint(&mut midpoints, a, b);
+                    let bc = out.midpoint(&mut midpoints, b, c);
+                    let ca = out.midpoint(&mut midpoints, c, a);
+                    out.triangles.push([a, ab, ca]);
+                    out.triangles.push([b, bc, ab]);
+                    out.triangles.push([c, ca, bc]);
+                    out.triangles.push([ab, bc, ca]);
+                }
+            }
+            if let Some(&m) = self.materials.get(face) {
+                out.materials.resize(out.triangles.len(), m);
+            }
+        }
+        out
+    }
+
+    //Vertex halfway along the edge from a to b with interpolated normal and uv, shared by the
+    //triangles on both sides of the edge
+    fn midpoint(&mut self, midpoints: &mut HashMap<(usize, usize), usize>, a: usize, b: usize) -> usize {
+        *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+            self.vertices.push((self.vertices[a] + self.vertices[b]) / 2.0);
+            if !self.normals.is_empty() {
+                let n = self.normals[a] + self.normals[b];
+                self.normals.push(if n.is_tiny(1e-12) { self.normals[a] } else { n.unit() });
+            }
+            if !self.uvs.is_empty() {
+                let (ua, va) = self.uvs[a];
+                let (ub, vb) = self.uvs[b];
+                self.uvs.push(((ua + ub) / 2.0, (va + vb) / 2.0));
+            }
+            self.vertices.len() - 1
+        })
+    }
+
+    //Number of material slots the triangles refer to, at least 1
+    pub fn material_slots(&self) -> usize {
+        self.materials.iter().max().map_or(1, |m| m + 1)
+    }
+
+    pub fn longest_edge(&self) -> fVec {
+        self.triangles
+            .iter()
+            .flat_map(|&[a, b, c]| [(a, b), (b, c), (c, a)])
+            .map(|(a, b)| (self.vertices[a] - self.vertices[b]).length())
+            .fold(0.0, fVec::max)
+    }
+
+    //Moves the vertices along their normals by scale times the luminance of height, after
+    //splitting edges longer than max_edge_length so the detail has vertices to land on. Splitting
+    //stops early once the mesh has max_triangles. Meshes without uvs look up the height by
+    //position only.
+    pub fn displaced(&self, height: &dyn Texture, scale: fVec, max_edge_length: fVec, max_triangles: usize) -> Mesh {
+        let mut mesh = self.clone();
+        if mesh.normals.len() != mesh.vertices.len() {
+            mesh.compute_normals();
+        }
+        while mesh.longest_edge() > max_edge_length {
+            let split = mesh.split_long_edges(max_edge_length);
+            if split.triangles.len() > max_triangles {
+                break;
+            }
+            mesh = split;
+        }
+
+        for i in 0..mesh.vertices.len() {
+            let (u, v) = mesh.uvs.get(i).copied().unwrap_or((0.0, 0.0));
+            let h = height.value(u, v, mesh.vertices[i]).luminance();
+            mesh.vertices[i] = mesh.vertices[i] + mesh.normals[i] * (h * scale);
+        }
+        mesh.compute_normals();
+        mesh
+    }
+
+    pub fn transformed(mut self, scale: fVec, offset: Vec3) -> Mesh {
+        for v in self.vertices.iter_mut() {
+            *v = *v * scale + offset;
+        }
+        self
+    }
+
+    //Append as a named object to a Wavefront OBJ stream, vertex_offset is the number of
+    //vertices written before (OBJ indices are global and 1-based)
+    pub fn write_obj(&self, out: &mut impl Write, name: &str, vertex_offset: usize) -> io::Result<()> {
+        writeln!(out, "o {}", name)?;
+        for v in self.vertices.iter() {
+            writeln!(out, "v {} {} {}", v.x, v.y, v.z)?;
+        }
+        for n in self.normals.iter() {
+            writeln!(out, "vn {} {} {}", n.x, n.y, n.z)?;
+        }
+        for tri in self.triangles.iter() {
+            let [a, b, c] = tri.map(|i| i + vertex_offset + 1);
+            writeln!(out, "f {a}//{a} {b}//{b} {c}//{c}")?;
+        }
+        Ok(())
+    }
+}
+
+//Renderable triangle mesh with its own triangle BVH
+pub struct MeshObject {
+    pub mesh: Mesh,
+    //Indexed by the material slots of the mesh, slots past the end use the first
+    pub materials: Vec<Rc<dyn Material>>,
+    bvh: Bvh,
+}
+
+impl MeshObject {
+    pub fn new(mesh: Mesh, material: Rc<dyn Material>) -> MeshObject {
+        MeshObject::with_materials(mesh, vec![material])
+    }
+
+    //materials must not be empty
+    pub fn with_materials(mesh: Mesh, materials: Vec<Rc<dyn Material>>) -> MeshObject {
+        assert!(!materials.is_empty(), "mesh object without materials");
+        let mut obj = MeshObject {
+            mesh,
+            materials,
+            bvh: Bvh::build(&[]),
+        };
+        obj.prepare();
+        obj
+    }
+
+    fn slot(&self, face: usize) -> usize {
+        self.mesh.materials.get(face).copied().unwrap_or(0)
+    }
+
+    fn triangle_bounds(&self, tri: [usize; 3]) -> Aabb {
+        Aabb::from_points(tri.iter().map(|&i| self.mesh.vertices[i]))
+    }
+
+    //Möller-Trumbore, returns distance and barycentric coordinates of b and c
+    //Texture density of a triangle, uv units per unit of length
+    fn uv_per_unit(&self, [a, b, c]: [usize; 3]) -> fVec {
+        let v = &self.mesh.vertices;
+        let area = (v[b] - v[a]).cross(v[c] - v[a]).length();
+        //Barycentric coordinates span half the unit square
+        let uv_area = if self.mesh.uvs.is_empty() {
+            1.0
+        } else {
+            let st = &self.mesh.uvs;
+            ((st[b].0 - st[a].0) * (st[c].1 - st[a].1) - (st[c].0 - st[a].0) * (st[b].1 - st[a].1)).abs()
+        };
+        if area <= 0.0 {
+            return 0.0;
+        }
+        (uv_area / area).sqrt()
+    }
+
+    fn hit_triangle(&self, ray: &Ray, [a, b, c]: [usize; 3]) -> Option<(fVec, fVec, fVec)> {
+        let v = &self.mesh.vertices;
+        let e1 = v[b] - v[a];
+        let e2 = v[c] - v[a];
+        let p = ray.direction.cross(e2);
+        let det = e1 * p;
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv = 1.0 / det;
+        let s = ray.origin - v[a];
+        let u = (s * p) * inv;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = s.cross(e1);
+        let w = (ray.direction * q) * inv;
+        if w < 0.0 || u + w > 1.0 {
+            return None;
+        }
+        let t = (e2 * q) * inv;
+        if t < ray.min || t > ray.max {
+            return None;
+        }
+        Some((t, u, w))
+    }
+
+    //Surface at barycentric (u, w) of face, hit at t
+    fn hit_result(&self, ray: &Ray, t: fVec, u: fVec, w: fVec, face: usize) -> HitResult {
+        let [a, b, c] = self.mesh.triangles[face];
+        let n = &self.mesh.normals;
+        let normal = n[a] * (1.0 - u - w) + n[b] * u + n[c] * w;
+        let uv = if self.mesh.uvs.is_empty() {
+            (u, w)
+        } else {
+            let st = &self.mesh.uvs;
+            (
+                st[a].0 * (1.0 - u - w) + st[b].0 * u + st[c].0 * w,
+                st[a].1 * (1.0 - u - w) + st[b].1 * u + st[c].1 * w,
+            )
+        };
+        HitResult {
+            intersect: ray.at(t),
+            normal: normal.unit(),
+            tangent: self.mesh.vertices[b] - self.mesh.vertices[a],
+            uv,
+            uv_width: ray.uv_width(t, normal.unit(), self.uv_per_unit([a, b, c])),
+            face,
+            at: t,
+        }
+    }
+
+    //Whether the ray hits a triangle that stops accepts, ending the traversal at the first one
+    fn any_hit(&self, ray: &Ray, nodes: &mut usize, stops: impl Fn(&HitResult) -> bool) -> bool {
+        let (hit, visited) = self.bvh.any(ray, |i| {
+            self.hit_triangle(ray, self.mesh.triangles[i])
+                .is_some_and(|(t, u, w)| stops(&self.hit_result(ray, t, u, w, i)))
+        });
+        *nodes += visited;
+        hit
+    }
+}
+
+impl Hit for MeshObject {
+    fn hit(&self, ray: &Ray) -> Option<HitResult> {
+        self.hit_counted(ray, &mut 0)
+    }
+
+    fn hit_counted(&self, ray: &Ray, nodes: &mut usize) -> Option<HitResult> {
+        let mut closest = None;
+        *nodes += self.bvh.traverse(ray, |i, ray| {
+            let tri = self.mesh.triangles[i];
+            let (t, u, w) = self.hit_triangle(ray, tri)?;
+            closest = Some((t, u, w, i));
+            Some(t)
+        });
+
+        let (t, u, w, face) = closest?;
+        Some(self.hit_result(ray, t, u, w, face))
+    }
+
+    fn occluded(&self, ray: &Ray, nodes: &mut usize) -> bool {
+        self.any_hit(ray, nodes, |hit| stops_ray(self.material_at(hit), hit, ray))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.materials[0].as_ref()
+    }
+
+    fn material_at(&self, hit: &HitResult) -> &dyn Material {
+        self.materials.get(self.slot(hit.face)).unwrap_or(&self.materials[0]).as_ref()
+    }
+
+    fn prepare(&mut self) {
+        let bounds: Vec<Aabb> = self.mesh.triangles.iter().map(|&tri| self.triangle_bounds(tri)).collect();
+        self.bvh = Bvh::build(&bounds);
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(self.mesh.vertices.iter().copied()))
+    }
+
+    fn to_mesh(&self, _subdivisions: usize) -> Option<Mesh> {
+        Some(self.mesh.clone())
+    }
+}
+
+//Placement of a shared mesh, scaled uniformly and then moved by offset. Copies of one loaded
+//asset can look different: material replaces the materials of all slots, overrides those of
+//single slots.
+pub struct MeshInstance {
+    pub mesh: Rc<MeshObject>,
+    pub scale: fVec,
+    pub offset: Vec3,
+    pub material: Option<Rc<dyn Material>>,
+    pub overrides: Vec<Option<Rc<dyn Material>>>,
+}
+
+impl MeshInstance {
+    pub fn new(mesh: Rc<MeshObject>) -> MeshInstance {
+        MeshInstance {
+            mesh,
+            scale: 1.0,
+            offset: Vec3::origin(),
+            material: None,
+            overrides: Vec::new(),
+        }
+    }
+
+    //The local ray keeps the ray parameter, so distances need no conversion
+    fn local_ray(&self, ray: &Ray) -> Ray {
+        Ray {
+            origin: (ray.origin - self.offset) / self.scale,
+            direction: ray.direction / self.scale,
+            cone_width: ray.cone_width / self.scale,
+            cone_spread: ray.cone_spread / self.scale,
+            ..*ray
+        }
+    }
+
+    fn to_world(&self, ray: &Ray, hit: HitResult) -> HitResult {
+        HitResult {
+            intersect: ray.at(hit.at),
+            tangent: hit.tangent * self.scale,
+            ..hit
+        }
+    }
+
+    fn slot_material(&self, slot: usize) -> Option<&dyn Material> {
+        match self.overrides.get(slot) {
+            Some(Some(m)) => Some(m.as_ref()),
+            _ => self.material.as_deref(),
+        }
+    }
+}
+
+impl Hit for MeshInstance {
+    fn hit(&self, ray: &Ray) -> Option<HitResult> {
+        self.hit_counted(ray, &mut 0)
+    }
+
+    fn hit_counted(&self, ray: &Ray, nodes: &mut usize) -> Option<HitResult> {
+        let hit = self.mesh.hit_counted(&self.local_ray(ray), nodes)?;
+        Some(self.to_world(ray, hit))
+    }
+
+    //Cutouts are decided by the instance's materials, on the hit in world space
+    fn occluded(&self, ray: &Ray, nodes: &mut usize) -> bool {
+        self.mesh.any_hit(&self.local_ray(ray), nodes, |hit| {
+            let hit = self.to_world(ray, *hit);
+            stops_ray(self.material_at(&hit), &hit, ray)
+        })
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.slot_material(0).unwrap_or_else(|| self.mesh.material())
+    }
+
+    fn material_at(&self, hit: &HitResult) -> &dyn Material {
+        self.slot_material(self.mesh.slot(hit.face))
+            .unwrap_or_else(|| self.mesh.material_at(hit))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let b = self.mesh.bounds()?;
+        Some(Aabb {
+            min: b.min * self.scale + self.offset,
+            max: b.max * self.scale + self.offset,
+        })
+    }
+
+    fn to_mesh(&self, _subdivisions: usize) -> Option<Mesh> {
+        Some(self.mesh.mesh.clone().transformed(self.scale, self.offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Thin sliver next to a regular triangle, only the sliver's long edges need splitting
+    fn sliver() -> Mesh {
+        Mesh {
+            vertices: vec![
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Vec3::new(8.0, 0.5, 0.0),
+            ],
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            triangles: vec![[0, 1, 2], [1, 3, 2]],
+            materials: vec![0, 1],
+        }
+    }
+
+    #[test]
+    fn split_long_edges_is_adaptive_and_watertight() {
+        let mut mesh = sliver();
+        while mesh.longest_edge() > 1.5 {
+            mesh = mesh.split_long_edges(1.5);
+        }
+        assert!(mesh.triangles.len() < sliver().subdivided().subdivided().subdivided().triangles.len());
+        assert_eq!(mesh.materials.len(), mesh.triangles.len());
+
+        //Inner edges are shared by exactly two triangles, one in each direction
+        let mut edges: HashMap<(usize, usize), i32> = HashMap::new();
+        for &[a, b, c] in mesh.triangles.iter() {
+            for (p, q) in [(a, b), (b, c), (c, a)] {
+                *edges.entry((p.min(q), p.max(q))).or_default() += if p < q { 1 } else { -1 };
+            }
+        }
+        let boundary_length: fVec = edges
+            .iter()
+            .filter(|(_, &n)| n != 0)
+            .map(|(&(p, q), _)| (mesh.vertices[p] - mesh.vertices[q]).length())
+            .sum();
+        let outline = 2.0 + (7.0 as fVec).hypot(0.5) + (8.0 as fVec).hypot(0.5);
+        assert!((boundary_length - outline).abs() < 1e-4, "{} vs {}", boundary_length, outline);
+    }
+
+    //Shadow rays agree with the closest hit but stop at the first layer of a stack of quads they reach
+    #[test]
+    fn occluded_stops_at_any_hit() {
+        let mut mesh = Mesh {
+            vertices: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            triangles: Vec::new(),
+            materials: Vec::new(),
+        };
+        for layer in 0..8 {
+            let z = layer as fVec;
+            let n = mesh.vertices.len();
+            for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                mesh.vertices.push(Vec3::new(x, y, z));
+                mesh.normals.push(Vec3::new(0.0, 0.0, -1.0));
+            }
+            mesh.triangles.extend([[n, n + 1, n + 2], [n, n + 2, n + 3]]);
+            mesh.materials.extend([0, 0]);
+        }
+        let obj = MeshObject::new(mesh, Rc::new(crate::material::DebugMaterial {}));
+
+        for (x, y, max) in [(0.5, 0.2, fVec::INFINITY), (-0.3, 0.9, 3.5), (0.0, 0.0, 0.5), (1.5, 0.0, fVec::INFINITY)] {
+            let mut ray = Ray::new(Vec3::new(x, y, -1.0), Vec3::new(0.0, 0.0, 1.0));
+            ray.max = max;
+            let (mut closest_nodes, mut any_nodes) = (0, 0);
+            let closest = obj.hit_counted(&ray, &mut closest_nodes);
+            assert_eq!(obj.occluded(&ray, &mut any_nodes), closest.is_some());
+            if closest.is_some() {
+                assert!(any_nodes < closest_nodes, "{any_nodes} vs {closest_nodes}");
+            }
+        }
+    }
+}
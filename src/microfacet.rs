@@ -0,0 +1,286 @@
+use std::f32::consts::PI;
+use std::sync::OnceLock;
+
+use crate::image::*;
+use crate::linalg::*;
+
+//GGX / Trowbridge-Reitz microfacet distribution, alpha = roughness^2
+
+#[inline]
+pub fn roughness_to_alpha(roughness: fVec) -> fVec {
+    (roughness * roughness).max(0.001)
+}
+
+//Density of microfacet normals with the given cosine to the surface normal
+#[inline]
+pub fn ggx_d(n_dot_h: fVec, alpha: fVec) -> fVec {
+    if n_dot_h <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+//Smith masking for a single direction
+#[inline]
+pub fn smith_g1(n_dot_v: fVec, alpha: fVec) -> fVec {
+    if n_dot_v <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    2.0 * n_dot_v / (n_dot_v + (a2 + (1.0 - a2) * n_dot_v * n_dot_v).sqrt())
+}
+
+#[inline]
+pub fn smith_g(n_dot_l: fVec, n_dot_v: fVec, alpha: fVec) -> fVec {
+    smith_g1(n_dot_l, alpha) * smith_g1(n_dot_v, alpha)
+}
+
+#[inline]
+pub fn schlick_fresnel(cos: fVec, f0: Color) -> Color {
+    let w = (1.0 - cos.clamp(0.0, 1.0)).powi(5);
+    f0 + (Color::white() - f0) * w
+}
+
+//Microfacet normal around normal, distributed proportional to D(h) * (n . h)
+pub fn sample_ggx_normal(normal: Vec3, alpha: fVec, u1: fVec, u2: fVec) -> Vec3 {
+    let tan2 = alpha * alpha * u1 / (1.0 - u1).max(1e-6);
+    let cos_theta = 1.0 / (1.0 + tan2).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    let (tangent, bitangent) = normal.basis();
+    (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta + normal * cos_theta
+}
+
+//Density of reflected directions when sampling with sample_ggx_normal
+#[inline]
+pub fn ggx_reflection_pdf(n_dot_h: fVec, v_dot_h: fVec, alpha: fVec) -> fVec {
+    if v_dot_h <= 0.0 {
+        return 0.0;
+    }
+    ggx_d(n_dot_h, alpha) * n_dot_h / (4.0 * v_dot_h)
+}
+
+//Anisotropic GGX, directions are given in the local frame (tangent, bitangent, normal)
+
+#[inline]
+pub fn ggx_d_aniso(h: Vec3, alpha_x: fVec, alpha_y: fVec) -> fVec {
+    if h.z <= 0.0 {
+        return 0.0;
+    }
+    let d = (h.x / alpha_x).powi(2) + (h.y / alpha_y).powi(2) + h.z * h.z;
+    1.0 / (PI * alpha_x * alpha_y * d * d)
+}
+
+#[inline]
+pub fn smith_g1_aniso(v: Vec3, alpha_x: fVec, alpha_y: fVec) -> fVec {
+    if v.z <= 0.0 {
+        return 0.0;
+    }
+    let tan2 = ((alpha_x * v.x).powi(2) + (alpha_y * v.y).powi(2)) / (v.z * v.z);
+    2.0 / (1.0 + (1.0 + tan2).sqrt())
+}
+
+//Local microfacet normal distributed proportional to D(h) * h.z, by sampling the slope
+pub fn sample_ggx_aniso_normal(alpha_x: fVec, alpha_y: fVec, u1: fVec, u2: fVec) -> Vec3 {
+    let r = (u1 / (1.0 - u1).max(1e-6)).sqrt();
+    let phi = 2.0 * PI * u2;
+    Vec3::new(-alpha_x * r * phi.cos(), -alpha_y * r * phi.sin(), 1.0).unit()
+}
+
+//Exact unpolarized Fresnel reflectance of a dielectric interface, cos on the incident side and
+//eta the IOR ratio transmitted over incident. 1 under total internal reflection.
+pub fn dielectric_fresnel(cos: fVec, eta: fVec) -> fVec {
+    let cos_i = cos.clamp(0.0, 1.0);
+    let sin_t2 = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin_t2 >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin_t2).sqrt();
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+//Exact unpolarized Fresnel reflectance of a conductor with complex IOR eta + i k, per channel
+pub fn conductor_fresnel(cos: fVec, eta: Color, k: Color) -> Color {
+    let cos = cos.clamp(0.0, 1.0);
+    let cos2 = cos * cos;
+    let sin2 = 1.0 - cos2;
+    let channel = |eta: fCol, k: fCol| {
+        let t0 = eta * eta - k * k - sin2;
+        let a2b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+        let t1 = a2b2 + cos2;
+        let t2 = 2.0 * a * cos;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        0.5 * (rs + rp)
+    };
+    Color::new(channel(eta.r, k.r), channel(eta.g, k.g), channel(eta.b, k.b))
+}
+
+//Kulla-Conty multiple scattering compensation. Single scattering GGX loses the light that
+//bounces more than once between microfacets, which adds up to a third for very rough surfaces.
+//An extra diffuse-like lobe gives it back, scaled by the precomputed single scattering albedo.
+
+const ALBEDO_SIZE: usize = 32;
+
+struct AlbedoTable {
+    //Directional albedo E(cos, roughness), roughness major
+    albedo: Vec<fVec>,
+    //Cosine weighted hemispherical average of E per roughness
+    average: Vec<fVec>,
+}
+
+fn albedo_table() -> &'static AlbedoTable {
+    static TABLE: OnceLock<AlbedoTable> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        const STRATA: usize = 16;
+        let mut albedo = Vec::with_capacity(ALBEDO_SIZE * ALBEDO_SIZE);
+        for j in 0..ALBEDO_SIZE {
+            let alpha = roughness_to_alpha(j as fVec / (ALBEDO_SIZE - 1) as fVec);
+            for i in 0..ALBEDO_SIZE {
+                let n_dot_v = (i as fVec / (ALBEDO_SIZE - 1) as fVec).max(0.001);
+                let view = Vec3::new((1.0 - n_dot_v * n_dot_v).sqrt(), 0.0, n_dot_v);
+                //Stratified GGX importance sampling, the weight is G * (v.h) / ((n.v) * (n.h))
+                let mut sum = 0.0;
+                for a in 0..STRATA {
+                    for b in 0..STRATA {
+                        let u1 = (a as fVec + 0.5) / STRATA as fVec;
+                        let u2 = (b as fVec + 0.5) / STRATA as fVec;
+                        let h = sample_ggx_normal(Vec3::unit_z(), alpha, u1, u2);
+                        let l = h * (2.0 * (view * h)) - view;
+                        if l.z > 0.0 {
+                            sum += smith_g(l.z, n_dot_v, alpha) * (view * h) / (n_dot_v * h.z);
+                        }
+                    }
+                }
+                albedo.push((sum / (STRATA * STRATA) as fVec).min(1.0));
+            }
+        }
+        let average = albedo
+            .chunks(ALBEDO_SIZE)
+            .map(|row| cosine_average(|cos| interpolate(row, cos)))
+            .collect();
+        AlbedoTable { albedo, average }
+    })
+}
+
+//2 * integral of f(cos) * cos over [0, 1], midpoint rule
+fn cosine_average(f: impl Fn(fVec) -> fVec) -> fVec {
+    const STEPS: usize = 64;
+    (0..STEPS)
+        .map(|i| {
+            let cos = (i as fVec + 0.5) / STEPS as fVec;
+            f(cos) * cos
+        })
+        .sum::<fVec>()
+        * 2.0
+        / STEPS as fVec
+}
+
+//Linear lookup in an evenly spaced table over [0, 1]
+#[inline]
+fn interpolate(table: &[fVec], x: fVec) -> fVec {
+    let pos = x.clamp(0.0, 1.0) * (table.len() - 1) as fVec;
+    let i = (pos as usize).min(table.len() - 2);
+    let f = pos - i as fVec;
+    table[i] * (1.0 - f) + table[i + 1] * f
+}
+
+//Fraction of light reflected by single scattering GGX with a white Fresnel term
+pub fn ggx_albedo(n_dot_v: fVec, roughness: fVec) -> fVec {
+    let table = albedo_table();
+    let pos = roughness.clamp(0.0, 1.0) * (ALBEDO_SIZE - 1) as fVec;
+    let j = (pos as usize).min(ALBEDO_SIZE - 2);
+    let f = pos - j as fVec;
+    let row = |j: usize| interpolate(&table.albedo[j * ALBEDO_SIZE..(j + 1) * ALBEDO_SIZE], n_dot_v);
+    row(j) * (1.0 - f) + row(j + 1) * f
+}
+
+pub fn ggx_average_albedo(roughness: fVec) -> fVec {
+    interpolate(&albedo_table().average, roughness)
+}
+
+//Cosine weighted hemispherical average of schlick_fresnel
+#[inline]
+pub fn average_fresnel(f0: Color) -> Color {
+    f0 * (20.0 / 21.0) + Color::white() * (1.0 / 21.0)
+}
+
+//Multiple scattering lobe to add to the single scattering BRDF, without the cosine term.
+//f_avg tints the energy by the average Fresnel over all the extra bounces.
+pub fn ggx_multiscatter(n_dot_l: fVec, n_dot_v: fVec, roughness: fVec, f_avg: Color) -> Color {
+    let e_avg = ggx_average_albedo(roughness);
+    if e_avg >= 1.0 {
+        return Color::black();
+    }
+    let lobe = (1.0 - ggx_albedo(n_dot_l, roughness)) * (1.0 - ggx_albedo(n_dot_v, roughness)) / (PI * (1.0 - e_avg));
+    let tint = Color::new(
+        f_avg.r * f_avg.r * e_avg / (1.0 - f_avg.r * (1.0 - e_avg)),
+        f_avg.g * f_avg.g * e_avg / (1.0 - f_avg.g * (1.0 - e_avg)),
+        f_avg.b * f_avg.b * e_avg / (1.0 - f_avg.b * (1.0 - e_avg)),
+    );
+    tint * lobe
+}
+
+//Charlie sheen distribution (Estevez and Kulla) for cloth: fibers standing up from the surface
+//reflect most at grazing angles, giving velvet its bright rim. Larger roughness spreads the rim
+//towards normal incidence, below 0.25 the lobe would reflect more than it receives.
+#[inline]
+pub fn charlie_d(n_dot_h: fVec, roughness: fVec) -> fVec {
+    let inv_alpha = 1.0 / roughness.clamp(0.25, 1.0);
+    let sin2 = (1.0 - n_dot_h * n_dot_h).max(1.0 / 128.0);
+    (2.0 + inv_alpha) * sin2.powf(0.5 * inv_alpha) / (2.0 * PI)
+}
+
+//Neubelt and Pettineo's visibility term paired with charlie_d, already divided by 4 n.l n.v
+#[inline]
+pub fn sheen_visibility(n_dot_l: fVec, n_dot_v: fVec) -> fVec {
+    1.0 / (4.0 * (n_dot_l + n_dot_v - n_dot_l * n_dot_v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Reflected energy of a white GGX surface lit by a uniform white environment: single scattering
+    //importance sampled from the BRDF, plus the multiple scattering lobe integrated over the cosine
+    fn furnace(n_dot_v: fVec, roughness: fVec) -> fVec {
+        const STRATA: usize = 128;
+        let alpha = roughness_to_alpha(roughness);
+        let view = Vec3::new((1.0 - n_dot_v * n_dot_v).sqrt(), 0.0, n_dot_v);
+        let mut single = 0.0;
+        for a in 0..STRATA {
+            for b in 0..STRATA {
+                let u1 = (a as fVec + 0.5) / STRATA as fVec;
+                let u2 = (b as fVec + 0.5) / STRATA as fVec;
+                let h = sample_ggx_normal(Vec3::unit_z(), alpha, u1, u2);
+                let l = h * (2.0 * (view * h)) - view;
+                let pdf = ggx_reflection_pdf(h.z, view * h, alpha);
+                if l.z > 0.0 && pdf > 0.0 {
+                    let f = ggx_d(h.z, alpha) * smith_g(l.z, n_dot_v, alpha) / (4.0 * l.z * n_dot_v);
+                    single += f * l.z / pdf;
+                }
+            }
+        }
+        single /= (STRATA * STRATA) as fVec;
+        //The lobe only depends on n.l, 2 pi * integral of f * cos over the cosine
+        let multi = PI * cosine_average(|cos| ggx_multiscatter(cos, n_dot_v, roughness, Color::white()).r);
+        single + multi
+    }
+
+    #[test]
+    fn multiscatter_passes_white_furnace() {
+        for roughness in [0.2, 0.4, 0.6, 0.8, 1.0] {
+            for n_dot_v in [0.3, 0.6, 0.9] {
+                let energy = furnace(n_dot_v, roughness);
+                assert!((energy - 1.0).abs() < 0.02, "roughness {roughness} n.v {n_dot_v}: {energy}");
+            }
+        }
+    }
+}
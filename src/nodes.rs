@@ -0,0 +1,265 @@
+use std::cell::{Ref, RefCell};
+use std::io;
+use std::rc::Rc;
+
+use crate::image::*;
+use crate::linalg::*;
+use crate::material::*;
+use crate::microfacet::*;
+use crate::texture::*;
+use crate::tracer::*;
+use crate::usd::PreviewSurface;
+
+//Shader node graphs, so materials can be put together from textures and math in a scene file
+//instead of new material structs. Every value is a color, scalars are gray and read back as
+//their luminance. Nodes only take inputs from nodes added before them, which rules out cycles.
+
+pub type NodeId = usize;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Input {
+    Value(Color),
+    Node(NodeId),
+}
+
+impl From<fVec> for Input {
+    fn from(v: fVec) -> Input {
+        Input::Value(Color::new(v, v, v))
+    }
+}
+
+//Per channel operations of a math node
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MathOp {
+    Add,
+    Subtract,
+    Multiply,
+    //0 where dividing by 0
+    Divide,
+    //0 for negative bases
+    Power,
+    Minimum,
+    Maximum,
+}
+
+impl MathOp {
+    pub fn from_name(name: &str) -> Option<MathOp> {
+        Some(match name {
+            "add" => MathOp::Add,
+            "subtract" => MathOp::Subtract,
+            "multiply" => MathOp::Multiply,
+            "divide" => MathOp::Divide,
+            "power" => MathOp::Power,
+            "minimum" => MathOp::Minimum,
+            "maximum" => MathOp::Maximum,
+            _ => return None,
+        })
+    }
+
+    fn apply(self, a: fCol, b: fCol) -> fCol {
+        match self {
+            MathOp::Add => a + b,
+            MathOp::Subtract => a - b,
+            MathOp::Multiply => a * b,
+            MathOp::Divide if b == 0.0 => 0.0,
+            MathOp::Divide => a / b,
+            MathOp::Power if a < 0.0 => 0.0,
+            MathOp::Power => a.powf(b),
+            MathOp::Minimum => a.min(b),
+            MathOp::Maximum => a.max(b),
+        }
+    }
+}
+
+pub enum Node {
+    Texture(Rc<dyn Texture>),
+    //Surface coordinates as (u, v, 0)
+    Uv,
+    //World space hit position
+    Position,
+    //Dielectric reflectance towards the viewer, as Blender's Fresnel node
+    Fresnel { ior: Input },
+    Math { op: MathOp, a: Input, b: Input },
+    //a where factor is 0, b where it is 1
+    Mix { factor: Input, a: Input, b: Input },
+}
+
+impl Node {
+    fn inputs(&self) -> Vec<Input> {
+        match self {
+            Node::Texture(_) | Node::Uv | Node::Position => Vec::new(),
+            Node::Fresnel { ior } => vec![*ior],
+            Node::Math { a, b, .. } => vec![*a, *b],
+            Node::Mix { factor, a, b } => vec![*factor, *a, *b],
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("node graph: {}", msg))
+}
+
+#[derive(Default)]
+pub struct NodeGraph {
+    nodes: Vec<Node>,
+    //Node outputs of the last eval, reused so shading calls don't allocate
+    values: RefCell<Vec<Color>>,
+}
+
+impl NodeGraph {
+    pub fn new() -> NodeGraph {
+        NodeGraph::default()
+    }
+
+    pub fn add(&mut self, node: Node) -> io::Result<NodeId> {
+        for input in node.inputs() {
+            if let Input::Node(id) = input {
+                if id >= self.nodes.len() {
+                    return Err(invalid(&format!("input {} refers to a later node", id)));
+                }
+            }
+        }
+        self.nodes.push(node);
+        self.values.get_mut().reserve(1);
+        Ok(self.nodes.len() - 1)
+    }
+
+    //Outputs of all nodes, indexed by NodeId
+    pub fn eval(&self, ray: &Ray, hit: &HitResult) -> Ref<'_, [Color]> {
+        let mut values = self.values.borrow_mut();
+        values.clear();
+        for node in self.nodes.iter() {
+            let get = |input: &Input| match *input {
+                Input::Value(c) => c,
+                Input::Node(id) => values[id],
+            };
+            let value = match node {
+                Node::Texture(tex) => tex.at_hit(hit),
+                Node::Uv => Color::new(hit.uv.0, hit.uv.1, 0.0),
+                Node::Position => Color::new(hit.intersect.x, hit.intersect.y, hit.intersect.z),
+                Node::Fresnel { ior } => {
+                    let ior = get(ior).luminance().max(1.0);
+                    let eta = if hit.is_outside(ray) { ior } else { 1.0 / ior };
+                    let f = dielectric_fresnel((hit.normal * ray.direction.unit()).abs(), eta);
+                    Color::new(f, f, f)
+                }
+                Node::Math { op, a, b } => {
+                    let (a, b) = (get(a), get(b));
+                    Color::new(op.apply(a.r, b.r), op.apply(a.g, b.g), op.apply(a.b, b.b))
+                }
+                Node::Mix { factor, a, b } => {
+                    let t = get(factor).luminance().clamp(0.0, 1.0);
+                    get(a) * (1.0 - t) + get(b) * t
+                }
+            };
+            values.push(value);
+        }
+        drop(values);
+        Ref::map(self.values.borrow(), |values| values.as_slice())
+    }
+}
+
+//Principled inputs a node can drive
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PrincipledInput {
+    BaseColor,
+    Metallic,
+    Roughness,
+    Specular,
+    Sheen,
+    SheenTint,
+    Clearcoat,
+    ClearcoatRoughness,
+    Transmission,
+}
+
+impl PrincipledInput {
+    //Under the names used by scene files
+    pub const ALL: [(&'static str, PrincipledInput); 9] = [
+        ("base_color", PrincipledInput::BaseColor),
+        ("metallic", PrincipledInput::Metallic),
+        ("roughness", PrincipledInput::Roughness),
+        ("specular", PrincipledInput::Specular),
+        ("sheen", PrincipledInput::Sheen),
+        ("sheen_tint", PrincipledInput::SheenTint),
+        ("clearcoat", PrincipledInput::Clearcoat),
+        ("clearcoat_roughness", PrincipledInput::ClearcoatRoughness),
+        ("transmission", PrincipledInput::Transmission),
+    ];
+
+    fn apply(self, material: &mut PrincipledMaterial, value: Color) {
+        let scalar = value.luminance();
+        match self {
+            PrincipledInput::BaseColor => material.base_color = value,
+            PrincipledInput::Metallic => material.metallic = scalar.clamp(0.0, 1.0),
+            PrincipledInput::Roughness => material.roughness = scalar.clamp(0.0, 1.0),
+            PrincipledInput::Specular => material.specular = scalar.max(0.0),
+            PrincipledInput::Sheen => material.sheen = scalar.max(0.0),
+            PrincipledInput::SheenTint => material.sheen_tint = value,
+            PrincipledInput::Clearcoat => material.clearcoat = scalar.max(0.0),
+            PrincipledInput::ClearcoatRoughness => material.clearcoat_roughness = scalar.clamp(0.0, 1.0),
+            PrincipledInput::Transmission => material.transmission = scalar.clamp(0.0, 1.0),
+        }
+    }
+}
+
+//Principled material with some inputs driven by a node graph, evaluated at every shading call
+pub struct NodeMaterial {
+    pub graph: NodeGraph,
+    pub links: Vec<(PrincipledInput, NodeId)>,
+    //Inputs not linked keep the values set here
+    pub material: RefCell<PrincipledMaterial>,
+}
+
+impl NodeMaterial {
+    fn shade(&self, ray: &Ray, hit: &HitResult) -> Ref<'_, PrincipledMaterial> {
+        let values = self.graph.eval(ray, hit);
+        {
+            let mut material = self.material.borrow_mut();
+            for (input, node) in self.links.iter() {
+                input.apply(&mut material, values[*node]);
+            }
+        }
+        self.material.borrow()
+    }
+}
+
+impl Material for NodeMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        self.shade(ray, hit).bounce(ray, hit)
+    }
+
+    fn bounce_in(&self, ray: &Ray, hit: &HitResult, outside_ior: fVec) -> (Color, Option<Ray>) {
+        self.shade(ray, hit).bounce_in(ray, hit, outside_ior)
+    }
+
+    //A linked transmission is only known at a hit, those surfaces always get a medium
+    fn medium(&self) -> Option<Medium> {
+        let material = self.material.borrow();
+        if self.links.iter().any(|(input, _)| *input == PrincipledInput::Transmission) {
+            Some(Medium {
+                ior: material.ior,
+                priority: material.priority,
+                absorption: material.absorption,
+            })
+        } else {
+            material.medium()
+        }
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3) -> Color {
+        self.shade(ray, hit).eval(ray, hit, light_dir)
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitResult, dir: Vec3) -> fVec {
+        self.shade(ray, hit).pdf(ray, hit, dir)
+    }
+
+    fn albedo(&self, ray: &Ray, hit: &HitResult) -> Color {
+        self.shade(ray, hit).albedo(ray, hit)
+    }
+
+    fn preview(&self) -> Option<PreviewSurface> {
+        self.material.borrow().preview()
+    }
+}
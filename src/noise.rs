@@ -0,0 +1,137 @@
+use std::rc::Rc;
+
+use rand::{seq::SliceRandom, RngCore};
+
+use crate::image::*;
+use crate::linalg::*;
+use crate::material::rand_on_unit_sphere;
+use crate::texture::*;
+
+const PERLIN_SIZE: usize = 256;
+
+//Gradient noise on the integer lattice, smooth and roughly in [-1, 1]
+pub struct Perlin {
+    gradients: Vec<Vec3>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
+}
+
+impl Perlin {
+    pub fn new(rng: &mut (impl RngCore + ?Sized)) -> Self {
+        let mut perm = || {
+            let mut p: Vec<usize> = (0..PERLIN_SIZE).collect();
+            p.shuffle(rng);
+            p
+        };
+        let (perm_x, perm_y, perm_z) = (perm(), perm(), perm());
+        Self {
+            gradients: (0..PERLIN_SIZE).map(|_| rand_on_unit_sphere(rng)).collect(),
+            perm_x,
+            perm_y,
+            perm_z,
+        }
+    }
+
+    #[inline]
+    fn gradient(&self, x: i32, y: i32, z: i32) -> Vec3 {
+        let mask = PERLIN_SIZE as i32 - 1;
+        self.gradients[self.perm_x[(x & mask) as usize] ^ self.perm_y[(y & mask) as usize] ^ self.perm_z[(z & mask) as usize]]
+    }
+
+    pub fn noise(&self, p: Vec3) -> fVec {
+        let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let f = Vec3::new(p.x - x0, p.y - y0, p.z - z0);
+        let (x0, y0, z0) = (x0 as i32, y0 as i32, z0 as i32);
+        //Quintic fade keeps the second derivative continuous across cells
+        let fade = |t: fVec| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+        let (u, v, w) = (fade(f.x), fade(f.y), fade(f.z));
+
+        let mut sum = 0.0;
+        for (dx, dy, dz) in [(0, 0, 0), (1, 0, 0), (0, 1, 0), (1, 1, 0), (0, 0, 1), (1, 0, 1), (0, 1, 1), (1, 1, 1)] {
+            let corner = Vec3::new(dx as fVec, dy as fVec, dz as fVec);
+            let weight = (if dx == 1 { u } else { 1.0 - u })
+                * (if dy == 1 { v } else { 1.0 - v })
+                * (if dz == 1 { w } else { 1.0 - w });
+            sum += weight * (self.gradient(x0 + dx, y0 + dy, z0 + dz) * (f - corner));
+        }
+        sum
+    }
+
+    //Fractional Brownian motion: octaves of noise at doubling frequency and halving amplitude
+    pub fn fbm(&self, p: Vec3, octaves: usize) -> fVec {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut p = p;
+        for _ in 0..octaves {
+            sum += amplitude * self.noise(p);
+            amplitude *= 0.5;
+            p = p * 2.0;
+        }
+        sum
+    }
+
+    //Like fbm() with absolute values, giving creases where the noise crosses zero
+    pub fn turbulence(&self, p: Vec3, octaves: usize) -> fVec {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut p = p;
+        for _ in 0..octaves {
+            sum += amplitude * self.noise(p).abs();
+            amplitude *= 0.5;
+            p = p * 2.0;
+        }
+        sum
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoisePattern {
+    Fbm,
+    Turbulence,
+    //Veins along x distorted by turbulence
+    Marble { distortion: fVec },
+    //Rings around the y axis distorted by turbulence
+    Wood { rings: fVec, distortion: fVec },
+}
+
+//Solid texture blending between two colors by a noise pattern at the hit point
+pub struct NoiseTexture {
+    pub perlin: Rc<Perlin>,
+    pub pattern: NoisePattern,
+    //Frequency of the pattern in world space
+    pub scale: fVec,
+    pub octaves: usize,
+    pub first: Color,
+    pub second: Color,
+}
+
+impl NoiseTexture {
+    //Blend factor in [0, 1] at p, also usable for roughness or displacement
+    pub fn amount(&self, p: Vec3) -> fVec {
+        let p = p * self.scale;
+        let t = match self.pattern {
+            NoisePattern::Fbm => 0.5 * (1.0 + self.perlin.fbm(p, self.octaves)),
+            NoisePattern::Turbulence => self.perlin.turbulence(p, self.octaves),
+            NoisePattern::Marble { distortion } => {
+                0.5 * (1.0 + (p.x + distortion * self.perlin.turbulence(p, self.octaves)).sin())
+            }
+            NoisePattern::Wood { rings, distortion } => {
+                let r = (p.x * p.x + p.z * p.z).sqrt() * rings;
+                (r + distortion * self.perlin.turbulence(p, self.octaves)).rem_euclid(1.0)
+            }
+        };
+        t.clamp(0.0, 1.0)
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, _u: fVec, _v: fVec, p: Vec3) -> Color {
+        let t = self.amount(p);
+        self.first * (1.0 - t) + self.second * t
+    }
+
+    fn fvalue(&self, _u: fVec, _v: fVec, p: Vec3) -> fVec {
+        self.amount(p)
+    }
+}
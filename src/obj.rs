@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::linalg::*;
+use crate::mesh::*;
+
+//Subset of Wavefront OBJ: positions, normals, texture coordinates, polygonal faces and usemtl
+//groups. Polygons are fanned into triangles, everything else (mtllib, groups, smoothing, lines)
+//is ignored. Normals and uvs are kept only if every face corner has them.
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+//1 based index, negative ones count back from the last element read so far
+fn index(token: &str, count: usize) -> io::Result<usize> {
+    let i: i64 = token.parse().map_err(|_| invalid(&format!("bad index {}", token)))?;
+    let i = if i < 0 { count as i64 + i } else { i - 1 };
+    if i < 0 || i >= count as i64 {
+        return Err(invalid(&format!("index {} out of range", token)));
+    }
+    Ok(i as usize)
+}
+
+fn numbers<const N: usize>(fields: &[&str]) -> io::Result<[fVec; N]> {
+    let mut out: [fVec; N] = [0.0; N];
+    for (i, v) in out.iter_mut().enumerate() {
+        let field = fields.get(i).ok_or_else(|| invalid("missing coordinate"))?;
+        *v = field.parse().map_err(|_| invalid(&format!("bad number {}", field)))?;
+        if !v.is_finite() {
+            return Err(invalid("non-finite coordinate"));
+        }
+    }
+    Ok(out)
+}
+
+//Mesh with a material slot per triangle and the usemtl names of the slots, in order of first
+//use. Faces before the first usemtl have no material and take slot 0, the first material.
+pub fn load_obj(path: &Path) -> io::Result<(Mesh, Vec<String>)> {
+    let src = fs::read_to_string(path)?;
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut names: Vec<String> = Vec::new();
+    let mut slot = 0;
+
+    //Mesh vertices are unique (position, uv, normal) corners
+    let mut corners: HashMap<(usize, Option<usize>, Option<usize>), usize> = HashMap::new();
+    let mut mesh = Mesh {
+        vertices: Vec::new(),
+        normals: Vec::new(),
+        uvs: Vec::new(),
+        triangles: Vec::new(),
+        materials: Vec::new(),
+    };
+    let (mut all_normals, mut all_uvs) = (true, true);
+
+    for (n, line) in src.lines().enumerate() {
+        let context = |e: io::Error| invalid(&format!("{}:{}: {}", path.display(), n + 1, e));
+        let fields: Vec<&str> = line.split('#').next().unwrap_or("").split_whitespace().collect();
+        match fields.first().copied() {
+            Some("v") => {
+                let [x, y, z] = numbers(&fields[1..]).map_err(context)?;
+                positions.push(Vec3::new(x, y, z));
+            }
+            Some("vn") => {
+                let [x, y, z] = numbers(&fields[1..]).map_err(context)?;
+                normals.push(Vec3::new(x, y, z));
+            }
+            Some("vt") => {
+                let [u, v] = numbers(&fields[1..]).map_err(context)?;
+                uvs.push((u, v));
+            }
+            Some("usemtl") => {
+                let name = fields[1..].join(" ");
+                slot = match names.iter().position(|n| *n == name) {
+                    Some(i) => i,
+                    None => {
+                        names.push(name);
+                        names.len() - 1
+                    }
+                };
+            }
+            Some("f") => {
+                let mut face = Vec::with_capacity(fields.len() - 1);
+                for corner in fields[1..].iter() {
+                    let mut parts = corner.split('/');
+                    let v = index(parts.next().unwrap_or(""), positions.len()).map_err(context)?;
+                    let vt = match parts.next() {
+                        Some(t) if !t.is_empty() => Some(index(t, uvs.len()).map_err(context)?),
+                        _ => None,
+                    };
+                    let vn = match parts.next() {
+                        Some(t) if !t.is_empty() => Some(index(t, normals.len()).map_err(context)?),
+                        _ => None,
+                    };
+                    all_uvs &= vt.is_some();
+                    all_normals &= vn.is_some();
+                    let next = mesh.vertices.len();
+                    let i = *corners.entry((v, vt, vn)).or_insert(next);
+                    if i == next {
+                        mesh.vertices.push(positions[v]);
+                        mesh.uvs.push(vt.map_or((0.0, 0.0), |t| uvs[t]));
+                        mesh.normals.push(vn.map_or(Vec3::unit_y(), |t| normals[t]));
+                    }
+                    face.push(i);
+                }
+                if face.len() < 3 {
+                    return Err(context(invalid("face with less than 3 vertices")));
+                }
+                for k in 1..face.len() - 1 {
+                    mesh.triangles.push([face[0], face[k], face[k + 1]]);
+                    mesh.materials.push(slot);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !all_uvs {
+        mesh.uvs.clear();
+    }
+    if all_normals {
+        for n in mesh.normals.iter_mut() {
+            *n = if n.is_tiny(1e-12) { Vec3::unit_y() } else { n.unit() };
+        }
+    } else {
+        mesh.normals.clear();
+        mesh.compute_normals();
+    }
+    if names.len() <= 1 {
+        mesh.materials.clear();
+    }
+    Ok((mesh, names))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Faces before the first usemtl share slot 0 with it, repeated names reuse their slot
+    #[test]
+    fn usemtl_assigns_slots() {
+        let path = std::env::temp_dir().join("raytracing_usemtl_test.obj");
+        fs::write(
+            &path,
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+             f 1 2 3\nusemtl red\nf 1 3 4\nusemtl blue\nf 1 2 3 4\nusemtl red\nf -4 -3 -2\n",
+        )
+        .unwrap();
+        let (mesh, names) = load_obj(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(names, ["red", "blue"]);
+        assert_eq!(mesh.triangles.len(), 5);
+        assert_eq!(mesh.materials, [0, 0, 1, 1, 0]);
+        assert_eq!(mesh.vertices.len(), 4);
+        assert!(mesh.uvs.is_empty());
+    }
+}
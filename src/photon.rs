@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use rand::{Rng, RngCore};
+
+use crate::image::*;
+use crate::linalg::*;
+use crate::tracer::*;
+
+const MAX_PHOTON_BOUNCES: usize = 16;
+
+struct Photon {
+    position: Vec3,
+    //Unit direction the photon was travelling in when it was stored
+    direction: Vec3,
+    power: Color,
+}
+
+//Caustic photons (light -> specular+ -> diffuse) in a hashed grid with cells of size 2 * radius
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    radius: fVec,
+    grid: HashMap<(i32, i32, i32), Vec<usize>>,
+}
+
+impl PhotonMap {
+    pub fn build(scene: &Scene, count: usize, radius: fVec, rng: &mut impl RngCore) -> PhotonMap {
+        let mut map = PhotonMap {
+            photons: Vec::new(),
+            radius,
+            grid: HashMap::new(),
+        };
+        let lights = scene.lights();
+        if lights.is_empty() || count == 0 {
+            return map;
+        }
+
+        for _ in 0..count {
+            let light = &lights[rng.gen_range(0..lights.len())];
+            let (mut ray, power) = match light.emit(rng) {
+                Some(p) => p,
+                None => continue,
+            };
+            let mut power = power * (lights.len() as fCol / count as fCol);
+            let mut specular = false;
+
+            for _ in 0..MAX_PHOTON_BOUNCES {
+                let (hit, obj) = match scene.hit(&ray) {
+                    Some(h) => h,
+                    None => break,
+                };
+                let material = obj.material_at(&hit);
+                if !material.is_specular() {
+                    if specular {
+                        map.photons.push(Photon {
+                            position: hit.intersect,
+                            direction: ray.direction.unit(),
+                            power,
+                        });
+                    }
+                    break;
+                }
+                match material.bounce(&ray, &hit) {
+                    (col, Some(bounced)) => {
+                        power = power * col;
+                        ray = bounced;
+                        specular = true;
+                    }
+                    (_, None) => break,
+                }
+            }
+        }
+
+        for (i, photon) in map.photons.iter().enumerate() {
+            let cell = map.cell(photon.position);
+            map.grid.entry(cell).or_default().push(i);
+        }
+        map
+    }
+
+    fn cell(&self, p: Vec3) -> (i32, i32, i32) {
+        let size = 2.0 * self.radius;
+        (
+            (p.x / size).floor() as i32,
+            (p.y / size).floor() as i32,
+            (p.z / size).floor() as i32,
+        )
+    }
+
+    //Radiance reflected towards the ray from photons within the gather radius
+    pub fn estimate(&self, ray: &Ray, hit: &HitResult, material: &dyn Material) -> Color {
+        if self.photons.is_empty() {
+            return Color::black();
+        }
+
+        let r2 = self.radius * self.radius;
+        let (cx, cy, cz) = self.cell(hit.intersect);
+        let mut sum = Color::black();
+
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let indices = match self.grid.get(&(cx + dx, cy + dy, cz + dz)) {
+                        Some(i) => i,
+                        None => continue,
+                    };
+                    for &i in indices {
+                        let photon = &self.photons[i];
+                        let d = photon.position - hit.intersect;
+                        if d * d > r2 {
+                            continue;
+                        }
+                        let light_dir = -photon.direction;
+                        let cos = hit.normal * light_dir;
+                        if cos <= 0.0 {
+                            continue;
+                        }
+                        //eval includes the cosine term which the photon density already accounts for
+                        sum = sum + material.eval(ray, hit, light_dir) * photon.power * (1.0 / cos);
+                    }
+                }
+            }
+        }
+
+        sum * (1.0 / (std::f32::consts::PI * r2))
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::{stdout, Write};
+use std::time::Duration;
+
+//State of a render, reported after every tile
+#[derive(Clone, Debug, Default)]
+pub struct Progress {
+    //Including tiles already done before this render started, e.g. from a checkpoint
+    pub tiles_done: usize,
+    pub tiles: usize,
+    //Pixel samples of this render so far and in total, without tiles done before it started
+    pub samples_done: u64,
+    pub samples: u64,
+    pub elapsed: Duration,
+    //Extrapolated from the samples so far, None until the first tile is done
+    pub eta: Option<Duration>,
+    //Camera rays per second, each starting a whole path
+    pub rays_per_second: f64,
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f64 {
+        if self.samples == 0 {
+            1.0
+        } else {
+            self.samples_done as f64 / self.samples as f64
+        }
+    }
+
+    //Account for a finished tile, camera_rays and elapsed are totals of the render so far
+    pub(crate) fn tile_done(&mut self, samples: u64, camera_rays: u64, elapsed: Duration) {
+        self.tiles_done += 1;
+        self.samples_done += samples;
+        self.elapsed = elapsed;
+        let secs = elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.rays_per_second = camera_rays as f64 / secs;
+        }
+        if self.samples_done > 0 {
+            let left = self.samples.saturating_sub(self.samples_done) as f64 / self.samples_done as f64;
+            self.eta = Some(elapsed.mul_f64(left));
+        }
+    }
+}
+
+//Receives progress of a Renderer, e.g. to drive a progress bar or log. All methods are called
+//from the rendering thread between tiles, so they should return quickly.
+pub trait ProgressSink {
+    //A render step not made of tiles starts, like the passes of the half resolution mode
+    fn phase(&self, _name: &str) {}
+
+    fn update(&self, progress: &Progress);
+
+    //Also called when the render was interrupted
+    fn finish(&self, _progress: &Progress) {}
+
+    //Something went wrong on the side without stopping the render, like writing a proxy image
+    fn warning(&self, _message: &str) {}
+}
+
+//Single status line on stdout, the default of a Renderer
+pub struct ConsoleProgress;
+
+impl ProgressSink for ConsoleProgress {
+    fn phase(&self, name: &str) {
+        println!("{}", name);
+    }
+
+    fn update(&self, progress: &Progress) {
+        print!("\rTiles done: {}/{}", progress.tiles_done, progress.tiles);
+        if let Some(eta) = progress.eta {
+            print!(", {:.1} Mrays/s, {}s left   ", progress.rays_per_second / 1e6, eta.as_secs());
+        }
+        //A failed flush only delays the status line
+        let _ = stdout().flush();
+    }
+
+    fn finish(&self, _progress: &Progress) {
+        println!();
+    }
+
+    fn warning(&self, message: &str) {
+        eprintln!("\nWarning: {}", message);
+    }
+}
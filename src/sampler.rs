@@ -0,0 +1,95 @@
+use std::f32::consts::PI;
+
+use crate::linalg::*;
+
+//How camera rays pick their position within the pixel, on the lens and in the shutter interval
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Sampler {
+    //Independent random numbers per sample
+    Random,
+    //Halton sequence shared by all pixels, decorrelated per pixel by a random toroidal shift
+    //(Cranley-Patterson rotation) so its structure doesn't repeat across the image
+    Halton,
+}
+
+const PRIMES: [u32; 5] = [2, 3, 5, 7, 11];
+
+//Van der Corput sequence in the given base, mirrored digits of index after the radix point
+pub(crate) fn radical_inverse(base: u32, mut index: u64) -> fVec {
+    let inv_base = 1.0 / base as f64;
+    let mut inv = inv_base;
+    let mut result = 0.0;
+    while index > 0 {
+        result += (index % base as u64) as f64 * inv;
+        index /= base as u64;
+        inv *= inv_base;
+    }
+    (result as fVec).min(1.0 - fVec::EPSILON)
+}
+
+//Dimension dim of the Halton point with the given index, shifted by rotation modulo 1
+#[inline]
+pub(crate) fn halton_rotated(dim: usize, index: u64, rotation: fVec) -> fVec {
+    (radical_inverse(PRIMES[dim], index) + rotation).fract()
+}
+
+pub(crate) const HALTON_DIMENSIONS: usize = PRIMES.len();
+
+//Map the unit square onto the unit disc, preserving stratification (Shirley-Chiu)
+pub(crate) fn concentric_disc(u: fVec, v: fVec) -> (fVec, fVec) {
+    let a = 2.0 * u - 1.0;
+    let b = 2.0 * v - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, (PI / 4.0) * (b / a))
+    } else {
+        (b, PI / 2.0 - (PI / 4.0) * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+//Shape of the lens opening, which out of focus highlights take on
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Bokeh {
+    //Straight aperture blades forming a regular polygon, 0 for a round opening
+    pub blades: u32,
+    //Degrees clockwise, 0 puts a corner at the top
+    pub rotation: fVec,
+    //Squashes the opening towards the film center the further out the film position is, by up
+    //to this fraction in the corners, like the cat's eye bokeh of lenses vignetting at the edges.
+    //The light that would be cut off is kept.
+    pub cat_eye: fVec,
+}
+
+impl Bokeh {
+    //Point on the opening inscribed in the unit disc, as (up, right), from a sample u, v in
+    //[0, 1]^2 at film coordinates s, t in [0, 1] from the top left
+    pub fn sample(&self, u: fVec, v: fVec, s: fVec, t: fVec) -> (fVec, fVec) {
+        let (mut up, mut right) = if self.blades < 3 {
+            concentric_disc(u, v)
+        } else {
+            //Uniform in one of the triangles between the center and two neighboring corners
+            let n = self.blades as fVec;
+            let blade = (u * n).floor().min(n - 1.0);
+            let u = u * n - blade;
+            let corner = |i: fVec| (self.rotation.to_radians() + 2.0 * PI * i / n).sin_cos();
+            let ((r0, u0), (r1, u1)) = (corner(blade), corner(blade + 1.0));
+            let a = u.sqrt();
+            (a * ((1.0 - v) * u0 + v * u1), a * ((1.0 - v) * r0 + v * r1))
+        };
+        if self.cat_eye > 0.0 {
+            let (out_up, out_right) = (1.0 - 2.0 * t, 2.0 * s - 1.0);
+            let out = (out_up * out_up + out_right * out_right).sqrt();
+            if out > 0.0 {
+                let (dir_up, dir_right) = (out_up / out, out_right / out);
+                let squash = (1.0 - self.cat_eye * out / 2f32.sqrt()).max(0.0);
+                let along = (up * dir_up + right * dir_right) * (squash - 1.0);
+                up += along * dir_up;
+                right += along * dir_right;
+            }
+        }
+        (up, right)
+    }
+}
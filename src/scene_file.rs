@@ -0,0 +1,728 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use rand::prelude::*;
+use rand::rngs::SmallRng;
+
+use crate::error::*;
+use crate::hit::*;
+use crate::image::*;
+use crate::json::*;
+use crate::light::*;
+use crate::linalg::*;
+use crate::material::*;
+use crate::mesh::*;
+use crate::nodes::*;
+use crate::obj::*;
+use crate::texture::*;
+use crate::tracer::*;
+
+//JSON scene format, version 1. Meant as the target of DCC exporters such as a Blender add-on.
+//
+//Coordinates are in scene units with +Y up and a left handed frame. Blender (+Z up, right
+//handed) positions and normals convert by swapping y and z: (x, y, z) -> (x, z, y).
+//
+//{
+//  "version": 1,
+//  "camera": {"from": [x, y, z], "at": [x, y, z], "fov": 45, "aperture": 0, "focus_distance": |at - from|,
+//             "blades": 0, "blade_rotation": 0, "cat_eye": 0, "shift": [0, 0], "tilt": [0, 0]},
+//  ("focus_distance": "auto" focuses on the surface at the center of the image)
+//  (to match camera metadata also "film_back": [36, 24], "focal_length": 50 and "film_offset": [0, 0]
+//   in millimeters, and "overscan": [x, y] pixels of the image around the frame)
+//  (every camera also takes "up": [0, 1, 0] and "roll": 0 in degrees clockwise, or a camera to world
+//   "matrix" of 16 numbers in glTF's column major order instead of "from", "at" and "up", which is
+//   right handed and mirrored into the scene frame, see camera_matrix_pose())
+//  (and for motion blur "motion": [{"time": t, "from": [x, y, z], "at": [x, y, z], "up", "roll"}, ...]
+//   with "shutter": [open, close] defaulting to the first and last time)
+//  "camera": {"projection": "orthographic", "from": [x, y, z], "at": [x, y, z], "view_width": w},
+//  "camera": {"projection": "fisheye", "from": [x, y, z], "at": [x, y, z], "fov": 180,
+//             "mapping": "equidistant" | "equisolid", "aperture": 0, "focus_distance": |at - from|,
+//             "blades": 0, "blade_rotation": 0, "cat_eye": 0},
+//  "background": [r, g, b],
+//  "environment": {"type": "gradient", "color": [r, g, b], "strength": 1},
+//  "materials": {
+//    "<name>": {
+//      "base_color": [r, g, b], "metallic": 0, "roughness": 0.5, "specular": 0.5,
+//      "sheen": 0, "sheen_tint": [1, 1, 1], "sheen_roughness": 0.5,
+//      "clearcoat": 0, "clearcoat_roughness": 0.03, "transmission": 0,
+//      "ior": 1.45, "alpha": 1, "emission": [r, g, b], "emission_strength": 0,
+//      "metallic_texture": "metal.png", "roughness_texture": "rough.png",
+//      "nodes": {"<node>": {"type": "...", ...}}
+//    }
+//  },
+//  "objects": [
+//    {"type": "mesh", "file": "cube.mesh", "material": "<name>", "scale": 1, "offset": [x, y, z]},
+//    {"type": "mesh", "file": "car.mesh", "materials": ["<name>", "<name>"]},
+//    {"type": "mesh", "file": "chair.obj"},
+//    {"type": "mesh", "file": "rock.mesh", "displacement": {"file": "height.png", "scale": 0.1,
+//     "max_edge": 0.05, "max_triangles": 1000000}},
+//    {"type": "sphere", "center": [x, y, z], "radius": 1, "material": "<name>"},
+//    {"type": "torus", "center": [x, y, z], "major_radius": 1, "minor_radius": 0.25, "material": "<name>"}
+//  ],
+//  "lights": [
+//    {"type": "point", "position": [x, y, z], "color": [r, g, b], "intensity": 10},
+//    {"type": "spot", "position": [x, y, z], "direction": [x, y, z], "color": [r, g, b],
+//     "intensity": 10, "angle": 30, "blend": 0.15}
+//  ]
+//}
+//
+//Materials carry the Principled BSDF inputs under Blender's names, anything left out takes
+//Blender's default. Grayscale textures, relative to the JSON file, scale metallic and roughness.
+//A positive emission_strength makes an emitter, alpha below 1 a cutout.
+//
+//Nodes build a shader graph, see nodes.rs. The inputs base_color, metallic, roughness, specular,
+//sheen, sheen_tint, clearcoat, clearcoat_roughness and transmission can name a node instead of
+//holding a value. Node inputs are numbers, [r, g, b] or the name of a node listed before:
+//  {"type": "image", "file": "wood.png"}          color texture
+//  {"type": "data", "file": "bump.png"}           non-color texture
+//  {"type": "uv"}, {"type": "position"}
+//  {"type": "fresnel", "ior": 1.45}
+//  {"type": "math", "op": "multiply", "a": 1, "b": 1}
+//     op is add, subtract, multiply, divide, power, minimum or maximum
+//  {"type": "mix", "factor": 0.5, "a": [0, 0, 0], "b": [1, 1, 1]}
+//The environment lights everything from infinitely far away. A gradient fades from black straight
+//down to color straight up, a uniform one is color in every direction. "background" is short for
+//a gradient environment, "environment" wins if both are given.
+//Objects take an optional "name" for cryptomatte mattes, "object<index>" otherwise.
+//Light intensity is in radiance units per steradian, spot angles are half angles in degrees.
+//Unknown keys are ignored so exporters can add data without breaking older loaders.
+//
+//Mesh files, relative to the JSON file, hold little endian binary data:
+//  magic          8 bytes  "RTMESH\0\x01"
+//  vertex count   u32
+//  triangle count u32
+//  flags          u32      bit 0: normals present, bit 1: uvs present,
+//                          bit 2: material slots present
+//  positions      f32 x 3 per vertex
+//  normals        f32 x 3 per vertex, if flagged
+//  uvs            f32 x 2 per vertex, if flagged
+//  indices        u32 x 3 per triangle
+//  material slots u32 per triangle, if flagged
+//Meshes without normals get smooth normals computed on load. Files ending in .obj are read as
+//Wavefront OBJ instead, see obj.rs: each usemtl group is a slot, using the scene material of the
+//same name or the default one, and faces before the first usemtl belong to the first slot.
+//A mesh object lists a material per slot under "materials", slots past the end of the list use
+//its first, or one for all slots under "material". Objects naming the same
+//file share its triangles, "scale" and "offset" place each copy. A displacement moves the vertices
+//along their normals by scale times the height texture, after splitting edges longer than
+//max_edge (a 16th of the longest edge by default), both in the units of the mesh file. Displaced
+//meshes aren't shared.
+
+pub(crate) const SCENE_FILE_VERSION: usize = 1;
+pub(crate) const MESH_MAGIC: &[u8; 8] = b"RTMESH\0\x01";
+
+pub struct SceneFile {
+    pub scene: Scene,
+    pub camera: Option<Box<dyn CameraModel>>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn number(obj: &Json, key: &str, default: fVec) -> io::Result<fVec> {
+    match obj.get(key) {
+        None => Ok(default),
+        Some(v) => v
+            .as_f64()
+            .map(|n| n as fVec)
+            .ok_or_else(|| invalid(&format!("{} must be a number", key))),
+    }
+}
+
+fn triple(obj: &Json, key: &str) -> io::Result<Option<[fVec; 3]>> {
+    let v = match obj.get(key) {
+        None => return Ok(None),
+        Some(v) => v,
+    };
+    match v.as_array() {
+        Some([a, b, c]) => match (a.as_f64(), b.as_f64(), c.as_f64()) {
+            (Some(a), Some(b), Some(c)) => Ok(Some([a as fVec, b as fVec, c as fVec])),
+            _ => Err(invalid(&format!("{} must contain numbers", key))),
+        },
+        _ => Err(invalid(&format!("{} must be an array of 3 numbers", key))),
+    }
+}
+
+fn pair(obj: &Json, key: &str) -> io::Result<Option<[fVec; 2]>> {
+    let v = match obj.get(key) {
+        None => return Ok(None),
+        Some(v) => v,
+    };
+    match v.as_array() {
+        Some([a, b]) => match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => Ok(Some([a as fVec, b as fVec])),
+            _ => Err(invalid(&format!("{} must contain numbers", key))),
+        },
+        _ => Err(invalid(&format!("{} must be an array of 2 numbers", key))),
+    }
+}
+
+//Lens position, look at point and up vector from "matrix" or "from", "at" and "up"
+fn camera_pose(obj: &Json, default_up: Vec3) -> io::Result<(Vec3, Vec3, Vec3)> {
+    if let Some(matrix) = obj.get("matrix") {
+        let values: Vec<fVec> = match matrix.as_array() {
+            Some(values) if values.len() == 16 => values
+                .iter()
+                .map(|v| v.as_f64().map(|v| v as fVec))
+                .collect::<Option<_>>()
+                .ok_or_else(|| invalid("matrix must contain numbers"))?,
+            _ => return Err(invalid("matrix must be an array of 16 numbers")),
+        };
+        //Column by column like glTF
+        let pose = camera_matrix_pose(std::array::from_fn(|row| std::array::from_fn(|col| values[col * 4 + row])));
+        let finite = |v: Vec3| v.x.is_finite() && v.y.is_finite() && v.z.is_finite();
+        if !(finite(pose.0) && finite(pose.1) && finite(pose.2)) {
+            return Err(invalid("matrix must have a non-zero z axis"));
+        }
+        return Ok(pose);
+    }
+    let up = triple(obj, "up")?.map_or(default_up, |[x, y, z]| Vec3::new(x, y, z));
+    Ok((vec3(obj, "from")?, vec3(obj, "at")?, up))
+}
+
+fn vec3(obj: &Json, key: &str) -> io::Result<Vec3> {
+    let [x, y, z] = triple(obj, key)?.ok_or_else(|| invalid(&format!("missing {}", key)))?;
+    Ok(Vec3::new(x, y, z))
+}
+
+fn color(obj: &Json, key: &str, default: Color) -> io::Result<Color> {
+    Ok(triple(obj, key)?.map_or(default, |[r, g, b]| Color::new(r, g, b)))
+}
+
+fn items<'a>(doc: &'a Json, key: &str) -> io::Result<&'a [Json]> {
+    match doc.get(key) {
+        None => Ok(&[]),
+        Some(v) => v.as_array().ok_or_else(|| invalid(&format!("{} must be an array", key))),
+    }
+}
+
+fn environment(env: &Json) -> io::Result<Box<dyn Environment>> {
+    let color = color(env, "color", Color::new(1.0, 1.0, 1.0))? * number(env, "strength", 1.0)?;
+    match env.get("type").and_then(|t| t.as_str()) {
+        Some("gradient") => Ok(Box::new(Background { color })),
+        Some("uniform") => Ok(Box::new(UniformEnvironment { color })),
+        Some(other) => Err(invalid(&format!("unknown type {}", other))),
+        None => Err(invalid("missing type")),
+    }
+}
+
+//Load a scene, the camera renders at width x height
+pub fn load_scene_file(path: &str, width: usize, height: usize, seed: u64) -> Result<SceneFile> {
+    SceneDescription::read(path)?.build(width, height, seed)
+}
+
+//Scene file read and parsed once, for building several copies of the scene. Scenes can't be
+//shared across threads, so every render thread builds its own.
+pub struct SceneDescription {
+    path: String,
+    doc: Json,
+}
+
+impl SceneDescription {
+    pub fn read(path: &str) -> Result<SceneDescription> {
+        let doc = fs::read_to_string(path)
+            .and_then(|text| Json::parse(&text))
+            .map_err(|e| Error::scene(path, e))?;
+        Ok(SceneDescription {
+            path: path.to_string(),
+            doc,
+        })
+    }
+
+    //The camera renders at width x height, seed only seeds the random state of the materials
+    pub fn build(&self, width: usize, height: usize, seed: u64) -> Result<SceneFile> {
+        let dir = Path::new(&self.path).parent().unwrap_or(Path::new(""));
+        build(&self.doc, dir, width, height, seed).map_err(|e| Error::scene(&self.path, e))
+    }
+}
+
+fn build(doc: &Json, dir: &Path, width: usize, height: usize, seed: u64) -> io::Result<SceneFile> {
+    let mut rng = SmallRng::seed_from_u64(seed);
+
+    match doc.get("version").and_then(Json::as_usize) {
+        Some(SCENE_FILE_VERSION) => {}
+        Some(v) => return Err(invalid(&format!("unsupported version {}", v))),
+        None => return Err(invalid("missing version")),
+    }
+
+    let mut materials: HashMap<&str, Rc<dyn Material>> = HashMap::new();
+    match doc.get("materials") {
+        None => {}
+        Some(Json::Object(entries)) => {
+            for (name, desc) in entries.iter() {
+                let material = principled(desc, dir, &mut rng).map_err(|e| invalid(&format!("material {}: {}", name, e)))?;
+                materials.insert(name, material);
+            }
+        }
+        Some(_) => return Err(invalid("materials must be an object")),
+    }
+    let named = |name: &str| -> io::Result<Rc<dyn Material>> {
+        materials
+            .get(name)
+            .cloned()
+            .ok_or_else(|| invalid(&format!("unknown material {}", name)))
+    };
+    let material = |obj: &Json, rng: &mut SmallRng| -> io::Result<Rc<dyn Material>> {
+        match obj.get("material").map(|m| m.as_str()) {
+            None => Ok(principled(&Json::Object(Vec::new()), dir, rng)?),
+            Some(Some(name)) => named(name),
+            Some(None) => Err(invalid("material must be a name")),
+        }
+    };
+
+    let mut scene = Scene::new();
+    let mut meshes: HashMap<&str, Rc<MeshObject>> = HashMap::new();
+    for (i, obj) in items(doc, "objects")?.iter().enumerate() {
+        let context = |e: io::Error| invalid(&format!("object {}: {}", i, e));
+        let id = match obj.get("type").and_then(Json::as_str) {
+            Some("mesh") => {
+                let file = obj.get("file").and_then(Json::as_str).ok_or_else(|| context(invalid("missing file")))?;
+                let shared = match (meshes.get(file), obj.get("displacement")) {
+                    (Some(mesh), None) => mesh.clone(),
+                    (_, displacement) => {
+                        let (mut mesh, slot_names) = if file.ends_with(".obj") {
+                            load_obj(&dir.join(file)).map_err(context)?
+                        } else {
+                            (load_mesh(&dir.join(file)).map_err(context)?, Vec::new())
+                        };
+                        if let Some(desc) = displacement {
+                            mesh = displaced(mesh, desc, dir).map_err(|e| context(invalid(&format!("displacement: {}", e))))?;
+                        }
+                        let default = principled(&Json::Object(Vec::new()), dir, &mut rng)?;
+                        let slots = if slot_names.is_empty() {
+                            vec![default]
+                        } else {
+                            let slot = |name: &String| materials.get(name.as_str()).cloned().unwrap_or_else(|| default.clone());
+                            slot_names.iter().map(slot).collect()
+                        };
+                        let mesh = Rc::new(MeshObject::with_materials(mesh, slots));
+                        if displacement.is_none() {
+                            meshes.insert(file, mesh.clone());
+                        }
+                        mesh
+                    }
+                };
+                let mut instance = MeshInstance::new(shared);
+                instance.scale = number(obj, "scale", 1.0).map_err(context)?;
+                if instance.scale.is_nan() || instance.scale <= 0.0 {
+                    return Err(context(invalid("scale must be positive")));
+                }
+                instance.offset = triple(obj, "offset").map_err(context)?.map_or(Vec3::origin(), |[x, y, z]| Vec3::new(x, y, z));
+                match (obj.get("materials"), obj.get("material")) {
+                    //Keeps the materials of the mesh's own slots
+                    (None, None) => {}
+                    (None, Some(_)) => instance.material = Some(material(obj, &mut rng).map_err(context)?),
+                    (Some(list), _) => {
+                        let names = list.as_array().ok_or_else(|| context(invalid("materials must be an array")))?;
+                        for name in names {
+                            let name = name.as_str().ok_or_else(|| context(invalid("materials must be names")))?;
+                            instance.overrides.push(Some(named(name).map_err(context)?));
+                        }
+                        //Slots past the end of the list take its first material
+                        instance.material = instance.overrides.first().cloned().flatten();
+                    }
+                }
+                scene.add(Box::new(instance))
+            }
+            Some("sphere") => scene.add(Box::new(Sphere {
+                origin: vec3(obj, "center").map_err(context)?,
+                radius: number(obj, "radius", 1.0).map_err(context)?,
+                material: material(obj, &mut rng).map_err(context)?,
+            })),
+            Some("torus") => {
+                let minor_radius = number(obj, "minor_radius", 0.25).map_err(context)?;
+                if minor_radius <= 0.0 {
+                    return Err(context(invalid("minor_radius must be positive")));
+                }
+                scene.add(Box::new(Torus {
+                    origin: vec3(obj, "center").map_err(context)?,
+                    major_radius: number(obj, "major_radius", 1.0).map_err(context)?,
+                    minor_radius,
+                    material: material(obj, &mut rng).map_err(context)?,
+                }))
+            }
+            Some(other) => return Err(context(invalid(&format!("unknown type {}", other)))),
+            None => return Err(context(invalid("missing type"))),
+        };
+        match obj.get("name").map(Json::as_str) {
+            None => {}
+            Some(Some(name)) => scene.set_object_name(id, name),
+            Some(None) => return Err(context(invalid("name must be a string"))),
+        }
+    }
+    //For the material mattes
+    for (name, material) in materials.iter() {
+        scene.set_material_name(material.clone(), name);
+    }
+
+    for (i, light) in items(doc, "lights")?.iter().enumerate() {
+        let context = |e: io::Error| invalid(&format!("light {}: {}", i, e));
+        let color = color(light, "color", Color::white()).map_err(context)?;
+        let intensity = number(light, "intensity", 1.0).map_err(context)?;
+        match light.get("type").and_then(Json::as_str) {
+            Some("point") => scene.add_light(Box::new(PointLight {
+                origin: vec3(light, "position").map_err(context)?,
+                color,
+                intensity,
+                profile: None,
+            })),
+            Some("spot") => scene.add_light(Box::new(SpotLight {
+                origin: vec3(light, "position").map_err(context)?,
+                direction: vec3(light, "direction").map_err(context)?,
+                color,
+                intensity,
+                angle: number(light, "angle", 30.0).map_err(context)?,
+                blend: number(light, "blend", 0.15).map_err(context)?,
+                profile: None,
+            })),
+            Some(other) => return Err(context(invalid(&format!("unknown type {}", other)))),
+            None => return Err(context(invalid("missing type"))),
+        }
+    }
+
+    if let Some(background) = triple(doc, "background")? {
+        let [r, g, b] = background;
+        scene.set_environment(Box::new(Background {
+            color: Color::new(r, g, b),
+        }));
+    }
+    if let Some(env) = doc.get("environment") {
+        scene.set_environment(environment(env).map_err(|e| invalid(&format!("environment: {}", e)))?);
+    }
+
+    let camera: Option<Box<dyn CameraModel>> = match doc.get("camera") {
+        None => None,
+        Some(cam) => {
+            let context = |e: io::Error| invalid(&format!("camera: {}", e));
+            let (from, at, up) = camera_pose(cam, Vec3::unit_y()).map_err(context)?;
+            let roll = number(cam, "roll", 0.0).map_err(context)?;
+            let blades = match cam.get("blades") {
+                None => 0,
+                Some(n) => n.as_usize().ok_or_else(|| invalid("camera: blades must be a whole number"))? as u32,
+            };
+            let camera: Box<dyn CameraModel> = match cam
+                .get("projection")
+                .map(|p| p.as_str().ok_or_else(|| invalid("camera: projection must be a string")))
+            {
+                None | Some(Ok("perspective")) => {
+                    let mut builder = Camera::builder(from, at)
+                        .resolution(width, height)
+                        .up(up)
+                        .roll(roll)
+                        .fov(number(cam, "fov", 45.0).map_err(context)?)
+                        .aperture(number(cam, "aperture", 0.0).map_err(context)?)
+                        .blades(blades, number(cam, "blade_rotation", 0.0).map_err(context)?)
+                        .cat_eye(number(cam, "cat_eye", 0.0).map_err(context)?);
+                    if let Some([x, y]) = pair(cam, "shift").map_err(context)? {
+                        builder = builder.shift(x, y);
+                    }
+                    if let Some([x, y]) = pair(cam, "tilt").map_err(context)? {
+                        builder = builder.tilt(x, y);
+                    }
+                    if let Some([w, h]) = pair(cam, "film_back").map_err(context)? {
+                        builder = builder.film_back(w, h);
+                    }
+                    if cam.get("focal_length").is_some() {
+                        builder = builder.focal_length(number(cam, "focal_length", 0.0).map_err(context)?);
+                    }
+                    if let Some([x, y]) = pair(cam, "film_offset").map_err(context)? {
+                        builder = builder.film_offset(x, y);
+                    }
+                    if let Some([x, y]) = pair(cam, "overscan").map_err(context)? {
+                        if [x, y].iter().any(|v| *v < 0.0 || v.fract() != 0.0) {
+                            return Err(invalid("camera: overscan must be whole numbers of pixels"));
+                        }
+                        builder = builder.overscan(x as usize, y as usize);
+                    }
+                    builder = match cam.get("focus_distance") {
+                        None => builder,
+                        Some(Json::String(auto)) if auto == "auto" => builder.autofocus(&scene),
+                        Some(_) => builder.focus_distance(number(cam, "focus_distance", 0.0).map_err(context)?),
+                    };
+                    Box::new(builder.build().map_err(|e| context(e.into()))?)
+                }
+                Some(Ok("orthographic")) => {
+                    let mut builder = OrthographicCamera::builder(from, at).resolution(width, height).up(up).roll(roll);
+                    if cam.get("view_width").is_some() {
+                        builder = builder.view_width(number(cam, "view_width", 0.0).map_err(context)?);
+                    }
+                    Box::new(builder.build().map_err(|e| context(e.into()))?)
+                }
+                Some(Ok("fisheye")) => {
+                    let mapping = match cam.get("mapping").map(|m| m.as_str()) {
+                        None | Some(Some("equidistant")) => FisheyeMapping::Equidistant,
+                        Some(Some("equisolid")) => FisheyeMapping::Equisolid,
+                        _ => return Err(invalid("camera: mapping must be equidistant or equisolid")),
+                    };
+                    let mut builder = FisheyeCamera::builder(from, at)
+                        .resolution(width, height)
+                        .up(up)
+                        .roll(roll)
+                        .fov(number(cam, "fov", 180.0).map_err(context)?)
+                        .mapping(mapping)
+                        .aperture(number(cam, "aperture", 0.0).map_err(context)?)
+                        .blades(blades, number(cam, "blade_rotation", 0.0).map_err(context)?)
+                        .cat_eye(number(cam, "cat_eye", 0.0).map_err(context)?);
+                    builder = match cam.get("focus_distance") {
+                        None => builder,
+                        Some(Json::String(auto)) if auto == "auto" => builder.autofocus(&scene),
+                        Some(_) => builder.focus_distance(number(cam, "focus_distance", 0.0).map_err(context)?),
+                    };
+                    Box::new(builder.build().map_err(|e| context(e.into()))?)
+                }
+                Some(Ok(other)) => return Err(invalid(&format!("camera: unknown projection {}", other))),
+                Some(Err(e)) => return Err(e),
+            };
+
+            let motion = items(cam, "motion").map_err(context)?;
+            if motion.is_empty() {
+                Some(camera)
+            } else {
+                let context = |e: io::Error| invalid(&format!("camera: motion: {}", e));
+                let mut builder = MovingCamera::builder(camera);
+                for key in motion {
+                    let time = key.get("time").and_then(|t| t.as_f64()).ok_or_else(|| context(invalid("time must be a number")))?;
+                    let (from, at, up) = camera_pose(key, up).map_err(context)?;
+                    let roll = number(key, "roll", roll).map_err(context)?;
+                    builder = builder.key(time as fVec, from, at, up, roll).map_err(|e| context(e.into()))?;
+                }
+                if let Some([open, close]) = pair(cam, "shutter").map_err(context)? {
+                    builder = builder.shutter(open, close);
+                }
+                Some(Box::new(builder.build().map_err(|e| invalid(&format!("camera: {}", e)))?))
+            }
+        }
+    };
+
+    Ok(SceneFile { scene, camera })
+}
+
+//Nodes of a material in document order, by name
+fn node_graph(desc: &Json, dir: &Path) -> io::Result<(NodeGraph, HashMap<String, NodeId>)> {
+    let mut graph = NodeGraph::new();
+    let mut names = HashMap::new();
+    let entries = match desc.get("nodes") {
+        None => return Ok((graph, names)),
+        Some(Json::Object(entries)) => entries,
+        Some(_) => return Err(invalid("nodes must be an object")),
+    };
+
+    for (name, node) in entries.iter() {
+        let context = |e: io::Error| invalid(&format!("node {}: {}", name, e));
+        let input = |key: &str, default: fVec| -> io::Result<Input> {
+            match node.get(key) {
+                None => Ok(Input::from(default)),
+                Some(Json::String(other)) => names
+                    .get(other)
+                    .map(|id| Input::Node(*id))
+                    .ok_or_else(|| invalid(&format!("{} refers to unknown node {}", key, other))),
+                Some(Json::Number(n)) => Ok(Input::from(*n as fVec)),
+                Some(_) => Ok(Input::Value(color(node, key, Color::black())?)),
+            }
+        };
+        let file = || node.get("file").and_then(Json::as_str).ok_or_else(|| invalid("missing file"));
+        let parsed = match node.get("type").and_then(Json::as_str) {
+            Some("image") => file().and_then(|f| load_image_texture(&dir.join(f).to_string_lossy())).map(Node::Texture),
+            Some("data") => file()
+                .and_then(|f| ImageTexture::load_data(dir.join(f)))
+                .map(|tex| Node::Texture(Rc::new(tex))),
+            Some("uv") => Ok(Node::Uv),
+            Some("position") => Ok(Node::Position),
+            Some("fresnel") => input("ior", 1.45).map(|ior| Node::Fresnel { ior }),
+            Some("math") => {
+                let op = node.get("op").and_then(Json::as_str).unwrap_or("add");
+                match MathOp::from_name(op) {
+                    Some(op) => input("a", 0.0).and_then(|a| Ok(Node::Math { op, a, b: input("b", 0.0)? })),
+                    None => Err(invalid(&format!("unknown op {}", op))),
+                }
+            }
+            Some("mix") => input("factor", 0.5).and_then(|factor| {
+                Ok(Node::Mix {
+                    factor,
+                    a: input("a", 0.0)?,
+                    b: input("b", 1.0)?,
+                })
+            }),
+            Some(other) => Err(invalid(&format!("unknown type {}", other))),
+            None => Err(invalid("missing type")),
+        };
+        let id = graph.add(parsed.map_err(context)?).map_err(context)?;
+        names.insert(name.clone(), id);
+    }
+    Ok((graph, names))
+}
+
+//Principled BSDF inputs to the closest material
+fn principled(desc: &Json, dir: &Path, rng: &mut SmallRng) -> io::Result<Rc<dyn Material>> {
+    //Inputs naming a node are left out of the plain values
+    let (graph, names) = node_graph(desc, dir)?;
+    let mut links = Vec::new();
+    for (key, input) in PrincipledInput::ALL {
+        if let Some(name) = desc.get(key).and_then(Json::as_str) {
+            let node = names.get(name).ok_or_else(|| invalid(&format!("{} refers to unknown node {}", key, name)))?;
+            links.push((key, input, *node));
+        }
+    }
+    let desc = &match desc {
+        Json::Object(fields) => Json::Object(
+            fields
+                .iter()
+                .filter(|(k, _)| !links.iter().any(|(key, _, _)| key == k))
+                .cloned()
+                .collect(),
+        ),
+        other => other.clone(),
+    };
+
+    let strength = number(desc, "emission_strength", 0.0)?;
+    if strength > 0.0 {
+        let emission = color(desc, "emission", Color::white())?;
+        return Ok(Rc::new(EmissiveMaterial {
+            radiance: Rc::new(emission),
+            strength,
+        }));
+    }
+
+    let mut material = PrincipledMaterial::new(
+        color(desc, "base_color", Color::new(0.8, 0.8, 0.8))?,
+        Box::new(RefCell::new(SmallRng::seed_from_u64(rng.gen()))),
+    );
+    material.metallic = number(desc, "metallic", material.metallic)?.clamp(0.0, 1.0);
+    material.roughness = number(desc, "roughness", material.roughness)?.clamp(0.0, 1.0);
+    material.specular = number(desc, "specular", material.specular)?.max(0.0);
+    material.sheen = number(desc, "sheen", material.sheen)?.max(0.0);
+    material.sheen_tint = color(desc, "sheen_tint", material.sheen_tint)?;
+    material.sheen_roughness = number(desc, "sheen_roughness", material.sheen_roughness)?.clamp(0.0, 1.0);
+    material.clearcoat = number(desc, "clearcoat", material.clearcoat)?.max(0.0);
+    material.clearcoat_roughness = number(desc, "clearcoat_roughness", material.clearcoat_roughness)?.clamp(0.0, 1.0);
+    material.transmission = number(desc, "transmission", material.transmission)?.clamp(0.0, 1.0);
+    material.ior = number(desc, "ior", material.ior)?.max(1.0);
+    let data_map = |key: &str| -> io::Result<Option<Rc<dyn Texture>>> {
+        match desc.get(key).map(Json::as_str) {
+            None => Ok(None),
+            Some(Some(file)) => Ok(Some(Rc::new(ImageTexture::load_data(dir.join(file))?))),
+            Some(None) => Err(invalid(&format!("{} must be a file name", key))),
+        }
+    };
+    material.metallic_map = data_map("metallic_texture")?;
+    material.roughness_map = data_map("roughness_texture")?;
+
+    let material: Box<dyn Material> = if links.is_empty() {
+        Box::new(material)
+    } else {
+        Box::new(NodeMaterial {
+            graph,
+            links: links.into_iter().map(|(_, input, node)| (input, node)).collect(),
+            material: RefCell::new(material),
+        })
+    };
+
+    let alpha = number(desc, "alpha", 1.0)?.clamp(0.0, 1.0);
+    if alpha < 1.0 {
+        return Ok(Rc::new(CutoutMaterial {
+            base: material,
+            opacity: Rc::new(Color::new(alpha, alpha, alpha)),
+        }));
+    }
+    Ok(Rc::from(material))
+}
+
+fn displaced(mesh: Mesh, desc: &Json, dir: &Path) -> io::Result<Mesh> {
+    let file = desc.get("file").and_then(Json::as_str).ok_or_else(|| invalid("missing file"))?;
+    let height = ImageTexture::load_data(dir.join(file))?;
+    let scale = number(desc, "scale", 1.0)?;
+    let max_edge = number(desc, "max_edge", mesh.longest_edge() / 16.0)?;
+    if max_edge.is_nan() || max_edge <= 0.0 {
+        return Err(invalid("max_edge must be positive"));
+    }
+    let max_triangles = number(desc, "max_triangles", 1_000_000.0)?;
+    if max_triangles.is_nan() || max_triangles < 1.0 {
+        return Err(invalid("max_triangles must be at least 1"));
+    }
+    Ok(mesh.displaced(&height, scale, max_edge, max_triangles as usize))
+}
+
+pub(crate) fn load_mesh(path: &Path) -> io::Result<Mesh> {
+    let data = fs::read(path)?;
+    let context = |msg: &str| invalid(&format!("{}: {}", path.display(), msg));
+    if data.len() < 20 || &data[..8] != MESH_MAGIC {
+        return Err(context("not a mesh file"));
+    }
+    let mut pos = 8;
+    let u32_at = |pos: &mut usize| {
+        let v = u32::from_le_bytes(data[*pos..*pos + 4].try_into().unwrap());
+        *pos += 4;
+        v as usize
+    };
+    let vertex_count = u32_at(&mut pos);
+    let triangle_count = u32_at(&mut pos);
+    let flags = u32_at(&mut pos);
+    let (has_normals, has_uvs, has_slots) = (flags & 1 != 0, flags & 2 != 0, flags & 4 != 0);
+
+    let floats_per_vertex = 3 + if has_normals { 3 } else { 0 } + if has_uvs { 2 } else { 0 };
+    let ints_per_triangle = if has_slots { 4 } else { 3 };
+    let expected = 20 + 4 * (vertex_count * floats_per_vertex + ints_per_triangle * triangle_count);
+    if data.len() != expected {
+        return Err(context(&format!("expected {} bytes, found {}", expected, data.len())));
+    }
+
+    let floats = |n: usize, pos: &mut usize| -> Vec<fVec> {
+        let out = data[*pos..*pos + 4 * n]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        *pos += 4 * n;
+        out
+    };
+    let to_vec3 = |v: Vec<fVec>| -> Vec<Vec3> { v.chunks_exact(3).map(|c| Vec3::new(c[0], c[1], c[2])).collect() };
+
+    let vertices = to_vec3(floats(3 * vertex_count, &mut pos));
+    let normals = if has_normals {
+        to_vec3(floats(3 * vertex_count, &mut pos))
+    } else {
+        Vec::new()
+    };
+    let uvs = if has_uvs {
+        floats(2 * vertex_count, &mut pos).chunks_exact(2).map(|c| (c[0], c[1])).collect()
+    } else {
+        Vec::new()
+    };
+    if vertices.iter().chain(normals.iter()).any(|v| !(v.x.is_finite() && v.y.is_finite() && v.z.is_finite())) {
+        return Err(context("non-finite vertex data"));
+    }
+
+    let mut triangles = Vec::with_capacity(triangle_count);
+    for _ in 0..triangle_count {
+        let tri = [u32_at(&mut pos), u32_at(&mut pos), u32_at(&mut pos)];
+        if tri.iter().any(|&i| i >= vertex_count) {
+            return Err(context("vertex index out of range"));
+        }
+        triangles.push(tri);
+    }
+    let materials = if has_slots {
+        (0..triangle_count).map(|_| u32_at(&mut pos)).collect()
+    } else {
+        Vec::new()
+    };
+
+    let mut mesh = Mesh {
+        vertices,
+        normals,
+        uvs,
+        triangles,
+        materials,
+    };
+    if mesh.normals.is_empty() {
+        mesh.compute_normals();
+    } else {
+        for n in mesh.normals.iter_mut() {
+            *n = if n.is_tiny(1e-12) { Vec3::unit_y() } else { n.unit() };
+        }
+    }
+    Ok(mesh)
+}
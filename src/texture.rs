@@ -0,0 +1,320 @@
+use std::{collections::HashMap, fs, io, path::Path, rc::Rc};
+
+use crate::image::*;
+use crate::linalg::*;
+use crate::tracer::HitResult;
+
+//Spatially varying color, looked up by surface coordinates (u, v) in [0, 1] and the hit point
+pub trait Texture {
+    fn value(&self, u: fVec, v: fVec, p: Vec3) -> Color;
+
+    //Average over a footprint of the given width in uv units, for textures that alias when
+    //point sampled from far away
+    fn filtered(&self, u: fVec, v: fVec, p: Vec3, _width: fVec) -> Color {
+        self.value(u, v, p)
+    }
+
+    //Filtered lookup at a ray hit
+    #[inline]
+    fn at_hit(&self, hit: &HitResult) -> Color {
+        self.filtered(hit.uv.0, hit.uv.1, hit.intersect, hit.uv_width)
+    }
+
+    //Single channel for material parameters like roughness, the luminance of grayscale maps
+    fn fvalue(&self, u: fVec, v: fVec, p: Vec3) -> fVec {
+        self.value(u, v, p).luminance()
+    }
+}
+
+//Constant color everywhere
+impl Texture for Color {
+    #[inline]
+    fn value(&self, _u: fVec, _v: fVec, _p: Vec3) -> Color {
+        *self
+    }
+}
+
+//Constant gray everywhere, for scalar parameters
+impl Texture for fVec {
+    #[inline]
+    fn value(&self, _u: fVec, _v: fVec, _p: Vec3) -> Color {
+        Color::new(*self, *self, *self)
+    }
+
+    #[inline]
+    fn fvalue(&self, _u: fVec, _v: fVec, _p: Vec3) -> fVec {
+        *self
+    }
+}
+
+//Parameter factor scaled by an optional map at the hit, the glTF convention for textured parameters
+#[inline]
+pub(crate) fn scalar_at_hit(factor: fVec, map: &Option<Rc<dyn Texture>>, hit: &HitResult) -> fVec {
+    match map {
+        Some(map) => factor * map.fvalue(hit.uv.0, hit.uv.1, hit.intersect),
+        None => factor,
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Texture: {}", msg))
+}
+
+//One resolution of a mipmap, row major from the top
+struct MipLevel {
+    width: usize,
+    height: usize,
+    texels: Vec<Color>,
+}
+
+impl MipLevel {
+    #[inline]
+    fn texel(&self, x: isize, y: isize) -> Color {
+        let x = x.rem_euclid(self.width as isize) as usize;
+        let y = y.rem_euclid(self.height as isize) as usize;
+        self.texels[y * self.width + x]
+    }
+
+    //Bilinear interpolation between the four nearest texel centers
+    fn bilinear(&self, u: fVec, v: fVec) -> Color {
+        let x = u.rem_euclid(1.0) * self.width as fVec - 0.5;
+        let y = (1.0 - v.rem_euclid(1.0)) * self.height as fVec - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let (x0, y0) = (x0 as isize, y0 as isize);
+
+        let top = self.texel(x0, y0) * (1.0 - fx) + self.texel(x0 + 1, y0) * fx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - fx) + self.texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    //Half the resolution, each texel the average of the 2x2 block it covers
+    fn downsample(&self) -> MipLevel {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut texels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let (x0, y0) = (2 * x as isize, 2 * y as isize);
+                let (x1, y1) = ((x0 + 1).min(self.width as isize - 1), (y0 + 1).min(self.height as isize - 1));
+                let sum = self.texel(x0, y0) + self.texel(x1, y0) + self.texel(x0, y1) + self.texel(x1, y1);
+                texels.push(sum * 0.25);
+            }
+        }
+        MipLevel { width, height, texels }
+    }
+}
+
+//Bitmap wrapped around the surface, repeating outside [0, 1] with v pointing up.
+//Filtered lookups blend between the two mipmap levels closest to the footprint.
+pub struct ImageTexture {
+    //Full resolution first, down to 1x1
+    levels: Vec<MipLevel>,
+}
+
+impl ImageTexture {
+    //Loads a PNG, JPEG, BMP, Radiance HDR or OpenEXR file, chosen by extension
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_image(&Image::load(path)?)
+    }
+
+    //Loads a map of non-color data like roughness or metallic, whose values are used as stored
+    pub fn load_data(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_image(&Image::load_data(path)?)
+    }
+
+    fn from_image(img: &Image) -> io::Result<Self> {
+        Self::from_texels(img.width(), img.height(), img.straight_pixels())
+    }
+
+    fn from_texels(width: usize, height: usize, texels: Vec<Color>) -> io::Result<Self> {
+        if width == 0 || height == 0 || texels.len() != width * height {
+            return Err(invalid("image size does not match pixel data"));
+        }
+        let mut levels = vec![MipLevel { width, height, texels }];
+        while let Some(last) = levels.last().filter(|l| l.width > 1 || l.height > 1) {
+            levels.push(last.downsample());
+        }
+        Ok(Self { levels })
+    }
+
+    pub fn width(&self) -> usize {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> usize {
+        self.levels[0].height
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: fVec, v: fVec, _p: Vec3) -> Color {
+        self.levels[0].bilinear(u, v)
+    }
+
+    //Trilinear: the footprint covers 2^lod texels of the full resolution
+    fn filtered(&self, u: fVec, v: fVec, p: Vec3, width: fVec) -> Color {
+        let texels = width * self.width().max(self.height()) as fVec;
+        if texels.is_nan() || texels <= 1.0 {
+            return self.value(u, v, p);
+        }
+        let lod = texels.log2().min((self.levels.len() - 1) as fVec);
+        let fine = lod.floor() as usize;
+        let coarse = (fine + 1).min(self.levels.len() - 1);
+        let t = lod - fine as fVec;
+        self.levels[fine].bilinear(u, v) * (1.0 - t) + self.levels[coarse].bilinear(u, v) * t
+    }
+}
+
+//Placeholder in file names of UDIM texture sets, e.g. body_color.<UDIM>.png
+pub(crate) const UDIM_TOKEN: &str = "<UDIM>";
+
+//Texture set split into tiles over the uv plane, as exported by film asset pipelines. The image
+//for tile 1001 + floor(u) + 10 floor(v) covers the unit square at (floor(u), floor(v)) and
+//u in [0, 10). Parts of the uv plane without a tile are black.
+pub struct UdimTexture {
+    tiles: HashMap<usize, ImageTexture>,
+}
+
+impl UdimTexture {
+    //Loads every file matching pattern with UDIM_TOKEN replaced by a four digit tile number
+    pub fn load(pattern: &str) -> io::Result<Self> {
+        let (prefix, suffix) = pattern
+            .split_once(UDIM_TOKEN)
+            .ok_or_else(|| invalid("UDIM pattern without <UDIM>"))?;
+        let (dir, file_prefix) = match prefix.rfind('/') {
+            Some(i) => (&prefix[..=i], &prefix[i + 1..]),
+            None => ("", prefix),
+        };
+
+        let mut tiles = HashMap::new();
+        for entry in fs::read_dir(if dir.is_empty() { "." } else { dir })? {
+            let name = entry?.file_name();
+            let Some(number) = name
+                .to_str()
+                .and_then(|n| n.strip_prefix(file_prefix))
+                .and_then(|n| n.strip_suffix(suffix))
+                .filter(|n| n.len() == 4)
+                .and_then(|n| n.parse::<usize>().ok())
+                .filter(|&n| n >= 1001)
+            else {
+                continue;
+            };
+            let path = format!("{}{}{}{}", dir, file_prefix, number, suffix);
+            tiles.insert(number - 1001, ImageTexture::load(&path)?);
+        }
+        if tiles.is_empty() {
+            return Err(invalid(&format!("no UDIM tiles match {}", pattern)));
+        }
+        Ok(Self { tiles })
+    }
+
+    //Tile numbers present in the set
+    pub fn tile_numbers(&self) -> Vec<usize> {
+        let mut numbers: Vec<usize> = self.tiles.keys().map(|i| i + 1001).collect();
+        numbers.sort_unstable();
+        numbers
+    }
+
+    //Tile covering (u, v) and the coordinates within it
+    #[inline]
+    fn tile(&self, u: fVec, v: fVec) -> Option<(&ImageTexture, fVec, fVec)> {
+        let (col, row) = (u.floor(), v.floor());
+        if !(0.0..10.0).contains(&col) || row < 0.0 {
+            return None;
+        }
+        let tile = self.tiles.get(&(col as usize + 10 * row as usize))?;
+        Some((tile, u - col, v - row))
+    }
+}
+
+impl Texture for UdimTexture {
+    fn value(&self, u: fVec, v: fVec, p: Vec3) -> Color {
+        self.tile(u, v).map_or(Color::black(), |(tile, u, v)| tile.value(u, v, p))
+    }
+
+    fn filtered(&self, u: fVec, v: fVec, p: Vec3, width: fVec) -> Color {
+        self.tile(u, v).map_or(Color::black(), |(tile, u, v)| tile.filtered(u, v, p, width))
+    }
+}
+
+//Image texture for asset loaders, a UDIM set if the path contains UDIM_TOKEN
+pub(crate) fn load_image_texture(path: &str) -> io::Result<Rc<dyn Texture>> {
+    if path.contains(UDIM_TOKEN) {
+        Ok(Rc::new(UdimTexture::load(path)?))
+    } else {
+        Ok(Rc::new(ImageTexture::load(path)?))
+    }
+}
+
+//Alternating squares, frequency squares per unit of u and v
+pub struct CheckerTexture {
+    pub even: Color,
+    pub odd: Color,
+    pub frequency: fVec,
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, u: fVec, v: fVec, _p: Vec3) -> Color {
+        let cell = (u * self.frequency).floor() + (v * self.frequency).floor();
+        if cell.rem_euclid(2.0) < 1.0 {
+            self.even
+        } else {
+            self.odd
+        }
+    }
+
+    //Fades to the average color once the footprint spans a whole cell
+    fn filtered(&self, u: fVec, v: fVec, p: Vec3, width: fVec) -> Color {
+        let blur = (width * self.frequency).clamp(0.0, 1.0);
+        self.value(u, v, p) * (1.0 - blur) + (self.even + self.odd) * (0.5 * blur)
+    }
+}
+
+//Bands across u, first covers the given fraction of each period
+pub struct StripeTexture {
+    pub first: Color,
+    pub second: Color,
+    pub frequency: fVec,
+    pub ratio: fVec,
+}
+
+impl Texture for StripeTexture {
+    fn value(&self, u: fVec, _v: fVec, _p: Vec3) -> Color {
+        if (u * self.frequency).rem_euclid(1.0) < self.ratio {
+            self.first
+        } else {
+            self.second
+        }
+    }
+
+    //Fades to the average color once the footprint spans a whole period
+    fn filtered(&self, u: fVec, v: fVec, p: Vec3, width: fVec) -> Color {
+        let blur = (width * self.frequency).clamp(0.0, 1.0);
+        let average = self.first * self.ratio + self.second * (1.0 - self.ratio);
+        self.value(u, v, p) * (1.0 - blur) + average * blur
+    }
+}
+
+//UV debugging pattern: u in red and v in green with white lines every 1/cells, so stretching,
+//seams and flipped axes are visible at a glance
+pub struct UvGridTexture {
+    pub cells: fVec,
+    //Line width as a fraction of a cell
+    pub line_width: fVec,
+}
+
+impl Texture for UvGridTexture {
+    fn value(&self, u: fVec, v: fVec, _p: Vec3) -> Color {
+        let on_line = |x: fVec| {
+            let f = (x * self.cells).rem_euclid(1.0);
+            f < self.line_width / 2.0 || f > 1.0 - self.line_width / 2.0
+        };
+        if on_line(u) || on_line(v) {
+            return Color::white();
+        }
+        Color::new(u.rem_euclid(1.0), v.rem_euclid(1.0), 0.0)
+    }
+}
@@ -1,23 +1,312 @@
 use rand::prelude::*;
-use std::io::stdout;
-use std::io::Write;
+use rand::rngs::SmallRng;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::bvh::*;
+use crate::checkpoint::*;
+use crate::error::*;
+use crate::filter::*;
+use crate::framebuffer::*;
+use crate::geom::*;
+use crate::guiding::*;
 use crate::image::*;
+use crate::light::*;
 use crate::linalg::*;
+use crate::material::rand_on_unit_sphere;
+use crate::mesh::*;
+use crate::photon::*;
+use crate::progress::*;
+use crate::sampler::*;
+use crate::usd::PreviewSurface;
 
 #[derive(Clone, Copy)]
 pub struct HitResult {
     pub intersect: Vec3,
     pub normal: Vec3,
+    //Direction of increasing surface parameter u, used to orient anisotropic materials
+    pub tangent: Vec3,
+    //Surface coordinates in [0, 1] for texture lookups
+    pub uv: (fVec, fVec),
+    //Width of the ray's footprint in uv units for texture filtering, 0 for point sampling
+    pub uv_width: fVec,
+    //Index of the hit triangle for meshes, 0 for other surfaces
+    pub face: usize,
     pub at: fVec,
 }
 pub trait Material {
     fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>);
+
+    //Reflected fraction of light arriving from light_dir, including the cosine term
+    fn eval(&self, _ray: &Ray, _hit: &HitResult, _light_dir: Vec3) -> Color {
+        Color::black()
+    }
+
+    //Probability density of bounce() scattering into dir, 0 for specular materials
+    fn pdf(&self, _ray: &Ray, _hit: &HitResult, _dir: Vec3) -> fVec {
+        0.0
+    }
+
+    //Surface color for the albedo AOV. Must not consume random numbers, recording it would change
+    //the sampled path otherwise.
+    fn albedo(&self, _ray: &Ray, _hit: &HitResult) -> Color {
+        Color::white()
+    }
+
+    //Shadow catchers are replaced by the backdrop in camera rays, keeping only the shadows cast onto them
+    fn is_shadow_catcher(&self) -> bool {
+        false
+    }
+
+    //Whether bounce() always produces a perfect mirror or refraction direction
+    fn is_specular(&self) -> bool {
+        false
+    }
+
+    //Closest UsdPreviewSurface parameters, for exporting to other applications
+    fn preview(&self) -> Option<PreviewSurface> {
+        None
+    }
+
+    //Probability that a ray stops at the hit instead of passing through, for cutouts like leaves
+    fn opacity(&self, _hit: &HitResult) -> fVec {
+        1.0
+    }
+
+    //Dielectrics taking part in nested dielectric tracking, see Medium
+    fn medium(&self) -> Option<Medium> {
+        None
+    }
+
+    //bounce() with a medium of the given IOR outside the surface instead of vacuum
+    fn bounce_in(&self, ray: &Ray, hit: &HitResult, _outside_ior: fVec) -> (Color, Option<Ray>) {
+        self.bounce(ray, hit)
+    }
+
+    //eval() and pdf() of the lobes bounce_in() samples
+    fn eval_in(&self, ray: &Ray, hit: &HitResult, light_dir: Vec3, _outside_ior: fVec) -> Color {
+        self.eval(ray, hit, light_dir)
+    }
+
+    fn pdf_in(&self, ray: &Ray, hit: &HitResult, dir: Vec3, _outside_ior: fVec) -> fVec {
+        self.pdf(ray, hit, dir)
+    }
+}
+
+//Interior of a dielectric object for nested dielectrics like liquid in a glass. Where objects
+//overlap, the medium with the highest priority fills the overlap and the surfaces of the others
+//inside it are ignored, so coincident or intersecting boundaries get the right IOR ratio.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Medium {
+    pub ior: fVec,
+    pub priority: i32,
+    //Beer-Lambert absorption per unit of distance, applied by the renderer to every path segment
+    //running through the medium
+    pub absorption: Color,
+}
+
+//Deeper nesting is ignored
+const MAX_NESTED_MEDIA: usize = 4;
+
+//Media a path is currently inside, keyed by object address
+#[derive(Clone, Copy, Default)]
+struct MediumStack {
+    entries: [(usize, Medium); MAX_NESTED_MEDIA],
+    len: usize,
+}
+
+//What a path does at the surface of a dielectric
+enum Crossing {
+    //Real interface with the IOR of the medium on the outside
+    Boundary(fVec),
+    //Surface inside a higher priority medium, passed through with the updated stack
+    Hidden(MediumStack),
+}
+
+impl MediumStack {
+    fn push(&mut self, object: usize, medium: Medium) {
+        if self.len < MAX_NESTED_MEDIA {
+            self.entries[self.len] = (object, medium);
+            self.len += 1;
+        }
+    }
+
+    fn remove(&mut self, object: usize) {
+        if let Some(i) = self.entries[..self.len].iter().position(|(o, _)| *o == object) {
+            self.entries.copy_within(i + 1..self.len, i);
+            self.len -= 1;
+        }
+    }
+
+    //Medium in effect ignoring the given object, the most recently entered on equal priority
+    fn active(&self, except: usize) -> Option<Medium> {
+        self.entries[..self.len]
+            .iter()
+            .filter(|(o, _)| *o != except)
+            .fold(None, |best: Option<Medium>, (_, m)| match best {
+                Some(b) if b.priority > m.priority => Some(b),
+                _ => Some(*m),
+            })
+    }
+
+    //Beer-Lambert transmittance of the medium in effect along the ray up to t, all of the ray if None.
+    //Segments add up across bounces and hidden surfaces, so this is the absorption over the whole
+    //distance traveled inside.
+    fn attenuation(&self, ray: &Ray, t: Option<fVec>) -> Color {
+        //Object addresses are never usize::MAX, nothing is left out
+        let Some(medium) = self.active(usize::MAX) else {
+            return Color::white();
+        };
+        let distance = t.map_or(fVec::INFINITY, |t| t * ray.direction.length());
+        let channel = |a: fCol| if a <= 0.0 { 1.0 } else { (-a * distance).exp() };
+        let a = medium.absorption;
+        Color::new(channel(a.r), channel(a.g), channel(a.b))
+    }
+
+    fn crossing(&self, object: usize, medium: Medium, entering: bool) -> Crossing {
+        let outside = self.active(object);
+        if outside.is_some_and(|o| o.priority > medium.priority) {
+            let mut media = *self;
+            if entering {
+                media.push(object, medium);
+            } else {
+                media.remove(object);
+            }
+            return Crossing::Hidden(media);
+        }
+        Crossing::Boundary(outside.map_or(1.0, |o| o.ior))
+    }
+
+    //Stack after a bounce at a real interface, transmitted rays enter or leave the object
+    fn after_bounce(&self, object: usize, medium: Medium, ray: &Ray, hit: &HitResult, bounced: &Ray) -> MediumStack {
+        let mut media = *self;
+        let transmitted = (ray.direction * hit.normal) * (bounced.direction * hit.normal) > 0.0;
+        if transmitted {
+            if hit.is_outside(ray) {
+                media.push(object, medium);
+            } else {
+                media.remove(object);
+            }
+        }
+        media
+    }
+}
+
+#[inline]
+fn object_key(obj: &dyn Hit) -> usize {
+    obj as *const dyn Hit as *const () as usize
+}
+
+#[inline]
+fn material_key(material: &dyn Material) -> usize {
+    material as *const dyn Material as *const () as usize
+}
+
+//Light arriving from infinitely far away, seen by rays that miss every object of a Scene
+pub trait Environment {
+    fn radiance(&self, direction: Vec3) -> Color;
 }
 
 pub trait Hit {
     fn hit(&self, ray: &Ray) -> Option<HitResult>;
     fn material(&self) -> &dyn Material;
+
+    //Material at a hit, for objects with a material per face
+    fn material_at(&self, _hit: &HitResult) -> &dyn Material {
+        self.material()
+    }
+
+    //Build acceleration data before rendering starts, meshes build their triangle BVH here
+    fn prepare(&mut self) {}
+
+    //World space bounds after prepare(), None for unbounded objects like planes
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    //Fraction of light passing along the ray segment, 0 for opaque surfaces in the way.
+    //Cutouts are averaged instead of sampled, so their shadows are free of noise.
+    fn transmittance(&self, ray: &Ray) -> fVec {
+        let mut ray = *ray;
+        let mut transmittance = 1.0;
+        for _ in 0..MAX_CUTOUT_LAYERS {
+            let r = match self.hit(&ray) {
+                Some(r) => r,
+                None => return transmittance,
+            };
+            transmittance *= 1.0 - self.material_at(&r).opacity(&r).clamp(0.0, 1.0);
+            if transmittance <= 0.0 {
+                return 0.0;
+            }
+            ray.min = behind(r.at);
+        }
+        0.0
+    }
+
+    //Like hit(), adding the number of acceleration structure nodes visited to nodes
+    fn hit_counted(&self, ray: &Ray, _nodes: &mut usize) -> Option<HitResult> {
+        self.hit(ray)
+    }
+
+    //Whether an opaque part of the object lies along the ray, for shadow rays. Objects with an
+    //acceleration structure can stop at any such hit instead of searching the closest one.
+    fn occluded(&self, ray: &Ray, nodes: &mut usize) -> bool {
+        hit_opaque(self, ray, nodes).is_some()
+    }
+
+    //Triangle approximation for export, None for objects without a surface
+    fn to_mesh(&self, _subdivisions: usize) -> Option<Mesh> {
+        None
+    }
+}
+
+//Surfaces a ray passes through at most before being treated as blocked
+const MAX_CUTOUT_LAYERS: usize = 16;
+
+//Ray parameter just past a hit at t, to continue through a cutout
+#[inline]
+fn behind(t: fVec) -> fVec {
+    t + 1e-4 * t.max(1.0)
+}
+
+//Whether a ray stops at a hit on material instead of passing through a cutout
+pub(crate) fn stops_ray(material: &dyn Material, hit: &HitResult, ray: &Ray) -> bool {
+    let opacity = material.opacity(hit);
+    opacity >= 1.0 || cutout_random(hit.intersect, ray.direction) < opacity
+}
+
+//Closest hit on obj that isn't skipped by the material's opacity
+fn hit_opaque<H: Hit + ?Sized>(obj: &H, ray: &Ray, nodes: &mut usize) -> Option<HitResult> {
+    let mut ray = *ray;
+    for _ in 0..MAX_CUTOUT_LAYERS {
+        let r = obj.hit_counted(&ray, nodes)?;
+        if stops_ray(obj.material_at(&r), &r, &ray) {
+            return Some(r);
+        }
+        ray.min = behind(r.at);
+    }
+    None
+}
+
+//Uniform number in [0, 1) from the hit position and ray direction, so cutouts can be decided
+//without threading a random generator through intersection
+fn cutout_random(p: Vec3, d: Vec3) -> fVec {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for x in [p.x, p.y, p.z, d.x, d.y, d.z] {
+        h = (h ^ x.to_bits() as u64).wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    (h >> 40) as fVec / (1u64 << 24) as fVec
 }
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -26,6 +315,12 @@ pub struct Ray {
     pub direction: Vec3,
     pub min: fVec,
     pub max: fVec,
+    //Ray cone standing in for ray differentials: width of the footprint at the origin and its
+    //growth per unit of distance, both 0 for rays that don't filter textures
+    pub cone_width: fVec,
+    pub cone_spread: fVec,
+    //When the ray was sent, within the shutter interval of the camera
+    pub time: fVec,
 }
 
 impl Ray {
@@ -37,12 +332,40 @@ impl Ray {
     #[inline]
     pub fn new(origin: Vec3, direction: Vec3) -> Self {
         Self {
-            origin: origin,
-            direction: direction,
+            origin,
+            direction,
             min: 0.001,
             max: fVec::INFINITY,
+            cone_width: 0.0,
+            cone_spread: 0.0,
+            time: 0.0,
+        }
+    }
+
+    //Width of the cone at parameter t
+    #[inline]
+    pub fn footprint(&self, t: fVec) -> fVec {
+        self.cone_width + self.cone_spread * t * self.direction.length()
+    }
+
+    //Continue the cone of parent, bounced at parameter t. Surface curvature and roughness are
+    //ignored, so the cone only keeps widening at the camera's rate.
+    #[inline]
+    pub fn with_cone_from(self, parent: &Ray, t: fVec) -> Ray {
+        Ray {
+            cone_width: parent.footprint(t),
+            cone_spread: parent.cone_spread,
+            time: parent.time,
+            ..self
         }
     }
+
+    //Footprint at t in uv units on a surface with the given normal and texture density,
+    //stretched on oblique surfaces up to a limit that keeps grazing angles from blurring out
+    pub fn uv_width(&self, t: fVec, normal: Vec3, uv_per_unit: fVec) -> fVec {
+        let cos = (normal * self.direction.unit()).abs().max(0.25);
+        self.footprint(t) * uv_per_unit / cos
+    }
 }
 
 impl HitResult {
@@ -52,6 +375,16 @@ impl HitResult {
         dot < 0.0
     }
 
+    //Orthonormal (tangent, bitangent) pair around the normal
+    pub fn tangent_frame(&self) -> (Vec3, Vec3) {
+        let t = self.tangent - self.normal * (self.tangent * self.normal);
+        if t.is_tiny(0.0001) {
+            return self.normal.basis();
+        }
+        let t = t.unit();
+        (t, self.normal.cross(t))
+    }
+
     #[inline]
     pub fn surface_normal(&self, ray: &Ray) -> Vec3 {
         if self.is_outside(ray) {
@@ -62,6 +395,120 @@ impl HitResult {
     }
 }
 
+//Projection of film coordinates to camera rays, shared by the perspective Camera and
+//OrthographicCamera. Renderers only see cameras through it.
+pub trait CameraModel: Send + Sync {
+    //Pixels across and down the film
+    fn resolution(&self) -> (usize, usize);
+
+    //Ray through film coordinates s, t in [0, 1] from the top left, starting at a point on the
+    //unit lens disc
+    fn film_ray(&self, s: fVec, t: fVec, lens: (fVec, fVec)) -> Ray;
+
+    //Point on the unit lens disc for film_ray() from a sample u, v in [0, 1]^2, shaped by the
+    //aperture of the camera
+    #[inline]
+    fn lens_sample(&self, u: fVec, v: fVec, _s: fVec, _t: fVec) -> (fVec, fVec) {
+        concentric_disc(u, v)
+    }
+
+    //False for pinhole cameras, their rays all start at the lens center and lens_sample() is
+    //skipped
+    fn has_lens(&self) -> bool {
+        true
+    }
+
+    //Conservative frustum test, false only if no camera ray can reach the box. Rays start anywhere
+    //on the lens and may be jittered up to two pixels beyond the film by reconstruction filters.
+    fn may_see(&self, bounds: &Aabb) -> bool;
+
+    //Center of the lens and unit right, up and forward vectors
+    fn pose(&self) -> (Vec3, (Vec3, Vec3, Vec3));
+
+    //Times the shutter opens and closes, rays are sent at random times in between
+    fn shutter(&self) -> (fVec, fVec) {
+        (0.0, 0.0)
+    }
+
+    //film_ray() sent at the given time
+    #[inline]
+    fn film_ray_at(&self, s: fVec, t: fVec, lens: (fVec, fVec), time: fVec) -> Ray {
+        Ray {
+            time,
+            ..self.film_ray(s, t, lens)
+        }
+    }
+
+    #[inline]
+    fn ray_through(&self, u: usize, v: usize, offset_origin: (fVec, fVec), offset_target: (fVec, fVec)) -> Ray {
+        let (width, height) = self.resolution();
+        self.film_ray(
+            (u as fVec + offset_target.0) / width as fVec,
+            (v as fVec + offset_target.1) / height as fVec,
+            offset_origin,
+        )
+    }
+
+    //Continuous film coordinates and a lens sample in [0, 1]^2, for external samplers and
+    //splatting filters that don't work on pixel indices
+    #[inline]
+    fn ray_through_uv(&self, s: fVec, t: fVec, lens: (fVec, fVec)) -> Ray {
+        let lens = if self.has_lens() { self.lens_sample(lens.0, lens.1, s, t) } else { (0.0, 0.0) };
+        self.film_ray(s, t, lens)
+    }
+}
+
+//Film margin of the frustum tests, reconstruction filters jitter up to two pixels outwards
+fn film_margin(width: usize, height: usize) -> fVec {
+    2.0 / width.min(height).max(1) as fVec
+}
+
+//Ray start on a thin lens of the given radius around origin, lens is a point on the unit disc
+#[inline]
+fn lens_origin(origin: Vec3, right: Vec3, up: Vec3, aperture: fVec, lens: (fVec, fVec)) -> Vec3 {
+    origin + up * (lens.0 * aperture) + right * (lens.1 * aperture)
+}
+
+//Corners of a box in the right, up and forward frame of a camera at origin
+fn camera_space_corners(bounds: &Aabb, origin: Vec3, (right, up, forward): (Vec3, Vec3, Vec3)) -> [(fVec, fVec, fVec); 8] {
+    std::array::from_fn(|i| {
+        let p = Vec3::new(
+            if i & 1 == 0 { bounds.min.x } else { bounds.max.x },
+            if i & 2 == 0 { bounds.min.y } else { bounds.max.y },
+            if i & 4 == 0 { bounds.min.z } else { bounds.max.z },
+        ) - origin;
+        (p * right, p * up, p * forward)
+    })
+}
+
+//Unit right and up vectors of a camera looking along forward, with up as close to the requested
+//up as possible. Looking along up, the world axis closest to perpendicular is used instead.
+//Positive roll in degrees turns the camera clockwise around forward.
+pub(crate) fn camera_basis(forward: Vec3, up: Vec3, roll: fVec) -> (Vec3, Vec3) {
+    let mut right = up.cross(forward);
+    if right.length() < 1e-6 * up.length() {
+        let fallback = [Vec3::unit_z(), Vec3::unit_x(), Vec3::unit_y()]
+            .into_iter()
+            .min_by(|a, b| (*a * forward).abs().total_cmp(&(*b * forward).abs()))
+            .unwrap();
+        right = fallback.cross(forward);
+    }
+    let right = right.unit();
+    let up = forward.cross(right).unit();
+    let (sin, cos) = roll.to_radians().sin_cos();
+    (right * cos - up * sin, up * cos + right * sin)
+}
+
+//Lens position, the point one unit in front of it and the up vector of a camera to world matrix
+//as exported by glTF and USD: right handed with +Y up, the camera looking down its -Z axis and
+//m[row][col] with the translation in the last column. Mirrored into the left handed scene frame
+//like the USD import, scale is dropped. For Blender's +Z up matrix_world convert it as glTF does.
+pub(crate) fn camera_matrix_pose(m: [[fVec; 4]; 4]) -> (Vec3, Vec3, Vec3) {
+    let column = |c: usize| Vec3::new(m[0][c], m[1][c], -m[2][c]);
+    let from = column(3);
+    (from, from - column(2).unit(), column(1))
+}
+
 //Left handed coordinate system
 //u,v start from Top-Left
 pub struct Camera {
@@ -72,16 +519,31 @@ pub struct Camera {
     pub rasterize_width: usize,
     pub rasterize_height: usize,
     pub aperture: fVec,
+    pub bokeh: Bokeh,
+    //Moves the image right and up by fractions of its width without turning the camera, so
+    //vertical lines stay parallel when the subject is off center
+    pub shift: (fVec, fVec),
+    //Degrees the plane of focus is turned around the right and up axes, positive moves its top
+    //and right side further away. Only visible with an aperture.
+    pub tilt: (fVec, fVec),
     temp_right: Vec3,
     temp_up: Vec3,
 }
 
 impl Camera {
-    pub fn new(look_from: Vec3, look_at: Vec3, width: usize, height: usize, fov: fVec, aperture: fVec) -> Self {
+    //In focus focus_distance in front of the lens, see Scene::autofocus() to focus on a surface
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        look_from: Vec3,
+        look_at: Vec3,
+        width: usize,
+        height: usize,
+        fov: fVec,
+        aperture: fVec,
+        focus_distance: fVec,
+    ) -> Self {
         let dir = (look_at - look_from).unit();
-        let temp_right = Vec3::unit_y().cross(dir).unit();
-        let temp_up = dir.cross(temp_right).unit();
-        let focus_distance = (look_at - look_from).length();
+        let (temp_right, temp_up) = camera_basis(dir, Vec3::unit_y(), 0.0);
         let v_width = fVec::tan((fov*std::f32::consts::PI)/(360.0))*focus_distance*2.0;
 
         Self {
@@ -91,131 +553,2519 @@ impl Camera {
             viewport_height: (height as fVec / width as fVec) * v_width,
             rasterize_width: width,
             rasterize_height: height,
-            temp_right: temp_right,
-            temp_up: temp_up,
-            aperture: aperture,
+            temp_right,
+            temp_up,
+            aperture,
+            bokeh: Bokeh::default(),
+            shift: (0.0, 0.0),
+            tilt: (0.0, 0.0),
+        }
+    }
+
+    //Unit right, up and forward vectors
+    pub fn frame(&self) -> (Vec3, Vec3, Vec3) {
+        (self.temp_right, self.temp_up, self.direction.unit())
+    }
+
+    //Up is +y without roll after new(), see camera_basis()
+    pub fn set_orientation(&mut self, up: Vec3, roll: fVec) {
+        (self.temp_right, self.temp_up) = camera_basis(self.direction.unit(), up, roll);
+    }
+}
+
+impl CameraModel for Camera {
+    fn resolution(&self) -> (usize, usize) {
+        (self.rasterize_width, self.rasterize_height)
+    }
+
+    fn may_see(&self, bounds: &Aabb) -> bool {
+        //Rays focused on a tilted plane can cross close to the lens, beyond the frustum
+        if self.aperture > 0.0 && self.tilt != (0.0, 0.0) {
+            return true;
         }
+        let focus_distance = self.direction.length();
+        let margin = film_margin(self.rasterize_width, self.rasterize_height);
+        let (shift_x, shift_y) = (self.viewport_width * self.shift.0, self.viewport_width * self.shift.1);
+        let half_x = self.viewport_width * (0.5 + margin) + self.aperture;
+        let half_y = self.viewport_height * (0.5 + margin) + self.aperture;
+        let slope = |half: fVec, shift: fVec| (half + shift) / focus_distance;
+
+        let corners = camera_space_corners(bounds, self.origin, self.frame());
+        //Culled if all corners are outside the same plane
+        let outside = |f: &dyn Fn(fVec, fVec, fVec) -> bool| corners.iter().all(|&(x, y, z)| f(x, y, z));
+        !(outside(&|_, _, z| z < 0.0)
+            || outside(&|x, _, z| x > slope(half_x, shift_x) * z + self.aperture)
+            || outside(&|x, _, z| -x > slope(half_x, -shift_x) * z + self.aperture)
+            || outside(&|_, y, z| y > slope(half_y, shift_y) * z + self.aperture)
+            || outside(&|_, y, z| -y > slope(half_y, -shift_y) * z + self.aperture))
+    }
+
+    fn pose(&self) -> (Vec3, (Vec3, Vec3, Vec3)) {
+        (self.origin, self.frame())
+    }
+
+    #[inline]
+    fn lens_sample(&self, u: fVec, v: fVec, s: fVec, t: fVec) -> (fVec, fVec) {
+        self.bokeh.sample(u, v, s, t)
+    }
+
+    fn has_lens(&self) -> bool {
+        self.aperture > 0.0
     }
 
     #[inline]
-    pub fn ray_through(&self, u: usize, v: usize, offset_origin: (fVec, fVec), offset_target: (fVec, fVec)) -> Ray {
-        let u_step = self.viewport_width / self.rasterize_width as fVec;
-        let v_step = self.viewport_height / self.rasterize_height as fVec;
+    fn film_ray(&self, s: fVec, t: fVec, lens: (fVec, fVec)) -> Ray {
         let top_left = self.origin + self.direction
-            + self.temp_right * (self.viewport_width / -2.0)
-            + self.temp_up * (self.viewport_height / 2.0);
-
-        let from = self.origin + self.temp_up * (offset_origin.0*self.aperture) + self.temp_right * (offset_origin.1*self.aperture);
-        let to = top_left + self.temp_right * (u_step * (u as fVec + offset_target.0)) + (-self.temp_up) * (v_step * (v as fVec + offset_target.1));
-        Ray::new(
-            from,
-            to - from
-        )
+            + self.temp_right * (self.viewport_width * (self.shift.0 - 0.5))
+            + self.temp_up * (self.viewport_height / 2.0 + self.viewport_width * self.shift.1);
+
+        let from = lens_origin(self.origin, self.temp_right, self.temp_up, self.aperture, lens);
+        let mut to = top_left + self.temp_right * (self.viewport_width * s) + (-self.temp_up) * (self.viewport_height * t);
+        if self.aperture > 0.0 && self.tilt != (0.0, 0.0) {
+            //Where the ray through the lens center meets the tilted plane of focus
+            let normal = self.direction.unit()
+                - self.temp_up * self.tilt.0.to_radians().tan()
+                - self.temp_right * self.tilt.1.to_radians().tan();
+            let center = to - self.origin;
+            let along = center * normal;
+            to = if along > 1e-6 * center.length() {
+                self.origin + center * ((self.direction * normal) / along)
+            } else {
+                //Parallel to the plane, in focus at infinity
+                from + center
+            };
+        }
+        Ray {
+            //Angle covered by a pixel
+            cone_spread: self.viewport_height / (self.rasterize_height as fVec * self.direction.length()),
+            ..Ray::new(from, to - from)
+        }
     }
 }
 
-pub struct Scene {
-    objects: Vec<Box<dyn Hit>>,
+//Parallel rays for technical and isometric views without perspective distortion. The film is
+//view_width scene units across and everything is in focus.
+pub struct OrthographicCamera {
+    pub origin: Vec3,
+    pub view_width: fVec,
+    pub view_height: fVec,
+    pub rasterize_width: usize,
+    pub rasterize_height: usize,
+    right: Vec3,
+    up: Vec3,
+    forward: Vec3,
 }
 
-impl Scene {
-    pub fn new() -> Scene {
-        Scene {
-            objects: Vec::new(),
+impl OrthographicCamera {
+    //Rays start on the film plane through look_from, facing look_at
+    pub fn new(look_from: Vec3, look_at: Vec3, width: usize, height: usize, view_width: fVec) -> Self {
+        let forward = (look_at - look_from).unit();
+        let (right, up) = camera_basis(forward, Vec3::unit_y(), 0.0);
+        Self {
+            origin: look_from,
+            view_width,
+            view_height: (height as fVec / width as fVec) * view_width,
+            rasterize_width: width,
+            rasterize_height: height,
+            right,
+            up,
+            forward,
         }
     }
 
-    pub fn add(&mut self, obj: Box<dyn Hit>) {
-        self.objects.push(obj);
+    //Unit right, up and forward vectors
+    pub fn frame(&self) -> (Vec3, Vec3, Vec3) {
+        (self.right, self.up, self.forward)
     }
 
-    fn hit(&self, ray: &Ray) -> Option<(HitResult, &dyn Hit)> {
-        let mut temp_ray = *ray;
-        let mut hit_res = None;
+    //See Camera::set_orientation()
+    pub fn set_orientation(&mut self, up: Vec3, roll: fVec) {
+        (self.right, self.up) = camera_basis(self.forward, up, roll);
+    }
+}
 
-        for obj in self.objects.iter() {
-            let res = obj.hit(&temp_ray);
-            match res {
-                None => {}
-                Some(r) => {
-                    hit_res = Some((r, obj.as_ref()));
-                    temp_ray.max = r.at;
-                }
-            }
-        }
+impl CameraModel for OrthographicCamera {
+    fn resolution(&self) -> (usize, usize) {
+        (self.rasterize_width, self.rasterize_height)
+    }
 
-        hit_res
+    fn may_see(&self, bounds: &Aabb) -> bool {
+        let margin = film_margin(self.rasterize_width, self.rasterize_height);
+        let half_x = self.view_width * (0.5 + margin);
+        let half_y = self.view_height * (0.5 + margin);
+
+        let corners = camera_space_corners(bounds, self.origin, self.frame());
+        //Culled if all corners are outside the same side of the view box
+        let outside = |f: &dyn Fn(fVec, fVec, fVec) -> bool| corners.iter().all(|&(x, y, z)| f(x, y, z));
+        !(outside(&|_, _, z| z < 0.0)
+            || outside(&|x, _, _| x > half_x)
+            || outside(&|x, _, _| -x > half_x)
+            || outside(&|_, y, _| y > half_y)
+            || outside(&|_, y, _| -y > half_y))
+    }
+
+    fn pose(&self) -> (Vec3, (Vec3, Vec3, Vec3)) {
+        (self.origin, self.frame())
+    }
+
+    fn has_lens(&self) -> bool {
+        false
+    }
+
+    //Without a lens, its sample is ignored
+    #[inline]
+    fn film_ray(&self, s: fVec, t: fVec, _lens: (fVec, fVec)) -> Ray {
+        let from = self.origin + self.right * (self.view_width * (s - 0.5)) + self.up * (self.view_height * (0.5 - t));
+        Ray {
+            //Parallel rays keep the footprint of a pixel
+            cone_width: self.view_height / self.rasterize_height as fVec,
+            ..Ray::new(from, self.forward)
+        }
     }
 }
 
-pub struct Renderer {
-    samples: usize,
-    bounces: usize,
+//How a fisheye lens maps the angle from the view direction to the distance from the film center
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FisheyeMapping {
+    //Distance proportional to the angle, evenly spaced angles
+    Equidistant,
+    //Equal areas on the film cover equal solid angles, compressing the rim less than most lenses
+    Equisolid,
 }
 
-impl Renderer {
-    pub fn new(samples: usize, bounces: usize) -> Renderer {
-        Renderer {
-            samples: samples,
-            bounces: bounces,
+//Wide angle camera with a film circle spanning fov degrees across the width of the image, up to
+//360. Directions past straight back are clamped to it. Depth of field works like the perspective
+//Camera, the focus surface is a sphere of focus_distance around the lens.
+pub struct FisheyeCamera {
+    pub origin: Vec3,
+    pub fov: fVec,
+    pub mapping: FisheyeMapping,
+    pub aperture: fVec,
+    pub bokeh: Bokeh,
+    pub focus_distance: fVec,
+    pub rasterize_width: usize,
+    pub rasterize_height: usize,
+    right: Vec3,
+    up: Vec3,
+    forward: Vec3,
+}
+
+impl FisheyeCamera {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        look_from: Vec3,
+        look_at: Vec3,
+        width: usize,
+        height: usize,
+        fov: fVec,
+        mapping: FisheyeMapping,
+        aperture: fVec,
+        focus_distance: fVec,
+    ) -> Self {
+        let forward = (look_at - look_from).unit();
+        let (right, up) = camera_basis(forward, Vec3::unit_y(), 0.0);
+        Self {
+            origin: look_from,
+            fov,
+            mapping,
+            aperture,
+            bokeh: Bokeh::default(),
+            focus_distance,
+            rasterize_width: width,
+            rasterize_height: height,
+            right,
+            up,
+            forward,
         }
     }
 
-    pub fn render(&self, scene: &Scene, cam: &Camera) -> Image {
-        let width = cam.rasterize_width;
-        let height = cam.rasterize_height;
-        let samples = self.samples;
+    //Unit right, up and forward vectors
+    pub fn frame(&self) -> (Vec3, Vec3, Vec3) {
+        (self.right, self.up, self.forward)
+    }
 
-        let mut img = Image::new(width, height);
-        let mut rng = rand::rngs::SmallRng::from_entropy();
+    //See Camera::set_orientation()
+    pub fn set_orientation(&mut self, up: Vec3, roll: fVec) {
+        (self.right, self.up) = camera_basis(self.forward, up, roll);
+    }
 
-        for y in 0..height {
-            print!("\rCurrent line: {}", y);
-            stdout().flush().unwrap();
-            for x in 0..width {
-                let mut sum = Color::black();
-                let px = img.px_mut(x, y).unwrap();
+    //Angle from the forward direction at radius r on the film, the rim of the image circle is at 1
+    fn theta(&self, r: fVec) -> fVec {
+        let half_fov = (self.fov / 2.0).to_radians();
+        match self.mapping {
+            FisheyeMapping::Equidistant => r * half_fov,
+            FisheyeMapping::Equisolid => 2.0 * (r * (half_fov / 2.0).sin()).min(1.0).asin(),
+        }
+        .min(std::f32::consts::PI)
+    }
 
-                for _ in 0..samples {
-                    let rnum: fVec = rng.gen_range(0.0..1.0);
-                    let rnum2: fVec = rng.gen_range(0.0..1.0);
+    //Unit direction through film coordinates s, t
+    fn direction(&self, s: fVec, t: fVec) -> Vec3 {
+        //Film position with the rim of the image circle at 1 across the width
+        let x = 2.0 * s - 1.0;
+        let y = (1.0 - 2.0 * t) * self.rasterize_height as fVec / self.rasterize_width as fVec;
+        let r = (x * x + y * y).sqrt();
+        let (sin, cos) = self.theta(r).sin_cos();
+        let (dx, dy) = if r > 0.0 { (x / r, y / r) } else { (0.0, 0.0) };
+        self.forward * cos + self.right * (sin * dx) + self.up * (sin * dy)
+    }
+}
 
-                    let ray = cam.ray_through(x, y, rand_on_unit_disc(&mut rng),(rnum, rnum2));
+impl CameraModel for FisheyeCamera {
+    fn resolution(&self) -> (usize, usize) {
+        (self.rasterize_width, self.rasterize_height)
+    }
 
-                    sum = sum + self.colorize_ray(scene, &ray, self.bounces);
-                }
-                *px = (sum * (1.0 / samples as f32)).gamma2().into();
-            }
+    //Only boxes behind the camera are culled, and only if no pixel looks sideways or further back.
+    //The film corners lie outside the image circle and see the most oblique directions.
+    fn may_see(&self, bounds: &Aabb) -> bool {
+        let aspect = self.rasterize_height as fVec / self.rasterize_width as fVec;
+        let max_theta = self.theta((1.0 + aspect * aspect).sqrt());
+        //Margin for rounding
+        if max_theta + (1.0 as fVec).to_radians() >= std::f32::consts::FRAC_PI_2 {
+            return true;
         }
+        let corners = camera_space_corners(bounds, self.origin, self.frame());
+        !corners.iter().all(|&(_, _, z)| z < -self.aperture)
+    }
 
-        img
+    fn pose(&self) -> (Vec3, (Vec3, Vec3, Vec3)) {
+        (self.origin, self.frame())
     }
 
-    fn colorize_ray(&self, scene: &Scene, ray: &Ray, bounces: usize) -> Color {
-        if bounces <= 0 {
-            return Color::from_rgb(245, 66, 129);
+    #[inline]
+    fn lens_sample(&self, u: fVec, v: fVec, s: fVec, t: fVec) -> (fVec, fVec) {
+        self.bokeh.sample(u, v, s, t)
+    }
+
+    fn has_lens(&self) -> bool {
+        self.aperture > 0.0
+    }
+
+    #[inline]
+    fn film_ray(&self, s: fVec, t: fVec, lens: (fVec, fVec)) -> Ray {
+        let to = self.origin + self.direction(s, t) * self.focus_distance;
+        let from = lens_origin(self.origin, self.right, self.up, self.aperture, lens);
+        Ray {
+            //Angle covered by a pixel at the center of the film
+            cone_spread: self.fov.to_radians() / self.rasterize_width as fVec,
+            ..Ray::new(from, to - from)
         }
+    }
+}
 
-        let res = scene.hit(ray);
-        match res {
-            Some((r, obj)) => {
-                let (col, bounced_ray) = obj.material().bounce(ray, &r);
-                if let Some(b) = bounced_ray {
-                    col * self.colorize_ray(scene, &b, bounces - 1)
-                } else {
-                    col
-                }
-            }
-            None => Color::black(),
+//Position and orientation of a MovingCamera at a point in time
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CameraKey {
+    pub time: fVec,
+    pub origin: Vec3,
+    pub right: Vec3,
+    pub up: Vec3,
+    pub forward: Vec3,
+}
+
+impl CameraKey {
+    //See camera_basis() for up and roll
+    pub fn look_at(time: fVec, look_from: Vec3, look_at: Vec3, up: Vec3, roll: fVec) -> CameraKey {
+        let forward = (look_at - look_from).unit();
+        let (right, up) = camera_basis(forward, up, roll);
+        CameraKey {
+            time,
+            origin: look_from,
+            right,
+            up,
+            forward,
+        }
+    }
+}
+
+//Camera flying along keyed poses while its shutter is open, for motion blur. The film and lens
+//of the wrapped camera are kept and carried along, its own pose is replaced. Between keys the
+//position is interpolated linearly and the orientation by its forward and up vectors, before
+//the first and after the last key the camera holds still.
+pub struct MovingCamera {
+    camera: Box<dyn CameraModel>,
+    //Sorted by time
+    keys: Vec<CameraKey>,
+    shutter: (fVec, fVec),
+}
+
+impl MovingCamera {
+    //Only MovingCamera::builder() reaches this, after checking there is at least one key
+    pub(crate) fn new(camera: Box<dyn CameraModel>, mut keys: Vec<CameraKey>, shutter: (fVec, fVec)) -> Self {
+        keys.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Self { camera, keys, shutter }
+    }
+
+    //Lens center and unit right, up and forward vectors at time
+    pub fn pose_at(&self, time: fVec) -> (Vec3, (Vec3, Vec3, Vec3)) {
+        let next = self.keys.partition_point(|k| k.time <= time);
+        let (a, b) = match next {
+            0 => (self.keys[0], self.keys[0]),
+            n if n == self.keys.len() => (self.keys[n - 1], self.keys[n - 1]),
+            n => (self.keys[n - 1], self.keys[n]),
+        };
+        if a == b {
+            return (a.origin, (a.right, a.up, a.forward));
         }
+        let f = (time - a.time) / (b.time - a.time);
+        let lerp = |x: Vec3, y: Vec3| x * (1.0 - f) + y * f;
+        //Opposite directions have no halfway point, switch over instead
+        let forward = lerp(a.forward, b.forward);
+        let forward = if forward.is_tiny(1e-6) { if f < 0.5 { a.forward } else { b.forward } } else { forward.unit() };
+        let (right, up) = camera_basis(forward, lerp(a.up, b.up), 0.0);
+        (lerp(a.origin, b.origin), (right, up, forward))
     }
 }
 
-fn rand_on_unit_disc(rng: &mut impl RngCore ) -> (fVec, fVec) {
-    loop {
-        let x:(fVec, fVec) = (rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0));
-        if x.0 * x.0 + x.1 * x.1 <= 1.0 {
-            break x;
+impl CameraModel for MovingCamera {
+    fn resolution(&self) -> (usize, usize) {
+        self.camera.resolution()
+    }
+
+    //Poses between keys aren't covered by a single frustum, nothing is culled
+    fn may_see(&self, _bounds: &Aabb) -> bool {
+        true
+    }
+
+    //At the middle of the shutter interval
+    fn pose(&self) -> (Vec3, (Vec3, Vec3, Vec3)) {
+        self.pose_at((self.shutter.0 + self.shutter.1) / 2.0)
+    }
+
+    fn shutter(&self) -> (fVec, fVec) {
+        self.shutter
+    }
+
+    #[inline]
+    fn lens_sample(&self, u: fVec, v: fVec, s: fVec, t: fVec) -> (fVec, fVec) {
+        self.camera.lens_sample(u, v, s, t)
+    }
+
+    fn has_lens(&self) -> bool {
+        self.camera.has_lens()
+    }
+
+    #[inline]
+    fn film_ray(&self, s: fVec, t: fVec, lens: (fVec, fVec)) -> Ray {
+        let (open, close) = self.shutter;
+        self.film_ray_at(s, t, lens, (open + close) / 2.0)
+    }
+
+    //The ray of the wrapped camera, moved from its pose to the one at time
+    fn film_ray_at(&self, s: fVec, t: fVec, lens: (fVec, fVec), time: fVec) -> Ray {
+        let ray = self.camera.film_ray(s, t, lens);
+        let (from, (right, up, forward)) = self.camera.pose();
+        let (to, (to_right, to_up, to_forward)) = self.pose_at(time);
+        let rotate = |v: Vec3| to_right * (v * right) + to_up * (v * up) + to_forward * (v * forward);
+        Ray {
+            origin: to + rotate(ray.origin - from),
+            direction: rotate(ray.direction),
+            time,
+            ..ray
         }
     }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+//One eye of a stereo pair, half the interocular distance to the side of another camera. The
+//views are sheared instead of turned inwards (off-axis stereo), so both eyes agree at the
+//convergence distance, which appears at the depth of the display, without keystone distortion.
+pub struct StereoCamera<'a> {
+    camera: &'a dyn CameraModel,
+    //Towards the camera's right, negative for the left eye
+    offset: fVec,
+    convergence: fVec,
+}
+
+impl<'a> StereoCamera<'a> {
+    pub fn new(camera: &'a dyn CameraModel, eye: Eye, interocular: fVec, convergence: fVec) -> Self {
+        let offset = match eye {
+            Eye::Left => -interocular / 2.0,
+            Eye::Right => interocular / 2.0,
+        };
+        Self {
+            camera,
+            offset,
+            convergence,
+        }
+    }
+
+    //Moves points sideways by the eye offset at the lens, less with depth and none at the
+    //convergence distance, which maps rays of the camera to rays of the eye
+    fn shear(&self, ray: Ray) -> Ray {
+        let (origin, (right, _, forward)) = self.camera.pose();
+        let depth = (ray.origin - origin) * forward;
+        Ray {
+            origin: ray.origin + right * (self.offset * (1.0 - depth / self.convergence)),
+            direction: ray.direction - right * (self.offset * (ray.direction * forward) / self.convergence),
+            ..ray
+        }
+    }
+}
+
+impl CameraModel for StereoCamera<'_> {
+    fn resolution(&self) -> (usize, usize) {
+        self.camera.resolution()
+    }
+
+    //The sheared frustum isn't tested, nothing is culled
+    fn may_see(&self, _bounds: &Aabb) -> bool {
+        true
+    }
+
+    fn pose(&self) -> (Vec3, (Vec3, Vec3, Vec3)) {
+        let (origin, frame) = self.camera.pose();
+        (origin + frame.0 * self.offset, frame)
+    }
+
+    fn shutter(&self) -> (fVec, fVec) {
+        self.camera.shutter()
+    }
+
+    #[inline]
+    fn lens_sample(&self, u: fVec, v: fVec, s: fVec, t: fVec) -> (fVec, fVec) {
+        self.camera.lens_sample(u, v, s, t)
+    }
+
+    fn has_lens(&self) -> bool {
+        self.camera.has_lens()
+    }
+
+    #[inline]
+    fn film_ray(&self, s: fVec, t: fVec, lens: (fVec, fVec)) -> Ray {
+        self.shear(self.camera.film_ray(s, t, lens))
+    }
+
+    fn film_ray_at(&self, s: fVec, t: fVec, lens: (fVec, fVec), time: fVec) -> Ray {
+        self.shear(self.camera.film_ray_at(s, t, lens, time))
+    }
+}
+
+//Stable handle to an object in a Scene, stays valid when other objects are removed
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ObjectId(usize);
+
+impl ObjectId {
+    //Position in the scene's object list, counting removed objects
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+//Objects of a scene that camera rays can reach, see Scene::frustum_cull()
+pub struct FrustumCull {
+    bvh: Bvh,
+    bounded: Vec<usize>,
+    unbounded: Vec<usize>,
+    pub(crate) visible: usize,
+    pub(crate) culled: usize,
+}
+
+pub struct Scene {
+    objects: Vec<Option<Box<dyn Hit>>>,
+    //Per object, "object<index>" unless set, for cryptomatte mattes
+    object_names: Vec<String>,
+    //Keyed by material address like object_key(), the Rc keeps the material alive so the address
+    //can't be reused by another one
+    material_names: HashMap<usize, (Rc<dyn Material>, String)>,
+    lights: Vec<Box<dyn Light>>,
+    environment: Option<Box<dyn Environment>>,
+    prepared: bool,
+    //Built by prepare(), over the indices in bounded
+    bvh: Option<Bvh>,
+    bounded: Vec<usize>,
+    unbounded: Vec<usize>,
+}
+
+impl Default for Scene {
+    fn default() -> Scene {
+        Scene::new()
+    }
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        Scene {
+            objects: Vec::new(),
+            object_names: Vec::new(),
+            material_names: HashMap::new(),
+            lights: Vec::new(),
+            environment: None,
+            prepared: false,
+            bvh: None,
+            bounded: Vec::new(),
+            unbounded: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, mut obj: Box<dyn Hit>) -> ObjectId {
+        if self.prepared {
+            obj.prepare();
+        }
+        self.object_names.push(format!("object{}", self.objects.len()));
+        self.objects.push(Some(obj));
+        self.rebuild_bvh();
+        ObjectId(self.objects.len() - 1)
+    }
+
+    pub fn set_object_name(&mut self, id: ObjectId, name: &str) {
+        if let Some(n) = self.object_names.get_mut(id.0) {
+            *n = name.to_string();
+        }
+    }
+
+    pub fn object_name(&self, id: ObjectId) -> Option<&str> {
+        self.object_names.get(id.0).map(String::as_str)
+    }
+
+    //Name a material shared by objects
+    pub fn set_material_name(&mut self, material: Rc<dyn Material>, name: &str) {
+        self.material_names.insert(material_key(material.as_ref()), (material, name.to_string()));
+    }
+
+    pub fn material_name(&self, material: &dyn Material) -> Option<&str> {
+        self.material_names.get(&material_key(material)).map(|(_, name)| name.as_str())
+    }
+
+    //Swap out a single object, only the new object is prepared again
+    pub fn replace(&mut self, id: ObjectId, mut obj: Box<dyn Hit>) -> Option<Box<dyn Hit>> {
+        if self.prepared {
+            obj.prepare();
+        }
+        let old = self.objects.get_mut(id.0)?.replace(obj);
+        self.rebuild_bvh();
+        old
+    }
+
+    pub fn get(&self, id: ObjectId) -> Option<&dyn Hit> {
+        self.objects.get(id.0)?.as_deref()
+    }
+
+    pub fn remove(&mut self, id: ObjectId) -> Option<Box<dyn Hit>> {
+        let old = self.objects.get_mut(id.0)?.take();
+        self.rebuild_bvh();
+        old
+    }
+
+    //Object level BVH, only kept up to date once the scene is prepared
+    fn rebuild_bvh(&mut self) {
+        if !self.prepared {
+            return;
+        }
+        self.bounded.clear();
+        self.unbounded.clear();
+        let mut bounds = Vec::new();
+        for (i, obj) in self.objects.iter().enumerate() {
+            match obj.as_ref().map(|obj| obj.bounds()) {
+                Some(Some(b)) => {
+                    self.bounded.push(i);
+                    bounds.push(b);
+                }
+                Some(None) => self.unbounded.push(i),
+                None => {}
+            }
+        }
+        self.bvh = Some(Bvh::build(&bounds));
+    }
+
+    //Union of the bounds of all bounded objects, empty if there are none
+    pub fn bounds(&self) -> Aabb {
+        self.objects.iter().flatten().filter_map(|obj| obj.bounds()).fold(Aabb::empty(), Aabb::union)
+    }
+
+    pub fn add_light(&mut self, light: Box<dyn Light>) {
+        self.lights.push(light);
+    }
+
+    //Replaces the previous environment, without one missing rays are black
+    pub fn set_environment(&mut self, environment: Box<dyn Environment>) {
+        self.environment = Some(environment);
+    }
+
+    pub fn environment(&self) -> Option<&dyn Environment> {
+        self.environment.as_deref()
+    }
+
+    //Radiance along a ray that missed every object
+    pub fn miss(&self, ray: &Ray) -> Color {
+        match &self.environment {
+            Some(env) => env.radiance(ray.direction.unit()),
+            None => Color::black(),
+        }
+    }
+
+    pub fn prepare(&mut self) {
+        for obj in self.objects.iter_mut().flatten() {
+            obj.prepare();
+        }
+        self.prepared = true;
+        self.rebuild_bvh();
+    }
+
+    //Any-hit query, stops at the first opaque object along the ray
+    fn occluded(&self, ray: &Ray, nodes: &mut usize) -> bool {
+        let bvh = match &self.bvh {
+            Some(bvh) => bvh,
+            None => return self.objects.iter().flatten().any(|obj| obj.occluded(ray, nodes)),
+        };
+        if self.unbounded.iter().any(|&i| self.objects[i].as_ref().is_some_and(|obj| obj.occluded(ray, nodes))) {
+            return true;
+        }
+        let mut inner = 0;
+        let (occluded, visited) =
+            bvh.any(ray, |j| self.objects[self.bounded[j]].as_ref().is_some_and(|obj| obj.occluded(ray, &mut inner)));
+        *nodes += visited + inner;
+        occluded
+    }
+
+    //Product of the transmittance of all objects along the ray
+    fn transmittance(&self, ray: &Ray, nodes: &mut usize) -> fVec {
+        let mut transmittance = 1.0;
+        let mut test = |i: usize| {
+            if let Some(obj) = &self.objects[i] {
+                //Estimates may be negative, see Volume::transmittance()
+                if transmittance != 0.0 {
+                    transmittance *= obj.transmittance(ray);
+                }
+            }
+        };
+        match &self.bvh {
+            Some(bvh) => {
+                self.unbounded.iter().for_each(|&i| test(i));
+                *nodes += bvh.traverse(ray, |j, _| {
+                    test(self.bounded[j]);
+                    None
+                });
+            }
+            None => (0..self.objects.len()).for_each(test),
+        }
+        transmittance
+    }
+
+    //Write the geometry as Wavefront OBJ, one object per scene object
+    pub fn export_obj(&self, path: &str, subdivisions: usize) -> Result<()> {
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        let mut vertex_offset = 0;
+        for (i, obj) in self.objects.iter().enumerate() {
+            let mesh = match obj.as_ref().and_then(|o| o.to_mesh(subdivisions)) {
+                Some(m) => m,
+                None => continue,
+            };
+            mesh.write_obj(&mut out, &format!("object_{}", i), vertex_offset)?;
+            vertex_offset += mesh.vertices.len();
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    pub fn objects(&self) -> impl Iterator<Item = &dyn Hit> {
+        self.objects.iter().flatten().map(|obj| obj.as_ref())
+    }
+
+    pub fn lights(&self) -> &[Box<dyn Light>] {
+        &self.lights
+    }
+
+    pub fn hit(&self, ray: &Ray) -> Option<(HitResult, &dyn Hit)> {
+        self.hit_id(ray).map(|(r, id)| (r, self.objects[id.0].as_deref().unwrap()))
+    }
+
+    //Distance from look_from to the first surface towards look_at, what a camera there would
+    //focus on automatically. None if the ray leaves the scene.
+    pub fn autofocus(&self, look_from: Vec3, look_at: Vec3) -> Option<fVec> {
+        self.hit_id(&Ray::new(look_from, (look_at - look_from).unit())).map(|(r, _)| r.at)
+    }
+
+    //Closest hit and the handle of the object that was hit
+    pub fn hit_id(&self, ray: &Ray) -> Option<(HitResult, ObjectId)> {
+        self.hit_counted(ray, &mut 0)
+    }
+
+    //Number of BVH nodes, including those inside objects, visited to find the closest hit
+    pub fn bvh_nodes_visited(&self, ray: &Ray, cull: Option<&FrustumCull>) -> usize {
+        let mut nodes = 0;
+        self.hit_culled_id(ray, cull, &mut nodes);
+        nodes
+    }
+
+    //Bounded objects outside the camera frustum left out, only valid for camera rays. Objects
+    //without bounds are always kept.
+    pub fn frustum_cull(&self, cam: &dyn CameraModel) -> FrustumCull {
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+        let mut bounds = Vec::new();
+        let mut culled = 0;
+        for (i, obj) in self.objects.iter().enumerate() {
+            match obj.as_ref().map(|obj| obj.bounds()) {
+                Some(Some(b)) if cam.may_see(&b) => {
+                    bounded.push(i);
+                    bounds.push(b);
+                }
+                Some(Some(_)) => culled += 1,
+                Some(None) => unbounded.push(i),
+                None => {}
+            }
+        }
+        FrustumCull {
+            bvh: Bvh::build(&bounds),
+            visible: bounded.len(),
+            culled,
+            bounded,
+            unbounded,
+        }
+    }
+
+    //Closest hit of a camera ray, testing only the objects left by frustum culling
+    pub fn hit_culled(&self, ray: &Ray, cull: Option<&FrustumCull>) -> Option<(HitResult, &dyn Hit)> {
+        self.hit_visiting(ray, cull, &mut 0)
+    }
+
+    //Like hit_culled(), also adding the BVH nodes visited to nodes
+    pub fn hit_visiting(&self, ray: &Ray, cull: Option<&FrustumCull>, nodes: &mut usize) -> Option<(HitResult, &dyn Hit)> {
+        self.hit_culled_id(ray, cull, nodes)
+            .map(|(r, id)| (r, self.objects[id.0].as_deref().unwrap()))
+    }
+
+    fn hit_culled_id(&self, ray: &Ray, cull: Option<&FrustumCull>, nodes: &mut usize) -> Option<(HitResult, ObjectId)> {
+        match cull {
+            Some(cull) => self.hit_in(ray, &cull.bvh, &cull.bounded, &cull.unbounded, nodes),
+            None => self.hit_counted(ray, nodes),
+        }
+    }
+
+    fn hit_counted(&self, ray: &Ray, nodes: &mut usize) -> Option<(HitResult, ObjectId)> {
+        if let Some(bvh) = &self.bvh {
+            return self.hit_in(ray, bvh, &self.bounded, &self.unbounded, nodes);
+        }
+        let mut temp_ray = *ray;
+        let mut hit_res = None;
+        for (i, obj) in self.objects.iter().enumerate() {
+            if let Some(r) = obj.as_ref().and_then(|obj| hit_opaque(obj.as_ref(), &temp_ray, nodes)) {
+                temp_ray.max = r.at;
+                hit_res = Some((r, ObjectId(i)));
+            }
+        }
+        hit_res
+    }
+
+    //Unbounded objects, then the bounded objects referenced by the bvh
+    fn hit_in(
+        &self,
+        ray: &Ray,
+        bvh: &Bvh,
+        bounded: &[usize],
+        unbounded: &[usize],
+        nodes: &mut usize,
+    ) -> Option<(HitResult, ObjectId)> {
+        let mut temp_ray = *ray;
+        let mut hit_res = None;
+        let mut test = |i: usize, ray: &Ray, nodes: &mut usize| {
+            let r = hit_opaque(self.objects[i].as_deref()?, ray, nodes)?;
+            hit_res = Some((r, ObjectId(i)));
+            Some(r.at)
+        };
+
+        for &i in unbounded.iter() {
+            if let Some(t) = test(i, &temp_ray, nodes) {
+                temp_ray.max = t;
+            }
+        }
+        let mut inner = 0;
+        *nodes += bvh.traverse(&temp_ray, |j, ray| test(bounded[j], ray, &mut inner));
+        *nodes += inner;
+
+        hit_res
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Integrator {
+    //Full recursive path tracing
+    PathTracer,
+    //One bounce of direct and environment lighting, following only perfect mirrors further
+    DirectLighting,
+    //Debug view of acceleration structure efficiency: BVH nodes visited by each camera ray,
+    //from black over blue, green and yellow to red at max_nodes
+    BvhHeatmap { max_nodes: usize },
+}
+
+//Order tiles are rendered in. Camera rays and light picks are seeded by the pixel, but materials
+//draw from their own random generators and the path guide learns as tiles finish, so a different
+//order gives different noise, not a different expected image.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TileOrder {
+    //Row by row from the top
+    #[default]
+    Scanline,
+    //Outward from the center, where the viewer looks first
+    Spiral,
+    //Along a Hilbert curve, consecutive tiles are neighbours and share cached geometry
+    Hilbert,
+}
+
+impl TileOrder {
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        Some(match name {
+            "scanline" => TileOrder::Scanline,
+            "spiral" => TileOrder::Spiral,
+            "hilbert" => TileOrder::Hilbert,
+            _ => return None,
+        })
+    }
+}
+
+//Distance along the Hilbert curve covering an n x n grid, n a power of two
+fn hilbert_index(n: usize, mut x: usize, mut y: usize) -> usize {
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as usize;
+        let ry = (y & s > 0) as usize;
+        d += s * s * ((3 * rx) ^ ry);
+        //Rotate the quadrant so the curve continues where the last one ended
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x &= s - 1;
+        y &= s - 1;
+        s /= 2;
+    }
+    d
+}
+
+//Pixel rectangle [x0, x1) x [y0, y1) rendered as one unit of work
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RenderStats {
+    pub time: Duration,
+    pub camera_rays: u64,
+    //Closest hit queries, camera rays included
+    pub rays: u64,
+    //Visibility tests towards lights and of shadow catchers
+    pub shadow_rays: u64,
+    //Including the nodes of meshes' own BVHs
+    pub bvh_nodes: u64,
+    //Wall time of the steps of the render, in order
+    pub stages: Vec<(&'static str, Duration)>,
+    //Samples dropped because shading produced NaN or infinity
+    pub invalid_samples: u64,
+    //Stopped early through the CancelToken, some tiles are missing
+    pub interrupted: bool,
+    //Objects skipped by camera rays because they are outside the view, see Renderer::frustum_culling()
+    pub culled_objects: usize,
+    pub visible_objects: usize,
+}
+
+impl RenderStats {
+    pub fn total_rays(&self) -> u64 {
+        self.rays + self.shadow_rays
+    }
+
+    //Path segments per camera ray, pass-through hits of nested dielectrics count as segments
+    pub fn average_path_length(&self) -> f64 {
+        self.rays as f64 / self.camera_rays.max(1) as f64
+    }
+
+    //Combine with the stats of another part of the same frame rendered at the same time
+    pub fn merge(&mut self, other: &RenderStats) {
+        self.time = self.time.max(other.time);
+        self.camera_rays += other.camera_rays;
+        self.rays += other.rays;
+        self.shadow_rays += other.shadow_rays;
+        self.bvh_nodes += other.bvh_nodes;
+        self.invalid_samples += other.invalid_samples;
+        self.interrupted |= other.interrupted;
+        self.culled_objects = other.culled_objects;
+        self.visible_objects = other.visible_objects;
+        for (name, time) in other.stages.iter() {
+            match self.stages.iter_mut().find(|(n, _)| n == name) {
+                Some((_, t)) => *t = (*t).max(*time),
+                None => self.stages.push((name, *time)),
+            }
+        }
+    }
+}
+
+//Multi-line summary for performance tuning
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Rays: {} total, {} camera, {} bounce, {} shadow",
+            self.total_rays(),
+            self.camera_rays,
+            self.rays.saturating_sub(self.camera_rays),
+            self.shadow_rays
+        )?;
+        writeln!(f, "Average path length: {:.2} segments", self.average_path_length())?;
+        writeln!(
+            f,
+            "BVH nodes visited: {} ({:.1} per ray)",
+            self.bvh_nodes,
+            self.bvh_nodes as f64 / self.total_rays().max(1) as f64
+        )?;
+        if self.time.as_secs_f64() > 0.0 {
+            writeln!(f, "Throughput: {:.2} Mrays/s", self.total_rays() as f64 / self.time.as_secs_f64() / 1e6)?;
+        }
+        if self.invalid_samples > 0 {
+            writeln!(f, "Invalid samples: {}", self.invalid_samples)?;
+        }
+        write!(f, "Time:")?;
+        for (i, (name, time)) in self.stages.iter().enumerate() {
+            write!(f, "{} {} {:.2?}", if i == 0 { "" } else { "," }, name, time)?;
+        }
+        Ok(())
+    }
+}
+
+//Everything a render produces
+pub struct RenderResult {
+    //Resolved from frame with the display limit applied
+    pub image: Image,
+    pub frame: FrameBuffer,
+    pub stats: RenderStats,
+    pub warnings: Vec<String>,
+    geometry: GBuffer,
+    objects: Vec<Option<ObjectId>>,
+}
+
+//Readout of a single pixel of a finished render
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PixelInfo {
+    pub radiance: Color,
+    pub color: Pixel,
+    //Distance along the pixel center ray, infinite for the environment
+    pub depth: fVec,
+    pub normal: Vec3,
+    pub object: Option<ObjectId>,
+    pub samples: usize,
+}
+
+impl RenderResult {
+    pub fn inspect(&self, x: usize, y: usize) -> Option<PixelInfo> {
+        let color = self.image.pixel(x, y)?;
+        let i = y * self.image.width() + x;
+        Some(PixelInfo {
+            radiance: self.frame.color(x, y)?,
+            color,
+            depth: self.geometry.depth[i],
+            normal: self.geometry.normal[i],
+            object: self.objects[i],
+            samples: self.frame.weight(x, y)? as usize,
+        })
+    }
+}
+
+//What camera rays see when they don't hit any object,
+//the environment still lights the scene through secondary rays
+#[derive(Clone, Debug)]
+pub enum Backdrop {
+    Environment,
+    Color(Color),
+    //Backplate stretched over the film
+    Image(Rc<Image>),
+    //Black with zero alpha, for compositing over other footage. Shadows on shadow catchers are
+    //opaque black.
+    Transparent,
+}
+
+//Camera settings mapping scene radiance in cd/m² to display values, so lights can be given in
+//physical units. White is where a sensor of the given ISO saturates, with the usual headroom
+//of 1.2 for saturation based sensitivity, as in "Moving Frostbite to PBR" (Lagarde 2014).
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Exposure {
+    pub iso: fCol,
+    //Seconds
+    pub shutter_speed: fCol,
+    pub f_number: fCol,
+}
+
+impl Exposure {
+    //Exposure value at ISO 100
+    pub fn ev100(&self) -> fCol {
+        (self.f_number * self.f_number / self.shutter_speed * 100.0 / self.iso).log2()
+    }
+
+    //Factor from radiance to display values
+    pub fn scale(&self) -> fCol {
+        1.0 / (1.2 * self.ev100().exp2())
+    }
+}
+
+impl fmt::Display for Exposure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ISO {} ", self.iso)?;
+        if self.shutter_speed < 1.0 {
+            write!(f, "1/{}s", (1.0 / self.shutter_speed).round())?;
+        } else {
+            write!(f, "{}s", self.shutter_speed)?;
+        }
+        write!(f, " f/{}", self.f_number)
+    }
+}
+
+//Shared flag to stop a render from another thread or a signal handler. The renderer checks it
+//between tiles, so the image keeps every tile finished before cancelling.
+#[derive(Clone, Default, Debug)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    //Allow the next render to run, e.g. after a cancelled preview
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+impl From<Arc<AtomicBool>> for CancelToken {
+    fn from(flag: Arc<AtomicBool>) -> CancelToken {
+        CancelToken(flag)
+    }
+}
+
+pub struct Renderer {
+    samples: usize,
+    bounces: usize,
+    seed: u64,
+    integrator: Integrator,
+    tile_size: usize,
+    tile_auto_tune: bool,
+    tile_order: TileOrder,
+    cancel: Option<CancelToken>,
+    photons: usize,
+    photon_passes: usize,
+    photon_radius: fVec,
+    caustics: Vec<PhotonMap>,
+    sample_range: Option<Range<usize>>,
+    backdrop: Backdrop,
+    guide_cell_size: Option<fVec>,
+    guide: Option<PathGuide>,
+    half_res_indirect: bool,
+    sampler: Sampler,
+    proxy: Option<ProxyOutput>,
+    checkpoint: Option<CheckpointOutput>,
+    counters: RayCounters,
+    //Samples rendered by tune_tile_size(), counted in the stats of the next render_into()
+    tuned: Cell<RenderStats>,
+    stratified_lights: bool,
+    display_limit: Option<fCol>,
+    exposure: Option<Exposure>,
+    outline: Option<Outline>,
+    //Only this part of the image is rendered, the rest of the frame is left as it is
+    region: Option<Tile>,
+    //Off renders every camera as a pinhole
+    depth_of_field: bool,
+    //render() records AOVs into its frame
+    aovs: bool,
+    //Receives tile progress, None renders silently
+    progress: Option<Box<dyn ProgressSink>>,
+}
+
+//Small preview of the image in progress, rewritten periodically while rendering
+struct ProxyOutput {
+    path: String,
+    factor: usize,
+    interval: Duration,
+    transfer: Transfer,
+}
+
+//Ray and traversal counts of the render in progress, moved into RenderStats when it ends
+#[derive(Default)]
+struct RayCounters {
+    rays: Cell<u64>,
+    shadow_rays: Cell<u64>,
+    bvh_nodes: Cell<u64>,
+}
+
+impl RayCounters {
+    fn count(&self, counter: &Cell<u64>, nodes: usize) {
+        counter.set(counter.get() + 1);
+        self.bvh_nodes.set(self.bvh_nodes.get() + nodes as u64);
+    }
+
+    //Add the counts since the last call to stats
+    fn drain_into(&self, stats: &mut RenderStats) {
+        stats.rays += self.rays.take();
+        stats.shadow_rays += self.shadow_rays.take();
+        stats.bvh_nodes += self.bvh_nodes.take();
+    }
+}
+
+//Checkpoint of the tiles done so far, rewritten periodically while rendering
+struct CheckpointOutput {
+    path: String,
+    interval: Duration,
+    job: CheckpointJob,
+}
+
+//Per camera sample state handed down the path
+#[derive(Clone, Copy)]
+struct SampleContext<'a> {
+    caustics: Option<&'a PhotonMap>,
+    //Rotates through the lights across the samples of a pixel when lights are stratified
+    light_stratum: usize,
+    //Objects the camera ray is tested against, cleared for all later rays of the path
+    camera_cull: Option<&'a FrustumCull>,
+    //Dielectrics the current ray travels inside
+    media: MediumStack,
+    //Receives the first shaded hit of the path for the AOVs, cleared once it is recorded
+    first_hit: Option<&'a Cell<Option<PathHit>>>,
+}
+
+//First shaded hit of a camera ray
+#[derive(Clone, Copy)]
+struct PathHit {
+    ray: Ray,
+    hit: HitResult,
+    object: ObjectId,
+}
+
+//How a path continues at a hit, only ever lives for one match so the size difference doesn't matter
+#[allow(clippy::large_enum_variant)]
+enum Surface<'a> {
+    //Shade the hit, with the IOR outside the surface for dielectrics
+    Shade(Option<fVec>),
+    //Ignore the surface and trace on
+    PassThrough(Ray, SampleContext<'a>),
+}
+
+//Where a path goes on after bouncing off a surface
+struct Bounce<'a> {
+    ray: Ray,
+    ctx: SampleContext<'a>,
+    //Point at which path guiding learns the light arriving along ray
+    guided_at: Option<Vec3>,
+}
+
+//A path's next shaded surface: attenuation * (direct + weight * light arriving along bounce)
+struct PathVertex<'a> {
+    //Ray that hit the surface, after passing through hidden ones
+    ray: Ray,
+    hit: HitResult,
+    attenuation: Color,
+    //Emitted and directly reflected light
+    direct: Color,
+    weight: Color,
+    bounce: Option<Bounce<'a>>,
+    //Specular or a medium boundary, its indirect light is as sharp as the direct light
+    sharp: bool,
+}
+
+//Lives for one match like Surface
+#[allow(clippy::large_enum_variant)]
+enum PathStep<'a> {
+    //Light from the environment, attenuated by the media on the way
+    Miss(Color),
+    Hit(PathVertex<'a>),
+}
+
+impl PathStep<'_> {
+    fn attenuated(self, attenuation: Color) -> Self {
+        match self {
+            PathStep::Miss(col) => PathStep::Miss(attenuation * col),
+            PathStep::Hit(vertex) => PathStep::Hit(PathVertex {
+                attenuation: attenuation * vertex.attenuation,
+                ..vertex
+            }),
+        }
+    }
+}
+
+//Shading at the first hit of a camera ray, split into the parts computed at full resolution
+struct FirstHit<'a> {
+    //Emitted and directly reflected light, or the whole path for sharp surfaces
+    light: Color,
+    //Weight of the indirect light arriving along bounce
+    albedo: Color,
+    bounce: Option<Bounce<'a>>,
+    normal: Vec3,
+    depth: fVec,
+    //0 for a transparent backdrop
+    alpha: fCol,
+}
+
+impl Renderer {
+    pub fn new(samples: usize, bounces: usize) -> Renderer {
+        Renderer {
+            samples,
+            bounces,
+            seed: rand::random(),
+            integrator: Integrator::PathTracer,
+            tile_size: 32,
+            tile_auto_tune: false,
+            tile_order: TileOrder::Scanline,
+            cancel: None,
+            photons: 0,
+            photon_passes: 0,
+            photon_radius: 0.0,
+            caustics: Vec::new(),
+            sample_range: None,
+            backdrop: Backdrop::Environment,
+            guide_cell_size: None,
+            guide: None,
+            half_res_indirect: false,
+            sampler: Sampler::Random,
+            proxy: None,
+            checkpoint: None,
+            counters: RayCounters::default(),
+            tuned: Cell::default(),
+            stratified_lights: false,
+            display_limit: None,
+            exposure: None,
+            outline: None,
+            region: None,
+            depth_of_field: true,
+            aovs: false,
+            progress: Some(Box::new(ConsoleProgress)),
+        }
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    pub fn set_samples(&mut self, samples: usize) {
+        self.samples = samples;
+    }
+
+    pub fn set_bounces(&mut self, bounces: usize) {
+        self.bounces = bounces;
+    }
+
+    //Progress is printed to stdout by default, turn it off when stdout is used for something else
+    pub fn set_progress(&mut self, enabled: bool) {
+        self.progress = enabled.then(|| Box::new(ConsoleProgress) as Box<dyn ProgressSink>);
+    }
+
+    pub fn set_progress_sink(&mut self, sink: Box<dyn ProgressSink>) {
+        self.progress = Some(sink);
+    }
+
+    //Edge length of the square tiles the image is rendered and checkpointed in
+    pub fn set_tile_size(&mut self, size: usize) {
+        self.tile_size = size.max(1);
+    }
+
+    pub fn tile_size(&self) -> usize {
+        self.tile_size
+    }
+
+    //Let tune_tile_size() pick the fastest of a few tile sizes for the scene, render() calls it
+    pub fn set_tile_auto_tune(&mut self, enabled: bool) {
+        self.tile_auto_tune = enabled;
+    }
+
+    pub fn set_tile_order(&mut self, order: TileOrder) {
+        self.tile_order = order;
+    }
+
+    pub fn tile_order(&self) -> TileOrder {
+        self.tile_order
+    }
+
+    pub fn set_integrator(&mut self, integrator: Integrator) {
+        self.integrator = integrator;
+    }
+
+    //Once cancelled, rendering stops after the tile currently in progress and RenderStats::interrupted
+    //is set, the tiles left are marked as not done
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = Some(token);
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
+    }
+
+    pub fn set_backdrop(&mut self, backdrop: Backdrop) {
+        self.backdrop = backdrop;
+    }
+
+    //Learn the incoming radiance in an SD-tree while rendering and sample diffuse bounces from it,
+    //spatial cells aren't split below cell_size. Path tracer only.
+    pub fn set_path_guiding(&mut self, cell_size: fVec) {
+        self.guide_cell_size = Some(cell_size);
+    }
+
+    pub fn set_sampler(&mut self, sampler: Sampler) {
+        self.sampler = sampler;
+    }
+
+    pub fn sampler(&self) -> Sampler {
+        self.sampler
+    }
+
+    //Speed mode for previews: indirect light is only traced for one pixel of each 2x2 block and
+    //upsampled guided by the full resolution normals and depths, path tracer only
+    pub fn set_half_res_indirect(&mut self, enabled: bool) {
+        self.half_res_indirect = enabled;
+    }
+
+    //Debugging aid: only render the given indices of each pixel's sample sequence,
+    //e.g. 5..6 shows sample 5 alone which makes correlation between pixels visible
+    pub fn set_sample_range(&mut self, range: Range<usize>) {
+        self.sample_range = Some(range);
+    }
+
+    //Trace caustic photons from the lights in several passes with shrinking gather radius,
+    //sample i of each pixel uses pass i % passes (progressive photon mapping)
+    pub fn set_caustic_photons(&mut self, photons: usize, passes: usize, radius: fVec) {
+        self.photons = photons;
+        self.photon_passes = passes;
+        self.photon_radius = radius;
+    }
+
+    //Light only one light per shading point, picked so the samples of a pixel cycle through all
+    //lights instead of each light being lit at every hit. Cheaper with many lights, and unlike
+    //random picks no light is left out of a pixel once it has as many samples as there are lights.
+    pub fn set_stratified_lights(&mut self, enabled: bool) {
+        self.stratified_lights = enabled;
+    }
+
+    //Bright emitters are scaled down to this radiance in the displayed image without shifting their
+    //hue, the radiance kept in RenderResult is not clamped
+    pub fn set_display_limit(&mut self, max: fCol) {
+        self.display_limit = Some(max);
+    }
+
+    //Scale the radiance of the displayed image, None to keep it as rendered. Applied when the
+    //frame is resolved, so a finished frame can be resolved again at another exposure.
+    pub fn set_exposure(&mut self, exposure: Option<Exposure>) {
+        self.exposure = exposure;
+    }
+
+    pub fn exposure(&self) -> Option<Exposure> {
+        self.exposure
+    }
+
+    //Draw lines along silhouettes and creases once the whole frame is rendered, for toon shading
+    pub fn set_outline(&mut self, outline: Outline) {
+        self.outline = Some(outline);
+    }
+
+    //Render only the pixels in region, to iterate on part of an expensive image. Tiles start at
+    //its corner, so done flags of checkpoints depend on the region as well.
+    pub fn set_region(&mut self, region: Option<Tile>) {
+        self.region = region;
+    }
+
+    pub fn region(&self) -> Option<Tile> {
+        self.region
+    }
+
+    //The region clipped to the image, or the whole image
+    fn render_area(&self, cam: &dyn CameraModel) -> Tile {
+        let (width, height) = cam.resolution();
+        let region = self.region.unwrap_or(Tile {
+            x0: 0,
+            y0: 0,
+            x1: width,
+            y1: height,
+        });
+        Tile {
+            x0: region.x0.min(width),
+            y0: region.y0.min(height),
+            x1: region.x1.min(width),
+            y1: region.y1.min(height),
+        }
+    }
+
+    //Turn depth of field off to send all camera rays through the lens center, everything is in
+    //focus. For previews, the camera keeps its aperture for the final render.
+    pub fn set_depth_of_field(&mut self, enabled: bool) {
+        self.depth_of_field = enabled;
+    }
+
+    pub fn depth_of_field(&self) -> bool {
+        self.depth_of_field
+    }
+
+    //Record albedo, normal, depth and object AOVs in the frame of render(), see FrameBuffer::aovs().
+    //Frames passed to render_into() carry AOVs if they were created with them.
+    pub fn set_aovs(&mut self, enabled: bool) {
+        self.aovs = enabled;
+    }
+
+    pub fn aovs(&self) -> bool {
+        self.aovs
+    }
+
+    //Periodically write a PNG at 1/factor of the resolution to path, so long renders can be
+    //watched over slow links. Also written once rendering finishes.
+    //transfer should match the final image, so the proxy previews it faithfully
+    pub fn set_proxy(&mut self, path: &str, factor: usize, interval: Duration, transfer: Transfer) {
+        self.proxy = Some(ProxyOutput {
+            path: path.to_string(),
+            factor,
+            interval,
+            transfer,
+        });
+    }
+
+    //Periodically save a Checkpoint of the frame to path, so long renders survive crashes. The
+    //random state of materials is not saved, resumed tiles match up to that.
+    pub fn set_checkpoint(&mut self, path: &str, interval: Duration, job: CheckpointJob) {
+        self.checkpoint = Some(CheckpointOutput {
+            path: path.to_string(),
+            interval,
+            job,
+        });
+    }
+
+    //Scene preprocessing, separate from render() so a scene can be prepared once and rendered many times
+    pub fn prepare(&mut self, scene: &mut Scene) -> Duration {
+        let start = Instant::now();
+        scene.prepare();
+
+        self.guide = self
+            .guide_cell_size
+            .map(|size| PathGuide::new(scene.bounds(), size, self.seed));
+
+        self.caustics.clear();
+        if self.photons > 0 {
+            const ALPHA: fVec = 2.0 / 3.0;
+            let mut rng = rand::rngs::SmallRng::seed_from_u64(self.seed);
+            let mut radius_squared = self.photon_radius * self.photon_radius;
+            for pass in 1..=self.photon_passes {
+                let map = PhotonMap::build(scene, self.photons, radius_squared.sqrt(), &mut rng);
+                self.caustics.push(map);
+                radius_squared *= (pass as fVec + ALPHA) / (pass as fVec + 1.0);
+            }
+        }
+
+        start.elapsed()
+    }
+
+    //With auto tuning on, render a block in the middle of the image in tiles of each candidate
+    //size, one sample per candidate, and keep the fastest size for the rest of the render. The
+    //block lines up with the tiles of every candidate, so it is finished in the chosen size right
+    //away and returned as done tiles: nothing rendered for the timing is thrown away, its stats
+    //are part of those of the next render_into(). Cancelling leaves the frame and tile size as
+    //they were. Call after prepare() and before tiles(), the tile layout must not change during a
+    //render.
+    pub fn tune_tile_size(&mut self, scene: &Scene, cam: &dyn CameraModel, frame: &mut FrameBuffer) -> (usize, Vec<bool>) {
+        const CANDIDATES: [usize; 5] = [8, 16, 32, 64, 128];
+        let samples = self.sample_range.clone().unwrap_or(0..self.samples);
+        //One sample warms up caches so the first candidate is not penalized
+        let tuning = samples.start..samples.start + CANDIDATES.len() + 1;
+        //Half resolution indirect lighting renders the whole frame at once, ignoring done tiles
+        let half_res = self.half_res_indirect && self.integrator == Integrator::PathTracer;
+        let area = self.render_area(cam);
+        if !self.tile_auto_tune || half_res || tuning.end > samples.end || area.x0 == area.x1 || area.y0 == area.y1 {
+            return (self.tile_size, vec![false; self.tiles(cam).len()]);
+        }
+
+        //On the grid of the largest candidate, which the smaller ones divide
+        let block = CANDIDATES[CANDIDATES.len() - 1];
+        let corner = |start: usize, end: usize| start + (end - start) / 2 / block * block;
+        let (x0, y0) = (corner(area.x0, area.x1), corner(area.y0, area.y1));
+        let (x1, y1) = ((x0 + block).min(area.x1), (y0 + block).min(area.y1));
+        let start = Instant::now();
+        let cull = self.frustum_culling().then(|| scene.frustum_cull(cam));
+        let sample_range = self.sample_range.take();
+        let untouched = self.cancel.is_some().then(|| frame.clone());
+
+        let mut stats = RenderStats::default();
+        //None if cancelled before the block was finished
+        let mut render_block = |renderer: &mut Renderer, size: usize, samples: Range<usize>| {
+            renderer.sample_range = Some(samples);
+            let start = Instant::now();
+            for ty in (y0..y1).step_by(size) {
+                for tx in (x0..x1).step_by(size) {
+                    if renderer.cancelled() {
+                        return None;
+                    }
+                    let tile = Tile {
+                        x0: tx,
+                        y0: ty,
+                        x1: (tx + size).min(x1),
+                        y1: (ty + size).min(y1),
+                    };
+                    renderer.render_tile(scene, cam, frame, &tile, cull.as_ref(), &mut stats);
+                }
+            }
+            Some(start.elapsed())
+        };
+        let best = render_block(self, block, tuning.start..tuning.start + 1)
+            .and_then(|_| {
+                CANDIDATES
+                    .iter()
+                    .zip(tuning.start + 1..)
+                    .map(|(&size, s)| Some((render_block(self, size, s..s + 1)?, size)))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .and_then(|timings| timings.into_iter().min())
+            .map(|(_, size)| size)
+            .and_then(|size| render_block(self, size, tuning.end..samples.end).map(|_| size));
+
+        self.sample_range = sample_range;
+        self.counters.drain_into(&mut stats);
+        stats.stages.push(("tile size tuning", start.elapsed()));
+        stats.time = start.elapsed();
+        let Some(best) = best else {
+            if let Some(untouched) = untouched {
+                frame.copy_rect(&untouched, (x0, y0), (x1, y1));
+            }
+            stats.interrupted = true;
+            self.tuned.set(stats);
+            return (self.tile_size, vec![false; self.tiles(cam).len()]);
+        };
+        self.tuned.set(stats);
+        self.tile_size = best;
+        let done = self
+            .tiles(cam)
+            .iter()
+            .map(|t| t.x0 >= x0 && t.x1 <= x1 && t.y0 >= y0 && t.y1 <= y1)
+            .collect();
+        (best, done)
+    }
+
+    pub fn tiles(&self, cam: &dyn CameraModel) -> Vec<Tile> {
+        let area = self.render_area(cam);
+        let mut tiles = Vec::new();
+
+        for y0 in (area.y0..area.y1).step_by(self.tile_size) {
+            for x0 in (area.x0..area.x1).step_by(self.tile_size) {
+                tiles.push(Tile {
+                    x0,
+                    y0,
+                    x1: (x0 + self.tile_size).min(area.x1),
+                    y1: (y0 + self.tile_size).min(area.y1),
+                });
+            }
+        }
+
+        tiles
+    }
+
+    //Indices into tiles() in the order they are rendered. tiles() itself stays in scanline order,
+    //so done flags of checkpoints don't depend on the order.
+    pub fn tile_schedule(&self, cam: &dyn CameraModel) -> Vec<usize> {
+        let area = self.render_area(cam);
+        let tiles_x = (area.x1 - area.x0).div_ceil(self.tile_size);
+        let tiles_y = (area.y1 - area.y0).div_ceil(self.tile_size);
+        let mut schedule: Vec<usize> = (0..tiles_x * tiles_y).collect();
+        match self.tile_order {
+            TileOrder::Scanline => {}
+            TileOrder::Spiral => {
+                //In tile units from the center, ties go around counterclockwise from the right
+                let key = |i: &usize| {
+                    let dx = (i % tiles_x) as fVec + 0.5 - tiles_x as fVec / 2.0;
+                    let dy = (i / tiles_x) as fVec + 0.5 - tiles_y as fVec / 2.0;
+                    let angle = (-dy).atan2(dx).rem_euclid(std::f32::consts::TAU);
+                    (dx * dx + dy * dy, angle)
+                };
+                schedule.sort_by(|a, b| {
+                    let (a, b) = (key(a), key(b));
+                    a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1))
+                });
+            }
+            TileOrder::Hilbert => {
+                let n = tiles_x.max(tiles_y).next_power_of_two();
+                schedule.sort_by_key(|i| hilbert_index(n, i % tiles_x, i / tiles_x));
+            }
+        }
+        schedule
+    }
+
+    //Whole render of the camera's image, with the tile size tuned first if auto tuning is on
+    pub fn render(&mut self, scene: &Scene, cam: &dyn CameraModel) -> RenderResult {
+        let (width, height) = cam.resolution();
+        let mut frame = if self.aovs {
+            FrameBuffer::with_aovs(width, height)
+        } else {
+            FrameBuffer::new(width, height)
+        };
+        let (_, mut done) = self.tune_tile_size(scene, cam, &mut frame);
+        let mut stats = self.render_into(scene, cam, &mut frame, &mut done);
+
+        let (geometry, objects) = timed(&mut stats, "geometry buffers", || Self::center_geometry(scene, cam));
+        RenderResult {
+            image: self.resolve(&frame),
+            frame,
+            warnings: self.warnings(&stats),
+            stats,
+            geometry,
+            objects,
+        }
+    }
+
+    //Geometry seen through the pixel centers, for inspecting the result and drawing outlines
+    fn center_geometry(scene: &Scene, cam: &dyn CameraModel) -> (GBuffer, Vec<Option<ObjectId>>) {
+        let (width, height) = cam.resolution();
+        let mut geometry = GBuffer::new(width, height);
+        let mut objects = vec![None; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let ray = cam.ray_through(x, y, (0.0, 0.0), (0.5, 0.5));
+                if let Some((r, id)) = scene.hit_id(&ray) {
+                    let i = y * width + x;
+                    objects[i] = Some(id);
+                    geometry.normal[i] = r.surface_normal(&ray);
+                    geometry.depth[i] = (r.intersect - ray.origin).length();
+                }
+            }
+        }
+        (geometry, objects)
+    }
+
+    fn draw_outline(&self, scene: &Scene, cam: &dyn CameraModel, frame: &mut FrameBuffer) {
+        let Some(outline) = &self.outline else {
+            return;
+        };
+        let (geometry, _) = Self::center_geometry(scene, cam);
+        let mask = outline_mask(&geometry, outline);
+        let area = self.render_area(cam);
+        for y in area.y0..area.y1 {
+            for x in area.x0..area.x1 {
+                if !mask[y * geometry.width + x] {
+                    continue;
+                }
+                frame.set(x, y, outline.color);
+            }
+        }
+    }
+
+    //Render all tiles not yet marked as done, adding their samples to frame
+    pub fn render_into(&self, scene: &Scene, cam: &dyn CameraModel, frame: &mut FrameBuffer, done: &mut [bool]) -> RenderStats {
+        let start = Instant::now();
+        let mut stats = self.tuned.take();
+
+        //Upsampling needs the whole frame, so this mode renders in one go
+        if self.half_res_indirect && self.integrator == Integrator::PathTracer {
+            let half_start = Instant::now();
+            self.render_half_res_indirect(scene, cam, frame, &mut stats);
+            stats.stages.push(("half resolution indirect", half_start.elapsed()));
+            if !stats.interrupted {
+                if self.outline.is_some() {
+                    timed(&mut stats, "outline", || self.draw_outline(scene, cam, frame));
+                }
+                done.fill(true);
+            }
+            self.write_proxy(frame);
+            self.counters.drain_into(&mut stats);
+            stats.time += start.elapsed();
+            return stats;
+        }
+
+        let cull = self
+            .frustum_culling()
+            .then(|| timed(&mut stats, "frustum culling", || scene.frustum_cull(cam)));
+        if let Some(cull) = &cull {
+            stats.culled_objects = cull.culled;
+            stats.visible_objects = cull.visible;
+        }
+        let tiles = self.tiles(cam);
+        let mut last_proxy = Instant::now();
+        let mut last_checkpoint = Instant::now();
+
+        let samples_per_pixel = self.sample_range.as_ref().map_or(self.samples, |r| r.len()) as u64;
+        let tile_samples = |tile: &Tile| ((tile.x1 - tile.x0) * (tile.y1 - tile.y0)) as u64 * samples_per_pixel;
+        let mut progress = Progress {
+            tiles_done: done.iter().filter(|d| **d).count(),
+            tiles: tiles.len(),
+            samples: tiles.iter().zip(done.iter()).filter(|(_, d)| !**d).map(|(t, _)| tile_samples(t)).sum(),
+            ..Progress::default()
+        };
+        if let Some(sink) = &self.progress {
+            sink.update(&progress);
+        }
+
+        let tiles_start = Instant::now();
+        for i in self.tile_schedule(cam) {
+            let tile = &tiles[i];
+            if done[i] {
+                continue;
+            }
+            if self.cancelled() {
+                stats.interrupted = true;
+                break;
+            }
+
+            self.render_tile(scene, cam, frame, tile, cull.as_ref(), &mut stats);
+            done[i] = true;
+            if let Some(sink) = &self.progress {
+                progress.tile_done(tile_samples(tile), stats.camera_rays, start.elapsed());
+                sink.update(&progress);
+            }
+
+            if self.proxy.as_ref().is_some_and(|p| last_proxy.elapsed() >= p.interval) {
+                self.write_proxy(frame);
+                last_proxy = Instant::now();
+            }
+            if self.checkpoint.as_ref().is_some_and(|c| last_checkpoint.elapsed() >= c.interval) {
+                self.write_checkpoint(frame, done);
+                last_checkpoint = Instant::now();
+            }
+        }
+        stats.stages.push(("tiles", tiles_start.elapsed()));
+        if let Some(sink) = &self.progress {
+            sink.finish(&progress);
+        }
+        if self.outline.is_some() && done.iter().all(|d| *d) {
+            timed(&mut stats, "outline", || self.draw_outline(scene, cam, frame));
+        }
+        self.write_proxy(frame);
+
+        self.counters.drain_into(&mut stats);
+        stats.time += start.elapsed();
+        stats
+    }
+
+    //Preview integrators that shade the first hit without gathering light from all over the scene
+    //only test camera rays against objects in view, which pays off on large scenes
+    pub fn frustum_culling(&self) -> bool {
+        matches!(self.integrator, Integrator::DirectLighting | Integrator::BvhHeatmap { .. })
+    }
+
+    //Resolved pixel with the display limit and then the exposure applied. The limit is on scene
+    //radiance as before exposures existed, so changing the exposure doesn't move it.
+    fn display(&self, col: Color) -> Color {
+        let col = self.display_limit.map_or(col, |max| col.limit(max));
+        self.exposure.map_or(col, |exposure| col * exposure.scale())
+    }
+
+    //Image for output, with the display limit and exposure applied
+    //Image with alpha when the backdrop is transparent
+    pub fn resolve(&self, frame: &FrameBuffer) -> Image {
+        let mut img = if self.exposure.is_none() && self.display_limit.is_none() {
+            frame.resolve()
+        } else {
+            frame.resolve_with(|col| self.display(col))
+        };
+        if let Backdrop::Transparent = self.backdrop {
+            img.set_alpha(Some(frame.alpha_channel()));
+        }
+        img
+    }
+
+    //The image of resolve() as a tiled EXR, resolved and written one tile at a time so even huge
+    //renders never hold the whole image or the encoded file in memory besides the frame
+    pub fn save_exr_tiled(&self, frame: &FrameBuffer, path: &str, tile_size: usize, metadata: &[(String, String)]) -> Result<()> {
+        let alpha = matches!(self.backdrop, Backdrop::Transparent);
+        let channels: &[&str] = if alpha { &["R", "G", "B", "A"] } else { &["R", "G", "B"] };
+        let mut writer = TiledExrWriter::create(path, frame.width(), frame.height(), channels, tile_size, metadata)?;
+        let (tiles_x, tiles_y) = writer.tiles();
+        for ty in 0..tiles_y {
+            for tx in 0..tiles_x {
+                let (x0, y0, x1, y1) = writer.tile_rect(tx, ty);
+                let mut values = vec![Vec::with_capacity((x1 - x0) * (y1 - y0)); channels.len()];
+                for y in y0..y1 {
+                    for x in x0..x1 {
+                        let col = self.display(frame.color(x, y).unwrap());
+                        values[0].push(col.r);
+                        values[1].push(col.g);
+                        values[2].push(col.b);
+                        if alpha {
+                            values[3].push(frame.alpha(x, y).unwrap());
+                        }
+                    }
+                }
+                writer.write_tile(tx, ty, &values)?;
+            }
+        }
+        writer.finish()
+    }
+
+    //Written periodically and at the end of render_into(), and by whoever assembles the frame
+    //from several renders
+    pub fn write_proxy(&self, frame: &FrameBuffer) {
+        if let Some(proxy) = &self.proxy {
+            let mut img = self.resolve(frame).downscale(proxy.factor);
+            img.set_transfer(proxy.transfer);
+            if let Err(e) = img.save_png(&proxy.path) {
+                self.warn(&format!("could not write proxy image {}: {}", proxy.path, e));
+            }
+        }
+    }
+
+    //Through the progress sink, a silent renderer drops them
+    fn warn(&self, message: &str) {
+        if let Some(sink) = &self.progress {
+            sink.warning(message);
+        }
+    }
+
+    fn write_checkpoint(&self, frame: &FrameBuffer, done: &[bool]) {
+        if let Some(checkpoint) = &self.checkpoint {
+            let state = Checkpoint {
+                seed: self.seed,
+                samples: self.samples,
+                bounces: self.bounces,
+                sampler: self.sampler,
+                tile_size: self.tile_size,
+                region: self.region,
+                done: done.to_vec(),
+                frame: frame.clone(),
+                job: checkpoint.job.clone(),
+            };
+            if let Err(e) = state.save(&checkpoint.path) {
+                self.warn(&format!("could not write checkpoint {}: {}", checkpoint.path, e));
+            }
+        }
+    }
+
+    //Non-fatal problems worth reporting to whoever looks at the image
+    pub fn warnings(&self, stats: &RenderStats) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if stats.interrupted {
+            warnings.push("render was interrupted, image is incomplete".to_string());
+        }
+        if stats.invalid_samples > 0 {
+            warnings.push(format!(
+                "discarded {} samples with NaN or infinite radiance",
+                stats.invalid_samples
+            ));
+        }
+        if let Some(range) = &self.sample_range {
+            warnings.push(format!("only samples {:?} of each pixel were rendered", range));
+        }
+        if self.half_res_indirect && self.integrator != Integrator::PathTracer {
+            warnings.push("half resolution indirect lighting requires the path tracer, ignored".to_string());
+        } else if self.half_res_indirect && self.region.is_some() {
+            warnings.push("half resolution indirect lighting renders the whole image, the region is ignored".to_string());
+        }
+        warnings
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn render_tile(
+        &self,
+        scene: &Scene,
+        cam: &dyn CameraModel,
+        frame: &mut FrameBuffer,
+        tile: &Tile,
+        cull: Option<&FrustumCull>,
+        stats: &mut RenderStats,
+    ) {
+        let samples = self.sample_range.clone().unwrap_or(0..self.samples);
+
+        for y in tile.y0..tile.y1 {
+            for x in tile.x0..tile.x1 {
+                let mut sum = Color::black();
+                let mut coverage = 0.0;
+
+                for s in samples.clone() {
+                    let (ray, film, mut rng) = self.camera_sample(cam, x, y, s);
+                    let first_hit = Cell::new(None);
+                    let ctx = SampleContext {
+                        first_hit: frame.has_aovs().then_some(&first_hit),
+                        ..self.sample_context(x, y, s, cull)
+                    };
+
+                    let (col, alpha) = match self.backdrop_sample(scene, &ray, film, &mut rng, ctx) {
+                        Some(backdrop) => backdrop,
+                        None => (self.colorize_ray(scene, &ray, self.bounces, ctx), 1.0),
+                    };
+                    if frame.has_aovs() {
+                        frame.record_first_hit(x, y, &Self::first_hit_aovs(scene, first_hit.get(), col, alpha));
+                    }
+                    stats.camera_rays += 1;
+                    if Self::valid_sample(col, stats) {
+                        sum = sum + col;
+                        coverage += alpha;
+                    } else {
+                        //Counted as an opaque black sample
+                        coverage += 1.0;
+                    }
+                }
+                frame.accumulate(x, y, sum, coverage, samples.len() as fCol);
+            }
+        }
+    }
+
+    //NaN or infinity from shading, counted in the stats, must not reach the image
+    fn valid_sample(col: Color, stats: &mut RenderStats) -> bool {
+        let valid = col.r.is_finite() && col.g.is_finite() && col.b.is_finite();
+        if !valid {
+            stats.invalid_samples += 1;
+        }
+        valid
+    }
+
+    fn first_hit_aovs(scene: &Scene, first_hit: Option<PathHit>, radiance: Color, alpha: fCol) -> FirstHitSample<'_> {
+        match first_hit {
+            Some(PathHit { ray, hit: r, object: id }) => {
+                let material = scene.get(id).unwrap().material_at(&r);
+                let object_name = scene.object_name(id).unwrap();
+                FirstHitSample {
+                    albedo: material.albedo(&ray, &r),
+                    normal: r.surface_normal(&ray),
+                    depth: (r.intersect - ray.origin).length(),
+                    object: Some(id),
+                    //Unnamed materials can't be told apart across threads, they get no matte
+                    names: Some((object_name, scene.material_name(material))),
+                    radiance,
+                    alpha,
+                }
+            }
+            None => FirstHitSample {
+                albedo: Color::black(),
+                normal: Vec3::origin(),
+                depth: fVec::INFINITY,
+                object: None,
+                names: None,
+                radiance,
+                alpha,
+            },
+        }
+    }
+
+    //Camera ray for sample s of pixel (x, y), its film coordinates and the sample's random sequence
+    fn camera_sample(&self, cam: &dyn CameraModel, x: usize, y: usize, s: usize) -> (Ray, (fVec, fVec), SmallRng) {
+        let mut rng = SmallRng::seed_from_u64(self.sample_seed(x, y, s));
+        let (open, close) = cam.shutter();
+        let ((rnum, rnum2), lens, time) = match self.sampler {
+            Sampler::Random => {
+                let offset = (rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0));
+                let lens = (rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0));
+                //Only drawn with an open shutter, so still frames keep their random sequence
+                let time = if close > open { rng.gen_range(open..close) } else { open };
+                (offset, lens, time)
+            }
+            Sampler::Halton => {
+                //Same shift for all samples of the pixel
+                let mut pixel_rng = SmallRng::seed_from_u64(self.sample_seed(x, y, usize::MAX));
+                let rotation: [fVec; HALTON_DIMENSIONS] = pixel_rng.gen();
+                let d = |dim: usize| halton_rotated(dim, s as u64, rotation[dim]);
+                ((d(0), d(1)), (d(2), d(3)), open + (close - open) * d(4))
+            }
+        };
+
+        let (width, height) = cam.resolution();
+        let film = ((x as fVec + rnum) / width as fVec, (y as fVec + rnum2) / height as fVec);
+        //The lens numbers are drawn anyway, so turning depth of field off keeps the noise pattern
+        let lens = if self.depth_of_field && cam.has_lens() {
+            cam.lens_sample(lens.0, lens.1, film.0, film.1)
+        } else {
+            (0.0, 0.0)
+        };
+        let ray = cam.film_ray_at(film.0, film.1, lens, time);
+        (ray, film, rng)
+    }
+
+    fn sample_context<'a>(&'a self, x: usize, y: usize, sample: usize, cull: Option<&'a FrustumCull>) -> SampleContext<'a> {
+        SampleContext {
+            camera_cull: cull,
+            caustics: if self.caustics.is_empty() {
+                None
+            } else {
+                Some(&self.caustics[sample % self.caustics.len()])
+            },
+            //Pixels start at different lights
+            light_stratum: sample.wrapping_add(self.sample_seed(x, y, usize::MAX - 1) as usize),
+            media: MediumStack::default(),
+            first_hit: None,
+        }
+    }
+
+    fn render_half_res_indirect(
+        &self,
+        scene: &Scene,
+        cam: &dyn CameraModel,
+        frame: &mut FrameBuffer,
+        stats: &mut RenderStats,
+    ) {
+        let (width, height) = cam.resolution();
+        let (half_width, half_height) = (width.div_ceil(2), height.div_ceil(2));
+        let samples = self.sample_range.clone().unwrap_or(0..self.samples);
+        let count = samples.len().max(1) as fCol;
+
+        let mut light = vec![Color::black(); width * height];
+        let mut albedo = vec![Color::black(); width * height];
+        let mut coverage = vec![0.0; width * height];
+        let mut geometry = GBuffer::new(width, height);
+        let mut indirect = vec![Color::black(); half_width * half_height];
+        let mut half_geometry = GBuffer::new(half_width, half_height);
+
+        if let Some(sink) = &self.progress {
+            sink.phase("Rendering direct light at full resolution");
+        }
+        //Cancelling leaves the remaining rows black, checked per row as there are no tiles
+        for y in 0..height {
+            if self.cancelled() {
+                stats.interrupted = true;
+                break;
+            }
+            for x in 0..width {
+                let i = y * width + x;
+                for s in samples.clone() {
+                    let first = self.first_hit_sample(scene, cam, x, y, s);
+                    stats.camera_rays += 1;
+                    if Self::valid_sample(first.light + first.albedo, stats) {
+                        light[i] = light[i] + first.light * (1.0 / count);
+                        albedo[i] = albedo[i] + first.albedo * (1.0 / count);
+                        coverage[i] += first.alpha;
+                    } else {
+                        coverage[i] += 1.0;
+                    }
+                    if s == samples.start {
+                        geometry.normal[i] = first.normal;
+                        geometry.depth[i] = first.depth;
+                    }
+                }
+            }
+        }
+
+        if let Some(sink) = &self.progress {
+            sink.phase("Rendering indirect light at half resolution");
+        }
+        for hy in 0..half_height {
+            if stats.interrupted || self.cancelled() {
+                stats.interrupted = true;
+                break;
+            }
+            for hx in 0..half_width {
+                let i = hy * half_width + hx;
+                let (x, y) = ((2 * hx).min(width - 1), (2 * hy).min(height - 1));
+                for s in samples.clone() {
+                    let first = self.first_hit_sample(scene, cam, x, y, s);
+                    stats.camera_rays += 1;
+                    if let Some(bounce) = &first.bounce {
+                        let incoming = self.follow(scene, bounce, self.bounces - 1);
+                        if Self::valid_sample(incoming, stats) {
+                            indirect[i] = indirect[i] + incoming * (1.0 / count);
+                        }
+                    }
+                    if s == samples.start {
+                        half_geometry.normal[i] = first.normal;
+                        half_geometry.depth[i] = first.depth;
+                    }
+                }
+            }
+        }
+
+        let indirect = joint_bilateral_upsample(&indirect, &half_geometry, &geometry);
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let radiance = light[i] + albedo[i] * indirect[i];
+                frame.accumulate(x, y, radiance * samples.len() as fCol, coverage[i], samples.len() as fCol);
+            }
+        }
+    }
+
+    fn first_hit_sample(&self, scene: &Scene, cam: &dyn CameraModel, x: usize, y: usize, s: usize) -> FirstHit<'_> {
+        let (ray, film, mut rng) = self.camera_sample(cam, x, y, s);
+        let ctx = self.sample_context(x, y, s, None);
+
+        let mut first = FirstHit {
+            light: Color::black(),
+            albedo: Color::black(),
+            bounce: None,
+            normal: Vec3::origin(),
+            depth: fVec::INFINITY,
+            alpha: 1.0,
+        };
+        if let Some((col, alpha)) = self.backdrop_sample(scene, &ray, film, &mut rng, ctx) {
+            first.light = col;
+            first.alpha = alpha;
+            return first;
+        }
+
+        let vertex = match self.path_vertex(scene, &ray, self.bounces, ctx) {
+            PathStep::Miss(col) => {
+                first.light = col;
+                return first;
+            }
+            PathStep::Hit(vertex) => vertex,
+        };
+        first.normal = vertex.hit.surface_normal(&vertex.ray);
+        first.depth = (vertex.hit.intersect - ray.origin).length();
+        match vertex.bounce {
+            Some(bounce) if vertex.sharp || self.bounces <= 1 => {
+                let incoming = self.follow(scene, &bounce, self.bounces - 1);
+                first.light = vertex.attenuation * (vertex.direct + vertex.weight * incoming);
+            }
+            Some(bounce) => {
+                first.light = vertex.attenuation * vertex.direct;
+                first.albedo = vertex.attenuation * vertex.weight;
+                first.bounce = Some(bounce);
+            }
+            None => first.light = vertex.attenuation * vertex.direct,
+        }
+        first
+    }
+
+    //Color of a camera ray that shows the backdrop, either by missing the scene or by hitting a
+    //shadow catcher, None if the ray should be shaded normally
+    fn backdrop_sample(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        film: (fVec, fVec),
+        rng: &mut impl RngCore,
+        ctx: SampleContext,
+    ) -> Option<(Color, fCol)> {
+        if let Integrator::BvhHeatmap { .. } = self.integrator {
+            return None;
+        }
+        let plate = match &self.backdrop {
+            Backdrop::Environment => return None,
+            Backdrop::Color(col) => (*col, 1.0),
+            Backdrop::Image(img) => (img.color_at(film.0, film.1), 1.0),
+            Backdrop::Transparent => (Color::black(), 0.0),
+        };
+
+        match self.closest_hit_id(scene, ray, None) {
+            Some((r, id)) => {
+                let material = scene.get(id).unwrap().material_at(&r);
+                if !material.is_shadow_catcher() {
+                    return None;
+                }
+                Self::record_first_hit(ray, &r, id, ctx);
+                //The shadow darkens the plate and, for a transparent one, becomes opaque
+                let visible = self.shadow_catcher_visibility(scene, ray, &r, material, rng);
+                let (col, alpha) = plate;
+                Some((col * visible, 1.0 - visible * (1.0 - alpha)))
+            }
+            None => Some(plate),
+        }
+    }
+
+    //Direct light reaching a shadow catcher relative to the light it would get without anything in
+    //the way, 1 where nothing casts a shadow. Shadow rays end at the lights, and the environment
+    //counts with one cosine weighted direction.
+    fn shadow_catcher_visibility(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        hit: &HitResult,
+        material: &dyn Material,
+        rng: &mut impl RngCore,
+    ) -> fCol {
+        let mut unoccluded = Color::black();
+        let mut lit = Color::black();
+        for light in scene.lights.iter() {
+            let Some(sample) = light.illuminate(hit.intersect) else {
+                continue;
+            };
+            let light = material.eval(ray, hit, sample.direction) * sample.radiance;
+            if light == Color::black() {
+                continue;
+            }
+            let mut shadow_ray = Ray::new(hit.intersect, sample.direction);
+            shadow_ray.max = sample.distance;
+            unoccluded = unoccluded + light;
+            lit = lit + light * self.shadow_transmittance(scene, &shadow_ray);
+        }
+
+        let dir = hit.surface_normal(ray) + rand_on_unit_sphere(rng);
+        if !dir.is_tiny(0.0001) {
+            let env_ray = Ray::new(hit.intersect, dir.unit());
+            let light = scene.miss(&env_ray) * material.albedo(ray, hit);
+            unoccluded = unoccluded + light;
+            if !self.shadow_occluded(scene, &env_ray) {
+                lit = lit + light;
+            }
+        }
+
+        let total = unoccluded.luminance();
+        if total <= 0.0 {
+            return 1.0;
+        }
+        (lit.luminance() / total).clamp(0.0, 1.0)
+    }
+
+    //Closest hit, counted in the render stats
+    fn closest_hit<'a>(&self, scene: &'a Scene, ray: &Ray, cull: Option<&FrustumCull>) -> Option<(HitResult, &'a dyn Hit)> {
+        self.closest_hit_id(scene, ray, cull).map(|(r, id)| (r, scene.get(id).unwrap()))
+    }
+
+    fn closest_hit_id(&self, scene: &Scene, ray: &Ray, cull: Option<&FrustumCull>) -> Option<(HitResult, ObjectId)> {
+        let mut nodes = 0;
+        let res = scene.hit_culled_id(ray, cull, &mut nodes);
+        self.counters.count(&self.counters.rays, nodes);
+        res
+    }
+
+    //Hands the hit to the AOVs if it is the first shaded hit of a camera ray
+    fn record_first_hit<'a>(ray: &Ray, hit: &HitResult, object: ObjectId, ctx: SampleContext<'a>) -> SampleContext<'a> {
+        if let Some(first_hit) = ctx.first_hit {
+            first_hit.set(Some(PathHit { ray: *ray, hit: *hit, object }));
+        }
+        SampleContext { first_hit: None, ..ctx }
+    }
+
+    fn shadow_transmittance(&self, scene: &Scene, ray: &Ray) -> fVec {
+        let mut nodes = 0;
+        let transmittance = scene.transmittance(ray, &mut nodes);
+        self.counters.count(&self.counters.shadow_rays, nodes);
+        transmittance
+    }
+
+    fn shadow_occluded(&self, scene: &Scene, ray: &Ray) -> bool {
+        let mut nodes = 0;
+        let occluded = scene.occluded(ray, &mut nodes);
+        self.counters.count(&self.counters.shadow_rays, nodes);
+        occluded
+    }
+
+    //Every sample of every pixel gets its own random sequence, so any sample can be rendered in isolation
+    fn sample_seed(&self, x: usize, y: usize, sample: usize) -> u64 {
+        let mut h = self.seed;
+        for v in [x as u64, y as u64, sample as u64] {
+            //splitmix64 finalizer
+            h = (h ^ v).wrapping_add(0x9E3779B97F4A7C15);
+            h = (h ^ (h >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            h = (h ^ (h >> 27)).wrapping_mul(0x94D049BB133111EB);
+            h ^= h >> 31;
+        }
+        h
+    }
+
+    fn colorize_ray(&self, scene: &Scene, ray: &Ray, bounces: usize, ctx: SampleContext) -> Color {
+        match self.integrator {
+            Integrator::PathTracer => self.trace_path(scene, ray, bounces, ctx),
+            Integrator::DirectLighting => self.trace_direct(scene, ray, bounces, ctx),
+            Integrator::BvhHeatmap { max_nodes } => {
+                //Nothing is shaded, the AOVs show the closest hit
+                if ctx.first_hit.is_some() {
+                    if let Some((r, id)) = scene.hit_culled_id(ray, ctx.camera_cull, &mut 0) {
+                        Self::record_first_hit(ray, &r, id, ctx);
+                    }
+                }
+                let nodes = scene.bvh_nodes_visited(ray, ctx.camera_cull);
+                self.counters.count(&self.counters.rays, nodes);
+                heat_color(nodes, max_nodes)
+            }
+        }
+    }
+
+    fn trace_path(&self, scene: &Scene, ray: &Ray, bounces: usize, ctx: SampleContext) -> Color {
+        if bounces == 0 {
+            return Color::from_rgb(245, 66, 129);
+        }
+
+        match self.path_vertex(scene, ray, bounces, ctx) {
+            PathStep::Miss(col) => col,
+            PathStep::Hit(vertex) => {
+                let incoming = match &vertex.bounce {
+                    Some(bounce) => self.follow(scene, bounce, bounces - 1),
+                    None => Color::black(),
+                };
+                vertex.attenuation * (vertex.direct + vertex.weight * incoming)
+            }
+        }
+    }
+
+    //Shade the surface the ray hits and pick the bounce, shared by full paths and the half
+    //resolution indirect lighting that traces the bounces of first hits separately
+    fn path_vertex<'a>(&'a self, scene: &Scene, ray: &Ray, bounces: usize, ctx: SampleContext<'a>) -> PathStep<'a> {
+        let res = self.closest_hit_id(scene, ray, None);
+        let attenuation = ctx.media.attenuation(ray, res.as_ref().map(|(r, _)| r.at));
+        let Some((r, id)) = res else {
+            return PathStep::Miss(attenuation * scene.miss(ray));
+        };
+        let obj = scene.get(id).unwrap();
+        let material = obj.material_at(&r);
+        let outside_ior = match self.crossing(ray, &r, obj, ctx) {
+            Surface::Shade(ior) => ior,
+            Surface::PassThrough(through, ctx) => return self.path_vertex(scene, &through, bounces, ctx).attenuated(attenuation),
+        };
+        let ctx = Self::record_first_hit(ray, &r, id, ctx);
+        let ior = outside_ior.unwrap_or(1.0);
+        let mut direct = self.direct_light(scene, ray, &r, material, ior, ctx.light_stratum.wrapping_add(bounces));
+        if let Some(map) = ctx.caustics {
+            direct = direct + map.estimate(ray, &r, material);
+        }
+        let (col, bounced_ray) = match (&self.guide, outside_ior) {
+            (_, Some(ior)) => material.bounce_in(ray, &r, ior),
+            (Some(guide), None) => guide.bounce(ray, &r, material),
+            (None, None) => material.bounce(ray, &r),
+        };
+        let (direct, weight, bounce) = match bounced_ray {
+            Some(b) => {
+                let b = b.with_cone_from(ray, r.at);
+                let guided = self.guide.is_some() && material.pdf_in(ray, &r, b.direction, ior) > 0.0;
+                let bounce = Bounce {
+                    ctx: Self::after_bounce(ray, &r, obj, &b, ctx),
+                    ray: b,
+                    guided_at: guided.then_some(r.intersect),
+                };
+                (direct, col, Some(bounce))
+            }
+            None => (direct + col, Color::black(), None),
+        };
+        PathStep::Hit(PathVertex {
+            ray: *ray,
+            hit: r,
+            attenuation,
+            direct,
+            weight,
+            bounce,
+            sharp: material.is_specular() || outside_ior.is_some(),
+        })
+    }
+
+    //Light arriving along a bounce, which path guiding learns from
+    fn follow(&self, scene: &Scene, bounce: &Bounce, bounces: usize) -> Color {
+        let incoming = self.trace_path(scene, &bounce.ray, bounces, bounce.ctx);
+        if let (Some(guide), Some(point)) = (&self.guide, bounce.guided_at) {
+            guide.record(point, bounce.ray.direction, incoming);
+        }
+        incoming
+    }
+
+    fn trace_direct(&self, scene: &Scene, ray: &Ray, bounces: usize, ctx: SampleContext) -> Color {
+        if bounces == 0 {
+            return Color::black();
+        }
+
+        let (r, id) = match self.closest_hit_id(scene, ray, ctx.camera_cull) {
+            Some(res) => res,
+            None => return ctx.media.attenuation(ray, None) * scene.miss(ray),
+        };
+        let attenuation = ctx.media.attenuation(ray, Some(r.at));
+        let obj = scene.get(id).unwrap();
+        let material = obj.material_at(&r);
+        let outside_ior = match self.crossing(ray, &r, obj, ctx) {
+            Surface::Shade(ior) => ior,
+            Surface::PassThrough(through, ctx) => {
+                let ctx = SampleContext { camera_cull: None, ..ctx };
+                return attenuation * self.trace_direct(scene, &through, bounces, ctx);
+            }
+        };
+        let ctx = Self::record_first_hit(ray, &r, id, ctx);
+        let ior = outside_ior.unwrap_or(1.0);
+        let direct = self.direct_light(scene, ray, &r, material, ior, ctx.light_stratum.wrapping_add(bounces));
+        let radiance = match material.bounce_in(ray, &r, ior) {
+            (col, None) => direct + col,
+            (col, Some(b)) if material.is_specular() => {
+                let b = b.with_cone_from(ray, r.at);
+                let ctx = SampleContext { camera_cull: None, ..Self::after_bounce(ray, &r, obj, &b, ctx) };
+                direct + col * self.trace_direct(scene, &b, bounces - 1, ctx)
+            }
+            (col, Some(b)) => direct + col * self.emitted(scene, &b),
+        };
+        attenuation * radiance
+    }
+
+    //Nested dielectrics: whether the hit is shaded or lies inside a higher priority medium
+    fn crossing<'a>(&self, ray: &Ray, hit: &HitResult, obj: &dyn Hit, ctx: SampleContext<'a>) -> Surface<'a> {
+        let Some(medium) = obj.material_at(hit).medium() else {
+            return Surface::Shade(None);
+        };
+        match ctx.media.crossing(object_key(obj), medium, hit.is_outside(ray)) {
+            Crossing::Boundary(ior) => Surface::Shade(Some(ior)),
+            Crossing::Hidden(media) => {
+                let through = Ray::new(hit.intersect, ray.direction).with_cone_from(ray, hit.at);
+                Surface::PassThrough(through, SampleContext { media, ..ctx })
+            }
+        }
+    }
+
+    fn after_bounce<'a>(ray: &Ray, hit: &HitResult, obj: &dyn Hit, bounced: &Ray, ctx: SampleContext<'a>) -> SampleContext<'a> {
+        match obj.material_at(hit).medium() {
+            Some(medium) => SampleContext {
+                media: ctx.media.after_bounce(object_key(obj), medium, ray, hit, bounced),
+                ..ctx
+            },
+            None => ctx,
+        }
+    }
+
+    //Light arriving along the ray from emitters hit directly or the environment
+    fn emitted(&self, scene: &Scene, ray: &Ray) -> Color {
+        match self.closest_hit(scene, ray, None) {
+            Some((r, obj)) => match obj.material_at(&r).bounce(ray, &r) {
+                (col, None) => col,
+                (_, Some(_)) => Color::black(),
+            },
+            None => scene.miss(ray),
+        }
+    }
+
+    //stratum picks the light when lights are stratified, it should differ between the hits of a path.
+    //outside_ior is the medium the hit is seen from, as for Material::bounce_in().
+    fn direct_light(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        hit: &HitResult,
+        material: &dyn Material,
+        outside_ior: fVec,
+        stratum: usize,
+    ) -> Color {
+        let count = scene.lights.len();
+        let (lights, weight) = if self.stratified_lights && count > 1 {
+            let i = stratum % count;
+            (&scene.lights[i..i + 1], count as fCol)
+        } else {
+            (&scene.lights[..], 1.0)
+        };
+
+        let mut sum = Color::black();
+        for light in lights.iter() {
+            let sample = match light.illuminate(hit.intersect) {
+                Some(s) => s,
+                None => continue,
+            };
+            let f = material.eval_in(ray, hit, sample.direction, outside_ior);
+            if f == Color::black() {
+                continue;
+            }
+            let mut shadow_ray = Ray::new(hit.intersect, sample.direction);
+            shadow_ray.max = sample.distance;
+            let transmittance = self.shadow_transmittance(scene, &shadow_ray);
+            if transmittance != 0.0 {
+                sum = sum + f * sample.radiance * transmittance;
+            }
+        }
+        sum * weight
+    }
+}
+
+//Run f as a named stage of the render
+fn timed<T>(stats: &mut RenderStats, name: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let res = f();
+    stats.stages.push((name, start.elapsed()));
+    res
+}
+
+//Linear color of a heat map ramp, black at 0 and red at max
+fn heat_color(value: usize, max: usize) -> Color {
+    const RAMP: [(fCol, fCol, fCol); 5] = [
+        (0.0, 0.0, 0.0),
+        (0.0, 0.0, 1.0),
+        (0.0, 1.0, 0.0),
+        (1.0, 1.0, 0.0),
+        (1.0, 0.0, 0.0),
+    ];
+    let t = (value as fCol / max.max(1) as fCol).min(1.0) * (RAMP.len() - 1) as fCol;
+    let i = (t as usize).min(RAMP.len() - 2);
+    let f = t - i as fCol;
+    let (a, b) = (RAMP[i], RAMP[i + 1]);
+    let col = Color::new(a.0 + (b.0 - a.0) * f, a.1 + (b.1 - a.1) * f, a.2 + (b.2 - a.2) * f);
+    //Rendering encodes to sRGB
+    col.decode_srgb()
 }
\ No newline at end of file
@@ -0,0 +1,694 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use rand::prelude::*;
+use rand::rngs::SmallRng;
+
+use crate::error::*;
+use crate::image::*;
+use crate::linalg::*;
+use crate::material::*;
+use crate::mesh::*;
+use crate::tracer::*;
+
+//Subset of ascii USD (usda): meshes, xform ops, cameras and UsdPreviewSurface materials.
+//USD is right handed with +Y up, the z axis is mirrored on import and export.
+
+//Subset of the UsdPreviewSurface inputs
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PreviewSurface {
+    pub diffuse_color: Color,
+    pub metallic: fVec,
+    pub roughness: fVec,
+    pub opacity: fVec,
+    pub ior: fVec,
+}
+
+impl Default for PreviewSurface {
+    //Defaults from the UsdPreviewSurface spec
+    fn default() -> Self {
+        PreviewSurface {
+            diffuse_color: Color::new(0.18, 0.18, 0.18),
+            metallic: 0.0,
+            roughness: 0.5,
+            opacity: 1.0,
+            ior: 1.5,
+        }
+    }
+}
+
+impl PreviewSurface {
+    fn to_material(self, rng: &mut impl Rng) -> Rc<dyn Material> {
+        let rng = Box::new(RefCell::new(SmallRng::seed_from_u64(rng.gen())));
+        if self.opacity < 1.0 {
+            Rc::new(DielectricMaterial {
+                ior: self.ior,
+                absorption: Color::black(),
+                priority: 0,
+                rng,
+            })
+        } else {
+            Rc::new(PbrMaterial {
+                base_color: self.diffuse_color,
+                metallic: self.metallic,
+                roughness: self.roughness,
+                metallic_map: None,
+                roughness_map: None,
+                rng,
+            })
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("USD: {}", msg))
+}
+
+#[inline]
+fn mirror(v: Vec3) -> Vec3 {
+    Vec3::new(v.x, v.y, -v.z)
+}
+
+fn write_vec3s(out: &mut impl Write, values: impl Iterator<Item = Vec3>) -> io::Result<()> {
+    write!(out, "[")?;
+    for (i, v) in values.enumerate() {
+        if i > 0 {
+            write!(out, ", ")?;
+        }
+        write!(out, "({}, {}, {})", v.x, v.y, v.z)?;
+    }
+    write!(out, "]")
+}
+
+fn write_material(out: &mut impl Write, path: &str, name: &str, surface: &PreviewSurface) -> io::Result<()> {
+    let c = surface.diffuse_color;
+    writeln!(out, "        def Material \"{}\"", name)?;
+    writeln!(out, "        {{")?;
+    writeln!(out, "            token outputs:surface.connect = <{}/PreviewSurface.outputs:surface>", path)?;
+    writeln!(out, "            def Shader \"PreviewSurface\"")?;
+    writeln!(out, "            {{")?;
+    writeln!(out, "                uniform token info:id = \"UsdPreviewSurface\"")?;
+    writeln!(out, "                color3f inputs:diffuseColor = ({}, {}, {})", c.r, c.g, c.b)?;
+    writeln!(out, "                float inputs:metallic = {}", surface.metallic)?;
+    writeln!(out, "                float inputs:roughness = {}", surface.roughness)?;
+    writeln!(out, "                float inputs:opacity = {}", surface.opacity)?;
+    writeln!(out, "                float inputs:ior = {}", surface.ior)?;
+    writeln!(out, "                token outputs:surface")?;
+    writeln!(out, "            }}")?;
+    writeln!(out, "        }}")
+}
+
+fn write_camera(out: &mut impl Write, cam: &Camera) -> io::Result<()> {
+    let (_, up, forward) = cam.frame();
+    let (forward, up) = (mirror(forward), mirror(up));
+    let right = forward.cross(up);
+    let origin = mirror(cam.origin);
+    let focus_distance = cam.direction.length();
+    //Lens values are in tenths of a scene unit
+    let focal_length = 50.0;
+    let aperture = focal_length * cam.viewport_width / focus_distance;
+
+    writeln!(out, "    def Camera \"camera\"")?;
+    writeln!(out, "    {{")?;
+    writeln!(
+        out,
+        "        matrix4d xformOp:transform = ( ({}, {}, {}, 0), ({}, {}, {}, 0), ({}, {}, {}, 0), ({}, {}, {}, 1) )",
+        right.x, right.y, right.z, up.x, up.y, up.z, -forward.x, -forward.y, -forward.z, origin.x, origin.y, origin.z
+    )?;
+    writeln!(out, "        uniform token[] xformOpOrder = [\"xformOp:transform\"]")?;
+    writeln!(out, "        float focalLength = {}", focal_length)?;
+    writeln!(out, "        float horizontalAperture = {}", aperture)?;
+    writeln!(out, "        float verticalAperture = {}", aperture * cam.viewport_height / cam.viewport_width)?;
+    //Shift is in film widths, the offsets are in aperture units
+    writeln!(out, "        float horizontalApertureOffset = {}", aperture * cam.shift.0)?;
+    writeln!(out, "        float verticalApertureOffset = {}", aperture * cam.shift.1)?;
+    writeln!(out, "        float focusDistance = {}", focus_distance)?;
+    if cam.aperture > 0.0 {
+        writeln!(out, "        float fStop = {}", focal_length / 10.0 / (2.0 * cam.aperture))?;
+    }
+    writeln!(out, "    }}")
+}
+
+//Write all objects with a mesh representation, their preview materials and the camera
+pub fn export_usda(scene: &Scene, camera: Option<&Camera>, path: &str, subdivisions: usize) -> Result<()> {
+    let mut out = io::BufWriter::new(fs::File::create(path)?);
+    writeln!(out, "#usda 1.0")?;
+    writeln!(out, "(\n    defaultPrim = \"World\"\n    metersPerUnit = 1\n    upAxis = \"Y\"\n)\n")?;
+    writeln!(out, "def Xform \"World\"")?;
+    writeln!(out, "{{")?;
+
+    let mut materials = Vec::new();
+    for (i, obj) in scene.objects().enumerate() {
+        let mesh = match obj.to_mesh(subdivisions) {
+            Some(m) => m,
+            None => continue,
+        };
+        writeln!(out, "    def Mesh \"object_{}\"", i)?;
+        writeln!(out, "    {{")?;
+        writeln!(out, "        uniform bool doubleSided = 1")?;
+        let counts = vec!["3"; mesh.triangles.len()].join(", ");
+        writeln!(out, "        int[] faceVertexCounts = [{}]", counts)?;
+        //Mirroring flips the winding
+        let indices: Vec<String> = mesh.triangles.iter().flat_map(|&[a, b, c]| [a, c, b]).map(|i| i.to_string()).collect();
+        writeln!(out, "        int[] faceVertexIndices = [{}]", indices.join(", "))?;
+        write!(out, "        normal3f[] normals = ")?;
+        write_vec3s(&mut out, mesh.normals.iter().map(|&n| mirror(n)))?;
+        writeln!(out, " (\n            interpolation = \"vertex\"\n        )")?;
+        write!(out, "        point3f[] points = ")?;
+        write_vec3s(&mut out, mesh.vertices.iter().map(|&v| mirror(v)))?;
+        writeln!(out)?;
+        if let Some(surface) = obj.material().preview() {
+            writeln!(out, "        rel material:binding = </World/Looks/material_{}>", i)?;
+            materials.push((i, surface));
+        }
+        writeln!(out, "    }}")?;
+    }
+
+    if !materials.is_empty() {
+        writeln!(out, "    def Scope \"Looks\"")?;
+        writeln!(out, "    {{")?;
+        for (i, surface) in materials.iter() {
+            let name = format!("material_{}", i);
+            write_material(&mut out, &format!("/World/Looks/{}", name), &name, surface)?;
+        }
+        writeln!(out, "    }}")?;
+    }
+
+    if let Some(cam) = camera {
+        write_camera(&mut out, cam)?;
+    }
+    writeln!(out, "}}")?;
+    out.flush()?;
+    Ok(())
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Word(String),
+    Text(String),
+    Path(String),
+    Punct(char),
+}
+
+fn tokenize(src: &str) -> io::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = src.chars().collect();
+    let mut i = 0;
+    let read_until = |i: &mut usize, end: &str| -> io::Result<String> {
+        let end: Vec<char> = end.chars().collect();
+        let start = *i;
+        while *i + end.len() <= chars.len() {
+            if chars[*i..*i + end.len()] == end[..] {
+                let s = chars[start..*i].iter().collect();
+                *i += end.len();
+                return Ok(s);
+            }
+            *i += 1;
+        }
+        Err(invalid("unterminated string or path"))
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '#' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if chars[i..].starts_with(&['"', '"', '"']) {
+            i += 3;
+            tokens.push(Token::Text(read_until(&mut i, "\"\"\"")?));
+        } else if c == '"' || c == '\'' || c == '@' {
+            i += 1;
+            tokens.push(Token::Text(read_until(&mut i, &c.to_string())?));
+        } else if c == '<' {
+            i += 1;
+            tokens.push(Token::Path(read_until(&mut i, ">")?));
+        } else if "(){}[]=,;".contains(c) {
+            tokens.push(Token::Punct(c));
+            i += 1;
+        } else {
+            let start = i;
+            while i < chars.len() && !chars[i].is_whitespace() && !"(){}[]=,;\"'<@#".contains(chars[i]) {
+                i += 1;
+            }
+            //Array types like point3f[]
+            if chars[i..].starts_with(&['[', ']']) {
+                i += 2;
+            }
+            tokens.push(Token::Word(chars[start..i].iter().collect()));
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Value {
+    Number(f64),
+    Text(String),
+    Path(String),
+    List(Vec<Value>),
+}
+
+impl Value {
+    fn number(&self) -> Option<fVec> {
+        match self {
+            Value::Number(n) => Some(*n as fVec),
+            _ => None,
+        }
+    }
+
+    fn list(&self) -> &[Value] {
+        match self {
+            Value::List(l) => l,
+            _ => &[],
+        }
+    }
+
+    fn vec3(&self) -> Option<Vec3> {
+        match self.list() {
+            [x, y, z] => Some(Vec3::new(x.number()?, y.number()?, z.number()?)),
+            _ => None,
+        }
+    }
+
+    fn vec3s(&self) -> Vec<Vec3> {
+        self.list().iter().filter_map(|v| v.vec3()).collect()
+    }
+
+    fn indices(&self) -> Vec<usize> {
+        self.list().iter().filter_map(|v| v.number()).map(|n| n as usize).collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Prim {
+    kind: String,
+    path: String,
+    attributes: HashMap<String, Value>,
+    children: Vec<Prim>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> io::Result<Token> {
+        let token = self.tokens.get(self.pos).cloned().ok_or_else(|| invalid("unexpected end of file"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, c: char) -> io::Result<()> {
+        match self.next()? {
+            Token::Punct(p) if p == c => Ok(()),
+            t => Err(invalid(&format!("expected '{}', found {:?}", c, t))),
+        }
+    }
+
+    fn is_punct(&self, c: char) -> bool {
+        self.peek() == Some(&Token::Punct(c))
+    }
+
+    //Skip a bracketed group whose opening bracket is the next token
+    fn skip_group(&mut self) -> io::Result<()> {
+        let mut depth = 0;
+        loop {
+            match self.next()? {
+                Token::Punct('(' | '[' | '{') => depth += 1,
+                Token::Punct(')' | ']' | '}') => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        match self.peek() {
+            Some(Token::Punct('{')) => {
+                //Dictionaries and time samples are not supported
+                self.skip_group()?;
+                return Ok(Value::List(Vec::new()));
+            }
+            Some(Token::Punct('(' | '[')) => {}
+            _ => {
+                return Ok(match self.next()? {
+                    Token::Word(w) => w.parse().map(Value::Number).unwrap_or(Value::Text(w)),
+                    Token::Text(t) => Value::Text(t),
+                    Token::Path(p) => Value::Path(p),
+                    Token::Punct(c) => return Err(invalid(&format!("unexpected '{}'", c))),
+                });
+            }
+        }
+
+        let close = match self.next()? {
+            Token::Punct('(') => ')',
+            _ => ']',
+        };
+        let mut values = Vec::new();
+        loop {
+            if self.is_punct(close) {
+                self.pos += 1;
+                return Ok(Value::List(values));
+            }
+            values.push(self.value()?);
+            if self.is_punct(',') {
+                self.pos += 1;
+            }
+        }
+    }
+
+    fn prim(&mut self, parent: &str) -> io::Result<Prim> {
+        //def, over or class already consumed
+        let mut prim = Prim::default();
+        let name = loop {
+            match self.next()? {
+                Token::Word(kind) => prim.kind = kind,
+                Token::Text(name) => break name,
+                t => return Err(invalid(&format!("expected prim name, found {:?}", t))),
+            }
+        };
+        prim.path = format!("{}/{}", parent, name);
+        if self.is_punct('(') {
+            self.skip_group()?;
+        }
+        self.expect('{')?;
+
+        loop {
+            match self.peek() {
+                Some(Token::Punct('}')) => {
+                    self.pos += 1;
+                    return Ok(prim);
+                }
+                Some(Token::Punct(';')) => self.pos += 1,
+                Some(Token::Word(w)) if w == "def" || w == "over" || w == "class" => {
+                    self.pos += 1;
+                    let child = self.prim(&prim.path)?;
+                    prim.children.push(child);
+                }
+                Some(Token::Word(w)) if w == "variantSet" => {
+                    while !self.is_punct('{') {
+                        self.next()?;
+                    }
+                    self.skip_group()?;
+                }
+                Some(Token::Word(_)) => {
+                    //Modifiers and type followed by the attribute or relationship name
+                    let mut name = String::new();
+                    while let Some(Token::Word(w)) = self.peek() {
+                        name = w.clone();
+                        self.pos += 1;
+                    }
+                    if self.is_punct('=') {
+                        self.pos += 1;
+                        let value = self.value()?;
+                        prim.attributes.insert(name, value);
+                    }
+                    if self.is_punct('(') {
+                        self.skip_group()?;
+                    }
+                }
+                _ => return Err(invalid(&format!("unexpected {:?} in {}", self.peek(), prim.path))),
+            }
+        }
+    }
+
+    fn stage(&mut self) -> io::Result<Prim> {
+        let mut root = Prim::default();
+        if self.is_punct('(') {
+            self.skip_group()?;
+        }
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Word(w) if w == "def" || w == "over" || w == "class" => {
+                    self.pos += 1;
+                    let prim = self.prim("")?;
+                    root.children.push(prim);
+                }
+                t => return Err(invalid(&format!("unexpected {:?} at top level", t))),
+            }
+        }
+        Ok(root)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum XformOp {
+    Translate(Vec3),
+    Scale(Vec3),
+    //Degrees, applied in x, y, z order
+    Rotate(Vec3),
+    //Row vector convention as written in usda
+    Matrix([[fVec; 4]; 4]),
+}
+
+impl XformOp {
+    fn apply(&self, p: Vec3) -> Vec3 {
+        match *self {
+            XformOp::Translate(t) => p + t,
+            XformOp::Scale(s) => Vec3::new(p.x * s.x, p.y * s.y, p.z * s.z),
+            XformOp::Rotate(r) => {
+                let (sx, cx) = r.x.to_radians().sin_cos();
+                let (sy, cy) = r.y.to_radians().sin_cos();
+                let (sz, cz) = r.z.to_radians().sin_cos();
+                let p = Vec3::new(p.x, p.y * cx - p.z * sx, p.y * sx + p.z * cx);
+                let p = Vec3::new(p.x * cy + p.z * sy, p.y, -p.x * sy + p.z * cy);
+                Vec3::new(p.x * cz - p.y * sz, p.x * sz + p.y * cz, p.z)
+            }
+            XformOp::Matrix(m) => Vec3::new(
+                p.x * m[0][0] + p.y * m[1][0] + p.z * m[2][0] + m[3][0],
+                p.x * m[0][1] + p.y * m[1][1] + p.z * m[2][1] + m[3][1],
+                p.x * m[0][2] + p.y * m[1][2] + p.z * m[2][2] + m[3][2],
+            ),
+        }
+    }
+}
+
+//Ops of a prim in the order they apply to a point, i.e. reversed xformOpOrder
+fn xform_ops(prim: &Prim) -> Vec<XformOp> {
+    let order = match prim.attributes.get("xformOpOrder") {
+        Some(o) => o.list(),
+        None => return Vec::new(),
+    };
+    let mut ops = Vec::new();
+    for name in order.iter().rev() {
+        let name = match name {
+            Value::Text(n) => n.as_str(),
+            _ => continue,
+        };
+        let (invert, name) = match name.strip_prefix("!invert!") {
+            Some(n) => (true, n),
+            None => (false, name),
+        };
+        let value = match prim.attributes.get(name) {
+            Some(v) => v,
+            None => continue,
+        };
+        let kind = name.trim_start_matches("xformOp:").split(':').next().unwrap_or("");
+        let op = match (kind, value.number()) {
+            ("translate", _) => value.vec3().map(|t| XformOp::Translate(if invert { -t } else { t })),
+            ("scale", _) => value.vec3().map(XformOp::Scale),
+            ("rotateXYZ", _) => value.vec3().map(XformOp::Rotate),
+            ("rotateX", Some(a)) => Some(XformOp::Rotate(Vec3::new(a, 0.0, 0.0))),
+            ("rotateY", Some(a)) => Some(XformOp::Rotate(Vec3::new(0.0, a, 0.0))),
+            ("rotateZ", Some(a)) => Some(XformOp::Rotate(Vec3::new(0.0, 0.0, a))),
+            ("transform", _) => {
+                let rows: Vec<Vec<fVec>> = value
+                    .list()
+                    .iter()
+                    .map(|r| r.list().iter().filter_map(|v| v.number()).collect())
+                    .collect();
+                if rows.len() == 4 && rows.iter().all(|r| r.len() == 4) {
+                    let mut m = [[0.0; 4]; 4];
+                    for (i, row) in rows.iter().enumerate() {
+                        m[i].copy_from_slice(row);
+                    }
+                    Some(XformOp::Matrix(m))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        ops.extend(op);
+    }
+    ops
+}
+
+fn transform(ops: &[XformOp], p: Vec3) -> Vec3 {
+    ops.iter().fold(p, |p, op| op.apply(p))
+}
+
+fn preview_surface(material: &Prim) -> PreviewSurface {
+    let mut surface = PreviewSurface::default();
+    let shader = material.children.iter().find(|c| {
+        c.kind == "Shader" && c.attributes.get("info:id") == Some(&Value::Text("UsdPreviewSurface".to_string()))
+    });
+    if let Some(shader) = shader {
+        let input = |name: &str| shader.attributes.get(&format!("inputs:{}", name));
+        if let Some(c) = input("diffuseColor").and_then(|v| v.vec3()) {
+            surface.diffuse_color = Color::new(c.x, c.y, c.z);
+        }
+        let fields = [
+            ("metallic", &mut surface.metallic),
+            ("roughness", &mut surface.roughness),
+            ("opacity", &mut surface.opacity),
+            ("ior", &mut surface.ior),
+        ];
+        for (name, field) in fields {
+            if let Some(n) = input(name).and_then(|v| v.number()) {
+                *field = n;
+            }
+        }
+    }
+    surface
+}
+
+fn collect_materials<'a>(prim: &'a Prim, materials: &mut HashMap<&'a str, &'a Prim>) {
+    if prim.kind == "Material" {
+        materials.insert(&prim.path, prim);
+    }
+    for child in prim.children.iter() {
+        collect_materials(child, materials);
+    }
+}
+
+pub struct UsdStage {
+    pub objects: Vec<MeshObject>,
+    pub camera: Option<Camera>,
+}
+
+struct Importer<'a> {
+    materials: HashMap<&'a str, &'a Prim>,
+    rng: SmallRng,
+    width: usize,
+    height: usize,
+    stage: UsdStage,
+}
+
+impl Importer<'_> {
+    fn visit(&mut self, prim: &Prim, parent_ops: &[XformOp]) {
+        let mut ops = xform_ops(prim);
+        ops.extend_from_slice(parent_ops);
+
+        match prim.kind.as_str() {
+            "Mesh" => self.mesh(prim, &ops),
+            "Camera" if self.stage.camera.is_none() => self.camera(prim, &ops),
+            _ => {}
+        }
+        for child in prim.children.iter() {
+            self.visit(child, &ops);
+        }
+    }
+
+    fn mesh(&mut self, prim: &Prim, ops: &[XformOp]) {
+        let attr = |name: &str| prim.attributes.get(name);
+        let points = attr("points").map(|v| v.vec3s()).unwrap_or_default();
+        let counts = attr("faceVertexCounts").map(|v| v.indices()).unwrap_or_default();
+        let indices = attr("faceVertexIndices").map(|v| v.indices()).unwrap_or_default();
+
+        //Fan triangulation of the polygons, mirroring flips the winding
+        let mut triangles = Vec::new();
+        let mut start = 0;
+        for count in counts {
+            let face = match indices.get(start..start + count) {
+                Some(f) => f,
+                None => break,
+            };
+            for i in 1..count.saturating_sub(1) {
+                let tri = [face[0], face[i + 1], face[i]];
+                if tri.iter().all(|&v| v < points.len()) {
+                    triangles.push(tri);
+                }
+            }
+            start += count;
+        }
+
+        let mut mesh = Mesh {
+            vertices: points.into_iter().map(|p| mirror(transform(ops, p))).collect(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            triangles,
+            materials: Vec::new(),
+        };
+        mesh.compute_normals();
+
+        let surface = match attr("material:binding") {
+            Some(Value::Path(path)) => self.materials.get(path.as_str()).map(|m| preview_surface(m)),
+            _ => None,
+        };
+        let material = surface.unwrap_or_default().to_material(&mut self.rng);
+        self.stage.objects.push(MeshObject::new(mesh, material));
+    }
+
+    fn camera(&mut self, prim: &Prim, ops: &[XformOp]) {
+        let attr = |name: &str, default: fVec| prim.attributes.get(name).and_then(|v| v.number()).unwrap_or(default);
+        let focal_length = attr("focalLength", 50.0);
+        let horizontal_aperture = attr("horizontalAperture", 20.955);
+        let focus_distance = attr("focusDistance", 0.0);
+        let focus_distance = if focus_distance > 0.0 { focus_distance } else { 1.0 };
+        let f_stop = attr("fStop", 0.0);
+
+        let look_from = mirror(transform(ops, Vec3::origin()));
+        let look_at = mirror(transform(ops, Vec3::new(0.0, 0.0, -focus_distance)));
+        let fov = 2.0 * (horizontal_aperture / (2.0 * focal_length)).atan().to_degrees();
+        //Lens values are in tenths of a scene unit
+        let aperture = if f_stop > 0.0 { focal_length / 10.0 / (2.0 * f_stop) } else { 0.0 };
+        let up = mirror(transform(ops, Vec3::unit_y())) - look_from;
+        let mut camera = Camera::new(look_from, look_at, self.width, self.height, fov, aperture, focus_distance);
+        camera.set_orientation(up, 0.0);
+        //Film aspect of the camera instead of the resolution's
+        if let Some(vertical_aperture) = prim.attributes.get("verticalAperture").and_then(|v| v.number()).filter(|v| *v > 0.0) {
+            camera.viewport_height = camera.viewport_width * vertical_aperture / horizontal_aperture;
+        }
+        camera.shift = (
+            attr("horizontalApertureOffset", 0.0) / horizontal_aperture,
+            attr("verticalApertureOffset", 0.0) / horizontal_aperture,
+        );
+        self.stage.camera = Some(camera);
+    }
+}
+
+//Load the meshes with their bound preview materials and the first camera, rendered at width x height
+pub fn import_usda(path: &str, width: usize, height: usize, seed: u64) -> Result<UsdStage> {
+    import(path, width, height, seed).map_err(|e| Error::scene(path, e))
+}
+
+fn import(path: &str, width: usize, height: usize, seed: u64) -> io::Result<UsdStage> {
+    let src = fs::read_to_string(path)?;
+    if !src.starts_with("#usda") {
+        return Err(invalid("missing #usda header"));
+    }
+    let root = Parser {
+        tokens: tokenize(&src)?,
+        pos: 0,
+    }
+    .stage()?;
+
+    let mut materials = HashMap::new();
+    collect_materials(&root, &mut materials);
+    let mut importer = Importer {
+        materials,
+        rng: SmallRng::seed_from_u64(seed),
+        width,
+        height,
+        stage: UsdStage {
+            objects: Vec::new(),
+            camera: None,
+        },
+    };
+    importer.visit(&root, &[]);
+    Ok(importer.stage)
+}
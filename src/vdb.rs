@@ -0,0 +1,529 @@
+use std::{fs, io, io::Read, path::Path};
+
+use flate2::read::ZlibDecoder;
+
+use crate::linalg::*;
+use crate::volume::VoxelGrid;
+
+//Reader for float grids of OpenVDB files (file format 222 and later), densified into a VoxelGrid
+//over the bounding box of the active voxels. The tree is the standard 5-4-3 layout, values may
+//be saved as half floats, uncompressed or zip compressed. Blosc compressed buffers, the default
+//of most current tools, are only read when Blosc stored them raw.
+//Transforms must be axis aligned scales and translations, rotated or frustum grids are rejected.
+
+const MAGIC: i64 = 0x56444220;
+//First version with per grid compression and mask compressed node values
+const MIN_VERSION: u32 = 222;
+
+const COMPRESS_ZIP: u32 = 0x1;
+const COMPRESS_ACTIVE_MASK: u32 = 0x2;
+const COMPRESS_BLOSC: u32 = 0x4;
+
+//Dense grids above this many voxels would not fit in memory
+const MAX_VOXELS: usize = 1 << 28;
+
+//Log2 of the node sizes from the root's children down to the leaves
+const LEVELS: [u32; 3] = [5, 4, 3];
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("VDB: {}", msg))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    //Of the grid being read
+    compression: u32,
+    half: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.data.len());
+        let bytes = &self.data[self.pos..end.ok_or_else(|| invalid("unexpected end of file"))?];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+
+    fn vec3d(&mut self) -> io::Result<[f64; 3]> {
+        Ok([self.f64()?, self.f64()?, self.f64()?])
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| invalid("string is not UTF-8"))
+    }
+
+    fn coord(&mut self) -> io::Result<[i32; 3]> {
+        Ok([self.i32()?, self.i32()?, self.i32()?])
+    }
+
+    //Bit mask of a node with 2^(3 log2_dim) entries
+    fn mask(&mut self, log2_dim: u32) -> io::Result<Vec<u64>> {
+        let words = 1 << (3 * log2_dim - 6);
+        let bytes = self.bytes(words * 8)?;
+        Ok(bytes.chunks_exact(8).map(|w| u64::from_le_bytes(w.try_into().unwrap())).collect())
+    }
+
+    //Name, type and value of each entry, the value as its raw bytes
+    fn metadata(&mut self) -> io::Result<Vec<(String, String, &'a [u8])>> {
+        let count = self.u32()?;
+        (0..count)
+            .map(|_| {
+                let name = self.string()?;
+                let kind = self.string()?;
+                let len = self.u32()? as usize;
+                Ok((name, kind, self.bytes(len)?))
+            })
+            .collect()
+    }
+
+    //Buffer of count floats as saved with the grid's compression, a negative size marks data the
+    //compressor stored raw
+    fn floats(&mut self, count: usize) -> io::Result<Vec<fVec>> {
+        let size = count * if self.half { 2 } else { 4 };
+        let raw = if self.compression & (COMPRESS_BLOSC | COMPRESS_ZIP) != 0 {
+            let stored = self.i64()?;
+            if stored <= 0 {
+                if stored.unsigned_abs() != size as u64 {
+                    return Err(invalid("buffer size does not match the node"));
+                }
+                self.bytes(size)?.to_vec()
+            } else if self.compression & COMPRESS_BLOSC != 0 {
+                return Err(invalid("Blosc compressed grids are not supported, save the grid with zip or no compression"));
+            } else {
+                let zipped = self.bytes(usize::try_from(stored).map_err(|_| invalid("buffer too large"))?)?;
+                let mut raw = Vec::with_capacity(size);
+                ZlibDecoder::new(zipped).take(size as u64 + 1).read_to_end(&mut raw)?;
+                if raw.len() != size {
+                    return Err(invalid("buffer size does not match the node"));
+                }
+                raw
+            }
+        } else {
+            self.bytes(size)?.to_vec()
+        };
+        Ok(if self.half {
+            raw.chunks_exact(2).map(|h| half_to_f32(u16::from_le_bytes([h[0], h[1]]))).collect()
+        } else {
+            raw.chunks_exact(4).map(|f| f32::from_le_bytes(f.try_into().unwrap())).collect()
+        })
+    }
+
+    //All values of a node. With mask compression only the active ones are saved, inactive ones
+    //are the background, its negation or one or two saved values picked by a selection mask.
+    fn node_values(&mut self, log2_dim: u32, value_mask: &[u64], background: fVec) -> io::Result<Vec<fVec>> {
+        let count = 1 << (3 * log2_dim);
+        let flags = self.bytes(1)?[0];
+        if flags > 6 {
+            return Err(invalid("unknown node compression"));
+        }
+        let mut inactive = [if flags == 0 { background } else { -background }, background];
+        if matches!(flags, 2 | 4 | 5) {
+            inactive[0] = self.f32()?;
+        }
+        if flags == 5 {
+            inactive[1] = self.f32()?;
+        }
+        let selection = if matches!(flags, 3..=5) { Some(self.mask(log2_dim)?) } else { None };
+
+        if self.compression & COMPRESS_ACTIVE_MASK == 0 || flags == 6 {
+            return self.floats(count);
+        }
+        let active = value_mask.iter().map(|w| w.count_ones() as usize).sum();
+        let mut saved = self.floats(active)?.into_iter();
+        Ok((0..count)
+            .map(|i| match bit(value_mask, i) {
+                true => saved.next().unwrap(),
+                false => inactive[selection.as_ref().is_some_and(|s| bit(s, i)) as usize],
+            })
+            .collect())
+    }
+}
+
+fn bit(mask: &[u64], i: usize) -> bool {
+    mask[i >> 6] >> (i & 63) & 1 == 1
+}
+
+fn half_to_f32(h: u16) -> f32 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = (h >> 10 & 0x1f) as i32;
+    let mantissa = (h & 0x3ff) as f32;
+    sign * match exponent {
+        0 => mantissa * (2.0f32).powi(-24),
+        31 if mantissa == 0.0 => f32::INFINITY,
+        31 => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * (2.0f32).powi(exponent - 15),
+    }
+}
+
+//Position of entry i of a node in units of its children, x varies slowest
+fn child_offset(i: usize, log2_dim: u32) -> [i32; 3] {
+    let dim = (1 << log2_dim) - 1;
+    [(i >> (2 * log2_dim)) as i32, (i >> log2_dim & dim) as i32, (i & dim) as i32]
+}
+
+//Constant region of a root or internal node, in index space
+struct Tile {
+    origin: [i32; 3],
+    size: i32,
+    value: fVec,
+}
+
+struct Leaf {
+    origin: [i32; 3],
+    values: Vec<fVec>,
+    mask: Vec<u64>,
+}
+
+//Active tiles and the leaves of a tree in the order their buffers are saved
+#[derive(Default)]
+struct Tree {
+    tiles: Vec<Tile>,
+    leaves: Vec<Leaf>,
+}
+
+impl Tree {
+    fn read_topology(&mut self, r: &mut Reader, level: usize, origin: [i32; 3], background: fVec) -> io::Result<()> {
+        let log2_dim = LEVELS[level];
+        if level == LEVELS.len() - 1 {
+            let mask = r.mask(log2_dim)?;
+            self.leaves.push(Leaf { origin, values: Vec::new(), mask });
+            return Ok(());
+        }
+
+        let child_log2: u32 = LEVELS[level + 1..].iter().sum();
+        let child_mask = r.mask(log2_dim)?;
+        let value_mask = r.mask(log2_dim)?;
+        let values = r.node_values(log2_dim, &value_mask, background)?;
+        let child_origin = |i: usize| {
+            let offset = child_offset(i, log2_dim);
+            [0, 1, 2].map(|a| origin[a] + (offset[a] << child_log2))
+        };
+        for (i, value) in values.into_iter().enumerate() {
+            if bit(&value_mask, i) && !bit(&child_mask, i) {
+                self.tiles.push(Tile { origin: child_origin(i), size: 1 << child_log2, value });
+            }
+        }
+        for i in (0..values_len(log2_dim)).filter(|&i| bit(&child_mask, i)) {
+            self.read_topology(r, level + 1, child_origin(i), background)?;
+        }
+        Ok(())
+    }
+
+    fn read_buffers(&mut self, r: &mut Reader, background: fVec) -> io::Result<()> {
+        let log2_dim = LEVELS[LEVELS.len() - 1];
+        for leaf in self.leaves.iter_mut() {
+            let mask = r.mask(log2_dim)?;
+            leaf.values = r.node_values(log2_dim, &mask, background)?;
+            leaf.mask = mask;
+        }
+        Ok(())
+    }
+
+    //Inclusive index space bounds of the active voxels
+    fn active_bounds(&self) -> Option<([i32; 3], [i32; 3])> {
+        let log2_dim = LEVELS[LEVELS.len() - 1];
+        let tiles = self.tiles.iter().map(|t| (t.origin, t.origin.map(|c| c + t.size - 1)));
+        let voxels = self.leaves.iter().flat_map(|leaf| {
+            (0..values_len(log2_dim)).filter(|&i| bit(&leaf.mask, i)).map(|i| {
+                let offset = child_offset(i, log2_dim);
+                let p = [0, 1, 2].map(|a| leaf.origin[a] + offset[a]);
+                (p, p)
+            })
+        });
+        tiles.chain(voxels).reduce(|(lo, hi), (l, h)| {
+            ([0, 1, 2].map(|a| lo[a].min(l[a])), [0, 1, 2].map(|a| hi[a].max(h[a])))
+        })
+    }
+}
+
+fn values_len(log2_dim: u32) -> usize {
+    1 << (3 * log2_dim)
+}
+
+//Scale and translation from index to world space
+fn read_transform(r: &mut Reader) -> io::Result<([f64; 3], [f64; 3])> {
+    let map = r.string()?;
+    let (scale, translation) = match map.as_str() {
+        "ScaleMap" | "UniformScaleMap" => {
+            let scale = r.vec3d()?;
+            //Voxel size, inverse scale, inverse squared scale and half inverse scale
+            for _ in 0..4 {
+                r.vec3d()?;
+            }
+            (scale, [0.0; 3])
+        }
+        "ScaleTranslateMap" | "UniformScaleTranslateMap" => {
+            let translation = r.vec3d()?;
+            let scale = r.vec3d()?;
+            for _ in 0..4 {
+                r.vec3d()?;
+            }
+            (scale, translation)
+        }
+        "TranslationMap" => ([1.0; 3], r.vec3d()?),
+        "AffineMap" => {
+            //Row major for row vectors, the translation is the last row
+            let mut m = [0.0; 16];
+            for v in m.iter_mut() {
+                *v = r.f64()?;
+            }
+            let scale = [m[0], m[5], m[10]];
+            let off_diagonal = [1, 2, 3, 4, 6, 7, 8, 9, 11];
+            let largest = scale.iter().fold(0.0f64, |a, s| a.max(s.abs()));
+            if off_diagonal.iter().any(|&i| m[i].abs() > 1e-6 * largest) {
+                return Err(invalid("rotated or sheared grids are not supported"));
+            }
+            (scale, [m[12], m[13], m[14]])
+        }
+        _ => return Err(invalid(&format!("unsupported transform {}", map))),
+    };
+    if scale.iter().any(|s| !(*s > 0.0 && s.is_finite())) || translation.iter().any(|t| !t.is_finite()) {
+        return Err(invalid("voxel size must be positive"));
+    }
+    Ok((scale, translation))
+}
+
+fn read_grid(r: &mut Reader) -> io::Result<VoxelGrid> {
+    r.compression = r.u32()?;
+    for (name, kind, value) in r.metadata()? {
+        if name == "class" && kind == "string" && value == b"level set" {
+            return Err(invalid("level sets are not density grids"));
+        }
+    }
+    let (scale, translation) = read_transform(r)?;
+
+    let _buffer_count = r.i32()?;
+    let background = r.f32()?;
+    let tile_count = r.u32()?;
+    let child_count = r.u32()?;
+    let mut tree = Tree::default();
+    let root_child_size = 1 << LEVELS.iter().sum::<u32>();
+    for _ in 0..tile_count {
+        let origin = r.coord()?;
+        let value = r.f32()?;
+        let active = r.bytes(1)?[0] != 0;
+        if active {
+            tree.tiles.push(Tile { origin, size: root_child_size, value });
+        }
+    }
+    for _ in 0..child_count {
+        let origin = r.coord()?;
+        tree.read_topology(r, 0, origin, background)?;
+    }
+    tree.read_buffers(r, background)?;
+
+    let (lo, hi) = tree.active_bounds().ok_or_else(|| invalid("grid has no active voxels"))?;
+    let [nx, ny, nz] = [0, 1, 2].map(|a| (hi[a] as i64 - lo[a] as i64 + 1) as usize);
+    if nx.saturating_mul(ny).saturating_mul(nz) > MAX_VOXELS {
+        return Err(invalid("active voxels span too large a box to densify"));
+    }
+
+    //Densities are extinction, negative values would break delta tracking
+    let mut data = vec![background.max(0.0); nx * ny * nz];
+    let mut fill = |p: [i32; 3], value: fVec| {
+        if (0..3).all(|a| p[a] >= lo[a] && p[a] <= hi[a]) {
+            let [x, y, z] = [0, 1, 2].map(|a| (p[a] - lo[a]) as usize);
+            data[(z * ny + y) * nx + x] = value.max(0.0);
+        }
+    };
+    for tile in tree.tiles.iter() {
+        //Clipped to the bounds, which root tiles of 4096 voxels can exceed by far
+        let start = [0, 1, 2].map(|a| tile.origin[a].max(lo[a]));
+        let end = [0, 1, 2].map(|a| (tile.origin[a] + tile.size - 1).min(hi[a]));
+        for x in start[0]..=end[0] {
+            for y in start[1]..=end[1] {
+                for z in start[2]..=end[2] {
+                    fill([x, y, z], tile.value);
+                }
+            }
+        }
+    }
+    let leaf_log2 = LEVELS[LEVELS.len() - 1];
+    for leaf in tree.leaves.iter() {
+        for (i, value) in leaf.values.iter().enumerate() {
+            let offset = child_offset(i, leaf_log2);
+            fill([0, 1, 2].map(|a| leaf.origin[a] + offset[a]), *value);
+        }
+    }
+
+    //Voxel centers are at integer index coordinates
+    let world = |index: fVec, a: usize| (index as f64 * scale[a] + translation[a]) as fVec;
+    Ok(VoxelGrid {
+        min: Vec3::new(world(lo[0] as fVec - 0.5, 0), world(lo[1] as fVec - 0.5, 1), world(lo[2] as fVec - 0.5, 2)),
+        max: Vec3::new(world(hi[0] as fVec + 0.5, 0), world(hi[1] as fVec + 0.5, 1), world(hi[2] as fVec + 0.5, 2)),
+        resolution: (nx, ny, nz),
+        data,
+    })
+}
+
+//Density grid of a VDB file, the grid of the given name or else the one named "density", or the
+//first float grid if there is none
+pub fn load_vdb(path: impl AsRef<Path>, grid: Option<&str>) -> io::Result<VoxelGrid> {
+    parse_vdb(&fs::read(path)?, grid)
+}
+
+pub fn parse_vdb(data: &[u8], grid: Option<&str>) -> io::Result<VoxelGrid> {
+    let mut r = Reader { data, pos: 0, compression: 0, half: false };
+    if r.i64()? != MAGIC {
+        return Err(invalid("not a VDB file"));
+    }
+    let version = r.u32()?;
+    if version < MIN_VERSION {
+        return Err(invalid(&format!("file format {} is too old, at least {} is supported", version, MIN_VERSION)));
+    }
+    let _library_version = (r.u32()?, r.u32()?);
+    if r.bytes(1)?[0] == 0 {
+        return Err(invalid("streamed files without grid offsets are not supported"));
+    }
+    let _uuid = r.bytes(36)?;
+    r.metadata()?;
+
+    //Unique names of duplicates carry a suffix after a record separator
+    let mut grids = Vec::new();
+    for _ in 0..r.i32()? {
+        let name = r.string()?;
+        let name = name.split('\u{1e}').next().unwrap_or("").to_string();
+        let kind = r.string()?;
+        let _instance_parent = r.string()?;
+        let grid_pos = r.i64()?;
+        let _block_pos = r.i64()?;
+        let end_pos = r.i64()?;
+        grids.push((name, kind, grid_pos));
+        r.pos = usize::try_from(end_pos).map_err(|_| invalid("bad grid offset"))?;
+    }
+
+    let float_grid = |kind: &str| kind == "Tree_float_5_4_3" || kind == "Tree_float_5_4_3_HalfFloat";
+    let chosen = match grid {
+        Some(name) => grids.iter().find(|(n, _, _)| n == name),
+        None => grids.iter().find(|(n, k, _)| n == "density" && float_grid(k)).or_else(|| grids.iter().find(|(_, k, _)| float_grid(k))),
+    };
+    let Some((name, kind, grid_pos)) = chosen else {
+        let names: Vec<&str> = grids.iter().map(|(n, _, _)| n.as_str()).collect();
+        return Err(invalid(&format!("no such float grid, the file has [{}]", names.join(", "))));
+    };
+    if !float_grid(kind) {
+        return Err(invalid(&format!("grid {} is a {}, only float grids are supported", name, kind)));
+    }
+    r.half = kind.ends_with("_HalfFloat");
+    r.pos = usize::try_from(*grid_pos).map_err(|_| invalid("bad grid offset"))?;
+    read_grid(&mut r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::volume::Density;
+
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend((s.len() as u32).to_le_bytes());
+        out.extend(s.as_bytes());
+    }
+
+    fn mask(out: &mut Vec<u8>, log2_dim: u32, bits: &[usize]) {
+        let mut words = vec![0u64; 1 << (3 * log2_dim - 6)];
+        for &i in bits {
+            words[i >> 6] |= 1 << (i & 63);
+        }
+        out.extend(words.iter().flat_map(|w| w.to_le_bytes()));
+    }
+
+    //Mask compressed values: no inactive values stored, the active ones in order
+    fn values(out: &mut Vec<u8>, active: &[f32]) {
+        out.push(0);
+        out.extend(active.iter().flat_map(|v| v.to_le_bytes()));
+    }
+
+    //Fog volume with voxel size 0.5: one active 8^3 tile at x -16..-9 and a leaf at x -8..-1
+    //with two active voxels. Nodes are placed left of the origin to exercise negative offsets.
+    fn fog_volume() -> Vec<u8> {
+        let mut grid = Vec::new();
+        grid.extend(COMPRESS_ACTIVE_MASK.to_le_bytes());
+        grid.extend(1u32.to_le_bytes());
+        string(&mut grid, "class");
+        string(&mut grid, "string");
+        string(&mut grid, "fog volume");
+        string(&mut grid, "UniformScaleMap");
+        for v in [0.5, 0.5, 2.0, 4.0, 1.0] {
+            grid.extend([v, v, v].iter().flat_map(|c: &f64| c.to_le_bytes()));
+        }
+        grid.extend(1i32.to_le_bytes());
+        grid.extend(0f32.to_le_bytes());
+        grid.extend(0u32.to_le_bytes());
+        grid.extend(1u32.to_le_bytes());
+        grid.extend([-4096i32, 0, 0].iter().flat_map(|c| c.to_le_bytes()));
+        //Level 5 child at x slot 31, origin -128
+        mask(&mut grid, 5, &[31 << 10]);
+        mask(&mut grid, 5, &[]);
+        values(&mut grid, &[]);
+        //Level 4 tile at x slot 14, origin -16, and leaf at slot 15, origin -8
+        mask(&mut grid, 4, &[15 << 8]);
+        mask(&mut grid, 4, &[14 << 8]);
+        values(&mut grid, &[0.25]);
+        let leaf = [0, 7 << 6];
+        mask(&mut grid, 3, &leaf);
+        mask(&mut grid, 3, &leaf);
+        values(&mut grid, &[0.5, 1.0]);
+
+        let mut file = Vec::new();
+        file.extend(MAGIC.to_le_bytes());
+        file.extend(224u32.to_le_bytes());
+        file.extend([10u32, 0].iter().flat_map(|v| v.to_le_bytes()));
+        file.push(1);
+        file.extend(b"00000000-0000-0000-0000-000000000000");
+        file.extend(0u32.to_le_bytes());
+        file.extend(1i32.to_le_bytes());
+        string(&mut file, "density");
+        string(&mut file, "Tree_float_5_4_3");
+        string(&mut file, "");
+        let grid_pos = file.len() as i64 + 24;
+        let end_pos = grid_pos + grid.len() as i64;
+        file.extend([grid_pos, grid_pos, end_pos].iter().flat_map(|p| p.to_le_bytes()));
+        file.extend(grid);
+        file
+    }
+
+    #[test]
+    fn reads_tiles_and_leaves() {
+        let file = fog_volume();
+        let grid = parse_vdb(&file, None).unwrap();
+        assert_eq!(grid.resolution, (16, 8, 8));
+        assert!((grid.min - Vec3::new(-8.25, -0.25, -0.25)).length() < 1e-6);
+        assert!((grid.max - Vec3::new(-0.25, 3.75, 3.75)).length() < 1e-6);
+        //Voxel centers at index * 0.5
+        let at = |x, y, z| grid.density(Vec3::new(x, y, z));
+        assert!((at(-0.5, 0.0, 0.0) - 1.0).abs() < 1e-4);
+        assert!((at(-4.0, 0.0, 0.0) - 0.5).abs() < 1e-4);
+        assert!(at(-2.0, 0.0, 0.0).abs() < 1e-4);
+        assert!((at(-6.0, 1.5, 1.5) - 0.25).abs() < 1e-4);
+        assert_eq!(grid.max_density(), 1.0);
+
+        assert!(parse_vdb(&file, Some("temperature")).is_err());
+        assert!(parse_vdb(&file[..file.len() - 4], None).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+use std::cell::RefCell;
+use std::f32::consts::PI;
+use std::ops::DerefMut;
+use std::rc::Rc;
+
+use rand::{Rng, RngCore};
+
+use crate::geom::*;
+use crate::image::*;
+use crate::linalg::*;
+use crate::tracer::*;
+
+//Density of a participating medium in extinction per unit length
+pub trait Density {
+    fn density(&self, p: Vec3) -> fVec;
+    //Upper bound of density() inside the volume, used for delta tracking
+    fn max_density(&self) -> fVec;
+}
+
+pub struct ConstantDensity {
+    pub density: fVec,
+}
+
+impl Density for ConstantDensity {
+    fn density(&self, _p: Vec3) -> fVec {
+        self.density
+    }
+
+    fn max_density(&self) -> fVec {
+        self.density
+    }
+}
+
+//Atmosphere thinning out exponentially with height above base
+pub struct ExponentialDensity {
+    pub density: fVec,
+    pub base: fVec,
+    pub scale_height: fVec,
+}
+
+impl Density for ExponentialDensity {
+    fn density(&self, p: Vec3) -> fVec {
+        self.density * (-(p.y - self.base).max(0.0) / self.scale_height).exp()
+    }
+
+    fn max_density(&self) -> fVec {
+        self.density
+    }
+}
+
+//Densities on a regular grid spanning min to max, trilinearly interpolated
+pub struct VoxelGrid {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub resolution: (usize, usize, usize),
+    //x varies fastest, then y, then z
+    pub data: Vec<fVec>,
+}
+
+impl VoxelGrid {
+    #[inline]
+    fn at(&self, x: usize, y: usize, z: usize) -> fVec {
+        let (nx, ny, _) = self.resolution;
+        self.data[(z * ny + y) * nx + x]
+    }
+}
+
+impl Density for VoxelGrid {
+    fn density(&self, p: Vec3) -> fVec {
+        let (nx, ny, nz) = self.resolution;
+        let extent = self.max - self.min;
+        let rel = p - self.min;
+        let coords = [
+            (rel.x / extent.x, nx),
+            (rel.y / extent.y, ny),
+            (rel.z / extent.z, nz),
+        ];
+        if coords.iter().any(|(c, _)| !(0.0..=1.0).contains(c)) {
+            return 0.0;
+        }
+
+        let mut idx = [(0, 0, 0.0); 3];
+        for (i, (c, n)) in coords.iter().enumerate() {
+            let f = (c * *n as fVec - 0.5).clamp(0.0, (*n - 1) as fVec);
+            let i0 = f.floor() as usize;
+            idx[i] = (i0, (i0 + 1).min(n - 1), f - i0 as fVec);
+        }
+        let [(x0, x1, tx), (y0, y1, ty), (z0, z1, tz)] = idx;
+
+        let lerp = |a: fVec, b: fVec, t: fVec| a * (1.0 - t) + b * t;
+        let c00 = lerp(self.at(x0, y0, z0), self.at(x1, y0, z0), tx);
+        let c10 = lerp(self.at(x0, y1, z0), self.at(x1, y1, z0), tx);
+        let c01 = lerp(self.at(x0, y0, z1), self.at(x1, y0, z1), tx);
+        let c11 = lerp(self.at(x0, y1, z1), self.at(x1, y1, z1), tx);
+        lerp(lerp(c00, c10, ty), lerp(c01, c11, ty), tz)
+    }
+
+    fn max_density(&self) -> fVec {
+        self.data.iter().cloned().fold(0.0, fVec::max)
+    }
+}
+
+//Participating medium filling the inside of a closed boundary object.
+//Rays passing through are stopped at a distance sampled from the density (delta tracking),
+//the scattering at that point is described by the material, usually a PhaseMaterial.
+pub struct Volume {
+    pub boundary: Box<dyn Hit>,
+    pub density: Box<dyn Density>,
+    pub material: Rc<dyn Material>,
+    pub rng: Box<RefCell<dyn RngCore>>,
+}
+
+//Shadow rays through less majorant optical depth than this are rouletted, through more they are split
+const THIN_OPTICAL_DEPTH: fVec = 0.1;
+const DENSE_OPTICAL_DEPTH: fVec = 1.0;
+const MAX_SPLIT: usize = 8;
+
+impl Volume {
+    //Part of the ray inside the boundary
+    fn segment(&self, ray: &Ray) -> Option<(fVec, fVec)> {
+        let mut probe = *ray;
+        probe.min = fVec::NEG_INFINITY;
+        probe.max = fVec::INFINITY;
+        let entry = self.boundary.hit(&probe)?;
+        probe.min = entry.at + 0.0001;
+        let exit = self.boundary.hit(&probe)?;
+
+        let start = entry.at.max(ray.min);
+        let end = exit.at.min(ray.max);
+        if start >= end {
+            None
+        } else {
+            Some((start, end))
+        }
+    }
+
+    //Unbiased transmittance estimate by ratio tracking, with roulette once it gets small
+    fn ratio_tracking(&self, ray: &Ray, start: fVec, end: fVec, rng: &mut (impl RngCore + ?Sized)) -> fVec {
+        let max_density = self.density.max_density();
+        let speed = ray.direction.length();
+        let mut transmittance = 1.0;
+        let mut t = start;
+        loop {
+            let u: fVec = rng.gen_range(0.0..1.0);
+            t -= (1.0 - u).ln() / (max_density * speed);
+            if t >= end {
+                return transmittance;
+            }
+            transmittance *= 1.0 - self.density.density(ray.at(t)) / max_density;
+            if transmittance < 0.1 {
+                if rng.gen_range(0.0..1.0) < 0.5 {
+                    return 0.0;
+                }
+                transmittance *= 2.0;
+            }
+        }
+    }
+}
+
+impl Hit for Volume {
+    fn hit(&self, ray: &Ray) -> Option<HitResult> {
+        let (start, end) = self.segment(ray)?;
+
+        let max_density = self.density.max_density();
+        if max_density <= 0.0 {
+            return None;
+        }
+        let speed = ray.direction.length();
+        let mut rng = self.rng.borrow_mut();
+        let rng = rng.deref_mut();
+
+        let mut t = start;
+        loop {
+            let u: fVec = rng.gen_range(0.0..1.0);
+            t -= (1.0 - u).ln() / (max_density * speed);
+            if t >= end {
+                return None;
+            }
+            let p = ray.at(t);
+            if rng.gen_range(0.0..1.0) * max_density < self.density.density(p) {
+                return Some(HitResult {
+                    intersect: p,
+                    normal: -ray.direction.unit(),
+                    tangent: Vec3::origin(),
+                    uv: (0.0, 0.0),
+                    uv_width: 0.0,
+                    face: 0,
+                    at: t,
+                });
+            }
+        }
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    //Effort follows the majorant optical depth: thin segments are only tracked with probability
+    //proportional to it, dense ones average several estimates. The thin estimate can come out
+    //negative, callers must not clamp it or shadows through thin media end up too bright.
+    fn transmittance(&self, ray: &Ray) -> fVec {
+        let (start, end) = match self.segment(ray) {
+            Some(s) => s,
+            None => return 1.0,
+        };
+        let max_density = self.density.max_density();
+        if max_density <= 0.0 {
+            return 1.0;
+        }
+        let depth = max_density * (end - start) * ray.direction.length();
+        let mut rng = self.rng.borrow_mut();
+        let rng = rng.deref_mut();
+
+        if depth < THIN_OPTICAL_DEPTH {
+            //Roulette on the attenuation 1 - T, which is at most the optical depth. Unbiased only
+            //as a signed estimate, 1 - (1 - T) / p is below 0 for small p and dense spots.
+            let p = depth / THIN_OPTICAL_DEPTH;
+            if rng.gen_range(0.0..1.0) >= p {
+                return 1.0;
+            }
+            return 1.0 - (1.0 - self.ratio_tracking(ray, start, end, rng)) / p;
+        }
+
+        let n = if depth > DENSE_OPTICAL_DEPTH {
+            (depth.ceil() as usize).min(MAX_SPLIT)
+        } else {
+            1
+        };
+        (0..n).map(|_| self.ratio_tracking(ray, start, end, rng)).sum::<fVec>() / n as fVec
+    }
+
+    fn prepare(&mut self) {
+        self.boundary.prepare();
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.boundary.bounds()
+    }
+}
+
+//Henyey-Greenstein phase function, g > 0 scatters forward, g < 0 backward
+pub struct PhaseMaterial {
+    pub albedo: Color,
+    pub g: fVec,
+    pub rng: Box<RefCell<dyn RngCore>>,
+}
+
+impl PhaseMaterial {
+    fn phase(&self, cos: fVec) -> fVec {
+        let g = self.g;
+        let denom = 1.0 + g * g - 2.0 * g * cos;
+        (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+    }
+}
+
+impl Material for PhaseMaterial {
+    fn bounce(&self, ray: &Ray, hit: &HitResult) -> (Color, Option<Ray>) {
+        let mut rng = self.rng.borrow_mut();
+        let u: fVec = rng.gen_range(0.0..1.0);
+        let v: fVec = rng.gen_range(0.0..1.0);
+
+        let g = self.g;
+        let cos_theta = if g.abs() < 0.001 {
+            1.0 - 2.0 * u
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+            (1.0 + g * g - s * s) / (2.0 * g)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * v;
+
+        let forward = ray.direction.unit();
+        let (tangent, bitangent) = forward.basis();
+        let dir = forward * cos_theta + (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta;
+
+        (self.albedo, Some(Ray::new(hit.intersect, dir)))
+    }
+
+    fn eval(&self, ray: &Ray, _hit: &HitResult, light_dir: Vec3) -> Color {
+        self.albedo * self.phase(ray.direction.unit() * light_dir)
+    }
+
+    fn pdf(&self, ray: &Ray, _hit: &HitResult, dir: Vec3) -> fVec {
+        self.phase(ray.direction.unit() * dir.unit())
+    }
+    fn albedo(&self, _ray: &Ray, _hit: &HitResult) -> Color {
+        self.albedo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use super::*;
+    use crate::hit::Sphere;
+
+    fn fog(density: fVec) -> Volume {
+        let rng = || Box::new(RefCell::new(SmallRng::seed_from_u64(7)));
+        let material = Rc::new(PhaseMaterial {
+            albedo: Color::new(1.0, 1.0, 1.0),
+            g: 0.0,
+            rng: rng(),
+        });
+        Volume {
+            boundary: Box::new(Sphere {
+                origin: Vec3::origin(),
+                radius: 1.0,
+                material: material.clone(),
+            }),
+            density: Box::new(ConstantDensity { density }),
+            material,
+            rng: rng(),
+        }
+    }
+
+    #[test]
+    fn transmittance_is_unbiased() {
+        //Thin enough for roulette, tracked once and split
+        for density in [0.01, 0.04, 0.3, 2.0] {
+            let volume = fog(density);
+            let ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+            let n = 200_000;
+            let mean = (0..n).map(|_| volume.transmittance(&ray) as f64).sum::<f64>() / n as f64;
+            let expected = (-2.0 * density as f64).exp();
+            assert!((mean - expected).abs() < 0.005, "density {}: {} instead of {}", density, mean, expected);
+        }
+    }
+}